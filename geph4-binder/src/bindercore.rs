@@ -1,4 +1,7 @@
-use binder_transport::{BinderError, BridgeDescriptor, ExitDescriptor, SubscriptionInfo, UserInfo};
+use binder_transport::{
+    BinderError, BridgeDescriptor, ExitDescriptor, ReachabilityReport, SignedBridgeDescriptor,
+    SignedExitList, SubscriptionInfo, UserInfo,
+};
 
 use native_tls::{Certificate, TlsConnector};
 
@@ -7,7 +10,7 @@ use postgres_native_tls::MakeTlsConnector;
 use r2d2_postgres::PostgresConnectionManager;
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     convert::TryFrom,
     convert::TryInto,
     ffi::{CStr, CString},
@@ -21,8 +24,158 @@ pub struct BinderCore {
     captcha_service: String,
     mizaru_sk: Mutex<HashMap<String, mizaru::SecretKey>>,
     conn_pool: r2d2::Pool<PostgresConnectionManager<postgres_native_tls::MakeTlsConnector>>,
+    exit_loads: Mutex<HashMap<String, (u32, SystemTime)>>,
+    control_addrs: Mutex<HashMap<String, (SocketAddr, SystemTime)>>,
+    reachability: Mutex<Reachability>,
+    route_signatures: Mutex<HashMap<(String, SocketAddr), RouteSignature>>,
+}
+
+/// The latest signed announcement of a bridge route, keyed by exit hostname and bridge address. Like loads, these are only kept in memory, since routes expire within minutes anyway. Routes announced to another binder instance have no signature here until they're announced to this one.
+#[derive(Debug, Clone)]
+struct RouteSignature {
+    sosistab_pubkey: x25519_dalek::PublicKey,
+    bridge_group: String,
+    update_time: u64,
+    exit_signature: ed25519_dalek::Signature,
+}
+
+/// How long route signatures are kept. Routes expire from the database after two minutes.
+const ROUTE_SIGNATURE_WINDOW: Duration = Duration::from_secs(120);
+
+/// How long a load report counts for. Exits report every minute.
+const LOAD_REPORT_WINDOW: Duration = Duration::from_secs(180);
+
+/// How far in the future an exit's clock may put its load reports.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// Region, exit hostname, bridge and whether TCP was used.
+type ReachabilityKey = (String, String, Option<SocketAddr>, bool);
+
+/// Successes and failures reported, since the given time.
+#[derive(Debug, Clone, Copy)]
+struct ReachabilityCounts {
+    successes: u32,
+    failures: u32,
+    since: SystemTime,
 }
 
+/// What clients reported about reaching exits, over the last REACHABILITY_WINDOW.
+#[derive(Default)]
+struct Reachability {
+    counts: HashMap<ReachabilityKey, ReachabilityCounts>,
+    /// The counts summed over regions and transports, by exit hostname and bridge, so that telling whether a bridge seems dead doesn't go through every report.
+    by_bridge: HashMap<(String, SocketAddr), (u32, u32)>,
+    /// How many reports each reporter sent, by the hash of its token, since the given time.
+    reporters: HashMap<[u8; 32], (usize, SystemTime)>,
+    last_pruned: Option<SystemTime>,
+}
+
+impl Reachability {
+    /// Forgets reports older than REACHABILITY_WINDOW. Only does anything once every REACHABILITY_PRUNE_INTERVAL, since it goes through everything.
+    fn prune(&mut self, now: SystemTime) {
+        if let Some(last_pruned) = self.last_pruned {
+            if now.duration_since(last_pruned).unwrap_or_default() < REACHABILITY_PRUNE_INTERVAL {
+                return;
+            }
+        }
+        self.last_pruned = Some(now);
+        let expired = |since: SystemTime| {
+            now.duration_since(since).unwrap_or_default() >= REACHABILITY_WINDOW
+        };
+        let by_bridge = &mut self.by_bridge;
+        self.counts.retain(|(_, exit, bridge, _), counts| {
+            if !expired(counts.since) {
+                return true;
+            }
+            if let Some(bridge) = bridge {
+                let key = (exit.clone(), *bridge);
+                if let Some((successes, failures)) = by_bridge.get_mut(&key) {
+                    *successes = successes.saturating_sub(counts.successes);
+                    *failures = failures.saturating_sub(counts.failures);
+                    if *successes == 0 && *failures == 0 {
+                        by_bridge.remove(&key);
+                    }
+                }
+            }
+            false
+        });
+        self.reporters.retain(|_, (_, since)| !expired(*since));
+    }
+
+    /// Takes a batch of reports from a reporter, unless it already sent too many. Each report counts for at most REACHABILITY_MAX_COUNT successes and failures, so that no single reporter can make a bridge seem dead.
+    fn add(
+        &mut self,
+        now: SystemTime,
+        reporter: [u8; 32],
+        region: &str,
+        reports: &[ReachabilityReport],
+    ) -> Result<(), BinderError> {
+        self.prune(now);
+        let (sent, _) = self.reporters.entry(reporter).or_insert((0, now));
+        if *sent + reports.len() > MAX_REPORTS_PER_REPORTER {
+            return Err(BinderError::Other(
+                "too many reachability reports from this client".into(),
+            ));
+        }
+        *sent += reports.len();
+        for report in reports {
+            let key = (
+                region.to_ascii_lowercase(),
+                report.exit_hostname.clone(),
+                report.bridge,
+                report.use_tcp,
+            );
+            if !self.counts.contains_key(&key) && self.counts.len() >= MAX_REACHABILITY_ENTRIES {
+                continue;
+            }
+            let successes = report.successes.min(REACHABILITY_MAX_COUNT);
+            let failures = report.failures.min(REACHABILITY_MAX_COUNT);
+            let counts = self.counts.entry(key).or_insert(ReachabilityCounts {
+                successes: 0,
+                failures: 0,
+                since: now,
+            });
+            counts.successes = counts.successes.saturating_add(successes);
+            counts.failures = counts.failures.saturating_add(failures);
+            if let Some(bridge) = report.bridge {
+                let totals = self
+                    .by_bridge
+                    .entry((report.exit_hostname.clone(), bridge))
+                    .or_default();
+                totals.0 = totals.0.saturating_add(successes);
+                totals.1 = totals.1.saturating_add(failures);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether clients, from any region, recently reported a bridge to an exit as failing and never as working.
+    fn bridge_seems_dead(&self, exit_hostname: &str, bridge: SocketAddr) -> bool {
+        match self.by_bridge.get(&(exit_hostname.to_string(), bridge)) {
+            Some((successes, failures)) => *successes == 0 && *failures >= DEAD_BRIDGE_FAILURES,
+            None => false,
+        }
+    }
+}
+
+/// How long reachability reports count for.
+const REACHABILITY_WINDOW: Duration = Duration::from_secs(3600);
+
+/// How often expired reachability reports are forgotten.
+const REACHABILITY_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Most distinct reachability entries we keep, to bound memory.
+const MAX_REACHABILITY_ENTRIES: usize = 100_000;
+
+/// Most reports a single reporter may send per REACHABILITY_WINDOW. Clients report every 15 minutes.
+const MAX_REPORTS_PER_REPORTER: usize = 1024;
+
+/// Most successes or failures a single report counts for.
+const REACHABILITY_MAX_COUNT: u32 = 5;
+
+/// Bridges with this many reported failures, and no reported successes, are handed out last. This takes several reporters, since each report counts for at most REACHABILITY_MAX_COUNT.
+const DEAD_BRIDGE_FAILURES: u32 = 10;
+
 impl BinderCore {
     /// Creates a BinderCore.
     pub fn create(database_url: &str, captcha_service_url: &str, cert: &[u8]) -> BinderCore {
@@ -32,15 +185,34 @@ impl BinderCore {
             .unwrap();
         let connector = MakeTlsConnector::new(connector);
         let manager = PostgresConnectionManager::new(database_url.parse().unwrap(), connector);
-        BinderCore {
+        let core = BinderCore {
             captcha_service: captcha_service_url.to_string(),
             mizaru_sk: Mutex::new(HashMap::new()),
+            exit_loads: Mutex::new(HashMap::new()),
+            control_addrs: Mutex::new(HashMap::new()),
+            reachability: Mutex::new(Reachability::default()),
+            route_signatures: Mutex::new(HashMap::new()),
             conn_pool: r2d2::Builder::new()
                 .min_idle(Some(2))
                 .max_size(8)
                 .build(manager)
                 .unwrap(),
-        }
+        };
+        core.migrate()
+            .expect("cannot bring the database schema up to date");
+        core
+    }
+
+    /// Brings the database schema up to date with what this binder expects. Every change here must be safe to run again, since this runs on every start.
+    ///
+    /// - `exits.draining`: operators set this to true to drain an exit, which the exit learns when it next reports its load.
+    fn migrate(&self) -> Result<(), BinderError> {
+        let mut client = self.get_pg_conn()?;
+        client
+            .batch_execute(
+                "alter table exits add column if not exists draining boolean not null default false",
+            )
+            .map_err(|e| BinderError::DatabaseFailed(e.to_string()))
     }
 
     /// Obtains the master x25519 key.
@@ -358,6 +530,12 @@ impl BinderCore {
             );
             return Ok(());
         }
+        let signature = RouteSignature {
+            sosistab_pubkey,
+            bridge_group: bridge_group.to_string(),
+            update_time,
+            exit_signature,
+        };
         let update_time: std::time::SystemTime =
             std::time::UNIX_EPOCH + std::time::Duration::from_secs(update_time);
         let query = "insert into routes (hostname, sosistab_pubkey, bridge_address, bridge_group, update_time) values ($1, $2, $3, $4, $5)";
@@ -379,9 +557,177 @@ impl BinderCore {
         .map_err(|e| BinderError::DatabaseFailed(e.to_string()))?;
         txn.commit()
             .map_err(|e| BinderError::DatabaseFailed(e.to_string()))?;
+        let now = SystemTime::now();
+        let mut signatures = self.route_signatures.lock();
+        signatures.retain(|_, sig| {
+            let signed_at = std::time::UNIX_EPOCH + Duration::from_secs(sig.update_time);
+            now.duration_since(signed_at).unwrap_or_default() < ROUTE_SIGNATURE_WINDOW
+        });
+        signatures.insert((exit_hostname.to_string(), bridge_address), signature);
+        Ok(())
+    }
+
+    /// Records the load reported by an exit. Loads are only kept in memory, since they are useless once stale anyway. Returns whether the exit should drain, which operators ask for by setting the `draining` column of its row in `exits` (see [BinderCore::migrate]).
+    pub fn report_exit_load(
+        &self,
+        exit_hostname: &str,
+        session_count: u32,
+        report_time: u64,
+        exit_signature: ed25519_dalek::Signature,
+    ) -> Result<bool, BinderError> {
+        let mut client = self.get_pg_conn()?;
+        let (signing_key, draining) = {
+            let row = client
+                .query_one(
+                    "select signing_key,coalesce(draining,false) from exits where hostname=$1",
+                    &[&exit_hostname],
+                )
+                .map_err(|e| BinderError::Other(e.to_string()))?;
+            let bts: Vec<u8> = row.get(0);
+            (
+                ed25519_dalek::PublicKey::from_bytes(&bts)
+                    .map_err(|e| BinderError::Other(format!("bad signing key: {}", e)))?,
+                row.try_get::<_, bool>(1)
+                    .map_err(|e| BinderError::DatabaseFailed(e.to_string()))?,
+            )
+        };
+        let message = bincode::serialize(&(exit_hostname, session_count, report_time)).unwrap();
+        if signing_key
+            .verify_strict(&message, &exit_signature)
+            .is_err()
+        {
+            log::warn!(
+                "invalid signature on load report for {}! silently ignoring!",
+                exit_hostname
+            );
+            return Ok(false);
+        }
+        // a signed report can be replayed, so only fresh ones that are newer than what we have count
+        let report_time = std::time::UNIX_EPOCH + Duration::from_secs(report_time);
+        let now = SystemTime::now();
+        if now.duration_since(report_time).unwrap_or_default() > LOAD_REPORT_WINDOW
+            || report_time.duration_since(now).unwrap_or_default() > MAX_CLOCK_SKEW
+        {
+            return Err(BinderError::Other("load report too old or too new".into()));
+        }
+        let mut exit_loads = self.exit_loads.lock();
+        if let Some((_, last_time)) = exit_loads.get(exit_hostname) {
+            if *last_time >= report_time {
+                return Err(BinderError::Other(
+                    "load report not newer than the last".into(),
+                ));
+            }
+        }
+        exit_loads.insert(exit_hostname.to_string(), (session_count, report_time));
+        Ok(draining)
+    }
+
+    /// Records the address an exit reported taking bridge control connections on. Like loads, these are only kept in memory, and exits report them along with their loads.
+    pub fn report_control_addr(
+        &self,
+        exit_hostname: &str,
+        control_addr: SocketAddr,
+        report_time: u64,
+        exit_signature: ed25519_dalek::Signature,
+    ) -> Result<(), BinderError> {
+        let mut client = self.get_pg_conn()?;
+        let signing_key = {
+            let row = client
+                .query_one(
+                    "select signing_key from exits where hostname=$1",
+                    &[&exit_hostname],
+                )
+                .map_err(|e| BinderError::Other(e.to_string()))?;
+            let bts: Vec<u8> = row.get(0);
+            ed25519_dalek::PublicKey::from_bytes(&bts)
+                .map_err(|e| BinderError::Other(format!("bad signing key: {}", e)))?
+        };
+        let message =
+            bincode::serialize(&("control_addr", exit_hostname, control_addr, report_time))
+                .unwrap();
+        if signing_key
+            .verify_strict(&message, &exit_signature)
+            .is_err()
+        {
+            log::warn!(
+                "invalid signature on control address report for {}! silently ignoring!",
+                exit_hostname
+            );
+            return Ok(());
+        }
+        let report_time = std::time::UNIX_EPOCH + Duration::from_secs(report_time);
+        let now = SystemTime::now();
+        if now.duration_since(report_time).unwrap_or_default() > LOAD_REPORT_WINDOW
+            || report_time.duration_since(now).unwrap_or_default() > MAX_CLOCK_SKEW
+        {
+            return Err(BinderError::Other(
+                "control address report too old or too new".into(),
+            ));
+        }
+        let mut control_addrs = self.control_addrs.lock();
+        if let Some((_, last_time)) = control_addrs.get(exit_hostname) {
+            if *last_time >= report_time {
+                return Err(BinderError::Other(
+                    "control address report not newer than the last".into(),
+                ));
+            }
+        }
+        control_addrs.insert(exit_hostname.to_string(), (control_addr, report_time));
         Ok(())
     }
 
+    /// Gets the control address each exit reported, leaving out exits that haven't reported recently. Bridges reach the others at their hostnames.
+    pub fn get_control_addrs(&self) -> BTreeMap<String, SocketAddr> {
+        let now = SystemTime::now();
+        let mut control_addrs = self.control_addrs.lock();
+        control_addrs.retain(|_, (_, time)| {
+            now.duration_since(*time).unwrap_or_default() < LOAD_REPORT_WINDOW
+        });
+        control_addrs
+            .iter()
+            .map(|(hostname, (addr, _))| (hostname.clone(), *addr))
+            .collect()
+    }
+
+    /// Records reachability reports from a client, which must have a valid token. Tokens are blind-signed, so the reports still can't be tied to a user. Like loads, these are only kept in memory, and only for a while.
+    pub fn report_reachability(
+        &self,
+        level: &str,
+        unblinded_digest: &[u8],
+        unblinded_signature: &mizaru::UnblindedSignature,
+        region: &str,
+        reports: &[ReachabilityReport],
+    ) -> Result<(), BinderError> {
+        if region.len() > 8 || !region.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(BinderError::Other("invalid region".into()));
+        }
+        if reports.len() > 256 {
+            return Err(BinderError::Other("too many reachability reports".into()));
+        }
+        if !self.validate(level, unblinded_digest, unblinded_signature)? {
+            return Err(BinderError::NoUserFound);
+        }
+        self.reachability.lock().add(
+            SystemTime::now(),
+            *blake3::hash(unblinded_digest).as_bytes(),
+            region,
+            reports,
+        )
+    }
+
+    /// Gets the number of sessions each exit reported, leaving out exits that haven't reported recently.
+    pub fn get_exit_loads(&self) -> BTreeMap<String, u32> {
+        let now = SystemTime::now();
+        let mut exit_loads = self.exit_loads.lock();
+        exit_loads.retain(|_, (_, time)| {
+            now.duration_since(*time).unwrap_or_default() < LOAD_REPORT_WINDOW
+        });
+        exit_loads
+            .iter()
+            .map(|(hostname, (load, _))| (hostname.clone(), *load))
+            .collect()
+    }
+
     /// Get all exits
     pub fn get_exits(&self, only_free: bool) -> Result<Vec<ExitDescriptor>, BinderError> {
         let mut client = self.get_pg_conn()?;
@@ -415,6 +761,20 @@ impl BinderCore {
         Ok(toret)
     }
 
+    /// Get all exits, signed with the master key, so that clients can check lists that didn't come straight from us.
+    pub fn get_signed_exits(&self, only_free: bool) -> Result<SignedExitList, BinderError> {
+        let exits = self.get_exits(only_free)?;
+        let signed_unixtime = SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        Ok(SignedExitList::sign(
+            exits,
+            signed_unixtime,
+            &self.get_master_sk()?,
+        ))
+    }
+
     /// Get all bridges.
     pub fn get_bridges(
         &self,
@@ -423,6 +783,47 @@ impl BinderCore {
         unblinded_signature: &mizaru::UnblindedSignature,
         exit_hostname: &str,
     ) -> Result<Vec<BridgeDescriptor>, BinderError> {
+        let routes =
+            self.get_bridge_routes(level, unblinded_digest, unblinded_signature, exit_hostname)?;
+        Ok(routes.into_iter().map(|v| v.0).collect())
+    }
+
+    /// Get all bridges, each with the exit's signature over its route. Bridges whose signatures we don't have are left out.
+    pub fn get_signed_bridges(
+        &self,
+        level: &str,
+        unblinded_digest: &[u8],
+        unblinded_signature: &mizaru::UnblindedSignature,
+        exit_hostname: &str,
+    ) -> Result<Vec<SignedBridgeDescriptor>, BinderError> {
+        let routes =
+            self.get_bridge_routes(level, unblinded_digest, unblinded_signature, exit_hostname)?;
+        let signatures = self.route_signatures.lock();
+        Ok(routes
+            .into_iter()
+            .filter_map(|(bridge, group)| {
+                let sig = signatures.get(&(exit_hostname.to_string(), bridge.endpoint))?;
+                if sig.sosistab_pubkey != bridge.sosistab_key || sig.bridge_group != group {
+                    return None;
+                }
+                Some(SignedBridgeDescriptor {
+                    bridge,
+                    bridge_group: group,
+                    route_unixtime: sig.update_time,
+                    exit_signature: sig.exit_signature,
+                })
+            })
+            .collect())
+    }
+
+    /// Get all bridge routes to an exit, along with their bridge groups, in the order they should be handed out.
+    fn get_bridge_routes(
+        &self,
+        level: &str,
+        unblinded_digest: &[u8],
+        unblinded_signature: &mizaru::UnblindedSignature,
+        exit_hostname: &str,
+    ) -> Result<Vec<(BridgeDescriptor, String)>, BinderError> {
         if !self.validate(level, unblinded_digest, unblinded_signature)? {
             return Err(BinderError::NoUserFound);
         }
@@ -461,8 +862,14 @@ impl BinderCore {
             .collect();
         res.sort_by(|a, b| a.1.cmp(&b.1));
         res.dedup_by(|a, b| a.1 == b.1);
+        // bridges that clients say don't work go last, but are still handed out, since they might only be blocked in some regions
+        let reachability = self.reachability.lock();
+        res.sort_by_cached_key(|(desc, _)| {
+            reachability.bridge_seems_dead(exit_hostname, desc.endpoint)
+        });
+        drop(reachability);
         log::debug!("serving out {} bridges", res.len());
-        Ok(res.into_iter().map(|v| v.0).collect())
+        Ok(res)
     }
 }
 
@@ -137,6 +137,45 @@ fn handle_request_once(
             statsd_client.incr("AddBridgeRoute");
             Ok(BinderResponse::Okay)
         }),
+        // report exit load
+        BinderRequestData::ReportExitLoad {
+            exit_hostname,
+            session_count,
+            report_unixtime,
+            exit_signature,
+        } => db_retry(|| {
+            let draining = core.report_exit_load(
+                exit_hostname,
+                *session_count,
+                *report_unixtime,
+                *exit_signature,
+            )?;
+            statsd_client.incr("ReportExitLoad");
+            // exits too old to drain can't decode the new response, so they only get it when there's something to say
+            if draining {
+                Ok(BinderResponse::ReportExitLoadResp { draining })
+            } else {
+                Ok(BinderResponse::Okay)
+            }
+        }),
+        // reachability reports from clients
+        BinderRequestData::ReportReachability {
+            level,
+            unblinded_digest,
+            unblinded_signature,
+            region,
+            reports,
+        } => db_retry(|| {
+            core.report_reachability(
+                level,
+                unblinded_digest,
+                unblinded_signature,
+                region,
+                reports,
+            )?;
+            statsd_client.incr("ReportReachability");
+            Ok(BinderResponse::Okay)
+        }),
         // get exits
         BinderRequestData::GetExits => db_retry(|| {
             let response = core.get_exits(false)?;
@@ -148,6 +187,40 @@ fn handle_request_once(
             statsd_client.incr("GetFreeExits");
             Ok(BinderResponse::GetExitsResp(response))
         }),
+        // get exits, signed
+        BinderRequestData::GetSignedExits { only_free } => db_retry(|| {
+            let response = core.get_signed_exits(*only_free)?;
+            statsd_client.incr("GetSignedExits");
+            Ok(BinderResponse::GetSignedExitsResp(response))
+        }),
+        // get exit loads
+        BinderRequestData::GetExitLoads => db_retry(|| {
+            statsd_client.incr("GetExitLoads");
+            Ok(BinderResponse::GetExitLoadsResp(core.get_exit_loads()))
+        }),
+        // report the control address of an exit
+        BinderRequestData::ReportControlAddr {
+            exit_hostname,
+            control_addr,
+            report_unixtime,
+            exit_signature,
+        } => db_retry(|| {
+            core.report_control_addr(
+                exit_hostname,
+                *control_addr,
+                *report_unixtime,
+                *exit_signature,
+            )?;
+            statsd_client.incr("ReportControlAddr");
+            Ok(BinderResponse::Okay)
+        }),
+        // get control addresses of exits
+        BinderRequestData::GetControlAddrs => db_retry(|| {
+            statsd_client.incr("GetControlAddrs");
+            Ok(BinderResponse::GetControlAddrsResp(
+                core.get_control_addrs(),
+            ))
+        }),
         // get bridges
         BinderRequestData::GetBridges {
             level,
@@ -160,6 +233,22 @@ fn handle_request_once(
             statsd_client.incr("GetBridges");
             Ok(BinderResponse::GetBridgesResp(resp))
         }),
+        // get bridges, with the exits' signatures
+        BinderRequestData::GetSignedBridges {
+            level,
+            unblinded_digest,
+            unblinded_signature,
+            exit_hostname,
+        } => db_retry(|| {
+            let resp = core.get_signed_bridges(
+                level,
+                unblinded_digest,
+                unblinded_signature,
+                exit_hostname,
+            )?;
+            statsd_client.incr("GetSignedBridges");
+            Ok(BinderResponse::GetSignedBridgesResp(resp))
+        }),
     };
     req.respond(res);
     Ok(())
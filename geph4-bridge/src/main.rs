@@ -1,12 +1,16 @@
 use std::{
     collections::HashMap,
     net::{IpAddr, SocketAddr},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
 };
 
+use anyhow::Context;
 use binder_transport::{BinderClient, BinderRequestData, BinderResponse, ExitDescriptor};
 use env_logger::Env;
-use once_cell::sync::Lazy;
+use once_cell::sync::OnceCell;
 use smol::prelude::*;
 use std::time::Duration;
 use structopt::StructOpt;
@@ -17,34 +21,79 @@ struct Opt {
     /// HTTP(S) address of the binder
     binder_http: String,
 
-    #[structopt(
-        long,
-        default_value = "124526f4e692b589511369687498cce57492bf4da20f8d26019c1cc0c80b6e4b"
-    )]
-    /// x25519 master key of the binder
-    binder_master_pk: String,
+    #[structopt(long)]
+    /// x25519 master key of the binder, in hex. Defaults to the key built in at compile time, which is the public binder's unless GEPH_BINDER_MASTER_PK was set then.
+    binder_master_pk: Option<String>,
 
-    /// bridge secret. All bridges and exits know this secret, and it's used to prevent random people from spamming the bridge table.
+    /// file containing the binder's x25519 master key in hex, as an alternative to --binder-master-pk for deployments with their own binder.
     #[structopt(long)]
-    bridge_secret: String,
+    binder_master_pk_file: Option<std::path::PathBuf>,
+
+    /// bridge secret. All bridges and exits know this secret, and it's used to prevent random people from spamming the bridge table. This shows up in process listings, so prefer --bridge-secret-file or the GEPH_BRIDGE_SECRET environment variable.
+    #[structopt(long)]
+    bridge_secret: Option<String>,
+
+    /// file containing the bridge secret, as an alternative to --bridge-secret.
+    #[structopt(long)]
+    bridge_secret_file: Option<std::path::PathBuf>,
 
     /// bridge group.
     #[structopt(long, default_value = "other")]
     bridge_group: String,
+
+    /// before announcing ourselves to an exit again, check that it answers sosistab handshakes over UDP on the port it gave us. While the check fails, we stop announcing, so the exit's route through us expires at the binder instead of being handed to clients who can't use it.
+    #[structopt(long)]
+    verify_routes: bool,
+
+    /// address to serve a plain-text status page on, showing the public address we advertise to exits and how many exits we forward to. Not served if not given.
+    #[structopt(long)]
+    status_listen: Option<SocketAddr>,
+
+    /// TOML or YAML file of flags to use when they're not given on the command line, such as `bridge_group = "other"`.
+    #[structopt(long)]
+    config: Option<std::path::PathBuf>,
 }
 
 fn main() -> anyhow::Result<()> {
     smol::block_on(async move {
-        let opt: Opt = Opt::from_args();
+        let opt: Opt = Opt::from_iter(configfile::args()?);
         env_logger::Builder::from_env(Env::default().default_filter_or("geph4_bridge=info")).init();
+        if let Some(config) = &opt.config {
+            log::info!("using flags from {:?}", config);
+        }
+        let binder_master_pk = x25519_dalek::PublicKey::from(configfile::binder_master_pk(
+            "binder-master-pk",
+            opt.binder_master_pk.as_deref(),
+            opt.binder_master_pk_file.as_deref(),
+        )?);
+        let bridge_secret = configfile::secret(
+            "bridge-secret",
+            opt.bridge_secret.as_deref(),
+            opt.bridge_secret_file.as_deref(),
+            "GEPH_BRIDGE_SECRET",
+        )?;
+        let my_ip = detect_my_ip()
+            .await
+            .context("cannot detect our public address, which exits need to forward to us")?;
+        log::info!("advertising public address {} to exits", my_ip);
+        MY_IP.set(my_ip).unwrap();
+        if let Some(status_listen) = opt.status_listen {
+            smol::spawn(serve_status(status_listen, opt.bridge_group.clone())).detach();
+        }
         run_command("iptables -t nat -F");
         run_command("iptables -t nat -A POSTROUTING -j MASQUERADE");
         let binder_client = Arc::new(binder_transport::HttpClient::new(
-            bincode::deserialize(&hex::decode(opt.binder_master_pk)?)?,
+            binder_master_pk,
             opt.binder_http,
             &[],
         ));
-        bridge_loop(binder_client, &opt.bridge_secret, &opt.bridge_group).await;
+        bridge_loop(
+            binder_client,
+            &bridge_secret,
+            &opt.bridge_group,
+            opt.verify_routes,
+        )
+        .await;
         Ok(())
     })
 }
@@ -56,10 +105,22 @@ async fn bridge_loop<'a>(
     binder_client: Arc<dyn BinderClient>,
     bridge_secret: &'a str,
     bridge_group: &'a str,
+    verify_routes: bool,
 ) {
     let mut current_exits: HashMap<String, smol::Task<anyhow::Result<()>>> = HashMap::new();
+    let control_addrs: ControlAddrs = Default::default();
     loop {
         let binder_client = binder_client.clone();
+        // binders too old to know control addresses fail this, leaving every exit at its hostname
+        match binder_client
+            .request(BinderRequestData::GetControlAddrs)
+            .await
+        {
+            Ok(BinderResponse::GetControlAddrsResp(addrs)) => {
+                *control_addrs.write().unwrap() = addrs.into_iter().collect()
+            }
+            other => log::debug!("cannot get control addresses: {:?}", other),
+        }
         let exits = binder_client.request(BinderRequestData::GetExits).await;
         if let Ok(BinderResponse::GetExitsResp(exits)) = exits {
             log::info!("got {} exits!", exits.len());
@@ -69,22 +130,30 @@ async fn bridge_loop<'a>(
                     log::info!("{} is a new exit, spawning a manager!", exit.hostname);
                     let task = smol::spawn(manage_exit(
                         exit.clone(),
+                        control_addrs.clone(),
                         bridge_secret.to_string(),
                         bridge_group.to_string(),
+                        verify_routes,
                     ));
                     current_exits.insert(exit.hostname, task);
                 }
             }
+            MANAGED_EXITS.store(current_exits.len(), Ordering::Relaxed);
         }
 
         smol::Timer::after(Duration::from_secs(30)).await;
     }
 }
 
+/// Where exits that don't take control connections at their hostnames take them, by hostname, as they told the binder.
+type ControlAddrs = Arc<RwLock<HashMap<String, SocketAddr>>>;
+
 async fn manage_exit(
     exit: ExitDescriptor,
+    control_addrs: ControlAddrs,
     bridge_secret: String,
     bridge_group: String,
+    verify_routes: bool,
 ) -> anyhow::Result<()> {
     let free_socket = std::iter::from_fn(|| Some(fastrand::u32(1000..65536)))
         .find_map(|port| std::net::UdpSocket::bind(format!("[::0]:{}", port)).ok())
@@ -100,9 +169,11 @@ async fn manage_exit(
         loop {
             if let Err(err) = manage_exit_once(
                 &exit,
+                &control_addrs,
                 &bridge_secret,
                 &bridge_group,
                 free_socket.local_addr().unwrap(),
+                verify_routes,
                 &send_routes,
             )
             .await
@@ -140,6 +211,33 @@ async fn manage_exit(
     smol::future::race(manage_fut, route_fut).await
 }
 
+/// Waits until the exit answers sosistab handshakes over UDP on the given port. The exit drops control connections that stay quiet for ten minutes, so if this takes that long, we reconnect and announce ourselves once more.
+async fn verify_route(
+    exit: &ExitDescriptor,
+    port: u16,
+    sosistab_pk: x25519_dalek::PublicKey,
+) -> anyhow::Result<()> {
+    loop {
+        let addr = smol::net::resolve(&format!("{}:{}", exit.hostname, port))
+            .await?
+            .into_iter()
+            .find(|addr| addr.is_ipv4())
+            .ok_or_else(|| anyhow::anyhow!("{} has no IPv4 address", exit.hostname))?;
+        match sosistab::probe_udp(addr, sosistab_pk, 4).await {
+            Ok(rtt) => {
+                log::debug!("route at {} answered in {:?}", exit.hostname, rtt);
+                return Ok(());
+            }
+            Err(err) => log::warn!(
+                "route at {} doesn't work over UDP ({}), not announcing it",
+                exit.hostname,
+                err
+            ),
+        }
+        smol::Timer::after(Duration::from_secs(30)).await;
+    }
+}
+
 fn run_command(s: &str) {
     log::info!("running command {}", s);
     std::process::Command::new("sh")
@@ -149,27 +247,100 @@ fn run_command(s: &str) {
         .unwrap();
 }
 
-static MY_IP: Lazy<IpAddr> = Lazy::new(|| {
-    ureq::get("http://checkip.amazonaws.com/")
-        .call()
-        .into_string()
-        .unwrap()
-        .trim()
-        .to_string()
-        .parse()
-        .unwrap()
-});
+/// Our public address, as detected on startup.
+static MY_IP: OnceCell<IpAddr> = OnceCell::new();
+
+/// How many exits we forward to.
+static MANAGED_EXITS: AtomicUsize = AtomicUsize::new(0);
+
+const MY_IP_URL: &str = "http://checkip.amazonaws.com/";
+const MY_IP_ATTEMPTS: u32 = 5;
+
+/// Asks what our public address is, a few times over with growing waits in between, since bridges often start before their network is fully up.
+async fn detect_my_ip() -> anyhow::Result<IpAddr> {
+    let mut backoff = Duration::from_secs(1);
+    let mut attempt = 1;
+    loop {
+        match smol::unblock(fetch_my_ip).await {
+            Ok(ip) => return Ok(ip),
+            Err(err) if attempt < MY_IP_ATTEMPTS => {
+                log::warn!(
+                    "cannot detect public address (attempt {}/{}): {:#}; retrying in {}s",
+                    attempt,
+                    MY_IP_ATTEMPTS,
+                    err,
+                    backoff.as_secs()
+                );
+                smol::Timer::after(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(err) => return Err(err.context(format!("gave up after {} attempts", attempt))),
+        }
+    }
+}
+
+fn fetch_my_ip() -> anyhow::Result<IpAddr> {
+    let response = ureq::get(MY_IP_URL).timeout(Duration::from_secs(10)).call();
+    if let Some(err) = response.synthetic_error() {
+        anyhow::bail!("cannot reach {}: {}", MY_IP_URL, err)
+    }
+    if !response.ok() {
+        anyhow::bail!("{} answered with status {}", MY_IP_URL, response.status())
+    }
+    let body = response.into_string()?;
+    body.trim().parse().with_context(|| {
+        format!(
+            "{} answered {:?}, which isn't an address",
+            MY_IP_URL,
+            body.trim()
+        )
+    })
+}
+
+/// Serves a plain-text status page to anyone who connects, whatever they ask for.
+async fn serve_status(listen: SocketAddr, bridge_group: String) -> anyhow::Result<()> {
+    let listener = smol::net::TcpListener::bind(listen).await?;
+    log::info!("status page listening on {}", listen);
+    loop {
+        let (mut client, _) = listener.accept().await?;
+        let body = format!(
+            "public_ip {}\nbridge_group {}\nexits {}\n",
+            MY_IP.get().unwrap(),
+            bridge_group,
+            MANAGED_EXITS.load(Ordering::Relaxed)
+        );
+        smol::spawn(async move {
+            // read the request, so that closing doesn't reset the connection before the response gets through
+            let mut request = [0u8; 4096];
+            drop(client.read(&mut request).await);
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            drop(client.write_all(response.as_bytes()).await);
+        })
+        .detach();
+    }
+}
 
 async fn manage_exit_once(
     exit: &ExitDescriptor,
+    control_addrs: &ControlAddrs,
     bridge_secret: &str,
     bridge_group: &str,
     mut my_addr: SocketAddr,
+    verify_routes: bool,
     route_update: &flume::Sender<(u16, x25519_dalek::PublicKey)>,
 ) -> anyhow::Result<()> {
     // get my ip address
-    my_addr.set_ip(*MY_IP);
-    let mut conn = smol::net::TcpStream::connect(&format!("{}:28080", exit.hostname)).await?;
+    my_addr.set_ip(*MY_IP.get().unwrap());
+    let control_addr = control_addrs.read().unwrap().get(&exit.hostname).copied();
+    let mut conn = match control_addr {
+        Some(control_addr) => smol::net::TcpStream::connect(control_addr).await?,
+        None => smol::net::TcpStream::connect(&format!("{}:28080", exit.hostname)).await?,
+    };
     // first read the challenge string
     let mut challenge_string = [0u8; 32];
     conn.read_exact(&mut challenge_string).await?;
@@ -189,6 +360,9 @@ async fn manage_exit_once(
             port,
             hex::encode(sosistab_pk.as_bytes())
         );
+        if verify_routes {
+            verify_route(exit, port, sosistab_pk).await?;
+        }
         // update route
         route_update.send_async((port, sosistab_pk)).await?;
         smol::Timer::after(Duration::from_secs(30)).await;
@@ -1,6 +1,7 @@
 use crate::{AuthOpt, CommonOpt};
 use binder_transport::{
     BinderClient, BinderError, BinderRequestData, BinderResponse, BridgeDescriptor, ExitDescriptor,
+    SignedBridgeDescriptor, SignedExitList,
 };
 
 use rand::prelude::*;
@@ -9,7 +10,9 @@ use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use sha2::Sha256;
 use smol::prelude::*;
 use smol_timeout::TimeoutExt;
-use std::{fmt::Debug, sync::Arc, time::Duration, time::SystemTime};
+use std::{
+    collections::BTreeMap, fmt::Debug, path::Path, sync::Arc, time::Duration, time::SystemTime,
+};
 
 /// An cached client
 pub struct ClientCache {
@@ -18,10 +21,25 @@ pub struct ClientCache {
     binder_client: Arc<dyn BinderClient>,
     free_pk: mizaru::PublicKey,
     plus_pk: mizaru::PublicKey,
+    binder_master: x25519_dalek::PublicKey,
     database: Box<dyn Fn() -> sled::Db + Send + Sync>,
+    sealer: Option<atrest::Sealer>,
     pub force_sync: bool,
 }
 
+/// A snapshot of the cached binder state, which can be carried to somewhere the binder can't be reached and imported there.
+///
+/// Whoever hands over the bundle can't forge anything in it: exit lists are checked against the binder's master key, the auth token against the mizaru keys, and bridges against the signing keys of their exits.
+#[derive(Serialize, Deserialize)]
+struct CacheBundle {
+    binder_master: x25519_dalek::PublicKey,
+    /// Cache entries, keyed without the username, exactly as they're stored.
+    entries: BTreeMap<String, Vec<u8>>,
+}
+
+/// Where the salt for encrypting the cache is kept.
+const SALT_KEY: &str = "atrest.salt";
+
 static NETWORK_TIMEOUT: Duration = Duration::from_secs(120);
 static STALE_TIMEOUT: Duration = Duration::from_secs(3);
 
@@ -32,6 +50,7 @@ impl ClientCache {
         password: &str,
         free_pk: mizaru::PublicKey,
         plus_pk: mizaru::PublicKey,
+        binder_master: x25519_dalek::PublicKey,
         binder_client: Arc<dyn BinderClient>,
         database: Box<dyn Fn() -> sled::Db + Send + Sync>,
     ) -> Self {
@@ -41,14 +60,30 @@ impl ClientCache {
             binder_client,
             free_pk,
             plus_pk,
+            binder_master,
             database,
+            sealer: None,
             force_sync: false,
         }
     }
 
+    /// Encrypts cache entries written from now on with the given sealer, and decrypts encrypted entries with it.
+    pub fn with_sealer(mut self, sealer: atrest::Sealer) -> Self {
+        self.sealer = Some(sealer);
+        self
+    }
+
     /// Create from options
     pub fn from_opts(common: &CommonOpt, auth: &AuthOpt) -> anyhow::Result<Self> {
-        let binder_client = common.to_binder_client();
+        Self::from_opts_with_binder(common, auth, common.to_binder_client()?)
+    }
+
+    /// Create from options, but with the given binder client
+    pub fn from_opts_with_binder(
+        common: &CommonOpt,
+        auth: &AuthOpt,
+        binder_client: Arc<dyn BinderClient>,
+    ) -> anyhow::Result<Self> {
         let credential_cache = auth.credential_cache.clone();
         let database = move || loop {
             match sled::open(credential_cache.clone()) {
@@ -64,17 +99,150 @@ impl ClientCache {
                 Err(e) => panic!(e),
             }
         };
+        let salt = if auth.encrypt_cache {
+            // the salt isn't secret, and is kept with the cache so that the same passphrase always works
+            let db = database();
+            match db.get(SALT_KEY)? {
+                Some(salt) => Some(salt.to_vec()),
+                None => {
+                    let salt = atrest::Sealer::new_salt();
+                    db.insert(SALT_KEY, salt.as_slice())?;
+                    Some(salt)
+                }
+            }
+        } else {
+            None
+        };
         let client_cache = ClientCache::new(
             &auth.username,
             &auth.password,
             common.binder_mizaru_free.clone(),
             common.binder_mizaru_plus.clone(),
+            common.binder_master()?,
             binder_client.clone(),
             Box::new(database),
         );
+        if let Some(salt) = salt {
+            let passphrase = atrest::passphrase("the credential cache")?;
+            return Ok(client_cache.with_sealer(atrest::Sealer::new(&passphrase, &salt)?));
+        }
         Ok(client_cache)
     }
 
+    /// Writes everything cached from the binder into a bundle at the given path.
+    pub fn export(&self, path: &Path) -> anyhow::Result<()> {
+        let db = self.database();
+        let suffix = format!("-{}", self.username);
+        let mut entries = BTreeMap::new();
+        for item in db.scan_prefix("cache.") {
+            let (key, value) = item?;
+            let key = String::from_utf8_lossy(&key);
+            if let Some(key) = key.strip_suffix(&suffix) {
+                // latencies depend on where they were measured, so they're useless elsewhere
+                if key != "cache.exit_latencies" {
+                    // bundles are never encrypted, since the passphrase stays here
+                    if let Some(value) = self.decode(key, &value) {
+                        entries.insert(key.to_string(), value);
+                    }
+                }
+            }
+        }
+        if entries.is_empty() {
+            anyhow::bail!("nothing cached for {} yet", self.username)
+        }
+        let bundle = CacheBundle {
+            binder_master: self.binder_master,
+            entries,
+        };
+        std::fs::write(path, bincode::serialize(&bundle)?)?;
+        log::info!(
+            "exported {} cache entries to {:?}",
+            bundle.entries.len(),
+            path
+        );
+        Ok(())
+    }
+
+    /// Loads a bundle written by [ClientCache::export] into the cache, checking everything that can be checked first. Nothing is imported if anything is wrong.
+    pub fn import(&self, path: &Path) -> anyhow::Result<()> {
+        let bundle: CacheBundle = bincode::deserialize(&std::fs::read(path)?)?;
+        if bundle.binder_master.as_bytes() != self.binder_master.as_bytes() {
+            anyhow::bail!("cache bundle is from a different binder")
+        }
+        for (key, value) in bundle.entries.iter() {
+            match key.as_str() {
+                "cache.auth_token" => {
+                    let (token, _): (Token, u64) = bincode::deserialize(value)?;
+                    let mizaru_pk = if token.level == "plus" {
+                        &self.plus_pk
+                    } else {
+                        &self.free_pk
+                    };
+                    if !mizaru_pk.blind_verify(&token.unblinded_digest, &token.unblinded_signature)
+                    {
+                        anyhow::bail!("cache bundle has a forged auth token")
+                    }
+                }
+                "cache.signed_exits" | "cache.signed_freeexits" => {
+                    let (exits, _): (SignedExitList, u64) = bincode::deserialize(value)?;
+                    if !exits.verify(&self.binder_master) {
+                        anyhow::bail!("cache bundle has a forged exit list")
+                    }
+                }
+                key if key.starts_with("cache.signed_bridges.") => {
+                    let hostname = key.trim_start_matches("cache.signed_bridges.");
+                    let (bridges, _): (Vec<SignedBridgeDescriptor>, u64) =
+                        bincode::deserialize(value)?;
+                    let exit = self.bundled_exit(&bundle, hostname)?.ok_or_else(|| {
+                        anyhow::anyhow!("cache bundle has bridges to unknown exit {}", hostname)
+                    })?;
+                    if !bridges.iter().all(|b| b.verify(&exit.signing_key)) {
+                        anyhow::bail!("cache bundle has a forged bridge to {}", hostname)
+                    }
+                }
+                // bundles from older clients have exits and bridges that can't be checked
+                "cache.exits" | "cache.freeexits" => {}
+                key if key.starts_with("cache.bridges.") => {}
+                other => anyhow::bail!("cache bundle has unknown entry {:?}", other),
+            }
+        }
+        let db = self.database();
+        let mut imported = 0;
+        for (key, value) in bundle.entries.iter() {
+            if key == "cache.exits" || key == "cache.freeexits" || key.starts_with("cache.bridges.")
+            {
+                log::warn!("not importing {}, since it isn't signed", key);
+                continue;
+            }
+            db.insert(self.to_key(key).as_bytes(), self.encode(value))?;
+            imported += 1;
+        }
+        log::info!("imported {} cache entries from {:?}", imported, path);
+        Ok(())
+    }
+
+    /// Finds an exit by hostname in a bundle's exit lists, or failing that, in the cache. Only lists the binder signed are looked in.
+    fn bundled_exit(
+        &self,
+        bundle: &CacheBundle,
+        hostname: &str,
+    ) -> anyhow::Result<Option<ExitDescriptor>> {
+        let mut exits = Vec::new();
+        for key in &["cache.signed_exits", "cache.signed_freeexits"] {
+            let list = match bundle.entries.get(*key) {
+                Some(value) => Some(bincode::deserialize::<(SignedExitList, u64)>(value)?.0),
+                None => self.get_cached_stale::<SignedExitList>(key),
+            };
+            if let Some(list) = list {
+                if !list.verify(&self.binder_master) {
+                    anyhow::bail!("exit list in {} isn't signed by the binder", key)
+                }
+                exits.extend(list.exits);
+            }
+        }
+        Ok(exits.into_iter().find(|exit| exit.hostname == hostname))
+    }
+
     fn get_cached_stale<T: DeserializeOwned + Clone + Debug>(&self, key: &str) -> Option<T> {
         if self.force_sync {
             return None;
@@ -84,10 +252,39 @@ impl ClientCache {
             .database()
             .get(&key.as_bytes())
             .unwrap()
+            .and_then(|v| self.decode(&key, &v))
             .map(|v| bincode::deserialize(&v).unwrap());
         existing.map(|v| v.0)
     }
 
+    /// Decodes a cache entry as stored on disk, decrypting it if it's encrypted. Entries that can't be decrypted are treated as missing, so they get fetched afresh.
+    fn decode(&self, key: &str, stored: &[u8]) -> Option<Vec<u8>> {
+        if !atrest::is_sealed(stored) {
+            return Some(stored.to_vec());
+        }
+        match &self.sealer {
+            Some(sealer) => match sealer.open(stored) {
+                Ok(plain) => Some(plain),
+                Err(err) => {
+                    log::warn!("cannot decrypt {}: {}", key, err);
+                    None
+                }
+            },
+            None => {
+                log::warn!("{} is encrypted, but --encrypt-cache isn't given", key);
+                None
+            }
+        }
+    }
+
+    /// Encodes a cache entry for storing on disk, encrypting it if asked to.
+    fn encode(&self, plain: &[u8]) -> Vec<u8> {
+        match &self.sealer {
+            Some(sealer) => sealer.seal(plain),
+            None => plain.to_vec(),
+        }
+    }
+
     fn database(&self) -> sled::Db {
         (self.database)()
     }
@@ -127,6 +324,7 @@ impl ClientCache {
             .database()
             .get(expanded_key.as_bytes())
             .unwrap()
+            .and_then(|v| self.decode(&expanded_key, &v))
             .map(|v| bincode::deserialize(&v).unwrap());
         if !self.force_sync {
             if let Some((existing, timeout)) = existing {
@@ -152,7 +350,7 @@ impl ClientCache {
         self.database()
             .insert(
                 expanded_key.as_bytes(),
-                bincode::serialize(&(fresh.clone(), deadline)).unwrap(),
+                self.encode(&bincode::serialize(&(fresh.clone(), deadline)).unwrap()),
             )
             .unwrap();
         log::trace!("about to return for {}!", expanded_key);
@@ -170,50 +368,117 @@ impl ClientCache {
         .await
     }
 
-    /// Gets a list of exits.
-    pub async fn get_exits(&self) -> anyhow::Result<Vec<ExitDescriptor>> {
-        self.get_cached_maybe_stale(
-            "cache.exits",
-            self.get_exits_fresh(),
-            Duration::from_secs(3600),
+    /// Obtains a token separate from the one `get_auth_token` returns, so that two exits can't link our sessions by comparing tokens with each other or the binder.
+    pub async fn get_second_hop_token(&self) -> anyhow::Result<Token> {
+        self.get_cached(
+            "cache.second_hop_token",
+            self.get_token_fresh(),
+            Duration::from_secs(86400),
         )
         .await
     }
 
+    /// Gets a list of exits.
+    pub async fn get_exits(&self) -> anyhow::Result<Vec<ExitDescriptor>> {
+        let list: SignedExitList = self
+            .get_cached_maybe_stale(
+                "cache.signed_exits",
+                self.get_exits_fresh(false),
+                Duration::from_secs(3600),
+            )
+            .await?;
+        Ok(list.exits)
+    }
+
     /// Gets a list of free exits.
     pub async fn get_free_exits(&self) -> anyhow::Result<Vec<ExitDescriptor>> {
-        self.get_cached_maybe_stale(
-            "cache.freeexits",
-            self.get_free_exits_fresh(),
-            Duration::from_secs(3600),
+        let list: SignedExitList = self
+            .get_cached_maybe_stale(
+                "cache.signed_freeexits",
+                self.get_exits_fresh(true),
+                Duration::from_secs(3600),
+            )
+            .await?;
+        Ok(list.exits)
+    }
+
+    /// Gets the number of sessions each exit last reported, for exits that reported recently. Loads change quickly, so they're only cached for a minute.
+    pub async fn get_exit_loads(&self) -> anyhow::Result<BTreeMap<String, u32>> {
+        self.get_cached(
+            "cache.exit_loads",
+            self.get_exit_loads_fresh(),
+            Duration::from_secs(60),
         )
         .await
     }
 
-    /// Gets a list of bridges.
-    pub async fn get_bridges(&self, exit_hostname: &str) -> anyhow::Result<Vec<BridgeDescriptor>> {
+    /// Gets the handshake latencies, in milliseconds, to exits. If the cached latencies are too old, the given probe is run to measure them again.
+    pub async fn get_exit_latencies(
+        &self,
+        probe: impl Future<Output = anyhow::Result<BTreeMap<String, f64>>>,
+    ) -> anyhow::Result<BTreeMap<String, f64>> {
+        self.get_cached("cache.exit_latencies", probe, Duration::from_secs(600))
+            .await
+    }
+
+    /// Gets a list of bridges to an exit, in the order the binder suggests. Only bridges whose routes the exit itself signed are returned, so that the binder, or whoever stands in for it, can't slip in bridges of its own.
+    pub async fn get_bridges(
+        &self,
+        exit: &ExitDescriptor,
+    ) -> anyhow::Result<Vec<BridgeDescriptor>> {
         let tok = self.get_auth_token().await?;
         let binder_client = self.binder_client.clone();
-        let exit_hostname = exit_hostname.to_string();
-        self.get_cached_maybe_stale(
-            &format!("cache.bridges.{}", exit_hostname),
-            async {
-                let res = timeout(binder_client.request(BinderRequestData::GetBridges {
-                    level: tok.level,
-                    unblinded_digest: tok.unblinded_digest,
-                    unblinded_signature: tok.unblinded_signature,
-                    exit_hostname,
-                }))
-                .await??;
-                if let BinderResponse::GetBridgesResp(bridges) = res {
-                    Ok(bridges)
-                } else {
-                    anyhow::bail!("invalid response")
-                }
-            },
-            Duration::from_secs(60),
+        let exit_hostname = exit.hostname.clone();
+        let signing_key = exit.signing_key;
+        let bridges: Vec<SignedBridgeDescriptor> = self
+            .get_cached_maybe_stale(
+                &format!("cache.signed_bridges.{}", exit_hostname),
+                async {
+                    let res = timeout(binder_client.request(BinderRequestData::GetSignedBridges {
+                        level: tok.level,
+                        unblinded_digest: tok.unblinded_digest,
+                        unblinded_signature: tok.unblinded_signature,
+                        exit_hostname,
+                    }))
+                    .await??;
+                    if let BinderResponse::GetSignedBridgesResp(bridges) = res {
+                        // a single bad signature means the whole response can't be trusted
+                        if !bridges.iter().all(|b| b.verify(&signing_key)) {
+                            anyhow::bail!("binder gave us bridges the exit didn't sign")
+                        }
+                        Ok(bridges)
+                    } else {
+                        anyhow::bail!("invalid response")
+                    }
+                },
+                Duration::from_secs(60),
+            )
+            .await?;
+        Ok(bridges.into_iter().map(|b| b.bridge).collect())
+    }
+
+    /// Sends reachability reports to the binder, along with our token, which the binder can't tie to the user.
+    pub async fn report_reachability(
+        &self,
+        region: &str,
+        reports: Vec<binder_transport::ReachabilityReport>,
+    ) -> anyhow::Result<()> {
+        let tok = self.get_auth_token().await?;
+        let binder_client = self.binder_client.clone();
+        let res = timeout(
+            binder_client.request(BinderRequestData::ReportReachability {
+                level: tok.level,
+                unblinded_digest: tok.unblinded_digest,
+                unblinded_signature: tok.unblinded_signature,
+                region: region.to_string(),
+                reports,
+            }),
         )
-        .await
+        .await??;
+        match res {
+            BinderResponse::Okay => Ok(()),
+            other => anyhow::bail!("unexpected response {:?}", other),
+        }
     }
 
     async fn get_token_fresh(&self) -> anyhow::Result<Token> {
@@ -274,20 +539,26 @@ impl ClientCache {
         anyhow::bail!("neither plus nor free worked");
     }
 
-    async fn get_exits_fresh(&self) -> anyhow::Result<Vec<ExitDescriptor>> {
+    async fn get_exits_fresh(&self, only_free: bool) -> anyhow::Result<SignedExitList> {
         let binder_client = self.binder_client.clone();
-        let res = timeout(binder_client.request(BinderRequestData::GetExits)).await??;
+        let res = timeout(binder_client.request(BinderRequestData::GetSignedExits { only_free }))
+            .await??;
         match res {
-            binder_transport::BinderResponse::GetExitsResp(exits) => Ok(exits),
+            binder_transport::BinderResponse::GetSignedExitsResp(list) => {
+                if !list.verify(&self.binder_master) {
+                    anyhow::bail!("binder gave us an exit list it didn't sign")
+                }
+                Ok(list)
+            }
             other => anyhow::bail!("unexpected response {:?}", other),
         }
     }
 
-    async fn get_free_exits_fresh(&self) -> anyhow::Result<Vec<ExitDescriptor>> {
+    async fn get_exit_loads_fresh(&self) -> anyhow::Result<BTreeMap<String, u32>> {
         let binder_client = self.binder_client.clone();
-        let res = timeout(binder_client.request(BinderRequestData::GetFreeExits)).await??;
+        let res = timeout(binder_client.request(BinderRequestData::GetExitLoads)).await??;
         match res {
-            binder_transport::BinderResponse::GetExitsResp(exits) => Ok(exits),
+            binder_transport::BinderResponse::GetExitLoadsResp(loads) => Ok(loads),
             other => anyhow::bail!("unexpected response {:?}", other),
         }
     }
@@ -0,0 +1,164 @@
+use crate::kalive::PathTrace;
+use smol::{channel::Sender, io::AsyncRead, stream::Stream};
+use std::{
+    io::Write,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// How many compressed chunks can wait for the HTTP client before compression pauses.
+const PIPE_CHUNKS: usize = 16;
+
+/// Builds the debug pack, a gzipped tar of the sosistab traces and the logs, as an HTTP body. The archive is compressed on a blocking thread while the body is read, so the compressed archive never has to be in memory all at once.
+pub fn debugpack_body(logs: Vec<String>, traces: Vec<PathTrace>) -> http_types::Body {
+    let (send, recv) = smol::channel::bounded(PIPE_CHUNKS);
+    smolscale::spawn(smol::unblock(move || {
+        if let Err(err) = write_debugpack(PipeWriter(send), &logs, &traces) {
+            log::debug!("debug pack cut short: {}", err);
+        }
+    }))
+    .detach();
+    http_types::Body::from_reader(
+        smol::io::BufReader::new(PipeReader {
+            recv,
+            buf: Vec::new(),
+            pos: 0,
+        }),
+        None,
+    )
+}
+
+fn write_debugpack(out: impl Write, logs: &[String], traces: &[PathTrace]) -> std::io::Result<()> {
+    let mut tar_build = tar::Builder::new(flate2::write::GzEncoder::new(
+        out,
+        flate2::Compression::default(),
+    ));
+    // the first path keeps the old file name; any other paths get a file each
+    for (i, trace) in traces.iter().enumerate() {
+        let first_time = match trace.stats.first() {
+            Some(first) => first.time,
+            None => continue,
+        };
+        let rows =
+            std::iter::once("time,last_recv,total_recv,total_loss,send_loss,ping".to_string())
+                .chain(trace.stats.iter().map(move |item| {
+                    format!(
+                        "{},{},{},{},{},{}",
+                        item.time
+                            .duration_since(first_time)
+                            .unwrap_or_default()
+                            .as_secs_f64(),
+                        item.high_recv,
+                        item.total_recv,
+                        item.total_loss,
+                        item.send_loss,
+                        item.ping.as_secs_f64() * 1000.0,
+                    )
+                }));
+        let name = if i == 0 {
+            "sosistab-trace.csv".to_string()
+        } else {
+            format!("sosistab-trace-{}.csv", i)
+        };
+        append_lines(&mut tar_build, &name, rows)?;
+    }
+    append_lines(&mut tar_build, "logs.txt", logs.iter().cloned())?;
+    tar_build.into_inner()?.finish()?.flush()
+}
+
+/// Appends a file made of the given lines. The lines are walked once for the size that goes in the tar header, then again as the file is written.
+fn append_lines<W: Write>(
+    tar_build: &mut tar::Builder<W>,
+    name: &str,
+    lines: impl Iterator<Item = String> + Clone,
+) -> std::io::Result<()> {
+    let size = lines.clone().map(|line| line.len() as u64 + 1).sum();
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(0o666);
+    header.set_size(size);
+    tar_build.append_data(
+        &mut header,
+        name,
+        LinesReader {
+            lines,
+            buf: Vec::new(),
+            pos: 0,
+        },
+    )
+}
+
+/// Reads lines one at a time, each followed by a newline.
+struct LinesReader<I> {
+    lines: I,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<I: Iterator<Item = String>> std::io::Read for LinesReader<I> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.buf.len() {
+            match self.lines.next() {
+                Some(line) => {
+                    self.buf = line.into_bytes();
+                    self.buf.push(b'\n');
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// The blocking end of the pipe, which the compressor writes into.
+struct PipeWriter(Sender<Vec<u8>>);
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        smol::block_on(self.0.send(buf.to_vec())).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "debug pack reader went away",
+            )
+        })?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The async end of the pipe, which the HTTP body reads from.
+struct PipeReader {
+    recv: smol::channel::Receiver<Vec<u8>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl AsyncRead for PipeReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        while this.pos == this.buf.len() {
+            match Pin::new(&mut this.recv).poll_next(cx) {
+                Poll::Ready(Some(chunk)) => {
+                    this.buf = chunk;
+                    this.pos = 0;
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let n = out.len().min(this.buf.len() - this.pos);
+        out[..n].copy_from_slice(&this.buf[this.pos..this.pos + n]);
+        this.pos += n;
+        Poll::Ready(Ok(n))
+    }
+}
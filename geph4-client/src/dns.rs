@@ -1,21 +1,36 @@
-use async_dup::Arc;
+use anyhow::Context;
 use async_net::SocketAddr;
 use async_tls::{client::TlsStream, TlsConnector};
+use parking_lot::Mutex;
 use smol::{
     channel::{Receiver, Sender},
     prelude::*,
 };
 use smol_timeout::TimeoutExt;
 use sosistab::mux::RelConn;
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{kalive::Keepalive, listener::LocalStream};
+
+/// How long a DNS query may wait for its reply.
+const DNS_TIMEOUT: Duration = Duration::from_secs(10);
 
-use crate::kalive::Keepalive;
+/// How many queries may wait for replies on one shared connection.
+const MAX_PENDING_QUERIES: usize = 1024;
 
 /// Handle DNS requests from localhost
-pub async fn dns_loop(addr: SocketAddr, keepalive: Keepalive) -> anyhow::Result<()> {
+pub async fn dns_loop(
+    addr: SocketAddr,
+    keepalive: Keepalive,
+    coalesce_window: Option<Duration>,
+) -> anyhow::Result<()> {
     let socket = smol::net::UdpSocket::bind(addr).await?;
     let mut buf = [0; 2048];
-    let pool = Arc::new(DnsPool::new(keepalive));
+    let pool = Arc::new(DnsPool::new(keepalive, coalesce_window));
     log::debug!("DNS loop started");
     loop {
         let (n, c_addr) = socket.recv_from(&mut buf).await?;
@@ -40,31 +55,42 @@ pub async fn dns_loop(addr: SocketAddr, keepalive: Keepalive) -> anyhow::Result<
     }
 }
 
-/// A DNS connection pool
+/// A DNS connection pool. DNS over TLS is safe to reuse across requests, so near-simultaneous requests can be coalesced onto the same tunneled connection rather than each opening their own.
 pub struct DnsPool {
     send_conn: Sender<TlsStream<RelConn>>,
     recv_conn: Receiver<TlsStream<RelConn>>,
     keepalive: Keepalive,
+    coalesce_window: Option<Duration>,
 }
 
 impl DnsPool {
-    /// Create a new pool based on a Keepalive
-    pub fn new(keepalive: Keepalive) -> Self {
+    /// Create a new pool based on a Keepalive. If a coalescing window is given, requests that find no idle connection wait up to that long for one to be freed before opening a new one.
+    pub fn new(keepalive: Keepalive, coalesce_window: Option<Duration>) -> Self {
         let (send_conn, recv_conn) = smol::channel::unbounded();
         Self {
             send_conn,
             recv_conn,
             keepalive,
+            coalesce_window,
         }
     }
 
+    /// Waits for an idle connection to be returned to the pool, up to the coalescing window.
+    async fn wait_idle(&self) -> Option<TlsStream<RelConn>> {
+        let window = self.coalesce_window?;
+        self.recv_conn.recv().timeout(window).await?.ok()
+    }
+
     /// Do a DNS request.
     pub async fn request(&self, buff: &[u8]) -> Option<Vec<u8>> {
         let dns_timeout = Duration::from_secs(10);
         let mut conn = {
-            let lala = self.recv_conn.try_recv();
+            let lala = match self.recv_conn.try_recv() {
+                Ok(v) => Some(v),
+                _ => self.wait_idle().await,
+            };
             match lala {
-                Ok(v) => v,
+                Some(v) => v,
                 _ => {
                     let tcp_conn = self
                         .keepalive
@@ -99,3 +125,185 @@ impl DnsPool {
         Some(true_buf)
     }
 }
+
+/// Shares tunneled connections to DNS servers among local clients speaking DNS over TCP.
+///
+/// DNS over TCP allows any number of queries on one connection, matched to their replies by ID, so every client of the same server can use one connection through the exit rather than each opening their own. Each query gets an ID of its own on the shared connection, which its reply trades back for the one the client picked.
+#[derive(Default)]
+pub struct DnsCoalescer {
+    upstreams: smol::lock::Mutex<HashMap<String, Arc<DnsUpstream>>>,
+}
+
+impl DnsCoalescer {
+    /// Relays DNS over TCP between a local client and the given server, through the connection shared by every client of that server.
+    pub async fn relay(
+        &self,
+        client: impl LocalStream,
+        server: &str,
+        keepalive: &Keepalive,
+    ) -> anyhow::Result<()> {
+        let (send_reply, recv_reply) = smol::channel::bounded(16);
+        let mut client_up = client.clone();
+        let upload = async move {
+            while let Some(query) = read_message(&mut client_up).await? {
+                self.upstream(server, keepalive)
+                    .await?
+                    .query(query, send_reply.clone())?;
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+        let mut client_down = client;
+        // ends once every query has its reply, since only queries waiting for replies still hold senders
+        let download = async move {
+            while let Ok(reply) = recv_reply.recv().await {
+                write_message(&mut client_down, &reply).await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+        // a client that's done asking still gets the replies to what it asked, for as long as they may take
+        let linger = async {
+            upload.await?;
+            smol::Timer::after(DNS_TIMEOUT).await;
+            Ok(())
+        };
+        download.or(linger).await
+    }
+
+    /// Gets the shared connection to the given server, opening a new one if there's none or it died.
+    async fn upstream(
+        &self,
+        server: &str,
+        keepalive: &Keepalive,
+    ) -> anyhow::Result<Arc<DnsUpstream>> {
+        let mut upstreams = self.upstreams.lock().await;
+        if let Some(upstream) = upstreams.get(server) {
+            if upstream.is_alive() {
+                return Ok(upstream.clone());
+            }
+        }
+        upstreams.retain(|_, upstream| upstream.is_alive());
+        let upstream = Arc::new(DnsUpstream::open(server, keepalive).await?);
+        log::debug!("opened shared DNS connection to {}", server);
+        upstreams.insert(server.to_string(), upstream.clone());
+        Ok(upstream)
+    }
+}
+
+/// A connection to a DNS server shared by its clients, with the queries sent over it that are still waiting for replies, by the IDs they were sent with.
+struct DnsUpstream {
+    send_query: Sender<Vec<u8>>,
+    pending: Arc<Mutex<HashMap<u16, PendingQuery>>>,
+    _task: smol::Task<()>,
+}
+
+struct PendingQuery {
+    client_id: u16,
+    reply: Sender<Vec<u8>>,
+    sent: Instant,
+}
+
+impl DnsUpstream {
+    async fn open(server: &str, keepalive: &Keepalive) -> anyhow::Result<Self> {
+        let conn = keepalive
+            .connect(server)
+            .timeout(DNS_TIMEOUT)
+            .await
+            .context("timed out connecting to DNS server")??;
+        let (send_query, recv_query) = smol::channel::bounded::<Vec<u8>>(64);
+        let pending: Arc<Mutex<HashMap<u16, PendingQuery>>> = Default::default();
+        let server = server.to_string();
+        let _task = smolscale::spawn({
+            let pending = pending.clone();
+            async move {
+                let mut conn_up = conn.clone();
+                let write_queries = async move {
+                    while let Ok(query) = recv_query.recv().await {
+                        write_message(&mut conn_up, &query).await?;
+                    }
+                    Ok::<_, anyhow::Error>(())
+                };
+                let mut conn_down = conn;
+                let read_replies = async {
+                    while let Some(mut reply) = read_message(&mut conn_down).await? {
+                        if reply.len() < 2 {
+                            continue;
+                        }
+                        let id = u16::from_be_bytes([reply[0], reply[1]]);
+                        if let Some(query) = pending.lock().remove(&id) {
+                            reply[..2].copy_from_slice(&query.client_id.to_be_bytes());
+                            drop(query.reply.try_send(reply));
+                        }
+                    }
+                    Ok::<_, anyhow::Error>(())
+                };
+                if let Err(err) = write_queries.or(read_replies).await {
+                    log::debug!("shared DNS connection to {} failed: {}", server, err)
+                }
+                // nothing is coming back for whatever is still waiting
+                pending.lock().clear();
+            }
+        });
+        Ok(DnsUpstream {
+            send_query,
+            pending,
+            _task,
+        })
+    }
+
+    fn is_alive(&self) -> bool {
+        !self.send_query.is_closed()
+    }
+
+    /// Sends a query under an ID of its own, to have its reply sent to the given channel.
+    fn query(&self, mut query: Vec<u8>, reply: Sender<Vec<u8>>) -> anyhow::Result<()> {
+        anyhow::ensure!(query.len() >= 2, "DNS message too short");
+        let id = {
+            let mut pending = self.pending.lock();
+            pending.retain(|_, query| query.sent.elapsed() < DNS_TIMEOUT);
+            anyhow::ensure!(
+                pending.len() < MAX_PENDING_QUERIES,
+                "too many DNS queries waiting for replies"
+            );
+            let id = std::iter::repeat_with(rand::random::<u16>)
+                .find(|id| !pending.contains_key(id))
+                .unwrap();
+            pending.insert(
+                id,
+                PendingQuery {
+                    client_id: u16::from_be_bytes([query[0], query[1]]),
+                    reply,
+                    sent: Instant::now(),
+                },
+            );
+            id
+        };
+        query[..2].copy_from_slice(&id.to_be_bytes());
+        self.send_query
+            .try_send(query)
+            .map_err(|_| anyhow::anyhow!("shared DNS connection is dead or too busy"))
+    }
+}
+
+/// Reads a DNS message prefixed with its length, as DNS over TCP sends them, or None if the stream ended between messages.
+async fn read_message(stream: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 2];
+    match stream.read_exact(&mut len).await {
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        res => res?,
+    }
+    let mut message = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut message).await?;
+    Ok(Some(message))
+}
+
+/// Writes a DNS message prefixed with its length.
+async fn write_message(
+    stream: &mut (impl AsyncWrite + Unpin),
+    message: &[u8],
+) -> std::io::Result<()> {
+    let mut framed = Vec::with_capacity(message.len() + 2);
+    framed.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    framed.extend_from_slice(message);
+    stream.write_all(&framed).await?;
+    stream.flush().await
+}
@@ -0,0 +1,73 @@
+use serde::Serialize;
+use smol::io::AsyncRead;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Serializes a JSON array one element at a time, as it's read, so that a large array never has to be in memory as a single string.
+pub struct JsonArrayReader<T> {
+    items: std::vec::IntoIter<T>,
+    buf: Vec<u8>,
+    pos: usize,
+    started: bool,
+    finished: bool,
+}
+
+impl<T: Serialize> JsonArrayReader<T> {
+    pub fn new(items: Vec<T>) -> Self {
+        JsonArrayReader {
+            items: items.into_iter(),
+            buf: Vec::new(),
+            pos: 0,
+            started: false,
+            finished: false,
+        }
+    }
+
+    /// Wraps the reader into an HTTP body.
+    pub fn into_body(self) -> http_types::Body
+    where
+        T: Send + Sync + Unpin + 'static,
+    {
+        http_types::Body::from_reader(smol::io::BufReader::new(self), None)
+    }
+}
+
+impl<T: Serialize + Unpin> AsyncRead for JsonArrayReader<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        out: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        while this.pos == this.buf.len() {
+            if this.finished {
+                return Poll::Ready(Ok(0));
+            }
+            this.buf.clear();
+            this.pos = 0;
+            let first = !this.started;
+            this.started = true;
+            match this.items.next() {
+                Some(item) => {
+                    this.buf.push(if first { b'[' } else { b',' });
+                    if let Err(err) = serde_json::to_writer(&mut this.buf, &item) {
+                        return Poll::Ready(Err(err.into()));
+                    }
+                }
+                None => {
+                    if first {
+                        this.buf.push(b'[');
+                    }
+                    this.buf.push(b']');
+                    this.finished = true;
+                }
+            }
+        }
+        let n = out.len().min(this.buf.len() - this.pos);
+        out[..n].copy_from_slice(&this.buf[this.pos..this.pos + n]);
+        this.pos += n;
+        Poll::Ready(Ok(n))
+    }
+}
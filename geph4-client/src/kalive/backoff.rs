@@ -0,0 +1,54 @@
+use std::time::Duration;
+
+/// How long to wait before each reconnect. The wait grows by a constant factor after every failure in a row, up to a maximum, and starts over once a connection works.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    multiplier: f64,
+    jitter: f64,
+    max_attempts: u32,
+    attempts: u32,
+}
+
+impl Backoff {
+    /// Creates a backoff. The jitter is the fraction by which each wait is randomly lengthened or shortened, and zero attempts means retrying forever.
+    pub fn new(
+        initial: Duration,
+        max: Duration,
+        multiplier: f64,
+        jitter: f64,
+        max_attempts: u32,
+    ) -> Self {
+        Backoff {
+            initial,
+            max: max.max(initial),
+            multiplier: multiplier.max(1.0),
+            jitter: jitter.max(0.0).min(1.0),
+            max_attempts,
+            attempts: 0,
+        }
+    }
+
+    /// Records a failure, returning how long to wait before trying again, or None if we've failed too many times in a row.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.max_attempts > 0 && self.attempts >= self.max_attempts {
+            return None;
+        }
+        let base = (self.initial.as_secs_f64() * self.multiplier.powi(self.attempts as i32))
+            .min(self.max.as_secs_f64());
+        self.attempts += 1;
+        let factor = 1.0 + self.jitter * (rand::random::<f64>() * 2.0 - 1.0);
+        Some(Duration::from_secs_f64(base * factor))
+    }
+
+    /// Starts over after a connection worked.
+    pub fn reset(&mut self) {
+        self.attempts = 0
+    }
+
+    /// How many failures there have been in a row.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+}
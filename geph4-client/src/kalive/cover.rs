@@ -0,0 +1,90 @@
+//! Cover traffic: dummy packets sent to the exit inside the session, which the exit decrypts and throws away.
+//!
+//! Encryption hides what a user sends, but not when or how much. Someone watching the connection to the bridge or exit can tell when the user is idle, when they load a page, and how big it is, which can be enough to fingerprint the sites they visit or to match the tunnel up with traffic elsewhere. Cover traffic blurs this by keeping the upload busy whether or not the user is doing anything.
+//!
+//! It has clear limits:
+//! - Only uploads are covered. Downloads, which are most of what browsing looks like, still show their timing and volume.
+//! - Real traffic above the cover rate still stands out, so a rate that hides big uploads costs a lot of bandwidth, all the time, for as long as the client is connected.
+//! - It does nothing against the exit itself, or anyone who can see traffic leaving the exit.
+//! - Dummy packets are encrypted like real ones and about as big, but they're spaced randomly, which a patient observer may be able to tell apart from the burstiness of real traffic.
+
+use std::{
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+use crate::stats::StatCollector;
+
+use super::Path;
+
+/// How often cover traffic is sent, on average. Each wait is randomly lengthened or shortened by up to half of this.
+const TICK: Duration = Duration::from_millis(50);
+
+/// Range of the length of dummy packets, which is about what real packets look like.
+const MIN_PACKET: usize = 64;
+const MAX_PACKET: usize = 1200;
+
+/// How cover traffic is shaped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoverPattern {
+    /// Send dummy packets at the configured rate no matter what, so that real traffic rides on top of a steady stream. This hides when the user is active better, at the full cost in bandwidth.
+    Constant,
+    /// Only send enough dummy packets to top real traffic up to the configured rate. This costs less, since real traffic counts towards the rate, but the upload is only flattened up to that rate.
+    Fill,
+}
+
+impl FromStr for CoverPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "constant" => Ok(CoverPattern::Constant),
+            "fill" => Ok(CoverPattern::Fill),
+            other => anyhow::bail!(
+                "unknown cover traffic pattern {:?} (expected constant or fill)",
+                other
+            ),
+        }
+    }
+}
+
+/// How much cover traffic to send, and how.
+#[derive(Debug, Clone, Copy)]
+pub struct CoverTraffic {
+    /// Bytes per second.
+    pub rate: u64,
+    pub pattern: CoverPattern,
+}
+
+/// Sends cover traffic over the paths, spread across them, until dropped.
+pub async fn cover_loop(cover: CoverTraffic, paths: Arc<Vec<Path>>, stats: Arc<StatCollector>) {
+    let mut last_tick = Instant::now();
+    let mut last_tx = stats.total_tx();
+    // bytes we're behind on. a second's worth at most, so that falling behind doesn't lead to a burst.
+    let mut owed = 0.0f64;
+    let mut next_path = 0usize;
+    loop {
+        smol::Timer::after(TICK.mul_f64(0.5 + rand::random::<f64>())).await;
+        let now = Instant::now();
+        owed += cover.rate as f64 * now.saturating_duration_since(last_tick).as_secs_f64();
+        last_tick = now;
+        let tx = stats.total_tx();
+        if cover.pattern == CoverPattern::Fill {
+            owed -= tx.saturating_sub(last_tx) as f64;
+        }
+        last_tx = tx;
+        owed = owed.max(0.0).min(cover.rate as f64);
+        while owed >= MIN_PACKET as f64 {
+            let len = rand::thread_rng()
+                .gen_range(MIN_PACKET, MAX_PACKET + 1)
+                .min(owed as usize);
+            paths[next_path % paths.len()].mux.send_padding(len);
+            next_path += 1;
+            stats.incr_cover_tx(len as u64);
+            owed -= len as f64;
+        }
+    }
+}
@@ -2,45 +2,67 @@ use crate::cache::ClientCache;
 use anyhow::Context;
 use binder_transport::ExitDescriptor;
 use smol::prelude::*;
-use std::time::{Duration, Instant};
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 
-use super::infal;
+use super::{
+    infal,
+    route::{connect_endpoint, Route},
+    BridgeSelect,
+};
+
+/// With [BridgeSelect::Spread], how long each bridge gets before we start trying the next one as well.
+const SPREAD_STAGGER: Duration = Duration::from_secs(2);
 
 pub async fn get_session(
     exit_info: ExitDescriptor,
     ccache: &ClientCache,
     use_bridges: bool,
+    bridge_select: BridgeSelect,
     use_tcp: bool,
-) -> anyhow::Result<sosistab::Session> {
+    avoid: &[SocketAddr],
+) -> anyhow::Result<(sosistab::Session, Route)> {
     let bridge_sess_async = async {
-        let bridges = ccache
-            .get_bridges(&exit_info.hostname)
+        let bridges: Vec<_> = ccache
+            .get_bridges(&exit_info)
             .await
-            .context("can't get bridges")?;
+            .context("can't get bridges")?
+            .into_iter()
+            .filter(|desc| !avoid.contains(&desc.endpoint))
+            .collect();
         log::debug!("got {} bridges", bridges.len());
         if bridges.is_empty() {
             anyhow::bail!("absolutely no bridges found")
         }
         let start = Instant::now();
-        // spawn a task for *every* bridge
+        // spawn a task for *every* bridge, though when spreading out, later bridges wait their turn
         let (send, recv) = smol::channel::unbounded();
         let _tasks: Vec<_> = bridges
             .into_iter()
-            .map(|desc| {
+            .enumerate()
+            .map(|(i, desc)| {
                 let send = send.clone();
+                let delay = match bridge_select {
+                    BridgeSelect::Fastest => Duration::from_secs(0),
+                    BridgeSelect::Spread => SPREAD_STAGGER * i as u32,
+                };
                 smolscale::spawn(async move {
+                    smol::Timer::after(delay).await;
                     log::debug!("connecting through {}...", desc.endpoint);
                     drop(
-                        send.send((desc.endpoint, {
+                        send.send((desc.clone(), {
                             // we effectively sum 3 RTTs. this filters out the high-jitter/high-loss crap.
                             if !use_tcp {
                                 for _ in 0u8..3 {
-                                    let _ = sosistab::connect_udp(desc.endpoint, desc.sosistab_key)
-                                        .await;
+                                    let _ =
+                                        sosistab::try_connect_udp(desc.endpoint, desc.sosistab_key)
+                                            .await;
                                 }
-                                sosistab::connect_udp(desc.endpoint, desc.sosistab_key).await
+                                connect_endpoint(desc.endpoint, desc.sosistab_key, false).await
                             } else {
-                                sosistab::connect_tcp(desc.endpoint, desc.sosistab_key).await
+                                connect_endpoint(desc.endpoint, desc.sosistab_key, true).await
                             }
                         }))
                         .await,
@@ -50,14 +72,35 @@ pub async fn get_session(
             .collect();
         // wait for a successful result
         loop {
-            let (saddr, res) = recv.recv().await.context("ran out of bridges")?;
+            let (desc, res) = recv.recv().await.context("ran out of bridges")?;
+            match &res {
+                Err(sosistab::ConnectError::BadServerKey) => log::warn!(
+                    "bridge {} presented the wrong key, so it's probably being tampered with",
+                    desc.endpoint
+                ),
+                Err(err) => log::debug!("bridge {} failed: {}", desc.endpoint, err),
+                Ok(_) => (),
+            }
+            crate::reachability::record(
+                &exit_info.hostname,
+                Some(desc.endpoint),
+                use_tcp,
+                res.is_ok(),
+            );
             if let Ok(res) = res {
                 log::info!(
                     "{} is our fastest bridge, latency={}",
-                    saddr,
+                    desc.endpoint,
                     start.elapsed().as_millis()
                 );
-                break Ok(res);
+                let route = Route::new(
+                    exit_info.clone(),
+                    desc.endpoint,
+                    desc.sosistab_key,
+                    true,
+                    use_tcp,
+                );
+                break Ok((res, route));
             }
         }
     };
@@ -73,12 +116,16 @@ pub async fn get_session(
                     .find(|v| v.is_ipv4())
                     .context("can't find ipv4 address for exit")?;
 
-                Ok(infal(if use_tcp {
-                    sosistab::connect_tcp(server_addr, exit_info.sosistab_key).await
-                } else {
-                    sosistab::connect_udp(server_addr, exit_info.sosistab_key).await
-                })
-                .await)
+                let route = Route::new(
+                    exit_info.clone(),
+                    server_addr,
+                    exit_info.sosistab_key,
+                    false,
+                    use_tcp,
+                );
+                let sess = infal(route.connect().await).await;
+                crate::reachability::record(&exit_info.hostname, None, use_tcp, true);
+                Ok((sess, route))
             }
             .or(async {
                 smol::Timer::after(Duration::from_secs(1)).await;
@@ -1,20 +1,62 @@
 use crate::{cache::ClientCache, main_connect::ConnectOpt};
 use crate::{stats::StatCollector, vpn::run_vpn};
 use anyhow::Context;
+use binder_transport::{ConnIntent, ConnRejection, ExitFeatures};
 use getsess::get_session;
+use natping::PingSchedule;
+use parking_lot::Mutex;
 use smol::channel::{Receiver, Sender};
 use smol::prelude::*;
 use smol_timeout::TimeoutExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
-use std::{sync::Arc, time::Instant};
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
 
+mod backoff;
+mod cover;
 mod getsess;
+mod multihop;
+mod natping;
+mod path;
+mod route;
+mod select;
+mod transport;
+pub use backoff::Backoff;
+pub use cover::{CoverPattern, CoverTraffic};
+pub use path::Path;
+pub use route::{connect_endpoint, Route, MAX_SHARDS, TCP_OPTIONS};
+pub use select::{probe_all, select_exit, BridgeSelect, ExitSelect};
+pub use transport::HandshakeCounts;
+
+/// A tunneled connection, or why the exit refused to make it.
+type ConnReply = Result<sosistab::mux::RelConn, ConnRejection>;
+
+/// A request for a tunneled connection, to a destination or with a raw label.
+struct ConnRequest {
+    target: String,
+    is_label: bool,
+    reply: Sender<ConnReply>,
+}
+
+/// The statistics a path's session has kept so far.
+pub struct PathTrace {
+    pub endpoint: SocketAddr,
+    pub stats: Vec<sosistab::SessionStat>,
+}
 
 /// An "actor" that keeps a client session alive.
 #[derive(Clone)]
 pub struct Keepalive {
-    open_socks5_conn: Sender<(String, Sender<sosistab::mux::RelConn>)>,
-    get_stats: Sender<Sender<Vec<sosistab::SessionStat>>>,
+    open_socks5_conn: Sender<ConnRequest>,
+    get_stats: Sender<Sender<Vec<PathTrace>>>,
+    force_reconnect: Sender<()>,
+    addr_preference: aioutils::AddrPreference,
+    direct_if_refused: bool,
+    dns_coalescer: Option<Arc<crate::dns::DnsCoalescer>>,
     _task: Arc<smol::Task<anyhow::Result<()>>>,
 }
 
@@ -23,52 +65,164 @@ impl Keepalive {
     pub fn new(stats: Arc<StatCollector>, cfg: ConnectOpt, ccache: Arc<ClientCache>) -> Self {
         let (send, recv) = smol::channel::unbounded();
         let (send_stats, recv_stats) = smol::channel::unbounded();
+        let (send_reconnect, recv_reconnect) = smol::channel::bounded(1);
         Keepalive {
             open_socks5_conn: send,
             get_stats: send_stats,
+            force_reconnect: send_reconnect,
+            addr_preference: cfg.addr_preference(),
+            direct_if_refused: cfg.direct_if_refused,
+            dns_coalescer: if cfg.coalesce_dns_tcp {
+                Some(Default::default())
+            } else {
+                None
+            },
             _task: Arc::new(smolscale::spawn(keepalive_actor(
-                stats, cfg, ccache, recv, recv_stats,
+                stats,
+                cfg,
+                ccache,
+                recv,
+                recv_stats,
+                recv_reconnect,
             ))),
         }
     }
 
     /// Opens a connection
     pub async fn connect(&self, remote: &str) -> anyhow::Result<sosistab::mux::RelConn> {
+        self.open(remote, false).await
+    }
+
+    /// Opens a connection with a label the exit handles itself, such as an echo or bind request, rather than a destination.
+    pub async fn connect_label(&self, label: &str) -> anyhow::Result<sosistab::mux::RelConn> {
+        self.open(label, true).await
+    }
+
+    async fn open(&self, target: &str, is_label: bool) -> anyhow::Result<sosistab::mux::RelConn> {
         let (send, recv) = smol::channel::bounded(1);
         self.open_socks5_conn
-            .send((remote.to_string(), send))
+            .send(ConnRequest {
+                target: target.to_string(),
+                is_label,
+                reply: send,
+            })
             .await?;
-        Ok(recv.recv().await?)
+        Ok(recv.recv().await??)
+    }
+
+    /// Which address family connections should prefer.
+    pub fn addr_preference(&self) -> aioutils::AddrPreference {
+        self.addr_preference
+    }
+
+    /// Whether destinations the exit refuses should be connected to directly.
+    pub fn direct_if_refused(&self) -> bool {
+        self.direct_if_refused
+    }
+
+    /// Shares connections to DNS servers among clients speaking DNS over TCP, if that's turned on.
+    pub fn dns_coalescer(&self) -> Option<&crate::dns::DnsCoalescer> {
+        self.dns_coalescer.as_deref()
     }
 
-    /// Gets session statistics
-    pub async fn get_stats(&self) -> anyhow::Result<Vec<sosistab::SessionStat>> {
+    /// Gets session statistics of every path, starting with the first.
+    pub async fn get_stats(&self) -> anyhow::Result<Vec<PathTrace>> {
         let (send, recv) = smol::channel::bounded(1);
         self.get_stats.send(send).await?;
         Ok(recv.recv().await?)
     }
+
+    /// Tears down the sessions to the exit and selects a route again right away, rather than waiting for the current route to fail. Connections through the old sessions are closed, while connections being opened wait for the new ones.
+    pub fn reconnect(&self) {
+        // a reconnect that's already pending covers this one too
+        drop(self.force_reconnect.try_send(()));
+    }
 }
 
 async fn keepalive_actor(
     stats: Arc<StatCollector>,
     cfg: ConnectOpt,
     ccache: Arc<ClientCache>,
-    recv_socks5_conn: Receiver<(String, Sender<sosistab::mux::RelConn>)>,
-    recv_get_stats: Receiver<Sender<Vec<sosistab::SessionStat>>>,
+    recv_socks5_conn: Receiver<ConnRequest>,
+    recv_get_stats: Receiver<Sender<Vec<PathTrace>>>,
+    recv_reconnect: Receiver<()>,
 ) -> anyhow::Result<()> {
+    let mut backoff = cfg.backoff();
+    // what we learn about the NAT outlives any one connection to an exit
+    let ping_schedule = Arc::new(Mutex::new(if crate::power::low_power() {
+        PingSchedule::low_power()
+    } else {
+        PingSchedule::default()
+    }));
+    stats.set_keepalive(ping_schedule.lock().interval(), None);
+    let _network_watch = smolscale::spawn(transport::reset_on_network_change());
     loop {
-        let cfg = cfg.clone();
         if let Err(err) = keepalive_actor_once(
             stats.clone(),
-            cfg,
+            cfg.clone(),
             ccache.clone(),
             recv_socks5_conn.clone(),
             recv_get_stats.clone(),
+            ping_schedule.clone(),
         )
+        .or(async {
+            recv_reconnect.recv().await?;
+            Err(anyhow::Error::new(ManualReconnect))
+        })
         .await
         {
-            log::warn!("keepalive_actor restarting: {:#?}", err);
-            smol::Timer::after(Duration::from_secs(1)).await;
+            if err.downcast_ref::<ManualReconnect>().is_some() {
+                log::info!("reconnecting on request");
+                stats.incr_manual_reconnects();
+                backoff.reset();
+                stats.set_reconnect_state(0, Duration::from_secs(0));
+                continue;
+            }
+            if err.downcast_ref::<ConnectTimeout>().is_some() && cfg.exit_on_connect_timeout {
+                log::error!("{}; giving up", err);
+                std::process::exit(1)
+            }
+            if let Some(draining) = err.downcast_ref::<ExitDraining>() {
+                if cfg.exit_select == ExitSelect::Exact {
+                    log::warn!("{}, but --exit-select exact allows no other exit", draining);
+                } else {
+                    log::warn!("{}; moving to another exit", draining);
+                }
+                select::avoid_draining(&draining.hostname);
+            }
+            // we only get connected to an exit once everything worked
+            if stats.is_connected() {
+                backoff.reset();
+            }
+            let delay = match backoff.next_delay() {
+                Some(delay) => delay,
+                None => {
+                    log::error!(
+                        "{}; giving up after {} failed reconnects",
+                        err,
+                        backoff.attempts()
+                    );
+                    std::process::exit(1)
+                }
+            };
+            stats.set_reconnect_state(backoff.attempts(), delay);
+            let (udp, tcp) = transport::counts();
+            stats.set_handshakes(udp, tcp);
+            log::warn!(
+                "keepalive_actor restarting in {:.1}s: {:#?}",
+                delay.as_secs_f64(),
+                err
+            );
+            let cut_short = async {
+                smol::Timer::after(delay).await;
+                false
+            }
+            .or(async { recv_reconnect.recv().await.is_ok() })
+            .await;
+            if cut_short {
+                log::info!("reconnecting on request");
+                stats.incr_manual_reconnects();
+            }
         }
     }
 }
@@ -77,64 +231,220 @@ async fn keepalive_actor_once(
     stats: Arc<StatCollector>,
     cfg: ConnectOpt,
     ccache: Arc<ClientCache>,
-    recv_socks5_conn: Receiver<(String, Sender<sosistab::mux::RelConn>)>,
-    recv_get_stats: Receiver<Sender<Vec<sosistab::SessionStat>>>,
+    recv_socks5_conn: Receiver<ConnRequest>,
+    recv_get_stats: Receiver<Sender<Vec<PathTrace>>>,
+    ping_schedule: Arc<Mutex<PingSchedule>>,
 ) -> anyhow::Result<()> {
     stats.set_exit_descriptor(None);
+    stats.set_first_hop(None);
+    stats.set_exit_selection(None);
+    stats.set_route(None);
+    let deadline = if cfg.connect_timeout > 0 {
+        Some(Instant::now() + Duration::from_secs(cfg.connect_timeout))
+    } else {
+        None
+    };
 
-    // find the exit
-    let mut exits = ccache.get_exits().await.context("can't get exits")?;
-    if exits.is_empty() {
-        anyhow::bail!("no exits found")
-    }
-    exits.sort_by(|a, b| {
-        strsim::damerau_levenshtein(&a.hostname, &cfg.exit_server)
-            .cmp(&strsim::damerau_levenshtein(&b.hostname, &cfg.exit_server))
-    });
-    let exit_info = exits[0].clone();
-
-    let session = if cfg.use_tcp {
-        get_session(exit_info, &ccache, cfg.use_bridges, true).await?
+    let (session, route, reason) = if let Some(path) = &cfg.force_route {
+        // bypass selection entirely
+        let route = stage(deadline, "binder fetch", Route::load(path, &ccache)).await?;
+        log::info!(
+            "forcing route to {} via {}",
+            route.exit.hostname,
+            route.endpoint
+        );
+        let session = stage(deadline, "handshake", async {
+            route.connect().await.context("cannot connect forced route")
+        })
+        .await?;
+        (session, route, format!("forced route from {:?}", path))
     } else {
-        // give UDP a head start
-        get_session(exit_info.clone(), &ccache, cfg.use_bridges, false).await?
+        // find the exit
+        let exits = stage(deadline, "binder fetch", async {
+            ccache.get_exits().await.context("can't get exits")
+        })
+        .await?;
+        let (exit_info, reason) = stage(
+            deadline,
+            "exit resolve",
+            select::select_exit(&cfg, &ccache, exits),
+        )
+        .await?;
+        log::info!("selected exit {} ({})", exit_info.hostname, reason);
+
+        let use_tcp = cfg.use_tcp || transport::fall_back_to_tcp();
+        let (session, route) = if use_tcp {
+            stage(
+                deadline,
+                "handshake",
+                get_session(
+                    exit_info,
+                    &ccache,
+                    cfg.use_bridges,
+                    cfg.bridge_select,
+                    true,
+                    &[],
+                ),
+            )
+            .await?
+        } else {
+            // give UDP a head start
+            stage(
+                deadline,
+                "handshake",
+                get_session(
+                    exit_info,
+                    &ccache,
+                    cfg.use_bridges,
+                    cfg.bridge_select,
+                    false,
+                    &[],
+                ),
+            )
+            .await?
+        };
+        (session, route, reason)
     };
+    let exit_info = route.exit.clone();
 
-    let mux = Arc::new(sosistab::mux::Multiplex::new(session));
     // now let's authenticate
-    let token = ccache.get_auth_token().await?;
-    authenticate_session(&mux, &token)
-        .timeout(Duration::from_secs(5))
-        .await
-        .ok_or_else(|| anyhow::anyhow!("authentication timed out"))??;
-    log::info!(
-        "KEEPALIVE MAIN LOOP for exit_host={}, use_bridges={}, use_tcp={}",
-        cfg.exit_server,
-        cfg.use_bridges,
-        cfg.use_tcp
-    );
-    stats.set_exit_descriptor(Some(exits[0].clone()));
-    let mux1 = mux.clone();
-    let _watchdog = smolscale::spawn(async move {
-        loop {
-            smol::Timer::after(Duration::from_secs(200)).await;
-            if mux1
-                .open_conn(None)
-                .timeout(Duration::from_secs(60))
-                .await
-                .is_none()
+    let token = stage(deadline, "binder fetch", ccache.get_auth_token()).await?;
+    let session_id = session.id().to_string();
+    let session_version = session.version();
+    let first_path = stage(
+        deadline,
+        "authentication",
+        Path::establish(session, route.clone(), &token),
+    )
+    .await;
+    let first_path = match first_path {
+        Ok(path) => path,
+        Err(err) => {
+            // draining exits turn sessions away on purpose
+            if err.downcast_ref::<ConnectTimeout>().is_none()
+                && err.downcast_ref::<ExitDraining>().is_none()
             {
-                log::warn!("watchdog conn didn't work!");
+                mux_failed(&cfg, &stats, &session_id, session_version, &route, &err);
             }
+            return Err(err);
         }
-    });
-
+    };
+    MUX_FAILURES.store(0, Ordering::Relaxed);
+    stats.set_protocol_version(
+        session_version,
+        sosistab::max_version() < sosistab::LATEST_VERSION,
+    );
+    let mut paths = vec![first_path];
+    // extra paths are best-effort, alternating transports and avoiding bridges we already use
+    for i in 1..cfg.multipath.max(1) {
+        let use_tcp = cfg.use_tcp || i % 2 == 1;
+        let avoid: Vec<SocketAddr> = paths.iter().map(|p| p.route.endpoint).collect();
+        let extra = async {
+            let (session, route) = if cfg.force_route.is_some() {
+                (route.connect().await?, route.clone())
+            } else {
+                get_session(
+                    exit_info.clone(),
+                    &ccache,
+                    cfg.use_bridges,
+                    cfg.bridge_select,
+                    use_tcp,
+                    &avoid,
+                )
+                .await?
+            };
+            Path::establish(session, route, &token).await
+        };
+        match extra.await {
+            Ok(path) => {
+                log::info!(
+                    "established extra path {} via {} (use_tcp={})",
+                    i,
+                    path.route.endpoint,
+                    path.route.use_tcp
+                );
+                paths.push(path)
+            }
+            Err(err) => log::warn!("could not establish extra path {}: {:?}", i, err),
+        }
+    }
+    log::info!(
+        "KEEPALIVE MAIN LOOP for exit_host={}, use_bridges={}, use_tcp={}, paths={}",
+        exit_info.hostname,
+        route.via_bridge,
+        route.use_tcp,
+        paths.len()
+    );
+    // with a second exit, connections go through a single path to it, tunneled through the paths to the first
+    let mut first_hop = Vec::new();
+    let mut _relay = None;
+    let mut exit_info = exit_info;
+    if let Some(second_exit) = &cfg.second_exit {
+        let exits = stage(deadline, "binder fetch", async {
+            ccache.get_exits().await.context("can't get exits")
+        })
+        .await?;
+        let second_info = exits
+            .into_iter()
+            .find(|e| &e.hostname == second_exit)
+            .with_context(|| format!("no exit is named {}", second_exit))?;
+        if second_info.hostname == exit_info.hostname {
+            anyhow::bail!("the second exit must differ from the first")
+        }
+        let second_token = stage(deadline, "binder fetch", ccache.get_second_hop_token()).await?;
+        let (second_path, relay) = stage(
+            deadline,
+            "second hop",
+            multihop::second_hop(&paths[0], second_info.clone(), &second_token),
+        )
+        .await?;
+        log::info!(
+            "tunneling through {} to second exit {}",
+            exit_info.hostname,
+            second_info.hostname
+        );
+        stats.set_first_hop(Some(exit_info.hostname.clone()));
+        first_hop = std::mem::replace(&mut paths, vec![second_path]);
+        _relay = Some(relay);
+        exit_info = second_info;
+    }
+    stats.set_exit_descriptor(Some(exit_info));
+    stats.set_reconnect_state(0, Duration::from_secs(0));
+    stats.set_exit_selection(Some(reason));
+    stats.set_route(Some(route));
+    let paths = Arc::new(paths);
     let (send_death, recv_death) = smol::channel::unbounded::<anyhow::Error>();
+    let _watchdogs: Vec<smol::Task<()>> = first_hop
+        .iter()
+        .chain(paths.iter())
+        .map(|path| {
+            smolscale::spawn(watchdog(
+                path.mux.clone(),
+                ping_schedule.clone(),
+                stats.clone(),
+                send_death.clone(),
+            ))
+        })
+        .collect();
+
+    // exits tell us when they start draining, so that we move elsewhere before they close the session
+    let _drain_watches: Vec<smol::Task<()>> = first_hop
+        .iter()
+        .chain(paths.iter())
+        .filter_map(|path| {
+            let conn = path.drain_notice.clone()?;
+            Some(smolscale::spawn(watch_drain(
+                conn,
+                path.route.exit.hostname.clone(),
+                send_death.clone(),
+            )))
+        })
+        .collect();
 
     // VPN mode
     let mut _nuunuu = None;
     if cfg.stdio_vpn {
-        let mux = mux.clone();
+        let mux = paths[0].mux.clone();
         let send_death = send_death.clone();
         let stats = stats.clone();
         _nuunuu = Some(smolscale::spawn(async move {
@@ -144,42 +454,166 @@ async fn keepalive_actor_once(
         }));
     }
 
-    let mux1 = mux.clone();
+    // cover traffic, for exits that know to throw it away
+    let _cover = match cfg.cover_traffic() {
+        Some(cover) if paths[0].features.contains(ExitFeatures::PADDING) => {
+            log::info!(
+                "sending {:?} cover traffic at {} KiB/s",
+                cover.pattern,
+                cover.rate / 1024
+            );
+            Some(smolscale::spawn(cover::cover_loop(
+                cover,
+                paths.clone(),
+                stats.clone(),
+            )))
+        }
+        Some(_) => {
+            log::warn!("exit doesn't support cover traffic, so none is sent");
+            None
+        }
+        None => None,
+    };
+
+    // warmup pool of idle conns. these are opened without a destination, which the exit then reads from the conn itself.
+    let (send_warm, recv_warm) = smol::channel::bounded(cfg.warmup_conns.max(1));
+    let _warmup = if cfg.warmup_conns > 0 {
+        let paths = paths.clone();
+        Some(smolscale::spawn(async move {
+            // failures are usually transient, so keep refilling, just more slowly
+            let mut backoff =
+                Backoff::new(Duration::from_secs(1), Duration::from_secs(30), 2.0, 0.2, 0);
+            for i in 0usize.. {
+                let path = &paths[i % paths.len()];
+                match path.mux.open_conn(None).await {
+                    Ok(conn) => {
+                        backoff.reset();
+                        if send_warm.send(conn).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(err) => {
+                        let delay = backoff.next_delay().unwrap_or_default();
+                        log::warn!(
+                            "could not open warmup conn via {}: {}; retrying in {:.1}s",
+                            path.route.endpoint,
+                            err,
+                            delay.as_secs_f64()
+                        );
+                        smol::Timer::after(delay).await;
+                    }
+                }
+            }
+        }))
+    } else {
+        None
+    };
+
+    let paths1 = paths.clone();
+    let next_path = AtomicUsize::new(0);
+    // every path goes to the same exit, so they all have the same features
+    let addr_preference = if paths[0].features.contains(ExitFeatures::ADDR_PREFERENCE) {
+        cfg.addr_preference()
+    } else {
+        if cfg.addr_preference() != aioutils::AddrPreference::Default {
+            log::warn!("exit doesn't support address family preferences, so they're ignored");
+        }
+        aioutils::AddrPreference::Default
+    };
+    let use_intent = paths[0].features.contains(ExitFeatures::CONN_INTENT);
+    let direct_fallback = cfg.direct_if_refused;
     async move {
         loop {
-            let (conn_host, conn_reply) = recv_socks5_conn
+            let ConnRequest {
+                target,
+                is_label,
+                reply: conn_reply,
+            } = recv_socks5_conn
                 .recv()
                 .await
                 .context("cannot get socks5 connect request")?;
-            let mux = mux.clone();
+            let priority = if is_label {
+                sosistab::Priority::Normal
+            } else {
+                cfg.conn_priority(&target)
+            };
+            // exits that understand intents tell us before dialing if they won't connect
+            let (label, intent) = if is_label {
+                (target.clone(), None)
+            } else if use_intent {
+                let intent = ConnIntent {
+                    destination: target.clone(),
+                    preference: addr_preference,
+                    direct_fallback,
+                };
+                (
+                    binder_transport::CONN_INTENT_LABEL.to_string(),
+                    Some(intent),
+                )
+            } else {
+                (addr_preference.tag(&target), None)
+            };
+            let paths = paths.clone();
             let send_death = send_death.clone();
+            let recv_warm = recv_warm.clone();
+            // spread connections across paths, falling back to the other paths if one fails
+            let first = next_path.fetch_add(1, Ordering::Relaxed);
             smolscale::spawn(async move {
                 let start = Instant::now();
-                let remote = (&mux).open_conn(Some(conn_host)).await;
-                match remote {
-                    Ok(remote) => {
-                        let sess_stats = mux.get_session().latest_stat();
-                        if let Some(stat) = sess_stats {
-                            log::debug!(
-                                "opened connection in {} ms; loss = {:.2}%",
-                                start.elapsed().as_millis(),
-                                stat.total_loss * 100.0
-                            );
-                        };
-                        conn_reply.send(remote).await?;
-                        Ok::<(), anyhow::Error>(())
+                // use a warm conn if there's one. those were opened at normal priority, which the exit keeps sending at, so other priorities need conns of their own
+                let warm = if priority == sosistab::Priority::Normal {
+                    recv_warm.try_recv().ok()
+                } else {
+                    None
+                };
+                if let Some(mut remote) = warm {
+                    if aioutils::write_pascalish(&mut remote, &label).await.is_ok() {
+                        if let Ok(verdict) = send_intent(&mut remote, &intent).await {
+                            log::debug!("used warm connection for {}", target);
+                            conn_reply.send(verdict.map(|_| remote)).await?;
+                            return Ok(());
+                        }
                     }
-                    Err(err) => {
-                        send_death
-                            .send(anyhow::anyhow!(
-                                "conn open error {} in {}s",
-                                err,
-                                start.elapsed().as_secs_f64()
-                            ))
+                }
+                let mut last_err = None;
+                for offset in 0..paths.len() {
+                    let path = &paths[(first + offset) % paths.len()];
+                    let opened = async {
+                        let mut remote = path
+                            .mux
+                            .open_conn_with_priority(Some(label.clone()), priority)
                             .await?;
-                        Ok(())
+                        let verdict = send_intent(&mut remote, &intent).await?;
+                        Ok::<_, anyhow::Error>(verdict.map(|_| remote))
+                    };
+                    match opened.await {
+                        Ok(remote) => {
+                            let sess_stats = path.mux.get_session().latest_stat();
+                            if let Some(stat) = sess_stats {
+                                log::debug!(
+                                    "opened connection via {} in {} ms; loss = {:.2}%",
+                                    path.route.endpoint,
+                                    start.elapsed().as_millis(),
+                                    stat.total_loss * 100.0
+                                );
+                            };
+                            conn_reply.send(remote).await?;
+                            return Ok::<(), anyhow::Error>(());
+                        }
+                        Err(err) => {
+                            log::warn!("path via {} failed: {}", path.route.endpoint, err);
+                            last_err = Some(err)
+                        }
                     }
                 }
+                send_death
+                    .send(anyhow::anyhow!(
+                        "conn open error {:?} in {}s",
+                        last_err,
+                        start.elapsed().as_secs_f64()
+                    ))
+                    .await?;
+                Ok(())
             })
             .detach();
         }
@@ -191,13 +625,232 @@ async fn keepalive_actor_once(
     .or(async {
         loop {
             let stat_send = recv_get_stats.recv().await?;
-            let stats = mux1.get_session().all_stats();
+            let stats = paths1
+                .iter()
+                .map(|path| PathTrace {
+                    endpoint: path.route.endpoint,
+                    stats: path.mux.get_session().all_stats(),
+                })
+                .collect();
             drop(stat_send.send(stats).await);
         }
     })
+    .or(async {
+        loop {
+            let (udp, tcp) = transport::counts();
+            stats.set_handshakes(udp, tcp);
+            stats.set_paths(
+                first_hop
+                    .iter()
+                    .chain(paths1.iter())
+                    .map(|p| p.stat())
+                    .collect(),
+            );
+            crate::power::background_wait(Duration::from_secs(5)).await;
+        }
+    })
+    .or(async {
+        // the sessions and their NAT bindings don't survive the machine sleeping, so reconnect right away rather than waiting for them to time out
+        let gap = detect_suspend().await;
+        log::warn!(
+            "clock jumped by {:.0}s, probably woke up from sleep; reconnecting",
+            gap.as_secs_f64()
+        );
+        stats.incr_suspend_reconnects();
+        anyhow::bail!("reconnecting after suspend")
+    })
     .await
 }
 
+/// Pings through a path whenever it's been quiet for as long as the ping schedule says, keeping its NAT bindings alive, and learning from whether they were. A ping that gets no answer means the path is dead, so it reports that to be reconnected.
+async fn watchdog(
+    mux: Arc<sosistab::mux::Multiplex>,
+    schedule: Arc<Mutex<PingSchedule>>,
+    stats: Arc<StatCollector>,
+    send_death: Sender<anyhow::Error>,
+) {
+    let total_recv = || {
+        mux.get_session()
+            .latest_stat()
+            .map(|stat| stat.total_recv)
+            .unwrap_or_default()
+    };
+    let mut last_recv = total_recv();
+    let mut quiet_since = Instant::now();
+    loop {
+        let interval = schedule.lock().interval();
+        smol::Timer::at(quiet_since + interval).await;
+        // traffic keeps the bindings alive by itself, and says nothing about how long they'd last without it
+        let recv = total_recv();
+        if recv != last_recv {
+            last_recv = recv;
+            quiet_since = Instant::now();
+            continue;
+        }
+        let silence = quiet_since.elapsed();
+        let answered = matches!(
+            mux.open_conn(None).timeout(Duration::from_secs(60)).await,
+            Some(Ok(_))
+        );
+        let mut schedule = schedule.lock();
+        if answered {
+            schedule.survived(silence);
+        } else {
+            schedule.reaped(silence);
+        }
+        stats.set_keepalive(schedule.interval(), schedule.nat_timeout());
+        drop(schedule);
+        if !answered {
+            drop(send_death.try_send(anyhow::anyhow!(
+                "keepalive ping after {:.0}s of silence got no answer",
+                silence.as_secs_f64()
+            )));
+            return;
+        }
+        last_recv = total_recv();
+        quiet_since = Instant::now();
+    }
+}
+
+/// Waits for the exit to say it's draining, and then reports that, so that we reconnect to another exit.
+async fn watch_drain(
+    mut conn: sosistab::mux::RelConn,
+    exit_hostname: String,
+    send_death: Sender<anyhow::Error>,
+) {
+    // anything else, such as the connection closing, means the session is dead, which the watchdog notices
+    if let Ok(binder_transport::SESSION_DRAINING) = aioutils::read_pascalish::<u8>(&mut conn).await
+    {
+        drop(
+            send_death.try_send(
+                ExitDraining {
+                    hostname: exit_hostname,
+                }
+                .into(),
+            ),
+        );
+    }
+}
+
+/// Waits until the clock jumps, which usually means the machine was suspended. Returns how long the jump was.
+async fn detect_suspend() -> Duration {
+    const THRESHOLD: Duration = Duration::from_secs(30);
+    let tick = if crate::power::low_power() {
+        Duration::from_secs(30)
+    } else {
+        Duration::from_secs(5)
+    };
+    loop {
+        let mono_start = Instant::now();
+        let wall_start = SystemTime::now();
+        smol::Timer::after(tick).await;
+        // depending on the platform, either clock might keep running while suspended
+        let elapsed = mono_start
+            .elapsed()
+            .max(wall_start.elapsed().unwrap_or_default());
+        if elapsed > tick + THRESHOLD {
+            return elapsed - tick;
+        }
+    }
+}
+
+/// How many sessions in a row were established, only for the multiplexed connections over them to fail.
+static MUX_FAILURES: AtomicUsize = AtomicUsize::new(0);
+
+/// After this many such failures, the exit probably doesn't get along with our protocol version.
+const MUX_FAILURE_LIMIT: usize = 3;
+
+/// Records that a session was established but couldn't be used, which points to version skew between the client and the exit. With `--protocol-downgrade`, repeated failures make later sessions use an older protocol version.
+fn mux_failed(
+    cfg: &ConnectOpt,
+    stats: &StatCollector,
+    session_id: &str,
+    version: u64,
+    route: &Route,
+    err: &anyhow::Error,
+) {
+    let failures = MUX_FAILURES.fetch_add(1, Ordering::Relaxed) + 1;
+    log::warn!(
+        "session {} to {} via {} (use_tcp={}, protocol version {}) was established, but it carries no connections ({} failures in a row): {:?}",
+        session_id,
+        route.exit.hostname,
+        route.endpoint,
+        route.use_tcp,
+        version,
+        failures,
+        err
+    );
+    if failures < MUX_FAILURE_LIMIT {
+        return;
+    }
+    if !cfg.protocol_downgrade {
+        log::error!(
+            "the exit doesn't seem to work with protocol version {}; --protocol-downgrade may help",
+            version
+        );
+    } else if version > 1 {
+        log::warn!("downgrading to protocol version {}", version - 1);
+        sosistab::set_max_version(version - 1);
+        MUX_FAILURES.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Error returned when connecting to an exit takes longer than `--connect-timeout`.
+#[derive(Debug)]
+struct ConnectTimeout {
+    stage: &'static str,
+}
+
+impl std::fmt::Display for ConnectTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "connect timeout exceeded during {}", self.stage)
+    }
+}
+
+impl std::error::Error for ConnectTimeout {}
+
+/// Error returned when a reconnect is asked for, through [Keepalive::reconnect].
+#[derive(Debug)]
+struct ManualReconnect;
+
+impl std::fmt::Display for ManualReconnect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "reconnect requested")
+    }
+}
+
+impl std::error::Error for ManualReconnect {}
+
+/// Error returned when the exit turns away or ends a session because it's draining, usually before maintenance.
+#[derive(Debug)]
+struct ExitDraining {
+    hostname: String,
+}
+
+impl std::fmt::Display for ExitDraining {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "exit {} is draining", self.hostname)
+    }
+}
+
+impl std::error::Error for ExitDraining {}
+
+/// Runs one stage of connecting to an exit, failing with a diagnostic naming the stage if the deadline passes first.
+async fn stage<T>(
+    deadline: Option<Instant>,
+    stage: &'static str,
+    fut: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    log::debug!("connect stage: {}", stage);
+    if let Some(deadline) = deadline {
+        fut.timeout(deadline.saturating_duration_since(Instant::now()))
+            .await
+            .ok_or_else(|| anyhow::Error::new(ConnectTimeout { stage }))?
+    } else {
+        fut.await
+    }
+}
+
 async fn infal<T, E>(v: Result<T, E>) -> T {
     if let Ok(v) = v {
         v
@@ -206,11 +859,26 @@ async fn infal<T, E>(v: Result<T, E>) -> T {
     }
 }
 
-/// authenticates a muxed session
+/// sends the intent of a freshly opened connection, if there is one, returning the exit's verdict
+async fn send_intent(
+    conn: &mut sosistab::mux::RelConn,
+    intent: &Option<ConnIntent>,
+) -> anyhow::Result<Result<(), ConnRejection>> {
+    match intent {
+        Some(intent) => {
+            aioutils::write_pascalish(conn, intent).await?;
+            Ok(aioutils::read_pascalish(conn).await?)
+        }
+        None => Ok(Ok(())),
+    }
+}
+
+/// authenticates a muxed session, returning the features the exit supports
 async fn authenticate_session(
     session: &sosistab::mux::Multiplex,
     token: &crate::cache::Token,
-) -> anyhow::Result<()> {
+    exit_hostname: &str,
+) -> anyhow::Result<(ExitFeatures, sosistab::mux::RelConn)> {
     let mut auth_conn = session.open_conn(None).await?;
     log::debug!("sending auth info...");
     aioutils::write_pascalish(
@@ -222,6 +890,17 @@ async fn authenticate_session(
         ),
     )
     .await?;
-    let _: u8 = aioutils::read_pascalish(&mut auth_conn).await?;
-    Ok(())
+    let response = aioutils::read_pascalish_raw(&mut auth_conn).await?;
+    // older exits just send a byte, without any features
+    match bincode::deserialize::<(u8, ExitFeatures)>(&response) {
+        Ok((binder_transport::SESSION_DRAINING, _)) => Err(ExitDraining {
+            hostname: exit_hostname.to_string(),
+        }
+        .into()),
+        Ok((_, features)) => Ok((features, auth_conn)),
+        Err(_) => {
+            let _: u8 = bincode::deserialize(&response)?;
+            Ok((ExitFeatures::default(), auth_conn))
+        }
+    }
 }
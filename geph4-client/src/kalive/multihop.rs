@@ -0,0 +1,50 @@
+use super::{path::Path, route::Route};
+use crate::cache::Token;
+use binder_transport::ExitDescriptor;
+use std::net::SocketAddr;
+
+/// Connects to a second exit through a path to the first, so that the first exit sees where we connect from but not where we connect to, and the second exit the reverse. The first exit needs no special support; it just sees TCP connections to the second exit's sosistab port, which a local relay opens through the first path. The returned task runs that relay.
+pub async fn second_hop(
+    first: &Path,
+    exit: ExitDescriptor,
+    token: &Token,
+) -> anyhow::Result<(Path, smol::Task<()>)> {
+    let listener = smol::net::TcpListener::bind("127.0.0.1:0").await?;
+    let local_addr: SocketAddr = listener.local_addr()?;
+    let upstream = format!("{}:19831", exit.hostname);
+    let mux = first.mux.clone();
+    let relay = smolscale::spawn(async move {
+        loop {
+            let client = match listener.accept().await {
+                Ok((client, _)) => client,
+                Err(err) => {
+                    log::warn!("second hop relay stopped: {}", err);
+                    return;
+                }
+            };
+            let mux = mux.clone();
+            let upstream = upstream.clone();
+            smolscale::spawn(async move {
+                let remote = match mux.open_conn(Some(upstream)).await {
+                    Ok(remote) => remote,
+                    Err(err) => {
+                        log::warn!("cannot reach second exit through the first: {}", err);
+                        return;
+                    }
+                };
+                let _ = smol::future::race(
+                    aioutils::copy_with_stats(remote.clone(), client.clone(), |_| ()),
+                    aioutils::copy_with_stats(client, remote, |_| ()),
+                )
+                .await;
+            })
+            .detach();
+        }
+    });
+    // sosistab over TCP, since the relay carries streams, not datagrams
+    let route = Route::new(exit.clone(), local_addr, exit.sosistab_key, false, true);
+    let session = route.connect().await?;
+    let mut path = Path::establish(session, route, token).await?;
+    path.hop = 2;
+    Ok((path, relay))
+}
@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+/// Shortest time between keepalive pings, however aggressive the NAT.
+const MIN_INTERVAL: Duration = Duration::from_secs(15);
+/// Longest time between keepalive pings, however long bindings seem to last.
+const MAX_INTERVAL: Duration = Duration::from_secs(300);
+/// Time between keepalive pings before we've learned anything.
+const INITIAL_INTERVAL: Duration = Duration::from_secs(20);
+/// Time between keepalive pings before we've learned anything, in low-power mode.
+const LOW_POWER_INITIAL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Learns how long the NAT bindings on the way to the exit survive without traffic, and so how often keepalive pings are needed. Pings start out frequent and space out for as long as bindings survive the silence between them. Once a binding is found to have been dropped, pings settle comfortably below the shortest silence that did that.
+#[derive(Debug, Clone)]
+pub struct PingSchedule {
+    interval: Duration,
+    /// The longest silence a binding has survived.
+    survived: Duration,
+    /// The shortest silence after which a binding was dropped.
+    reaped: Option<Duration>,
+    /// How much the interval grows after each ping that got through, until a binding is dropped.
+    growth: f64,
+    /// Fraction of the shortest silence that dropped a binding to settle at.
+    margin: f64,
+}
+
+impl Default for PingSchedule {
+    fn default() -> Self {
+        PingSchedule {
+            interval: INITIAL_INTERVAL,
+            survived: Duration::from_secs(0),
+            reaped: None,
+            growth: 1.5,
+            margin: 2.0 / 3.0,
+        }
+    }
+}
+
+impl PingSchedule {
+    /// A schedule for low-power mode, which pings less often at the risk of more bindings getting dropped.
+    pub fn low_power() -> Self {
+        PingSchedule {
+            interval: LOW_POWER_INITIAL_INTERVAL,
+            growth: 2.0,
+            margin: 0.8,
+            ..Default::default()
+        }
+    }
+
+    /// How long to let the tunnel go quiet before pinging.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Our best guess at how long the NAT keeps idle bindings, or None if no binding has been dropped yet.
+    pub fn nat_timeout(&self) -> Option<Duration> {
+        self.reaped
+    }
+
+    /// Records that a ping got through after the given silence.
+    pub fn survived(&mut self, silence: Duration) {
+        self.survived = self.survived.max(silence);
+        // a binding that now outlives what used to kill it means the NAT, or the network, has changed
+        if self.reaped.map(|r| r <= silence).unwrap_or(false) {
+            self.reaped = None;
+        }
+        self.interval = match self.reaped {
+            None => self.interval.mul_f64(self.growth),
+            Some(reaped) => reaped.mul_f64(self.margin),
+        }
+        .max(MIN_INTERVAL)
+        .min(MAX_INTERVAL);
+    }
+
+    /// Records that a ping after the given silence got no answer, presumably because the binding was dropped.
+    pub fn reaped(&mut self, silence: Duration) {
+        self.reaped = Some(self.reaped.unwrap_or(silence).min(silence));
+        if self.survived >= silence {
+            self.survived = Duration::from_secs(0);
+        }
+        self.interval = (silence / 2).max(MIN_INTERVAL).min(MAX_INTERVAL);
+    }
+}
@@ -0,0 +1,97 @@
+use super::{authenticate_session, route::Route};
+use crate::{
+    cache::Token,
+    stats::{PathStat, ShardPathStat},
+};
+use binder_transport::ExitFeatures;
+use smol_timeout::TimeoutExt;
+use sosistab::mux::Multiplex;
+use std::{sync::Arc, time::Duration};
+
+/// One authenticated session to the exit, along with the route it takes.
+pub struct Path {
+    pub mux: Arc<Multiplex>,
+    pub route: Route,
+    /// What the exit supports, as it told us when authenticating.
+    pub features: ExitFeatures,
+    /// 1 for a path to the first exit, 2 for a path tunneled through it to a second exit.
+    pub hop: u8,
+    /// The connection the session was authenticated over, which the exit tells us over when it starts draining. None if the exit doesn't support that.
+    pub drain_notice: Option<sosistab::mux::RelConn>,
+}
+
+impl Path {
+    /// Wraps a freshly connected session in a multiplex and authenticates it.
+    pub async fn establish(
+        session: sosistab::Session,
+        route: Route,
+        token: &Token,
+    ) -> anyhow::Result<Self> {
+        let mux = Arc::new(Multiplex::new(session));
+        let (features, auth_conn) = authenticate_session(&mux, token, &route.exit.hostname)
+            .timeout(Duration::from_secs(5))
+            .await
+            .ok_or_else(|| anyhow::anyhow!("authentication timed out"))??;
+        log::info!(
+            "session {} authenticated via {}; exit supports features {:#x}",
+            mux.get_session().id(),
+            route.endpoint,
+            features.0
+        );
+        Ok(Self {
+            mux,
+            route,
+            features,
+            hop: 1,
+            drain_notice: if features.contains(ExitFeatures::DRAIN_NOTICE) {
+                Some(auth_conn)
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Summarizes the current state of the path.
+    pub fn stat(&self) -> PathStat {
+        let latest = self.mux.get_session().latest_stat();
+        let queues = self.mux.get_session().queue_occupancy();
+        let shard_stats = self.mux.get_session().shard_stats().unwrap_or_default();
+        PathStat {
+            session_id: self.mux.get_session().id().to_string(),
+            hop: self.hop,
+            endpoint: self.route.endpoint,
+            via_bridge: self.route.via_bridge,
+            use_tcp: self.route.use_tcp,
+            shards: self
+                .mux
+                .get_session()
+                .active_shards()
+                .unwrap_or(self.route.shards),
+            benched_shards: shard_stats
+                .iter()
+                .filter(|s| s.benched)
+                .map(|s| s.shard_id)
+                .collect(),
+            shard_paths: shard_stats
+                .iter()
+                .filter(|s| s.active && !s.benched)
+                .map(|s| ShardPathStat {
+                    shard_id: s.shard_id,
+                    ping: s
+                        .rtt
+                        .map(|rtt| rtt.as_secs_f64() * 1000.0)
+                        .unwrap_or_default(),
+                    loss: s.loss * 100.0,
+                    bandwidth: s.bandwidth,
+                })
+                .collect(),
+            ping: latest
+                .map(|s| s.ping.as_secs_f64() * 1000.0)
+                .unwrap_or_default(),
+            loss: latest.map(|s| s.total_loss * 100.0).unwrap_or_default(),
+            upload_loss: latest.map(|s| s.send_loss * 100.0).unwrap_or_default(),
+            send_queue: queues.send,
+            recv_queue: queues.recv,
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use crate::cache::ClientCache;
+use anyhow::Context;
+use binder_transport::ExitDescriptor;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::{
+    net::SocketAddr,
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Most shards a session may adapt up to. Zero means sessions use sosistab's fixed shard counts instead.
+pub static MAX_SHARDS: AtomicUsize = AtomicUsize::new(0);
+
+/// How the sockets of TCP sessions are tuned.
+pub static TCP_OPTIONS: Lazy<RwLock<sosistab::TcpOptions>> = Lazy::new(Default::default);
+
+/// A fully-resolved route to an exit, detailed enough to reproduce the exact same connection later.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Route {
+    /// The exit at the end of the route.
+    pub exit: ExitDescriptor,
+    /// The sosistab endpoint we actually connect to. This is either the exit itself or a bridge.
+    pub endpoint: SocketAddr,
+    /// The sosistab public key of the endpoint.
+    pub sosistab_key: x25519_dalek::PublicKey,
+    /// Whether the endpoint is a bridge rather than the exit.
+    pub via_bridge: bool,
+    /// Whether the session runs over TCP rather than UDP.
+    pub use_tcp: bool,
+    /// Number of sosistab shards used by the session, or the most it may use if adaptive.
+    pub shards: usize,
+}
+
+impl Route {
+    /// Creates a route, filling in the shard count sosistab uses for the given transport.
+    pub fn new(
+        exit: ExitDescriptor,
+        endpoint: SocketAddr,
+        sosistab_key: x25519_dalek::PublicKey,
+        via_bridge: bool,
+        use_tcp: bool,
+    ) -> Self {
+        Self {
+            exit,
+            endpoint,
+            sosistab_key,
+            via_bridge,
+            use_tcp,
+            shards: match MAX_SHARDS.load(Ordering::Relaxed) {
+                0 if use_tcp => 16,
+                0 => 8,
+                max_shards => max_shards,
+            },
+        }
+    }
+
+    /// Loads a route previously exported through the stats server, making sure its exit still exists.
+    pub async fn load(path: &Path, ccache: &ClientCache) -> anyhow::Result<Self> {
+        let route: Route = serde_json::from_slice(
+            &smol::fs::read(path)
+                .await
+                .with_context(|| format!("cannot read route from {:?}", path))?,
+        )
+        .context("cannot parse route")?;
+        let exits = ccache.get_exits().await.context("can't get exits")?;
+        if !exits.contains(&route.exit) {
+            anyhow::bail!(
+                "exit {} in forced route no longer exists or has changed keys",
+                route.exit.hostname
+            )
+        }
+        Ok(route)
+    }
+
+    /// Connects a fresh sosistab session along this route.
+    pub async fn connect(&self) -> anyhow::Result<sosistab::Session> {
+        Ok(connect_endpoint(self.endpoint, self.sosistab_key, self.use_tcp).await?)
+    }
+}
+
+/// Connects a sosistab session to the given endpoint, with adaptive sharding if it's enabled. How the handshake went is recorded, to tell whether a transport is blocked.
+pub async fn connect_endpoint(
+    endpoint: SocketAddr,
+    sosistab_key: x25519_dalek::PublicKey,
+    use_tcp: bool,
+) -> Result<sosistab::Session, sosistab::ConnectError> {
+    let result = match (MAX_SHARDS.load(Ordering::Relaxed), use_tcp) {
+        (max_shards, true) => {
+            let options = *TCP_OPTIONS.read();
+            let max_shards = Some(max_shards).filter(|&max| max > 0);
+            sosistab::try_connect_tcp_tuned(endpoint, sosistab_key, max_shards, options).await
+        }
+        (0, false) => sosistab::try_connect_udp(endpoint, sosistab_key).await,
+        (max_shards, false) => {
+            sosistab::try_connect_udp_adaptive(endpoint, sosistab_key, max_shards).await
+        }
+    };
+    super::transport::record(use_tcp, &result);
+    result
+}
@@ -0,0 +1,250 @@
+use crate::{cache::ClientCache, main_connect::ConnectOpt};
+use anyhow::Context;
+use binder_transport::ExitDescriptor;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use smol_timeout::TimeoutExt;
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// How long we wait for a single exit to answer a latency probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many exits we probe at once when picking the one with the lowest latency.
+const PROBE_PARALLEL: usize = 32;
+
+/// How long to stay away from an exit that said it's draining, which is usually for maintenance.
+const DRAINING_AVOID: Duration = Duration::from_secs(3600);
+
+/// Exits that said they're draining, and when they did.
+static DRAINING: Lazy<Mutex<HashMap<String, Instant>>> = Lazy::new(Default::default);
+
+/// Remembers that an exit is draining, so that it isn't picked for a while.
+pub fn avoid_draining(hostname: &str) {
+    DRAINING.lock().insert(hostname.to_string(), Instant::now());
+}
+
+/// Strategy used to pick an exit out of the list the binder gives us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitSelect {
+    /// Only an exit whose hostname exactly matches `--exit-server`.
+    Exact,
+    /// The exit with the hostname most similar to `--exit-server`.
+    Fuzzy,
+    /// The exit that answers a handshake the fastest.
+    LowestLatency,
+    /// The exit with the lowest load as reported by the binder.
+    LeastLoaded,
+}
+
+impl FromStr for ExitSelect {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exact" => Ok(ExitSelect::Exact),
+            "fuzzy" => Ok(ExitSelect::Fuzzy),
+            "latency" => Ok(ExitSelect::LowestLatency),
+            "load" => Ok(ExitSelect::LeastLoaded),
+            other => anyhow::bail!(
+                "unknown exit selection strategy {:?} (expected exact, fuzzy, latency, or load)",
+                other
+            ),
+        }
+    }
+}
+
+/// Strategy used to pick a bridge out of the list the binder gives us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BridgeSelect {
+    /// The bridge that answers a handshake the fastest, trying all of them at once.
+    Fastest,
+    /// The bridges in the order the binder gives them, moving on to the next only when one is slow. The binder orders bridges differently for each user, so this spreads users out instead of piling them onto whichever bridge is closest.
+    Spread,
+}
+
+impl FromStr for BridgeSelect {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fastest" => Ok(BridgeSelect::Fastest),
+            "spread" => Ok(BridgeSelect::Spread),
+            other => anyhow::bail!(
+                "unknown bridge selection strategy {:?} (expected fastest or spread)",
+                other
+            ),
+        }
+    }
+}
+
+/// Picks an exit according to the configured strategy. Returns the exit, as well as a human-readable reason for picking it.
+pub async fn select_exit(
+    cfg: &ConnectOpt,
+    ccache: &ClientCache,
+    mut exits: Vec<ExitDescriptor>,
+) -> anyhow::Result<(ExitDescriptor, String)> {
+    if exits.is_empty() {
+        anyhow::bail!("no exits found")
+    }
+    if cfg.exit_select != ExitSelect::Exact {
+        exits = not_draining(exits);
+        if let Some(country) = &cfg.exit_country {
+            exits = in_country(country, exits);
+        }
+    }
+    match cfg.exit_select {
+        ExitSelect::Exact => {
+            let exit = exits
+                .into_iter()
+                .find(|e| e.hostname == cfg.exit_server)
+                .with_context(|| format!("no exit named exactly {}", cfg.exit_server))?;
+            Ok((exit, "exact hostname match".into()))
+        }
+        ExitSelect::Fuzzy => Ok(select_fuzzy(&cfg.exit_server, exits)),
+        ExitSelect::LowestLatency => {
+            let latencies = ccache
+                .get_exit_latencies(probe_all(exits.clone(), PROBE_PARALLEL))
+                .await
+                .context("cannot probe exits")?;
+            exits.sort_by(|a, b| {
+                let a = latencies.get(&a.hostname).cloned().unwrap_or(f64::INFINITY);
+                let b = latencies.get(&b.hostname).cloned().unwrap_or(f64::INFINITY);
+                a.partial_cmp(&b).unwrap()
+            });
+            let exit = exits[0].clone();
+            if let Some(latency) = latencies.get(&exit.hostname) {
+                Ok((exit, format!("lowest latency ({:.0} ms)", latency)))
+            } else {
+                log::warn!("no exit answered latency probes, falling back to fuzzy match");
+                Ok(select_fuzzy(&cfg.exit_server, exits))
+            }
+        }
+        ExitSelect::LeastLoaded => {
+            let loads = ccache.get_exit_loads().await.unwrap_or_else(|err| {
+                log::warn!("cannot get exit loads: {:?}", err);
+                BTreeMap::new()
+            });
+            // exits that haven't reported their load recently are never picked, unless none have
+            if let Some((exit, load)) = exits
+                .iter()
+                .filter_map(|e| Some((e, *loads.get(&e.hostname)?)))
+                .min_by_key(|(_, load)| *load)
+            {
+                Ok((exit.clone(), format!("least loaded ({} sessions)", load)))
+            } else {
+                log::warn!("no exit reported its load, falling back to fuzzy match");
+                Ok(select_fuzzy(&cfg.exit_server, exits))
+            }
+        }
+    }
+}
+
+/// Leaves out the exits that said they're draining, unless that leaves none.
+fn not_draining(exits: Vec<ExitDescriptor>) -> Vec<ExitDescriptor> {
+    let mut draining = DRAINING.lock();
+    draining.retain(|_, since| since.elapsed() < DRAINING_AVOID);
+    let remaining: Vec<_> = exits
+        .iter()
+        .filter(|e| !draining.contains_key(&e.hostname))
+        .cloned()
+        .collect();
+    if remaining.is_empty() {
+        exits
+    } else {
+        if remaining.len() < exits.len() {
+            log::debug!("avoiding draining exits {:?}", draining.keys());
+        }
+        remaining
+    }
+}
+
+/// Narrows down the exits to those in the given country, unless there are none.
+fn in_country(country: &str, exits: Vec<ExitDescriptor>) -> Vec<ExitDescriptor> {
+    let matching: Vec<_> = exits
+        .iter()
+        .filter(|e| e.country_code.eq_ignore_ascii_case(country))
+        .cloned()
+        .collect();
+    if matching.is_empty() {
+        log::warn!("no exits in country {:?}, considering all exits", country);
+        exits
+    } else {
+        log::debug!("{} exits in country {:?}", matching.len(), country);
+        matching
+    }
+}
+
+/// Picks the exit with the hostname most similar to the given one.
+fn select_fuzzy(exit_server: &str, mut exits: Vec<ExitDescriptor>) -> (ExitDescriptor, String) {
+    exits.sort_by(|a, b| {
+        strsim::damerau_levenshtein(&a.hostname, exit_server)
+            .cmp(&strsim::damerau_levenshtein(&b.hostname, exit_server))
+    });
+    let exit = exits[0].clone();
+    let reason = if exit.hostname == exit_server {
+        "exact hostname match".into()
+    } else {
+        format!("most similar hostname to {}", exit_server)
+    };
+    (exit, reason)
+}
+
+/// Probes every exit, at most the given number at once, returning the handshake latency in milliseconds of every exit that answered.
+pub async fn probe_all(
+    exits: Vec<ExitDescriptor>,
+    parallel: usize,
+) -> anyhow::Result<BTreeMap<String, f64>> {
+    let (send_exit, recv_exit) = smol::channel::unbounded();
+    for exit in exits {
+        send_exit.try_send(exit)?;
+    }
+    drop(send_exit);
+    let tasks: Vec<_> = (0..parallel.max(1))
+        .map(|_| {
+            let recv_exit = recv_exit.clone();
+            smolscale::spawn(async move {
+                let mut answered = Vec::new();
+                while let Ok(exit) = recv_exit.recv().await {
+                    let latency = probe_one(&exit).timeout(PROBE_TIMEOUT).await;
+                    match latency {
+                        Some(Ok(latency)) => {
+                            answered.push((exit.hostname, latency.as_secs_f64() * 1000.0))
+                        }
+                        Some(Err(err)) => {
+                            log::debug!("probing {} failed: {}", exit.hostname, err);
+                        }
+                        None => {
+                            log::debug!("probing {} timed out", exit.hostname);
+                        }
+                    }
+                }
+                answered
+            })
+        })
+        .collect();
+    let mut toret = BTreeMap::new();
+    for task in tasks {
+        for (hostname, latency) in task.await {
+            log::debug!("{} has latency {:.0} ms", hostname, latency);
+            toret.insert(hostname, latency);
+        }
+    }
+    Ok(toret)
+}
+
+/// Measures how long a sosistab handshake to the given exit takes.
+async fn probe_one(exit: &ExitDescriptor) -> anyhow::Result<Duration> {
+    let server_addr = aioutils::resolve(&format!("{}:19831", exit.hostname))
+        .await
+        .context("can't resolve hostname of exit")?
+        .into_iter()
+        .find(|v| v.is_ipv4())
+        .context("can't find ipv4 address for exit")?;
+    let start = Instant::now();
+    sosistab::connect_udp(server_addr, exit.sosistab_key).await?;
+    Ok(start.elapsed())
+}
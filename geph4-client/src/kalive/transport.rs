@@ -0,0 +1,105 @@
+use std::{
+    net::{IpAddr, UdpSocket},
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+/// After this many UDP handshakes in a row time out, UDP is probably blocked.
+const UDP_BLOCKED_AFTER: u64 = 3;
+
+/// How often to check whether we're on a different network.
+const NETWORK_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How handshakes over one transport have gone since we got on the current network.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct HandshakeCounts {
+    pub successes: u64,
+    pub timeouts: u64,
+    /// Timeouts since the last success.
+    pub consecutive_timeouts: u64,
+}
+
+impl HandshakeCounts {
+    fn record(&mut self, success: bool) {
+        if success {
+            self.successes += 1;
+            self.consecutive_timeouts = 0;
+        } else {
+            self.timeouts += 1;
+            self.consecutive_timeouts += 1;
+        }
+    }
+}
+
+/// UDP and TCP handshake outcomes, in that order.
+static COUNTS: Lazy<Mutex<(HandshakeCounts, HandshakeCounts)>> = Lazy::new(Default::default);
+
+/// Records how a handshake went. Only successes and timeouts say anything about whether the transport gets through, so other failures aren't counted.
+pub fn record(use_tcp: bool, result: &Result<sosistab::Session, sosistab::ConnectError>) {
+    let success = match result {
+        Ok(_) => true,
+        Err(sosistab::ConnectError::HandshakeTimeout) => false,
+        Err(_) => return,
+    };
+    let mut counts = COUNTS.lock();
+    if use_tcp {
+        counts.1.record(success)
+    } else {
+        counts.0.record(success)
+    }
+}
+
+/// The UDP and TCP handshake counts.
+pub fn counts() -> (HandshakeCounts, HandshakeCounts) {
+    *COUNTS.lock()
+}
+
+/// Whether UDP handshakes keep timing out, while TCP ones fare better, so that TCP should be used instead, saying so in the log if it should. TCP gets a chance even before it's been tried, but we go back to UDP if TCP times out just as much.
+pub fn fall_back_to_tcp() -> bool {
+    let (udp, tcp) = counts();
+    if udp.consecutive_timeouts < UDP_BLOCKED_AFTER
+        || tcp.consecutive_timeouts >= udp.consecutive_timeouts
+    {
+        return false;
+    }
+    if tcp.successes > 0 {
+        log::warn!(
+            "UDP appears blocked, using TCP ({} UDP handshakes in a row timed out, while TCP handshakes work)",
+            udp.consecutive_timeouts
+        );
+    } else {
+        log::warn!(
+            "{} UDP handshakes in a row timed out, so trying TCP",
+            udp.consecutive_timeouts
+        );
+    }
+    true
+}
+
+/// Forgets all handshake outcomes whenever we move to a different network, where they'd only mislead. Runs forever.
+pub async fn reset_on_network_change() {
+    let mut last = local_ip();
+    loop {
+        crate::power::background_wait(NETWORK_CHECK_INTERVAL).await;
+        let current = local_ip();
+        if current != last {
+            log::info!(
+                "network changed ({:?} -> {:?}), forgetting how handshakes went",
+                last,
+                current
+            );
+            *COUNTS.lock() = Default::default();
+            last = current;
+        }
+    }
+}
+
+/// The local address that outgoing traffic currently goes out from, which changes with the network. Nothing is actually sent.
+fn local_ip() -> Option<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("8.8.8.8:53").ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
@@ -0,0 +1,128 @@
+use std::{net::SocketAddr, path::PathBuf, str::FromStr};
+
+use anyhow::Context;
+
+/// Where a local listener listens: a TCP address, or, on Unix, the path of a Unix domain socket.
+#[derive(Debug, Clone)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse() {
+            return Ok(ListenAddr::Tcp(addr));
+        }
+        if !s.contains('/') {
+            anyhow::bail!("{:?} is neither a socket address nor a path", s)
+        }
+        if cfg!(unix) {
+            Ok(ListenAddr::Unix(PathBuf::from(s)))
+        } else {
+            anyhow::bail!("Unix domain sockets are not supported on this platform")
+        }
+    }
+}
+
+impl std::fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => addr.fmt(f),
+            ListenAddr::Unix(path) => path.display().fmt(f),
+        }
+    }
+}
+
+/// A listener for local applications.
+pub enum Listener {
+    Tcp(smol::net::TcpListener),
+    #[cfg(unix)]
+    Unix(smol::net::unix::UnixListener),
+}
+
+/// A connection from a local application.
+pub enum LocalConn {
+    Tcp(smol::net::TcpStream),
+    #[cfg(unix)]
+    Unix(smol::net::unix::UnixStream),
+}
+
+impl Listener {
+    /// Starts listening. Unix sockets are only usable by our own user, and a stale socket left behind by an earlier run is replaced, but nothing else at that path is.
+    pub async fn bind(addr: &ListenAddr) -> anyhow::Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(smol::net::TcpListener::bind(addr).await?)),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                use std::convert::TryFrom;
+                Ok(Listener::Unix(smol::net::unix::UnixListener::try_from(
+                    bind_unix(path)?,
+                )?))
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => unreachable!(),
+        }
+    }
+
+    /// Accepts a connection.
+    pub async fn accept(&self) -> std::io::Result<LocalConn> {
+        match self {
+            Listener::Tcp(listener) => Ok(LocalConn::Tcp(listener.accept().await?.0)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => Ok(LocalConn::Unix(listener.accept().await?.0)),
+        }
+    }
+}
+
+/// Binds a Unix socket that only our own user can connect to, replacing a stale socket left behind by an earlier run but nothing else at that path.
+///
+/// The socket is created inside a directory only we can enter, and moved into place once its permissions are set, so it's never open to anyone else, not even briefly.
+#[cfg(unix)]
+pub fn bind_unix(path: &std::path::Path) -> anyhow::Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            anyhow::bail!("{:?} already exists and isn't a socket", path)
+        }
+        std::fs::remove_file(path)
+            .with_context(|| format!("cannot remove stale socket {:?}", path))?;
+    }
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("{:?} doesn't name a file", path))?;
+    let private_dir = path.with_file_name(format!(
+        ".{}.{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)
+        .with_context(|| format!("cannot create directory {:?}", private_dir))?;
+    let bind = || -> anyhow::Result<_> {
+        let private_path = private_dir.join(file_name);
+        let listener = std::os::unix::net::UnixListener::bind(&private_path)
+            .with_context(|| format!("cannot create socket {:?}", path))?;
+        std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&private_path, path)
+            .with_context(|| format!("cannot move socket to {:?}", path))?;
+        Ok(listener)
+    };
+    let res = bind();
+    let _ = std::fs::remove_dir_all(&private_dir);
+    res
+}
+
+/// What handlers need from connections from local applications, whichever kind of socket they came over.
+pub trait LocalStream:
+    smol::io::AsyncRead + smol::io::AsyncWrite + Clone + Unpin + Send + Sync + 'static
+{
+}
+
+impl<T: smol::io::AsyncRead + smol::io::AsyncWrite + Clone + Unpin + Send + Sync + 'static>
+    LocalStream for T
+{
+}
@@ -1,31 +1,45 @@
 #![type_length_limit = "2000000"]
 
-use std::{io::Write, path::PathBuf, sync::Arc, time::Duration};
+use std::{io::Write, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use binder_transport::BinderClient;
 use flexi_logger::{DeferredNow, Record};
-use stats::GLOBAL_LOGGER;
 use structopt::StructOpt;
 mod cache;
+mod debugpack;
 mod kalive;
+mod listener;
 
 use once_cell::sync::Lazy;
 use prelude::*;
 mod dns;
+mod jsonstream;
 mod nettest;
+mod power;
 mod prelude;
+mod reachability;
 mod stats;
+mod transparent;
 mod vpn;
 
 mod china;
+mod main_bench;
 mod main_binderproxy;
 mod main_connect;
+mod main_diagnose;
+mod main_listexits;
 mod main_sync;
 #[derive(Debug, StructOpt)]
 enum Opt {
     Connect(main_connect::ConnectOpt),
     Sync(main_sync::SyncOpt),
     BinderProxy(main_binderproxy::BinderProxyOpt),
+    /// Measures round-trip time and throughput through the tunnel, using the exit's echo service.
+    Bench(main_bench::BenchOpt),
+    /// Walks through each stage of connecting to an exit, reporting how long each took and why any failed.
+    Diagnose(main_diagnose::DiagnoseOpt),
+    /// Lists the exits available, with how long each takes to answer, to help pick one for `connect --exit-server`.
+    ListExits(main_listexits::ListExitsOpt),
 }
 
 fn main() -> anyhow::Result<()> {
@@ -67,15 +81,11 @@ fn main() -> anyhow::Result<()> {
             record.line().unwrap_or(0),
             &record.args()
         );
-        let mut logger = GLOBAL_LOGGER.write();
-        logger.push_back(
+        stats::push_log_line(
             IP_REGEX
                 .replace_all(&detailed_line, "[redacted]")
                 .to_string(),
         );
-        if logger.len() > 100000 {
-            logger.pop_front();
-        }
         Ok(())
     }
 
@@ -85,26 +95,39 @@ fn main() -> anyhow::Result<()> {
         .format(logger)
         .start()
         .unwrap();
-    let opt: Opt = Opt::from_args();
+    let opt: Opt = Opt::from_iter(configfile::args()?);
     let version = env!("CARGO_PKG_VERSION");
     log::info!("geph4-client v{} starting...", version);
+    // warns right away if session keys are being logged
+    sosistab::keylog_enabled();
     // smolscale::permanently_single_threaded();
     smolscale::block_on(async move {
         match opt {
-            Opt::Connect(opt) => loop {
-                if let Err(err) = main_connect::main_connect(opt.clone()).await {
-                    log::error!("Something SERIOUSLY wrong has happened! {:#?}", err);
-                    smol::Timer::after(Duration::from_secs(1)).await;
+            Opt::Connect(opt) => {
+                // a bad key would otherwise fail every reconnect forever
+                opt.common.binder_master()?;
+                loop {
+                    if let Err(err) = main_connect::main_connect(opt.clone()).await {
+                        log::error!("Something SERIOUSLY wrong has happened! {:#?}", err);
+                        smol::Timer::after(Duration::from_secs(1)).await;
+                    }
                 }
-            },
+            }
             Opt::Sync(opt) => main_sync::main_sync(opt).await,
             Opt::BinderProxy(opt) => main_binderproxy::main_binderproxy(opt).await,
+            Opt::Bench(opt) => main_bench::main_bench(opt).await,
+            Opt::Diagnose(opt) => main_diagnose::main_diagnose(opt).await,
+            Opt::ListExits(opt) => main_listexits::main_listexits(opt).await,
         }
     })
 }
 
 #[derive(Debug, StructOpt, Clone)]
 pub struct CommonOpt {
+    #[structopt(long)]
+    /// TOML or YAML file of flags to use when they're not given on the command line, such as `exit_server = "us-hio-01.exits.geph.io"`.
+    config: Option<PathBuf>,
+
     #[structopt(
         long,
         default_value = "https://www.netlify.com/v4/,https://www.cdn77.com/,https://ajax.aspnetcdn.com/"
@@ -119,13 +142,25 @@ pub struct CommonOpt {
     /// HTTP(S) actual host of the binder
     binder_http_hosts: String,
 
-    #[structopt(
-        long,
-        default_value = "124526f4e692b589511369687498cce57492bf4da20f8d26019c1cc0c80b6e4b",
-        parse(from_str = str_to_x25519_pk)
-    )]
-    /// x25519 master key of the binder
-    binder_master: x25519_dalek::PublicKey,
+    #[structopt(long, parse(try_from_str = str_to_tls_pin))]
+    /// SHA-256 hash, in hex, of a TLS certificate the binder fronts may present. Can be given multiple times, and a front is accepted if it matches any of them. If not given, only the usual CA validation is done.
+    binder_tls_pins: Vec<[u8; 32]>,
+
+    #[structopt(long, default_value = "default")]
+    /// What the TLS handshake with the binder fronts should look like: "default", "chrome", "firefox", or "rotate" to switch between the two browsers on every connection. This only reorders cipher suites, which makes the handshake harder to block by an exact fingerprint, but it still doesn't look exactly like a browser's.
+    binder_tls_fingerprint: binder_transport::TlsFingerprint,
+
+    #[structopt(long)]
+    /// SOCKS5 proxy, without authentication, to reach the binder through when not going through the tunnel, such as when testing from an isolated network or bootstrapping over another circuit. Binder requests fail if it's unreachable.
+    binder_socks_proxy: Option<SocketAddr>,
+
+    #[structopt(long)]
+    /// x25519 master key of the binder, in hex. Defaults to the key built in at compile time, which is the public binder's unless GEPH_BINDER_MASTER_PK was set then.
+    binder_master: Option<String>,
+
+    #[structopt(long)]
+    /// file containing the binder's x25519 master key in hex, as an alternative to --binder-master for deployments with their own binder.
+    binder_master_pk_file: Option<PathBuf>,
 
     #[structopt(
         long,
@@ -145,7 +180,26 @@ pub struct CommonOpt {
 }
 
 impl CommonOpt {
-    pub fn to_binder_client(&self) -> Arc<dyn BinderClient> {
+    /// The binder's master key, from --binder-master, --binder-master-pk-file, or else built in.
+    pub fn binder_master(&self) -> anyhow::Result<x25519_dalek::PublicKey> {
+        Ok(configfile::binder_master_pk(
+            "binder-master",
+            self.binder_master.as_deref(),
+            self.binder_master_pk_file.as_deref(),
+        )?
+        .into())
+    }
+
+    pub fn to_binder_client(&self) -> anyhow::Result<Arc<dyn BinderClient>> {
+        self.to_binder_client_with(None)
+    }
+
+    /// Like `to_binder_client`, but opening connections to the binder with the given dialer.
+    pub fn to_binder_client_with(
+        &self,
+        dialer: Option<binder_transport::Dialer>,
+    ) -> anyhow::Result<Arc<dyn BinderClient>> {
+        let binder_master = self.binder_master()?;
         let fronts: Vec<_> = self
             .binder_http_fronts
             .split(',')
@@ -154,13 +208,22 @@ impl CommonOpt {
             .collect();
         let mut toret = binder_transport::MultiBinderClient::empty();
         for (front, host) in fronts {
-            toret = toret.add_client(binder_transport::HttpClient::new(
-                self.binder_master,
+            let mut client = binder_transport::HttpClient::new(
+                binder_master,
                 front,
                 &[("Host".to_string(), host.clone())],
-            ));
+            )
+            .with_tls_pins(&self.binder_tls_pins)
+            .with_tls_fingerprint(self.binder_tls_fingerprint);
+            if let Some(proxy) = self.binder_socks_proxy {
+                client = client.with_socks_proxy(proxy);
+            }
+            if let Some(dialer) = dialer.clone() {
+                client = client.with_dialer(dialer);
+            }
+            toret = toret.add_client(client);
         }
-        Arc::new(toret)
+        Ok(Arc::new(toret))
     }
 }
 
@@ -174,6 +237,10 @@ pub struct AuthOpt {
     /// where to store Geph's credential cache. The default value of "auto", meaning a platform-specific path that Geph gets to pick.
     credential_cache: PathBuf,
 
+    #[structopt(long)]
+    /// encrypt what's newly written to the credential cache with a passphrase, taken from the GEPH_PASSPHRASE environment variable or asked for on startup. This protects cached credentials if the disk is compromised, but Geph can then only start unattended if the passphrase is in its environment.
+    encrypt_cache: bool,
+
     #[structopt(long)]
     /// username
     username: String,
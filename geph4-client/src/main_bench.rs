@@ -0,0 +1,176 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cache::ClientCache, kalive::Keepalive, main_connect::ConnectOpt, stats::StatCollector,
+};
+use anyhow::Context;
+use smol::prelude::*;
+use smol_timeout::TimeoutExt;
+use structopt::StructOpt;
+
+/// Same as the exit's reserved echo label.
+pub(crate) const ECHO_LABEL: &str = "echo";
+
+/// How much is written to the echo service at a time.
+const ECHO_CHUNK: usize = 65536;
+
+#[derive(Debug, StructOpt, Clone)]
+pub struct BenchOpt {
+    #[structopt(flatten)]
+    connect: ConnectOpt,
+
+    #[structopt(long, default_value = "10")]
+    /// how long, in seconds, to measure throughput for.
+    seconds: u64,
+
+    #[structopt(long, default_value = "4")]
+    /// how many connections to measure throughput over at once.
+    parallel: usize,
+
+    #[structopt(long, default_value = "20")]
+    /// how many round trips to time.
+    pings: usize,
+}
+
+/// Measures the tunnel, exactly as `connect` would set it up, against the exit's echo service, and prints a report.
+///
+/// The echo service sends back what it gets, so every byte crosses the tunnel in both directions. The throughput measured is therefore that of the slower direction.
+pub async fn main_bench(opt: BenchOpt) -> anyhow::Result<()> {
+    log::info!("bench mode started");
+    opt.connect.set_globals();
+    let client_cache = ClientCache::from_opts(&opt.connect.common, &opt.connect.auth)
+        .context("cannot create ClientCache")?;
+    if let Some(path) = &opt.connect.import_cache {
+        client_cache
+            .import(path)
+            .context("cannot import cache bundle")?;
+    }
+    let stats = Arc::new(StatCollector::default());
+    let keepalive = Keepalive::new(stats.clone(), opt.connect.clone(), Arc::new(client_cache));
+
+    // the first connection waits for the tunnel to come up
+    let start = Instant::now();
+    let mut conn = open_echo(&keepalive)
+        .timeout(Duration::from_secs(120))
+        .await
+        .context("timed out connecting")??;
+    let connect_time = start.elapsed();
+    let mut pings = Vec::with_capacity(opt.pings);
+    let probe = [0u8; 32];
+    let mut reply = [0u8; 32];
+    for _ in 0..opt.pings {
+        let start = Instant::now();
+        conn.write_all(&probe).await?;
+        conn.flush().await?;
+        conn.read_exact(&mut reply).await?;
+        pings.push(start.elapsed());
+    }
+    drop(conn);
+    pings.sort_unstable();
+
+    let echoed = Arc::new(AtomicU64::new(0));
+    let deadline = Instant::now() + Duration::from_secs(opt.seconds);
+    let start = Instant::now();
+    let workers: Vec<smol::Task<anyhow::Result<()>>> = (0..opt.parallel.max(1))
+        .map(|_| {
+            let keepalive = keepalive.clone();
+            let echoed = echoed.clone();
+            smolscale::spawn(async move {
+                while Instant::now() < deadline {
+                    echo_stream(&keepalive, &echoed, deadline).await?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.await?;
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    let mbps = echoed.load(Ordering::Relaxed) as f64 * 8.0 / elapsed / 1_000_000.0;
+
+    if let Some(route) = stats.get_route() {
+        println!("exit:       {}", route.exit.hostname);
+        println!(
+            "route:      {} (bridge: {}, tcp: {})",
+            route.endpoint, route.via_bridge, route.use_tcp
+        );
+    }
+    println!("connect:    {:.0} ms", connect_time.as_secs_f64() * 1000.0);
+    if !pings.is_empty() {
+        println!(
+            "rtt:        {:.1} ms min, {:.1} ms median, {:.1} ms max",
+            pings[0].as_secs_f64() * 1000.0,
+            pings[pings.len() / 2].as_secs_f64() * 1000.0,
+            pings[pings.len() - 1].as_secs_f64() * 1000.0
+        );
+    }
+    println!("up/down:    {:.2} Mbps each way", mbps);
+    println!(
+        "(echoed {} bytes over {} connections in {:.1} s; both directions carry the same bytes, so this is the speed of the slower one)",
+        echoed.load(Ordering::Relaxed),
+        opt.parallel.max(1),
+        elapsed
+    );
+    Ok(())
+}
+
+/// Opens a connection to the echo service, skipping the timestamp it starts with.
+async fn open_echo(keepalive: &Keepalive) -> anyhow::Result<sosistab::mux::RelConn> {
+    let mut conn = keepalive.connect_label(ECHO_LABEL).await?;
+    let mut timestamp = [0u8; 8];
+    if conn.read_exact(&mut timestamp).await.is_err() {
+        anyhow::bail!(
+            "exit doesn't run an echo service ({:?})",
+            conn.close_reason()
+        )
+    }
+    Ok(conn)
+}
+
+/// Streams through the echo service over one connection until the deadline, counting echoed bytes. Returns early if the exit stops echoing, which older exits do after 64 KiB.
+async fn echo_stream(
+    keepalive: &Keepalive,
+    echoed: &AtomicU64,
+    deadline: Instant,
+) -> anyhow::Result<()> {
+    let conn = open_echo(keepalive).await?;
+    let writer = conn.clone();
+    let mut reader = conn;
+    let recv = async move {
+        let mut buf = [0u8; 8192];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                return Ok::<_, anyhow::Error>(());
+            }
+            echoed.fetch_add(n as u64, Ordering::Relaxed);
+        }
+    };
+    recv.or(async {
+        // writes fail once the exit stops echoing, which the reader then sees as the end of the stream
+        if let Err(err) = write_forever(writer).await {
+            log::debug!("echo writer stopped: {}", err);
+        }
+        smol::future::pending().await
+    })
+    .or(async {
+        smol::Timer::at(deadline).await;
+        Ok(())
+    })
+    .await
+}
+
+async fn write_forever(mut writer: sosistab::mux::RelConn) -> std::io::Result<()> {
+    let buf = vec![0u8; ECHO_CHUNK];
+    loop {
+        writer.write_all(&buf).await?;
+        writer.flush().await?;
+    }
+}
@@ -1,46 +1,125 @@
-use crate::{cache::ClientCache, kalive::Keepalive, stats::StatCollector, AuthOpt, CommonOpt};
-use crate::{china, stats::GLOBAL_LOGGER};
+use crate::{
+    cache::ClientCache,
+    kalive::{BridgeSelect, ExitSelect, Keepalive},
+    stats::StatCollector,
+    AuthOpt, CommonOpt,
+};
+use crate::{
+    china,
+    listener::{ListenAddr, Listener, LocalConn, LocalStream},
+    stats::{GLOBAL_LOGGER, GLOBAL_LOGGER_CAPACITY},
+};
 use anyhow::Context;
 use async_compat::Compat;
+use binder_transport::ConnRejection;
 use chrono::prelude::*;
+use parking_lot::Mutex;
 use smol_timeout::TimeoutExt;
-use std::{net::Ipv4Addr, net::SocketAddr, net::SocketAddrV4, sync::Arc, time::Duration};
+use sosistab::mux::CloseReason;
+use std::{
+    net::IpAddr, net::Ipv4Addr, net::SocketAddr, net::SocketAddrV4, path::PathBuf,
+    sync::atomic::Ordering, sync::Arc, time::Duration, time::SystemTime,
+};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt, Clone)]
 pub struct ConnectOpt {
     #[structopt(flatten)]
-    common: CommonOpt,
+    pub common: CommonOpt,
 
     #[structopt(flatten)]
-    auth: AuthOpt,
+    pub auth: AuthOpt,
 
     #[structopt(long)]
     /// whether or not to use bridges
     pub use_bridges: bool,
 
     #[structopt(long, default_value = "127.0.0.1:9910")]
-    /// where to listen for HTTP proxy connections
-    http_listen: SocketAddr,
+    /// where to listen for HTTP proxy connections. Like --socks5-listen, this can be the path of a Unix domain socket.
+    http_listen: ListenAddr,
     #[structopt(long, default_value = "127.0.0.1:9909")]
-    /// where to listen for SOCKS5 connections
-    socks5_listen: SocketAddr,
+    /// where to listen for SOCKS5 connections. On Unix, this can also be the path of a Unix domain socket, which only our own user can connect to. The HTTP proxy works by connecting to the SOCKS5 listener over TCP, so it's disabled in that case.
+    socks5_listen: ListenAddr,
     #[structopt(long, default_value = "127.0.0.1:9809")]
-    /// where to listen for REST-based local connections
-    stats_listen: SocketAddr,
+    /// where to listen for REST-based local connections. Like --socks5-listen, this can be the path of a Unix domain socket.
+    stats_listen: ListenAddr,
+
+    #[structopt(long)]
+    /// where to listen for connections redirected by iptables REDIRECT or TPROXY rules, which are tunneled to their original destination. Linux only. Optional.
+    transparent_listen: Option<SocketAddr>,
 
     #[structopt(long)]
     /// where to listen for proxied DNS requests. Optional.
     dns_listen: Option<SocketAddr>,
 
+    #[structopt(long)]
+    /// if set, DNS requests that find no idle upstream connection wait up to this many milliseconds for one to free up, instead of each opening a new connection through the exit.
+    dns_coalesce_ms: Option<u64>,
+
+    #[structopt(long)]
+    /// share one tunneled connection per DNS server among all SOCKS5 and transparent proxy connections to its port 53, rather than opening one through the exit for each. DNS over TCP allows many queries per connection, so this is safe for DNS, but breaks anything else on port 53.
+    pub coalesce_dns_tcp: bool,
+
     #[structopt(long, default_value = "us-hio-01.exits.geph.io")]
     /// which exit server to connect to. If there isn't an exact match, the exit server with the most similar hostname is picked.
     pub exit_server: String,
 
+    #[structopt(long)]
+    /// only pick among exits in this country, given as a two-letter code such as "jp". If the binder knows of no exits there, any exit may be picked. Ignored with `--exit-select exact`, where `--exit-server` always wins.
+    pub exit_country: Option<String>,
+
+    #[structopt(long)]
+    /// tunnel everything through the selected exit to this second exit, given by its exact hostname. The first exit then sees where you connect from but not what you connect to, and the second exit the reverse. This at least halves throughput and adds the latencies of both exits. The first exit must allow TCP connections to port 19831, which exits with a port whitelist don't.
+    pub second_exit: Option<String>,
+
+    #[structopt(long)]
+    /// a route previously exported from the /route endpoint of the stats server. If given, the client connects using exactly that route, bypassing exit and bridge selection.
+    pub force_route: Option<PathBuf>,
+
+    #[structopt(long, default_value = "fuzzy")]
+    /// how to pick an exit server. "exact" requires the exit to be named exactly --exit-server, "fuzzy" picks the most similar hostname, "latency" picks the exit that responds the fastest, and "load" picks the least loaded exit.
+    pub exit_select: ExitSelect,
+
+    #[structopt(long, default_value = "fastest")]
+    /// how to pick a bridge. "fastest" tries every bridge at once and uses whichever answers first, while "spread" tries them one after another in the order the binder suggests, moving on when one is slow, which spreads users more evenly across bridges.
+    pub bridge_select: BridgeSelect,
+
     #[structopt(long)]
     /// whether or not to exclude PRC domains
     exclude_prc: bool,
 
+    #[structopt(long, conflicts_with = "exclude-prc")]
+    /// refuse SOCKS5 connections, instead of holding them until the tunnel comes back, whenever there's no working session to the exit. Connections are never made outside the tunnel, so this can't be used with --exclude-prc.
+    kill_switch: bool,
+
+    #[structopt(long)]
+    /// if sessions to the exit repeatedly get established but then carry no connections, which happens when the client and exit disagree about the protocol, retry with older protocol versions. Older versions are weaker, so this is off by default.
+    pub protocol_downgrade: bool,
+
+    #[structopt(long, conflicts_with = "prefer-ipv4")]
+    /// connect to destinations over IPv6 when they have both IPv4 and IPv6 addresses. Tunneled connections are resolved by the exit, so this is ignored with exits too old to support it. Connections bypassing the tunnel due to --exclude-prc follow this too.
+    prefer_ipv6: bool,
+
+    #[structopt(long)]
+    /// connect to destinations over IPv4 when they have both IPv4 and IPv6 addresses, in the same way as --prefer-ipv6.
+    prefer_ipv4: bool,
+
+    #[structopt(long, conflicts_with = "kill-switch")]
+    /// connect directly, bypassing the tunnel, to destinations on ports the exit doesn't allow. Destinations refused for other reasons, such as internal addresses, are never bypassed. Only exits that say why they refuse connections are bypassed this way.
+    pub direct_if_refused: bool,
+
+    #[structopt(long, use_delimiter = true)]
+    /// comma-separated ports, such as 22,53, whose tunneled connections are sent ahead of everything else, both ways, so that SSH or DNS stay responsive during big downloads. Only list ports of connections that send little, since they can starve the rest. Exits too old to understand priorities send their side at normal priority.
+    pub high_priority_ports: Vec<u16>,
+
+    #[structopt(long, use_delimiter = true)]
+    /// comma-separated ports whose tunneled connections, both ways, only get bandwidth nothing else wants, for bulk transfers that shouldn't slow down browsing.
+    pub low_priority_ports: Vec<u16>,
+
+    #[structopt(long)]
+    /// help the binder find dead bridges and rank exits by periodically reporting which exits, bridges and transports worked, tagged with this coarse region, such as a two-letter country code. Reports carry nothing identifying the user. Off unless given.
+    report_reachability: Option<String>,
+
     #[structopt(long)]
     /// whether or not to wait for VPN commands on stdio
     pub stdio_vpn: bool,
@@ -53,46 +132,300 @@ pub struct ConnectOpt {
     /// a name for this test instance.
     nettest_name: Option<String>,
 
+    #[structopt(long)]
+    /// a file to append test results to, in the format given by --nettest-format. If set, will periodically do network testing.
+    nettest_output: Option<PathBuf>,
+
+    #[structopt(long, default_value = "json")]
+    /// format of --nettest-output: "json" for one JSON object per line, or "csv" for a header row followed by one row per test.
+    nettest_format: crate::nettest::NettestFormat,
+
     #[structopt(long)]
     /// whether or not to force TCP mode.
     pub use_tcp: bool,
+
+    #[structopt(long, default_value = "120")]
+    /// how many seconds connecting to an exit, from fetching the exit list to authenticating, may take before giving up and logging which stage got stuck. Zero disables the timeout.
+    pub connect_timeout: u64,
+
+    #[structopt(long)]
+    /// whether to exit the process, rather than retry, when the connect timeout is exceeded.
+    pub exit_on_connect_timeout: bool,
+
+    #[structopt(long, default_value = "1", parse(try_from_str = crate::prelude::str_to_secs))]
+    /// seconds to wait before reconnecting after the tunnel first fails.
+    pub reconnect_initial_delay: f64,
+
+    #[structopt(long, default_value = "30", parse(try_from_str = crate::prelude::str_to_secs))]
+    /// most seconds to wait between reconnects.
+    pub reconnect_max_delay: f64,
+
+    #[structopt(long, default_value = "2", parse(try_from_str = crate::prelude::str_to_multiplier))]
+    /// how much longer to wait after each failed reconnect than after the one before.
+    pub reconnect_multiplier: f64,
+
+    #[structopt(long, default_value = "0.2", parse(try_from_str = crate::prelude::str_to_fraction))]
+    /// fraction by which each wait between reconnects is randomly lengthened or shortened, so that many clients cut off at once don't reconnect in lockstep.
+    pub reconnect_jitter: f64,
+
+    #[structopt(long, default_value = "0")]
+    /// how many reconnects in a row may fail before the process exits. Zero retries forever.
+    pub reconnect_max_attempts: u32,
+
+    #[structopt(long, default_value = "0-1000")]
+    /// range, as MIN-MAX, of the padded length of handshake packets. Changing this from the default makes handshakes look different from every other client's, so only do so to mimic some other protocol.
+    handshake_padding: sosistab::HandshakePadding,
+
+    #[structopt(long, default_value = "none")]
+    /// how to disguise UDP traffic: "none", or "dtls" to make it look like a WebRTC call, at 13 bytes of overhead per packet. Exits and bridges must be new enough to understand it.
+    udp_obfuscation: sosistab::UdpObfuscation,
+
+    #[structopt(long, default_value = "plain")]
+    /// how to frame packets sent over TCP: "plain", or "padded" to mix in padding and split writes at random, so that TCP segment sizes don't give away the packets inside. Padding costs some bandwidth, which the exit's health endpoint reports. Exits too old to understand it get plain framing.
+    tcp_framing: sosistab::TcpFraming,
+
+    #[structopt(long, default_value = "interactive")]
+    /// what TCP sessions are tuned for: "interactive", which sends every write right away, or "bulk", which turns on Nagle's algorithm and uses 4 MiB socket buffers, making small writes wait up to a round trip but big transfers on fast, distant links quicker.
+    tcp_workload: sosistab::TcpOptions,
+
+    #[structopt(long)]
+    /// largest TCP segment, in bytes, that TCP sessions send, for networks that drop big segments without telling anyone. Only works on Linux and macOS.
+    tcp_max_segment: Option<u32>,
+
+    #[structopt(long, default_value = "adaptive")]
+    /// how much forward error correction to send: "adaptive" to send parity only as measured loss calls for, "off", or a percentage such as "20%" to always send at least that much parity. A fixed percentage helps on links with bursty loss, such as mobile or satellite links, but wastes that much bandwidth on clean links.
+    fec: sosistab::FecMode,
+
+    #[structopt(long, default_value = "10240")]
+    /// receive window, in KiB, of each tunneled connection. This caps how much downloaded data can pile up waiting for applications to read it.
+    recv_window_kb: usize,
+
+    #[structopt(long, default_value = "256")]
+    /// how many MiB all tunneled connections together may use for data waiting to be read or copied. A quarter of it goes to copy buffers, which stop growing with throughput once it's used up, and the rest to receive windows, which then shrink so that the exit sends more slowly. Each connection's receive window is the smaller of --recv-window-kb and its share of what's left. Lower this on devices with little memory.
+    buffer_budget_mb: usize,
+
+    #[structopt(long)]
+    /// for debugging: hash everything sent over each tunneled connection and check it against what arrives, so that data corrupted inside the tunnel fails loudly rather than reaching applications unnoticed. This costs CPU on every byte, and only checks anything if the exit turns it on too.
+    debug_integrity: bool,
+
+    #[structopt(long)]
+    /// cache bundle, exported by `sync --export` on a machine that can reach the binder, to load before connecting. This lets the client start without ever reaching the binder itself.
+    pub import_cache: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// if set, sessions start with a few shards and add or drop shards based on measured loss and throughput, up to this many. Otherwise, sessions always use 8 shards over UDP and 16 over TCP.
+    max_shards: Option<usize>,
+
+    #[structopt(long, default_value = "0")]
+    /// how many idle connections to the exit to keep open, so that new requests don't have to wait for a connection to open. Zero disables this.
+    pub warmup_conns: usize,
+
+    #[structopt(long, default_value = "1")]
+    /// how many sessions to the exit to keep open at once. New connections are spread across all of them, and fall back to the others if one fails. Each connection goes over a single session, so one connection is never faster than its session, but many together can use all of them. Extra sessions alternate between UDP and TCP, and avoid bridges already in use.
+    pub multipath: usize,
+
+    #[structopt(long, default_value = "0")]
+    /// rate, in KiB/s, of cover traffic: dummy packets sent to the exit, which throws them away, so that someone watching the connection can't as easily tell when and how much is being uploaded. Downloads aren't covered. This costs that much upload bandwidth for as long as the client is connected. Zero disables it, and exits too old to understand it get none.
+    cover_traffic_kbps: u64,
+
+    #[structopt(long, default_value = "fill")]
+    /// how cover traffic is sent: "fill" to top real uploads up to the rate, or "constant" to always send the full rate on top of them, which hides activity better at a higher cost.
+    cover_traffic_pattern: crate::kalive::CoverPattern,
+
+    #[structopt(long)]
+    /// once connected, talk to the binder through the tunnel rather than directly, so that only the initial connection is visible to the local network.
+    binder_via_tunnel: bool,
+
+    #[structopt(long)]
+    /// save battery on phones and laptops by waking up less often: keepalive pings are spaced out closer to the measured NAT timeout, and background work waits longer while no connections are open. New connections wake everything up right away, but network changes and suspends take longer to notice, and the tunnel is more often found dead, and reconnected, when the first connection after a long idle opens.
+    low_power: bool,
+
+    #[structopt(long, default_value = "100000")]
+    /// how many log lines to keep in memory for the debug pack. Older lines are dropped first.
+    log_buffer_lines: usize,
+}
+
+impl ConnectOpt {
+    /// Applies the settings that are process-wide rather than per-session.
+    pub fn set_globals(&self) {
+        self.handshake_padding.set();
+        self.udp_obfuscation.set();
+        self.tcp_framing.set();
+        self.fec.set();
+        crate::power::set_low_power(self.low_power);
+        sosistab::mux::set_recv_window(self.recv_window_kb * 1024);
+        let budget = self.buffer_budget_mb * 1024 * 1024;
+        aioutils::set_copy_budget(budget / 4);
+        sosistab::mux::set_buffer_budget(budget - budget / 4);
+        sosistab::mux::set_integrity_check(self.debug_integrity);
+        crate::kalive::MAX_SHARDS.store(self.max_shards.unwrap_or_default(), Ordering::Relaxed);
+        *crate::kalive::TCP_OPTIONS.write() = sosistab::TcpOptions {
+            max_segment: self.tcp_max_segment,
+            ..self.tcp_workload
+        };
+    }
+
+    /// How long to wait between reconnects.
+    pub fn backoff(&self) -> crate::kalive::Backoff {
+        crate::kalive::Backoff::new(
+            Duration::from_secs_f64(self.reconnect_initial_delay),
+            Duration::from_secs_f64(self.reconnect_max_delay),
+            self.reconnect_multiplier,
+            self.reconnect_jitter,
+            self.reconnect_max_attempts,
+        )
+    }
+
+    /// How much cover traffic to send, if any.
+    pub fn cover_traffic(&self) -> Option<crate::kalive::CoverTraffic> {
+        if self.cover_traffic_kbps == 0 {
+            return None;
+        }
+        Some(crate::kalive::CoverTraffic {
+            rate: self.cover_traffic_kbps * 1024,
+            pattern: self.cover_traffic_pattern,
+        })
+    }
+
+    /// The priority of tunneled connections to the given destination, which is normal unless its port was listed in --high-priority-ports or --low-priority-ports.
+    pub fn conn_priority(&self, destination: &str) -> sosistab::Priority {
+        let port = destination
+            .rsplit(':')
+            .next()
+            .and_then(|port| port.parse::<u16>().ok());
+        match port {
+            Some(port) if self.high_priority_ports.contains(&port) => sosistab::Priority::High,
+            Some(port) if self.low_priority_ports.contains(&port) => sosistab::Priority::Low,
+            _ => sosistab::Priority::Normal,
+        }
+    }
+
+    /// Which address family to prefer for destinations.
+    pub fn addr_preference(&self) -> aioutils::AddrPreference {
+        if self.prefer_ipv6 {
+            aioutils::AddrPreference::Ipv6
+        } else if self.prefer_ipv4 {
+            aioutils::AddrPreference::Ipv4
+        } else {
+            aioutils::AddrPreference::Default
+        }
+    }
 }
 
 pub async fn main_connect(opt: ConnectOpt) -> anyhow::Result<()> {
     log::info!("connect mode started");
+    if let Some(config) = &opt.common.config {
+        log::info!("using flags from {:?}", config);
+    }
+    GLOBAL_LOGGER_CAPACITY.store(opt.log_buffer_lines, Ordering::Relaxed);
+    opt.set_globals();
 
     //start socks 2 http
-    smolscale::spawn(Compat::new(socks2http::run_tokio(opt.http_listen, {
-        let mut addr = opt.socks5_listen;
-        addr.set_ip("127.0.0.1".parse().unwrap());
-        addr
-    })))
-    .detach();
+    match &opt.socks5_listen {
+        ListenAddr::Tcp(socks5_listen) => {
+            let mut socks5_addr = *socks5_listen;
+            socks5_addr.set_ip("127.0.0.1".parse().unwrap());
+            match &opt.http_listen {
+                ListenAddr::Tcp(http_listen) => {
+                    smolscale::spawn(Compat::new(socks2http::run_tokio(
+                        *http_listen,
+                        socks5_addr,
+                    )))
+                    .detach();
+                }
+                #[cfg(unix)]
+                ListenAddr::Unix(path) => {
+                    let http_listener = crate::listener::bind_unix(path)
+                        .with_context(|| format!("cannot bind http to {}", opt.http_listen))?;
+                    smolscale::spawn(Compat::new(socks2http::run_tokio_unix(
+                        http_listener,
+                        socks5_addr,
+                    )))
+                    .detach();
+                }
+                #[cfg(not(unix))]
+                ListenAddr::Unix(_) => unreachable!(),
+            }
+        }
+        ListenAddr::Unix(_) => {
+            log::warn!("not starting the HTTP proxy, since SOCKS5 listens on a Unix socket")
+        }
+    }
 
     let stat_collector = Arc::new(StatCollector::default());
     // create a db directory if doesn't exist
-    let client_cache =
-        ClientCache::from_opts(&opt.common, &opt.auth).context("cannot create ClientCache")?;
+    let keepalive_slot = Arc::new(Mutex::new(None));
+    let client_cache = if opt.binder_via_tunnel {
+        let dialer = tunnel_dialer(stat_collector.clone(), keepalive_slot.clone());
+        ClientCache::from_opts_with_binder(
+            &opt.common,
+            &opt.auth,
+            opt.common.to_binder_client_with(Some(dialer))?,
+        )
+    } else {
+        ClientCache::from_opts(&opt.common, &opt.auth)
+    }
+    .context("cannot create ClientCache")?;
+    if let Some(path) = &opt.import_cache {
+        client_cache
+            .import(path)
+            .context("cannot import cache bundle")?;
+    }
+    let client_cache = Arc::new(client_cache);
+    if let Some(region) = opt.report_reachability.clone() {
+        let client_cache = client_cache.clone();
+        smolscale::spawn(
+            async move { crate::reachability::report_loop(&client_cache, region).await },
+        )
+        .detach();
+    }
     // create a kalive
-    let keepalive = Keepalive::new(stat_collector.clone(), opt.clone(), Arc::new(client_cache));
+    let keepalive = Keepalive::new(stat_collector.clone(), opt.clone(), client_cache.clone());
+    *keepalive_slot.lock() = Some(keepalive.clone());
     // enter the socks5 loop
-    let socks5_listener = smol::net::TcpListener::bind(opt.socks5_listen)
+    let socks5_listener = Listener::bind(&opt.socks5_listen)
         .await
-        .context("cannot bind socks5")?;
-    let stat_listener = smol::net::TcpListener::bind(opt.stats_listen)
+        .with_context(|| format!("cannot bind socks5 to {}", opt.socks5_listen))?;
+    let stat_listener = Listener::bind(&opt.stats_listen)
         .await
-        .context("cannot bind stats")?;
+        .with_context(|| format!("cannot bind stats to {}", opt.stats_listen))?;
+    let transparent_listener = opt
+        .transparent_listen
+        .map(|listen| {
+            crate::transparent::bind_transparent(listen)
+                .with_context(|| format!("cannot bind transparent proxy to {}", listen))
+        })
+        .transpose()?;
     let scollect = stat_collector.clone();
+    smolscale::spawn(loss_diagnostic(keepalive.clone())).detach();
     // scope
     if let Some(dns_listen) = opt.dns_listen {
         log::debug!("starting dns...");
-        smolscale::spawn(crate::dns::dns_loop(dns_listen, keepalive.clone())).detach();
+        smolscale::spawn(crate::dns::dns_loop(
+            dns_listen,
+            keepalive.clone(),
+            opt.dns_coalesce_ms.map(Duration::from_millis),
+        ))
+        .detach();
     }
-    if let Some(nettest_server) = opt.nettest_server {
-        log::info!("Network testing enabled at {}!", nettest_server);
+    if opt.nettest_server.is_some() || opt.nettest_output.is_some() {
+        if let Some(nettest_server) = opt.nettest_server {
+            log::info!("Network testing enabled at {}!", nettest_server);
+        }
+        if let Some(nettest_output) = &opt.nettest_output {
+            log::info!("Network testing results go to {:?}", nettest_output);
+        }
         smolscale::spawn(crate::nettest::nettest(
-            opt.nettest_name.unwrap(),
-            nettest_server,
+            opt.nettest_name.clone(),
+            crate::nettest::NettestSinks {
+                server: opt.nettest_server,
+                output: opt
+                    .nettest_output
+                    .clone()
+                    .map(|path| (path, opt.nettest_format)),
+            },
+            stat_collector.clone(),
+            client_cache,
         ))
         .detach();
     }
@@ -100,37 +433,104 @@ pub async fn main_connect(opt: ConnectOpt) -> anyhow::Result<()> {
         let keepalive = keepalive.clone();
         smolscale::spawn(async move {
             loop {
-                let (stat_client, _) = stat_listener.accept().await?;
+                let stat_client = stat_listener.accept().await?;
                 let scollect = scollect.clone();
                 let keepalive = keepalive.clone();
                 smolscale::spawn(async move {
-                    drop(
-                        async_h1::accept(stat_client, |req| {
-                            handle_stats(scollect.clone(), &keepalive, req)
-                        })
-                        .await,
-                    );
+                    let serve = |req| handle_stats(scollect.clone(), &keepalive, req);
+                    drop(match stat_client {
+                        LocalConn::Tcp(conn) => async_h1::accept(conn, serve).await,
+                        #[cfg(unix)]
+                        LocalConn::Unix(conn) => async_h1::accept(conn, serve).await,
+                    });
                 })
                 .detach();
             }
         })
     };
     let exclude_prc = opt.exclude_prc;
+    let kill_switch = opt.kill_switch;
+    if let Some(transparent_listener) = transparent_listener {
+        smolscale::spawn(crate::transparent::transparent_loop(
+            transparent_listener,
+            stat_collector.clone(),
+            keepalive.clone(),
+            exclude_prc,
+        ))
+        .detach();
+    }
 
     loop {
-        let (s5client, _) = socks5_listener
+        let s5client = socks5_listener
             .accept()
             .await
             .context("cannot accept socks5")?;
         let keepalive = keepalive.clone();
         let stat_collector = stat_collector.clone();
         smolscale::spawn(async move {
-            handle_socks5(stat_collector, s5client, &keepalive, exclude_prc).await
+            let _active = crate::power::active();
+            match s5client {
+                LocalConn::Tcp(s5client) => {
+                    s5client.set_nodelay(true)?;
+                    handle_socks5(
+                        stat_collector,
+                        s5client,
+                        &keepalive,
+                        exclude_prc,
+                        kill_switch,
+                    )
+                    .await
+                }
+                #[cfg(unix)]
+                LocalConn::Unix(s5client) => {
+                    handle_socks5(
+                        stat_collector,
+                        s5client,
+                        &keepalive,
+                        exclude_prc,
+                        kill_switch,
+                    )
+                    .await
+                }
+            }
         })
         .detach()
     }
 }
-use std::io::prelude::*;
+/// Dials the binder through the tunnel whenever it's up. Otherwise, including while bootstrapping, it connects directly, since reconnecting the tunnel itself needs the binder.
+fn tunnel_dialer(
+    stats: Arc<StatCollector>,
+    keepalive: Arc<Mutex<Option<Keepalive>>>,
+) -> binder_transport::Dialer {
+    binder_transport::Dialer(Arc::new(
+        move |dest: String| -> binder_transport::DialFuture {
+            let keepalive = if stats.is_connected() {
+                keepalive.lock().clone()
+            } else {
+                None
+            };
+            Box::pin(async move {
+                if let Some(keepalive) = keepalive {
+                    log::debug!("dialing binder {} through the tunnel", dest);
+                    let conn = keepalive
+                        .connect(&dest)
+                        .timeout(Duration::from_secs(10))
+                        .await
+                        .ok_or_else(|| {
+                            aioutils::to_ioerror("timed out dialing binder through tunnel")
+                        })?
+                        .map_err(aioutils::to_ioerror)?;
+                    Ok(Some(aioutils::connify(conn)))
+                } else {
+                    Ok(None)
+                }
+            })
+        },
+    ))
+}
+
+/// Connection lists at least this long are serialized as they're sent, rather than all at once.
+const STREAM_CONNS_THRESHOLD: usize = 1000;
 
 /// Handle a request for stats
 async fn handle_stats(
@@ -141,62 +541,74 @@ async fn handle_stats(
     let mut res = http_types::Response::new(http_types::StatusCode::Ok);
     match _req.url().path() {
         "/debugpack" => {
-            // create logs and sosistab buffers
-            // form a tar
-            let tar_buffer = Vec::new();
-            let mut tar_build = tar::Builder::new(tar_buffer);
-            let mut logs_buffer = Vec::new();
-            {
-                let noo = GLOBAL_LOGGER.read();
-                for line in noo.iter() {
-                    writeln!(logs_buffer, "{}", line)?;
-                }
-            }
-            let detail = kalive.get_stats().timeout(Duration::from_secs(1)).await;
-            if let Some(detail) = detail {
-                let detail = detail?;
-                let mut sosistab_buf = Vec::new();
-                writeln!(sosistab_buf, "time,last_recv,total_recv,total_loss,ping")?;
-                if let Some(first) = detail.first() {
-                    let first_time = first.time;
-                    for item in detail.iter() {
-                        writeln!(
-                            sosistab_buf,
-                            "{},{},{},{},{}",
-                            item.time
-                                .duration_since(first_time)
-                                .unwrap_or_default()
-                                .as_secs_f64(),
-                            item.high_recv,
-                            item.total_recv,
-                            item.total_loss,
-                            item.ping.as_secs_f64() * 1000.0,
-                        )?;
-                    }
-                }
-                let mut sosis_header = tar::Header::new_gnu();
-                sosis_header.set_mode(0o666);
-                sosis_header.set_size(sosistab_buf.len() as u64);
-                tar_build.append_data(
-                    &mut sosis_header,
-                    "sosistab-trace.csv",
-                    sosistab_buf.as_slice(),
-                )?;
-            }
-            let mut logs_header = tar::Header::new_gnu();
-            logs_header.set_mode(0o666);
-            logs_header.set_size(logs_buffer.len() as u64);
-            tar_build.append_data(&mut logs_header, "logs.txt", logs_buffer.as_slice())?;
-            let result = tar_build.into_inner()?;
-            res.insert_header("content-type", "application/tar");
+            let logs: Vec<String> = GLOBAL_LOGGER.read().iter().cloned().collect();
+            let traces = match kalive.get_stats().timeout(Duration::from_secs(1)).await {
+                Some(traces) => traces?,
+                None => Vec::new(),
+            };
+            res.insert_header("content-type", "application/tar+gzip");
             res.insert_header(
                 "content-disposition",
                 format!(
-                    "attachment; filename=\"geph4-debug-{}.tar\"",
+                    "attachment; filename=\"geph4-debug-{}.tar.gz\"",
                     Local::now().to_rfc3339()
                 ),
             );
-            res.set_body(result);
+            res.set_body(crate::debugpack::debugpack_body(logs, traces));
+            Ok(res)
+        }
+        "/route" => {
+            if let Some(route) = stats.get_route() {
+                res.set_body(serde_json::to_string_pretty(&route)?);
+                res.insert_header("Content-Type", "application/json");
+            } else {
+                res.set_status(http_types::StatusCode::ServiceUnavailable);
+                res.set_body("not connected yet");
+            }
+            Ok(res)
+        }
+        "/connections" => {
+            // ?top=N keeps the N connections that moved the most bytes, and ?since=UNIX_SECS only those opened since then
+            let mut conns = stats.list_conns();
+            let mut top: Option<usize> = None;
+            let mut since: Option<f64> = None;
+            for (k, v) in _req.url().query_pairs() {
+                match k.as_ref() {
+                    "top" => top = Some(v.parse()?),
+                    "since" => since = Some(v.parse()?),
+                    _ => {}
+                }
+            }
+            if let Some(since) = since {
+                let now = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)?
+                    .as_secs_f64();
+                conns.retain(|c| now - c.age >= since);
+            }
+            if let Some(top) = top {
+                conns.sort_unstable_by_key(|c| std::cmp::Reverse(c.rx + c.tx));
+                conns.truncate(top);
+            }
+            if conns.len() < STREAM_CONNS_THRESHOLD {
+                res.set_body(serde_json::to_string(&conns)?);
+            } else {
+                res.set_body(crate::jsonstream::JsonArrayReader::new(conns).into_body());
+            }
+            res.insert_header("Content-Type", "application/json");
+            Ok(res)
+        }
+        path if path.starts_with("/connections/") && path.ends_with("/close") => {
+            let id = path
+                .trim_start_matches("/connections/")
+                .trim_end_matches("/close")
+                .parse::<u64>();
+            match id {
+                Ok(id) if stats.close_conn(id) => res.set_body("closing"),
+                _ => {
+                    res.set_status(http_types::StatusCode::NotFound);
+                    res.set_body("no such connection");
+                }
+            }
             Ok(res)
         }
         "/proxy.pac" => {
@@ -204,27 +616,36 @@ async fn handle_stats(
             Ok(res)
         }
         "/kill" => std::process::exit(0),
+        "/reconnect" => {
+            // unlike /kill, this keeps the process, and the local proxies, running
+            kalive.reconnect();
+            res.set_body("reconnecting");
+            Ok(res)
+        }
         _ => {
             let detail = kalive.get_stats().timeout(Duration::from_millis(100)).await;
-            if let Some(Ok(details)) = detail {
+            // the headline numbers are the first path's
+            if let Some(Ok(details)) = detail.map(|traces| {
+                traces.map(|traces| {
+                    traces
+                        .into_iter()
+                        .next()
+                        .map(|trace| trace.stats)
+                        .unwrap_or_default()
+                })
+            }) {
                 if let Some(detail) = details.last() {
                     stats.set_latency(detail.ping.as_secs_f64() * 1000.0);
-                    // compute loss
-                    let midpoint_stat = details[details.len() / 2];
-                    let delta_high = detail
-                        .high_recv
-                        .saturating_sub(midpoint_stat.high_recv)
-                        .max(1) as f64;
-                    let delta_total = detail
-                        .total_recv
-                        .saturating_sub(midpoint_stat.total_recv)
-                        .max(1) as f64;
-                    // dbg!(delta_total);
-                    // dbg!(delta_high);
-                    let loss = 1.0 - (delta_total / delta_high).min(1.0).max(0.0);
-                    stats.set_loss(loss * 100.0)
+                    stats.set_loss(download_loss(&details).unwrap_or_default() * 100.0);
+                    stats.set_upload_loss(detail.send_loss * 100.0);
                 }
             }
+            stats.set_log_lines(GLOBAL_LOGGER.read().len());
+            stats.set_window_blocked(sosistab::mux::window_blocked_count());
+            stats.set_buffer_usage(
+                sosistab::mux::buffered_bytes(),
+                aioutils::copy_buffer_bytes(),
+            );
             let jstats = serde_json::to_string(&stats)?;
             res.set_body(jstats);
             res.insert_header("Content-Type", "application/json");
@@ -233,14 +654,118 @@ async fn handle_stats(
     }
 }
 
+/// Handles a SOCKS5 BIND by having the exit listen for the inbound connection. As SOCKS5 requires, we reply once with where the exit listens and again with who connected.
+async fn handle_socks5_bind(
+    s5client: impl LocalStream,
+    expected_peer: &str,
+    keepalive: &Keepalive,
+) -> anyhow::Result<()> {
+    use socksv5::v5::*;
+    let reply = |addr: SocketAddr| {
+        let host = match addr.ip() {
+            IpAddr::V4(ip) => SocksV5Host::Ipv4(ip.octets()),
+            IpAddr::V6(ip) => SocksV5Host::Ipv6(ip.octets()),
+        };
+        write_request_status(
+            s5client.clone(),
+            SocksV5RequestStatus::Success,
+            host,
+            addr.port(),
+        )
+    };
+    let bound = async {
+        let mut conn = keepalive
+            .connect_label(&format!("bind {}", expected_peer))
+            .await?;
+        let listening: SocketAddr = aioutils::read_pascalish(&mut conn).await?;
+        Ok::<_, anyhow::Error>((conn, listening))
+    };
+    let (mut conn, listening) = match bound.await {
+        Ok(bound) => bound,
+        Err(err) => {
+            log::warn!("exit did not bind for {}: {:?}", expected_peer, err);
+            write_request_status(
+                s5client.clone(),
+                SocksV5RequestStatus::ConnectionNotAllowed,
+                SocksV5Host::Ipv4([0, 0, 0, 0]),
+                0,
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+    log::debug!("exit listening on {} for {}", listening, expected_peer);
+    reply(listening).await?;
+    let peer: SocketAddr = aioutils::read_pascalish(&mut conn).await?;
+    reply(peer).await?;
+    smol::future::race(
+        aioutils::copy_with_stats(conn.clone(), s5client.clone(), |_| ()),
+        aioutils::copy_with_stats(s5client, conn, |_| ()),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Computes recent download loss from session statistics, comparing the newer half against the older half.
+fn download_loss(details: &[sosistab::SessionStat]) -> Option<f64> {
+    let detail = details.last()?;
+    let midpoint_stat = details[details.len() / 2];
+    let delta_high = detail
+        .high_recv
+        .saturating_sub(midpoint_stat.high_recv)
+        .max(1) as f64;
+    let delta_total = detail
+        .total_recv
+        .saturating_sub(midpoint_stat.total_recv)
+        .max(1) as f64;
+    Some(1.0 - (delta_total / delta_high).min(1.0).max(0.0))
+}
+
+/// Periodically compares upload and download loss, logging when they diverge. That usually means only one direction of the path has a problem, which is worth knowing when troubleshooting.
+async fn loss_diagnostic(keepalive: Keepalive) {
+    const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+    // loss differences smaller than this are just noise
+    const MIN_DIFFERENCE: f64 = 0.05;
+    loop {
+        crate::power::background_wait(CHECK_INTERVAL).await;
+        let traces = match keepalive.get_stats().timeout(Duration::from_secs(1)).await {
+            Some(Ok(traces)) => traces,
+            _ => continue,
+        };
+        // each path separately, since only one of them may be at fault
+        for trace in traces {
+            let (down, up) = match (download_loss(&trace.stats), trace.stats.last()) {
+                (Some(down), Some(last)) => (down, last.send_loss),
+                _ => continue,
+            };
+            if (down - up).abs() > MIN_DIFFERENCE && down.max(up) > 2.0 * down.min(up) {
+                log::warn!(
+                    "asymmetric loss over {}: {:.2}% down, {:.2}% up; the {} path is probably at fault",
+                    trace.endpoint,
+                    down * 100.0,
+                    up * 100.0,
+                    if down > up { "download" } else { "upload" }
+                );
+            } else {
+                log::debug!(
+                    "loss check over {}: {:.2}% down, {:.2}% up",
+                    trace.endpoint,
+                    down * 100.0,
+                    up * 100.0
+                );
+            }
+        }
+    }
+}
+
 /// Handle a socks5 client from localhost.
 async fn handle_socks5(
     stats: Arc<StatCollector>,
-    s5client: smol::net::TcpStream,
+    s5client: impl LocalStream,
     keepalive: &Keepalive,
     exclude_prc: bool,
+    kill_switch: bool,
 ) -> anyhow::Result<()> {
-    s5client.set_nodelay(true)?;
     use socksv5::v5::*;
     let _handshake = read_handshake(s5client.clone()).await?;
     write_auth_method(s5client.clone(), SocksV5AuthMethod::Noauth).await?;
@@ -262,6 +787,19 @@ async fn handle_socks5(
         .to_string(),
         _ => anyhow::bail!("not supported"),
     };
+    if kill_switch && !stats.is_connected() {
+        write_request_status(
+            s5client,
+            SocksV5RequestStatus::NetworkUnreachable,
+            SocksV5Host::Ipv4([0, 0, 0, 0]),
+            0,
+        )
+        .await?;
+        anyhow::bail!("refusing {} since the tunnel is down", addr)
+    }
+    if let SocksV5Command::Bind = request.command {
+        return handle_socks5_bind(s5client, &addr, keepalive).await;
+    }
     write_request_status(
         s5client.clone(),
         SocksV5RequestStatus::Success,
@@ -269,37 +807,123 @@ async fn handle_socks5(
         port,
     )
     .await?;
+    relay(stats, s5client, &addr, v4addr, keepalive, exclude_prc).await
+}
+
+/// Connects directly to the given address, trying the preferred address family first.
+async fn connect_direct(
+    addr: &str,
+    preference: aioutils::AddrPreference,
+) -> std::io::Result<smol::net::TcpStream> {
+    let mut last_err = None;
+    for addr in aioutils::resolve_preferring(addr, preference).await? {
+        match smol::net::TcpStream::connect(addr).await {
+            Ok(conn) => return Ok(conn),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "no addresses to connect to")
+    }))
+}
+
+/// Relays a local client to the given address without going through the tunnel.
+async fn relay_direct(
+    client: impl LocalStream,
+    addr: &str,
+    preference: aioutils::AddrPreference,
+) -> anyhow::Result<()> {
+    let conn = connect_direct(addr, preference).await?;
+    smol::future::race(
+        aioutils::copy_with_stats(conn.clone(), client.clone(), |_| ()),
+        aioutils::copy_with_stats(client, conn, |_| ()),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Relays a local client to the given address, either through the tunnel or directly if it's excluded.
+pub(crate) async fn relay(
+    stats: Arc<StatCollector>,
+    client: impl LocalStream,
+    addr: &str,
+    v4addr: Option<Ipv4Addr>,
+    keepalive: &Keepalive,
+    exclude_prc: bool,
+) -> anyhow::Result<()> {
     let must_direct = exclude_prc
         && (china::is_chinese_host(addr.split(':').next().unwrap())
             || v4addr.map(china::is_chinese_ip).unwrap_or(false));
     if must_direct {
         log::debug!("bypassing {}", addr);
-        let conn = smol::net::TcpStream::connect(&addr).await?;
-        smol::future::race(
-            aioutils::copy_with_stats(conn.clone(), s5client.clone(), |_| ()),
-            aioutils::copy_with_stats(s5client.clone(), conn.clone(), |_| ()),
-        )
-        .await?;
+        relay_direct(client, addr, keepalive.addr_preference()).await?;
+    } else if let (Some(coalescer), Some("53")) =
+        (keepalive.dns_coalescer(), addr.rsplit(':').next())
+    {
+        log::debug!("sharing a DNS connection to {}", addr);
+        coalescer.relay(client, addr, keepalive).await?;
     } else {
-        let conn = keepalive.connect(&addr).await?;
-        smol::future::race(
-            aioutils::copy_with_stats(conn.clone(), s5client.clone(), |n| {
-                stats.incr_total_rx(n as u64)
-            }),
-            aioutils::copy_with_stats(s5client, conn, |n| stats.incr_total_tx(n as u64)),
+        let mut conn = match keepalive.connect(addr).await {
+            Ok(conn) => conn,
+            Err(err) => match err.downcast_ref::<ConnRejection>() {
+                // only refused ports are bypassed: addresses the exit won't reach, such as internal ones, and names it can't resolve aren't ours to reach around it either
+                Some(rejection @ ConnRejection::PortNotAllowed)
+                    if keepalive.direct_if_refused() =>
+                {
+                    log::debug!("bypassing {}, since {}", addr, rejection);
+                    return relay_direct(client, addr, keepalive.addr_preference()).await;
+                }
+                Some(rejection) => anyhow::bail!("cannot connect to {}: {}", addr, rejection),
+                None => return Err(err),
+            },
+        };
+        // TTFB is measured from here
+        let handle = stats.register_conn(addr);
+        let closed = async {
+            handle.wait_close().await;
+            log::debug!("closing {} on request", addr);
+            Ok(())
+        };
+        let res = smol::future::race(
+            smol::future::race(
+                aioutils::copy_with_stats(conn.clone(), client.clone(), |n| {
+                    handle.incr_rx(n as u64)
+                }),
+                aioutils::copy_with_stats(client, conn.clone(), |n| handle.incr_tx(n as u64)),
+            ),
+            closed,
         )
-        .await?;
+        .await;
+        match conn.close_reason() {
+            Some(CloseReason::Refused) => {
+                log::warn!(
+                    "exit refused to connect to {} (port or address not allowed)",
+                    addr
+                )
+            }
+            Some(CloseReason::Unreachable) => log::warn!("exit cannot reach {}", addr),
+            Some(reason) => log::debug!("exit closed {} ({:?})", addr, reason),
+            None => (),
+        }
+        let conn_stats = conn.stats();
+        log::debug!(
+            "{} done after {:.1}s: {} B up, {} B down, {} retransmits, RTT {}, TTFB {}",
+            addr,
+            conn_stats.duration.as_secs_f64(),
+            conn_stats.bytes_sent,
+            conn_stats.bytes_received,
+            conn_stats.retransmits,
+            conn_stats
+                .rtt
+                .map(|rtt| format!("{} ms", rtt.as_millis()))
+                .unwrap_or_else(|| "unknown".into()),
+            handle
+                .ttfb()
+                .map(|ttfb| format!("{} ms", ttfb.as_millis()))
+                .unwrap_or_else(|| "none".into())
+        );
+        res?;
+        conn.shutdown().await;
     }
     Ok(())
 }
-
-// /// Smallify the buffers for a TCP connection
-// fn debuffer(conn: async_net::TcpStream) -> async_net::TcpStream {
-//     let conn: Arc<smol::Async<std::net::TcpStream>> = conn.into();
-//     let conn: std::net::TcpStream = conn.get_ref().try_clone().unwrap();
-//     let conn: socket2::Socket = conn.into();
-//     conn.set_nodelay(true).unwrap();
-//     conn.set_recv_buffer_size(163840).unwrap();
-//     conn.set_send_buffer_size(163840).unwrap();
-//     smol::Async::new(conn.into_tcp_stream()).unwrap().into()
-// }
@@ -0,0 +1,270 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cache::ClientCache,
+    kalive::{connect_endpoint, select_exit, Path, Route},
+    main_bench::ECHO_LABEL,
+    main_connect::ConnectOpt,
+};
+use anyhow::Context;
+use serde::Serialize;
+use smol::prelude::*;
+use smol_timeout::TimeoutExt;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt, Clone)]
+pub struct DiagnoseOpt {
+    #[structopt(flatten)]
+    connect: ConnectOpt,
+
+    #[structopt(long)]
+    /// print the report as JSON rather than as text.
+    json: bool,
+
+    #[structopt(long, default_value = "30")]
+    /// how many seconds each stage may take before it counts as failed.
+    stage_timeout: u64,
+}
+
+/// The outcome of one stage of connecting.
+#[derive(Debug, Serialize)]
+struct Stage {
+    name: &'static str,
+    ok: bool,
+    millis: f64,
+    /// For failures, what kind of failure it was, such as `handshake_timeout` or `bad_server_key`.
+    category: Option<&'static str>,
+    detail: String,
+}
+
+/// Walks through every stage of connecting to an exit, the same way `connect` would, and reports how long each took and how it failed, if it did. Unlike `connect`, every transport is tried, even once one works, so that the report shows which are blocked.
+pub async fn main_diagnose(opt: DiagnoseOpt) -> anyhow::Result<()> {
+    opt.connect.set_globals();
+    let ccache = ClientCache::from_opts(&opt.connect.common, &opt.connect.auth)
+        .context("cannot create ClientCache")?;
+    if let Some(path) = &opt.connect.import_cache {
+        ccache.import(path).context("cannot import cache bundle")?;
+    }
+    let mut report = Report {
+        stages: Vec::new(),
+        timeout: Duration::from_secs(opt.stage_timeout),
+    };
+    let found_problem = report.run(&opt.connect, &ccache).await.is_none();
+    if opt.json {
+        println!("{}", serde_json::to_string_pretty(&report.stages)?);
+    } else {
+        for stage in report.stages.iter() {
+            println!(
+                "{:<18} {:<4} {:>8.0} ms  {}{}",
+                stage.name,
+                if stage.ok { "ok" } else { "FAIL" },
+                stage.millis,
+                stage
+                    .category
+                    .map(|c| format!("[{}] ", c))
+                    .unwrap_or_default(),
+                stage.detail
+            );
+        }
+    }
+    if found_problem {
+        let failed = report
+            .stages
+            .iter()
+            .rev()
+            .find(|s| !s.ok)
+            .map(|s| s.name)
+            .unwrap_or("unknown");
+        anyhow::bail!("cannot connect; the {} stage failed", failed)
+    }
+    Ok(())
+}
+
+struct Report {
+    stages: Vec<Stage>,
+    timeout: Duration,
+}
+
+impl Report {
+    /// Runs all the stages, stopping at the first one that later stages can't do without. Returns None if that happened.
+    async fn run(&mut self, cfg: &ConnectOpt, ccache: &ClientCache) -> Option<()> {
+        let exits = self
+            .stage("binder", ccache.get_exits(), |exits| {
+                format!("{} exits listed", exits.len())
+            })
+            .await?;
+        let token = self
+            .stage("auth token", ccache.get_auth_token(), |token| {
+                format!("{} user", token.level)
+            })
+            .await?;
+        let (exit, _) = self
+            .stage(
+                "exit selection",
+                select_exit(cfg, ccache, exits),
+                |(exit, reason)| format!("{} ({})", exit.hostname, reason),
+            )
+            .await?;
+        let direct_addr = self
+            .stage(
+                "exit resolution",
+                async {
+                    aioutils::resolve(&format!("{}:19831", exit.hostname))
+                        .await?
+                        .into_iter()
+                        .find(|addr| addr.is_ipv4())
+                        .context("exit has no IPv4 address")
+                },
+                |addr: &SocketAddr| addr.to_string(),
+            )
+            .await;
+        let bridges = self
+            .stage("bridges", ccache.get_bridges(&exit), |bridges| {
+                format!("{} bridges offered", bridges.len())
+            })
+            .await
+            .unwrap_or_default();
+
+        // every transport is tried, and the first to work carries the rest of the stages
+        let mut route: Option<(sosistab::Session, Route)> = None;
+        if let Some(addr) = direct_addr {
+            for (name, use_tcp) in [("direct udp", false), ("direct tcp", true)].iter() {
+                let sess = self
+                    .stage(
+                        name,
+                        async { Ok(connect_endpoint(addr, exit.sosistab_key, *use_tcp).await?) },
+                        |_| format!("handshake with {}", addr),
+                    )
+                    .await;
+                if let (Some(sess), None) = (sess, route.as_ref()) {
+                    let r = Route::new(exit.clone(), addr, exit.sosistab_key, false, *use_tcp);
+                    route = Some((sess, r));
+                }
+            }
+        }
+        if let Some(bridge) = bridges.first() {
+            let sess = self
+                .stage(
+                    "bridge udp",
+                    async {
+                        Ok(connect_endpoint(bridge.endpoint, bridge.sosistab_key, false).await?)
+                    },
+                    |_| format!("handshake with {}", bridge.endpoint),
+                )
+                .await;
+            if let (Some(sess), None) = (sess, route.as_ref()) {
+                let r = Route::new(
+                    exit.clone(),
+                    bridge.endpoint,
+                    bridge.sosistab_key,
+                    true,
+                    false,
+                );
+                route = Some((sess, r));
+            }
+        }
+        let (session, route) = match route {
+            Some(route) => route,
+            None => {
+                self.stages.push(Stage {
+                    name: "handshake",
+                    ok: false,
+                    millis: 0.0,
+                    category: Some("unreachable"),
+                    detail: "no transport reached the exit".into(),
+                });
+                return None;
+            }
+        };
+
+        let path = self
+            .stage(
+                "authentication",
+                Path::establish(session, route, &token),
+                |path| {
+                    format!(
+                        "session {} via {}",
+                        path.mux.get_session().id(),
+                        path.route.endpoint
+                    )
+                },
+            )
+            .await?;
+        self.stage("echo", echo(&path), |rtt| {
+            format!(
+                "round trip through the exit in {:.0} ms",
+                rtt.as_secs_f64() * 1000.0
+            )
+        })
+        .await?;
+        Some(())
+    }
+
+    /// Runs one stage, recording its outcome.
+    async fn stage<T>(
+        &mut self,
+        name: &'static str,
+        fut: impl Future<Output = anyhow::Result<T>>,
+        describe: impl FnOnce(&T) -> String,
+    ) -> Option<T> {
+        let start = Instant::now();
+        let res = fut
+            .timeout(self.timeout)
+            .await
+            .unwrap_or_else(|| Err(anyhow::anyhow!("timed out")));
+        let millis = start.elapsed().as_secs_f64() * 1000.0;
+        match res {
+            Ok(val) => {
+                self.stages.push(Stage {
+                    name,
+                    ok: true,
+                    millis,
+                    category: None,
+                    detail: describe(&val),
+                });
+                Some(val)
+            }
+            Err(err) => {
+                self.stages.push(Stage {
+                    name,
+                    ok: false,
+                    millis,
+                    category: Some(categorize(&err)),
+                    detail: format!("{:#}", err),
+                });
+                None
+            }
+        }
+    }
+}
+
+/// Classifies a failure, so that reports can be compared without parsing error messages.
+fn categorize(err: &anyhow::Error) -> &'static str {
+    match err.downcast_ref::<sosistab::ConnectError>() {
+        Some(sosistab::ConnectError::Bind(_)) => "bind",
+        Some(sosistab::ConnectError::HandshakeTimeout) => "handshake_timeout",
+        Some(sosistab::ConnectError::BadServerKey) => "bad_server_key",
+        Some(sosistab::ConnectError::Io(_)) => "io",
+        None if err.to_string() == "timed out" => "timeout",
+        None => "error",
+    }
+}
+
+/// Times a round trip through the exit's echo service.
+async fn echo(path: &Path) -> anyhow::Result<Duration> {
+    let mut conn = path.mux.open_conn(Some(ECHO_LABEL.into())).await?;
+    let mut timestamp = [0u8; 8];
+    conn.read_exact(&mut timestamp)
+        .await
+        .context("exit doesn't run an echo service")?;
+    let probe = [0u8; 32];
+    let mut reply = [0u8; 32];
+    let start = Instant::now();
+    conn.write_all(&probe).await?;
+    conn.flush().await?;
+    conn.read_exact(&mut reply).await?;
+    Ok(start.elapsed())
+}
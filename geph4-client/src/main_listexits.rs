@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+
+use crate::{cache::ClientCache, kalive::probe_all, AuthOpt, CommonOpt};
+use anyhow::Context;
+use serde::Serialize;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct ListExitsOpt {
+    #[structopt(flatten)]
+    common: CommonOpt,
+
+    #[structopt(flatten)]
+    auth: AuthOpt,
+
+    /// Lists the exits without measuring their latency.
+    #[structopt(long)]
+    no_ping: bool,
+
+    /// How many exits to measure at once.
+    #[structopt(long, default_value = "16")]
+    parallel: usize,
+
+    /// Prints the list as JSON rather than as a table.
+    #[structopt(long)]
+    json: bool,
+}
+
+/// One exit, as listed.
+#[derive(Debug, Serialize)]
+struct ListedExit {
+    hostname: String,
+    country_code: String,
+    city_code: String,
+    /// Sessions last reported by the exit, if it reported recently.
+    load: Option<u32>,
+    /// How long a handshake with the exit took, if it was measured and the exit answered.
+    rtt_ms: Option<f64>,
+}
+
+/// Prints the exits the binder lists, and how long each takes to answer a handshake, fastest first. Exits that weren't measured, or didn't answer, come last, sorted by hostname.
+pub async fn main_listexits(opt: ListExitsOpt) -> anyhow::Result<()> {
+    let ccache = ClientCache::from_opts(&opt.common, &opt.auth)?;
+    let exits = ccache.get_exits().await.context("can't get exits")?;
+    let latencies = if opt.no_ping {
+        BTreeMap::new()
+    } else {
+        probe_all(exits.clone(), opt.parallel).await?
+    };
+    let loads = ccache.get_exit_loads().await.unwrap_or_else(|err| {
+        log::warn!("cannot get exit loads: {:?}", err);
+        BTreeMap::new()
+    });
+    let mut listed: Vec<ListedExit> = exits
+        .into_iter()
+        .map(|exit| ListedExit {
+            load: loads.get(&exit.hostname).cloned(),
+            rtt_ms: latencies.get(&exit.hostname).cloned(),
+            hostname: exit.hostname,
+            country_code: exit.country_code,
+            city_code: exit.city_code,
+        })
+        .collect();
+    listed.sort_by(|a, b| {
+        let a_rtt = a.rtt_ms.unwrap_or(f64::INFINITY);
+        let b_rtt = b.rtt_ms.unwrap_or(f64::INFINITY);
+        a_rtt
+            .partial_cmp(&b_rtt)
+            .unwrap()
+            .then_with(|| a.hostname.cmp(&b.hostname))
+    });
+    if opt.json {
+        println!("{}", serde_json::to_string_pretty(&listed)?);
+        return Ok(());
+    }
+    println!(
+        "{:<36} {:<10} {:>8} {:>10}",
+        "HOSTNAME", "REGION", "LOAD", "RTT"
+    );
+    for exit in listed {
+        println!(
+            "{:<36} {:<10} {:>8} {:>10}",
+            exit.hostname,
+            format!("{}-{}", exit.country_code, exit.city_code),
+            exit.load
+                .map(|l| l.to_string())
+                .unwrap_or_else(|| "-".into()),
+            exit.rtt_ms
+                .map(|rtt| format!("{:.0} ms", rtt))
+                .unwrap_or_else(|| "-".into())
+        );
+    }
+    Ok(())
+}
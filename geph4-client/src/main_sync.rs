@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, path::PathBuf};
 
 use crate::cache::ClientCache;
 use crate::{AuthOpt, CommonOpt};
@@ -15,13 +15,22 @@ pub struct SyncOpt {
     /// Forces synchronization of fresh data.
     #[structopt(long)]
     force: bool,
+
+    /// After synchronizing, exports the cached binder data to this file, which `connect --import-cache` can load somewhere the binder can't be reached.
+    #[structopt(long)]
+    export: Option<PathBuf>,
 }
 
 pub async fn main_sync(opt: SyncOpt) -> anyhow::Result<()> {
     let mut client_cache = ClientCache::from_opts(&opt.common, &opt.auth)?;
     client_cache.force_sync = opt.force;
     log::info!("sync mode started (force = {})", opt.force);
-    if let Err(err) = attempt(&client_cache).await {
+    let res = attempt(&client_cache).await;
+    let res = match (&opt.export, res) {
+        (Some(path), Ok(())) => client_cache.export(path),
+        (_, res) => res,
+    };
+    if let Err(err) = res {
         let mut haha = HashMap::new();
         haha.insert("error".to_string(), err.to_string());
         let json = serde_json::to_string(&haha)?;
@@ -1,10 +1,130 @@
+//! Periodic network testing, for diagnosing how well the tunnel works from where the client runs.
+//!
+//! Every round downloads a 1 MB test file through the local SOCKS5 proxy, then records that along with how the session to the exit is doing and which exits answer a handshake. Results go to a statsd server given by `--nettest-server`, to a file given by `--nettest-output`, or both.
+//!
+//! The file gets one line per round and is only ever appended to. With `--nettest-format json`, each line is a JSON object with these fields:
+//!
+//! - `time`: Unix time the round started, in seconds.
+//! - `name`: the `--nettest-name`, or an empty string.
+//! - `exit`: hostname of the exit connected to, or an empty string if not connected.
+//! - `transport`: `udp` or `tcp`, or an empty string if not connected.
+//! - `via_bridge`: whether the session goes through a bridge.
+//! - `ping_ms`, `loss_pct`, `upload_loss_pct`: round trip and loss of the session, as in the stats.
+//! - `udp_ok`, `udp_timeouts`, `tcp_ok`, `tcp_timeouts`: handshakes that worked and that timed out over each transport, since the client got on the current network.
+//! - `download_ok`: whether the test download worked.
+//! - `download_secs`: how long the test download took, whether or not it worked.
+//! - `download_kibps`: the speed of the test download in KiB/s, or 0 if it failed.
+//! - `exit_rtts`: for every exit that answered a handshake, its hostname and how long the handshake took in milliseconds. Exits left out didn't answer.
+//!
+//! With `--nettest-format csv`, the file starts with a header row naming the same fields in the same order, and `exit_rtts` is written as space-separated `hostname=ms` pairs. Fields with commas, quotes or line breaks in them, such as some names, are quoted as RFC 4180 describes.
+//!
+//! Fields may be added in later versions, but only at the end, and existing fields keep their meaning.
+
+use crate::{cache::ClientCache, kalive::probe_all, stats::StatCollector};
 use async_net::SocketAddr;
+use serde::Serialize;
 use smol::prelude::*;
 use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::PathBuf,
     process::{ExitStatus, Stdio},
-    time::{Duration, Instant},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 
+/// How many exits are probed at once.
+const PROBE_PARALLEL: usize = 16;
+
+/// How `--nettest-output` is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NettestFormat {
+    /// One JSON object per line.
+    Json,
+    /// A header row, then one row per round.
+    Csv,
+}
+
+impl FromStr for NettestFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(NettestFormat::Json),
+            "csv" => Ok(NettestFormat::Csv),
+            other => anyhow::bail!("unknown nettest format {:?} (expected json or csv)", other),
+        }
+    }
+}
+
+/// Where network test results go.
+pub struct NettestSinks {
+    pub server: Option<SocketAddr>,
+    pub output: Option<(PathBuf, NettestFormat)>,
+}
+
+/// The results of one round of testing. The order of the fields is the order of the CSV columns.
+#[derive(Debug, Serialize)]
+struct NettestResult {
+    time: u64,
+    name: String,
+    exit: String,
+    transport: String,
+    via_bridge: bool,
+    ping_ms: f64,
+    loss_pct: f64,
+    upload_loss_pct: f64,
+    udp_ok: u64,
+    udp_timeouts: u64,
+    tcp_ok: u64,
+    tcp_timeouts: u64,
+    download_ok: bool,
+    download_secs: f64,
+    download_kibps: f64,
+    exit_rtts: BTreeMap<String, f64>,
+}
+
+const CSV_HEADER: &str = "time,name,exit,transport,via_bridge,ping_ms,loss_pct,upload_loss_pct,udp_ok,udp_timeouts,tcp_ok,tcp_timeouts,download_ok,download_secs,download_kibps,exit_rtts";
+
+impl NettestResult {
+    fn to_csv(&self) -> String {
+        let exit_rtts: Vec<String> = self
+            .exit_rtts
+            .iter()
+            .map(|(hostname, rtt)| format!("{}={:.1}", hostname, rtt))
+            .collect();
+        format!(
+            "{},{},{},{},{},{:.1},{:.2},{:.2},{},{},{},{},{},{:.3},{:.1},{}",
+            self.time,
+            csv_field(&self.name),
+            csv_field(&self.exit),
+            self.transport,
+            self.via_bridge,
+            self.ping_ms,
+            self.loss_pct,
+            self.upload_loss_pct,
+            self.udp_ok,
+            self.udp_timeouts,
+            self.tcp_ok,
+            self.tcp_timeouts,
+            self.download_ok,
+            self.download_secs,
+            self.download_kibps,
+            csv_field(&exit_rtts.join(" "))
+        )
+    }
+}
+
+/// Quotes a field as RFC 4180 asks, if it has anything that would otherwise break the row.
+fn csv_field(field: &str) -> String {
+    if field.contains(|c| matches!(c, ',' | '"' | '\r' | '\n')) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
 async fn system(line: &str) -> ExitStatus {
     let mut proc = smol::process::Command::new("/bin/sh")
         .arg("-c")
@@ -16,18 +136,117 @@ async fn system(line: &str) -> ExitStatus {
     proc.status().await.unwrap()
 }
 
-pub async fn nettest(nettest_name: String, nettest_server: SocketAddr) {
-    let stat_client = statsd::Client::new(nettest_server, &nettest_name).unwrap();
+pub async fn nettest(
+    nettest_name: Option<String>,
+    sinks: NettestSinks,
+    stats: Arc<StatCollector>,
+    ccache: Arc<ClientCache>,
+) {
+    let stat_client = sinks.server.map(|server| {
+        statsd::Client::new(server, nettest_name.as_deref().unwrap_or_default()).unwrap()
+    });
     loop {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
         log::info!("Measuring 1MB cachefly speed:");
+        let mut download_ok = false;
         let cachefly_time = measure_time(async {
-            system("curl -v --proxy socks5h://localhost:9909 https://cachefly.cachefly.net/1mb.test > /dev/null").await;
+            download_ok = system("curl -v --proxy socks5h://localhost:9909 https://cachefly.cachefly.net/1mb.test > /dev/null").await.success();
         }).await;
-        stat_client.timer("cachefly.duration", cachefly_time.as_millis() as _);
+        if let Some(stat_client) = &stat_client {
+            stat_client.timer("cachefly.duration", cachefly_time.as_millis() as _);
+        }
+        if let Some((path, format)) = &sinks.output {
+            let result = NettestResult {
+                time,
+                name: nettest_name.clone().unwrap_or_default(),
+                download_ok,
+                download_secs: cachefly_time.as_secs_f64(),
+                download_kibps: if download_ok {
+                    1024.0 / cachefly_time.as_secs_f64().max(0.001)
+                } else {
+                    0.0
+                },
+                exit_rtts: exit_rtts(&ccache).await,
+                ..session_result(&stats)
+            };
+            if let Err(err) = append_result(path.clone(), *format, result).await {
+                log::warn!("cannot write nettest result to {:?}: {}", path, err);
+            }
+        }
         smol::Timer::after(Duration::from_secs(30)).await;
     }
 }
 
+/// Fills in how the session to the exit is doing, leaving the rest empty.
+fn session_result(stats: &StatCollector) -> NettestResult {
+    let route = stats.get_route();
+    let path = stats.paths().into_iter().max_by_key(|path| path.hop);
+    let (udp, tcp) = stats.handshakes();
+    NettestResult {
+        time: 0,
+        name: String::new(),
+        exit: route
+            .as_ref()
+            .map(|route| route.exit.hostname.clone())
+            .unwrap_or_default(),
+        transport: match &route {
+            Some(route) if route.use_tcp => "tcp".into(),
+            Some(_) => "udp".into(),
+            None => String::new(),
+        },
+        via_bridge: route.map(|route| route.via_bridge).unwrap_or_default(),
+        ping_ms: path.as_ref().map(|path| path.ping).unwrap_or_default(),
+        loss_pct: path.as_ref().map(|path| path.loss).unwrap_or_default(),
+        upload_loss_pct: path.map(|path| path.upload_loss).unwrap_or_default(),
+        udp_ok: udp.successes,
+        udp_timeouts: udp.timeouts,
+        tcp_ok: tcp.successes,
+        tcp_timeouts: tcp.timeouts,
+        download_ok: false,
+        download_secs: 0.0,
+        download_kibps: 0.0,
+        exit_rtts: BTreeMap::new(),
+    }
+}
+
+/// Handshakes with every exit the binder lists, returning how long each that answered took.
+async fn exit_rtts(ccache: &ClientCache) -> BTreeMap<String, f64> {
+    let probed = async { probe_all(ccache.get_exits().await?, PROBE_PARALLEL).await };
+    match probed.await {
+        Ok(rtts) => rtts,
+        Err(err) => {
+            log::warn!("cannot probe exits for nettest: {}", err);
+            BTreeMap::new()
+        }
+    }
+}
+
+async fn append_result(
+    path: PathBuf,
+    format: NettestFormat,
+    result: NettestResult,
+) -> anyhow::Result<()> {
+    let line = match format {
+        NettestFormat::Json => serde_json::to_string(&result)?,
+        NettestFormat::Csv => result.to_csv(),
+    };
+    smol::unblock(move || {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        if format == NettestFormat::Csv && file.metadata()?.len() == 0 {
+            writeln!(file, "{}", CSV_HEADER)?;
+        }
+        writeln!(file, "{}", line)?;
+        Ok(())
+    })
+    .await
+}
+
 async fn measure_time(fut: impl Future<Output = ()>) -> Duration {
     let start = Instant::now();
     fut.await;
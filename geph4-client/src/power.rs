@@ -0,0 +1,72 @@
+//! Low-power mode, for phones and laptops running on battery, where every time the client wakes up costs battery.
+//!
+//! While no local connections are open, the client wakes up about once every 5 seconds to look for suspends and to refresh path statistics, every 10 seconds to look for network changes, every minute to compare upload and download loss, and whenever the tunnel needs a keepalive ping. In low-power mode:
+//!
+//! - Background work, which is everything but keepalive pings and suspend detection, waits [IDLE_STRETCH] times longer while no connections are open. A new SOCKS5 connection wakes it up right away.
+//! - Suspends are looked for every 30 seconds rather than every 5.
+//! - Keepalive pings start out further apart, space out faster, and settle closer to the NAT timeout the client has measured.
+//!
+//! Leaving out keepalive pings, an idle client then wakes up about 4 times a minute rather than about 30. In exchange, network changes take up to 100 seconds rather than 10 to be noticed, which delays switching to TCP where UDP is blocked, and reconnecting after a suspend can take up to 30 seconds rather than 5. Since pings run closer to the NAT timeout, a NAT that drops bindings a bit early more often breaks the tunnel while idle, so the first connection afterwards waits for a reconnect, usually a second or two. Traffic is unaffected while connections are open.
+
+use std::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use event_listener::Event;
+use once_cell::sync::Lazy;
+use smol::prelude::*;
+
+/// How many times longer background work waits while low-power mode is on and no connections are open.
+pub const IDLE_STRETCH: u32 = 10;
+
+static LOW_POWER: AtomicBool = AtomicBool::new(false);
+
+/// Local connections open right now.
+static ACTIVE_CONNS: AtomicUsize = AtomicUsize::new(0);
+
+/// Notified whenever a local connection opens.
+static ACTIVITY: Lazy<Event> = Lazy::new(Event::new);
+
+/// Turns low-power mode on or off.
+pub fn set_low_power(on: bool) {
+    LOW_POWER.store(on, Ordering::Relaxed)
+}
+
+/// Whether low-power mode is on.
+pub fn low_power() -> bool {
+    LOW_POWER.load(Ordering::Relaxed)
+}
+
+/// Marks a local connection as open, until the guard is dropped.
+pub fn active() -> ActiveGuard {
+    ACTIVE_CONNS.fetch_add(1, Ordering::Relaxed);
+    ACTIVITY.notify(usize::MAX);
+    ActiveGuard(())
+}
+
+/// Keeps a local connection counted as open.
+pub struct ActiveGuard(());
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        ACTIVE_CONNS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Waits `interval` between rounds of background work. In low-power mode, while no connections are open, waits [IDLE_STRETCH] times longer, unless a connection opens in the meantime.
+pub async fn background_wait(interval: Duration) {
+    let start = Instant::now();
+    if low_power() {
+        // listening before checking means a connection opening in between isn't missed
+        let activity = ACTIVITY.listen();
+        if ACTIVE_CONNS.load(Ordering::Relaxed) == 0 {
+            activity
+                .or(async {
+                    smol::Timer::after(interval * IDLE_STRETCH).await;
+                })
+                .await;
+        }
+    }
+    smol::Timer::at(start + interval).await;
+}
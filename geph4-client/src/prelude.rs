@@ -11,14 +11,41 @@ pub fn str_to_path(src: &str) -> PathBuf {
     }
 }
 
-pub fn str_to_x25519_pk(src: &str) -> x25519_dalek::PublicKey {
-    let raw_bts = hex::decode(src).unwrap();
-    let raw_bts: [u8; 32] = raw_bts.as_slice().try_into().unwrap();
-    x25519_dalek::PublicKey::from(raw_bts)
-}
-
 pub fn str_to_mizaru_pk(src: &str) -> mizaru::PublicKey {
     let raw_bts = hex::decode(src).unwrap();
     let raw_bts: [u8; 32] = raw_bts.as_slice().try_into().unwrap();
     mizaru::PublicKey(raw_bts)
 }
+
+pub fn str_to_tls_pin(src: &str) -> anyhow::Result<[u8; 32]> {
+    let raw_bts = hex::decode(src)?;
+    let raw_bts: [u8; 32] = raw_bts.as_slice().try_into()?;
+    Ok(raw_bts)
+}
+
+/// Parses a number of seconds, which must not be negative, and small enough to make a `Duration` out of even after jitter.
+pub fn str_to_secs(src: &str) -> anyhow::Result<f64> {
+    let secs: f64 = src.parse()?;
+    if !(0.0..=u32::MAX as f64).contains(&secs) {
+        anyhow::bail!("{} isn't a number of seconds", src)
+    }
+    Ok(secs)
+}
+
+/// Parses a factor that something grows by, which must be finite and at least 1.
+pub fn str_to_multiplier(src: &str) -> anyhow::Result<f64> {
+    let factor: f64 = src.parse()?;
+    if !factor.is_finite() || factor < 1.0 {
+        anyhow::bail!("{} must be a number of at least 1", src)
+    }
+    Ok(factor)
+}
+
+/// Parses a fraction between 0 and 1.
+pub fn str_to_fraction(src: &str) -> anyhow::Result<f64> {
+    let fraction: f64 = src.parse()?;
+    if !(0.0..=1.0).contains(&fraction) {
+        anyhow::bail!("{} must be between 0 and 1", src)
+    }
+    Ok(fraction)
+}
@@ -0,0 +1,69 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use binder_transport::ReachabilityReport;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use crate::cache::ClientCache;
+
+/// How often collected results are sent to the binder.
+const REPORT_INTERVAL: Duration = Duration::from_secs(900);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Results not yet reported, keyed by exit hostname, bridge and whether TCP was used.
+#[allow(clippy::clippy::type_complexity)]
+static PENDING: Lazy<Mutex<HashMap<(String, Option<SocketAddr>, bool), (u32, u32)>>> =
+    Lazy::new(Default::default);
+
+/// Records whether a way of reaching an exit worked. Does nothing unless reporting is turned on.
+pub fn record(exit_hostname: &str, bridge: Option<SocketAddr>, use_tcp: bool, success: bool) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut pending = PENDING.lock();
+    let counts = pending
+        .entry((exit_hostname.to_string(), bridge, use_tcp))
+        .or_default();
+    if success {
+        counts.0 = counts.0.saturating_add(1);
+    } else {
+        counts.1 = counts.1.saturating_add(1);
+    }
+}
+
+/// Turns on recording, and periodically reports what was recorded to the binder, tagged only with the given coarse region. Reports carry the same blind-signed token as our other binder requests, so the binder can limit how much each client reports, but still can't tell which user they came from.
+pub async fn report_loop(ccache: &ClientCache, region: String) {
+    ENABLED.store(true, Ordering::Relaxed);
+    loop {
+        crate::power::background_wait(REPORT_INTERVAL).await;
+        let reports: Vec<ReachabilityReport> = PENDING
+            .lock()
+            .drain()
+            .map(
+                |((exit_hostname, bridge, use_tcp), (successes, failures))| ReachabilityReport {
+                    exit_hostname,
+                    bridge,
+                    use_tcp,
+                    successes,
+                    failures,
+                },
+            )
+            .collect();
+        if reports.is_empty() {
+            continue;
+        }
+        // the binder takes at most this many at once
+        for chunk in reports.chunks(256) {
+            match ccache.report_reachability(&region, chunk.to_vec()).await {
+                Ok(()) => log::debug!("reported reachability of {} routes", chunk.len()),
+                Err(err) => log::debug!("could not report reachability: {}", err),
+            }
+        }
+    }
+}
@@ -1,20 +1,80 @@
-use std::collections::VecDeque;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use once_cell::sync::Lazy;
 use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
 
+/// How many connections the TTFB percentiles are over.
+const TTFB_SAMPLES: usize = 200;
+
 #[derive(Default, Serialize, Deserialize)]
 pub struct StatCollector {
     total_rx: Mutex<u64>,
     total_tx: Mutex<u64>,
+    /// Bytes of cover traffic sent, which aren't part of `total_tx`.
+    cover_tx: Mutex<u64>,
 
     open_conns: Mutex<u64>,
     open_latency: Mutex<f64>,
 
+    /// Median and 90th percentile, in milliseconds, of the time to first byte of the last [TTFB_SAMPLES] tunneled connections that got any data back: from the exit accepting the connection to the first byte coming back. High TTFB with good throughput points to the exit being slow to resolve or connect to destinations, while low TTFB with poor throughput points to congestion.
+    ttfb_median_ms: Mutex<Option<f64>>,
+    ttfb_p90_ms: Mutex<Option<f64>>,
+    #[serde(skip)]
+    ttfb_samples: Mutex<VecDeque<f64>>,
+
     loss: Mutex<f64>,
+    upload_loss: Mutex<f64>,
 
     exit_info: Mutex<Option<binder_transport::ExitDescriptor>>,
+    /// With a second exit, the first exit, which connections are tunneled through to reach `exit_info`.
+    first_hop: Mutex<Option<String>>,
+    exit_selection: Mutex<Option<String>>,
+
+    log_lines: Mutex<usize>,
+
+    paths: Mutex<Vec<PathStat>>,
+
+    suspend_reconnects: Mutex<u64>,
+    /// Reconnects asked for through the stats server.
+    manual_reconnects: Mutex<u64>,
+
+    /// Failed reconnects in a row, and how many seconds we're waiting before the next one.
+    reconnect_attempts: Mutex<u32>,
+    reconnect_delay: Mutex<f64>,
+
+    window_blocked: Mutex<usize>,
+
+    /// Bytes tunneled connections hold, received but not yet read, and in copy buffers. Together they stay around --buffer-budget-mb.
+    buffered_bytes: Mutex<usize>,
+    copy_buffer_bytes: Mutex<usize>,
+
+    /// Seconds of silence after which the tunnel is pinged, and the NAT timeout, in seconds, that's inferred from the pings.
+    keepalive_interval: Mutex<f64>,
+    nat_timeout: Mutex<Option<f64>>,
+
+    /// How UDP and TCP handshakes have gone on the current network.
+    udp_handshakes: Mutex<crate::kalive::HandshakeCounts>,
+    tcp_handshakes: Mutex<crate::kalive::HandshakeCounts>,
+
+    protocol_version: Mutex<u64>,
+    protocol_downgraded: Mutex<bool>,
+
+    #[serde(skip)]
+    route: Mutex<Option<crate::kalive::Route>>,
+
+    #[serde(skip)]
+    conns: Mutex<BTreeMap<u64, Arc<ConnEntry>>>,
+    #[serde(skip)]
+    next_conn_id: AtomicU64,
 }
 
 impl StatCollector {
@@ -24,15 +84,39 @@ impl StatCollector {
     pub fn incr_total_tx(&self, bytes: u64) {
         *self.total_tx.lock() += bytes;
     }
+    pub fn total_tx(&self) -> u64 {
+        *self.total_tx.lock()
+    }
+    pub fn incr_cover_tx(&self, bytes: u64) {
+        *self.cover_tx.lock() += bytes;
+    }
 
     pub fn set_latency(&self, ms: f64) {
         *self.open_latency.lock() = ms
     }
 
+    /// Records the time to first byte of a tunneled connection.
+    pub fn add_ttfb(&self, ttfb: Duration) {
+        let mut samples = self.ttfb_samples.lock();
+        samples.push_back(ttfb.as_secs_f64() * 1000.0);
+        if samples.len() > TTFB_SAMPLES {
+            samples.pop_front();
+        }
+        let mut sorted: Vec<f64> = samples.iter().copied().collect();
+        sorted.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+        let percentile = |p: usize| sorted[(sorted.len() - 1) * p / 100];
+        *self.ttfb_median_ms.lock() = Some(percentile(50));
+        *self.ttfb_p90_ms.lock() = Some(percentile(90));
+    }
+
     pub fn set_loss(&self, loss: f64) {
         *self.loss.lock() = loss
     }
 
+    pub fn set_upload_loss(&self, loss: f64) {
+        *self.upload_loss.lock() = loss
+    }
+
     // pub fn get_latency(&self) -> f64 {
     //     *self.open_latency.lock()
     // }
@@ -40,7 +124,272 @@ impl StatCollector {
     pub fn set_exit_descriptor(&self, desc: Option<binder_transport::ExitDescriptor>) {
         *self.exit_info.lock() = desc
     }
+
+    pub fn set_first_hop(&self, hostname: Option<String>) {
+        *self.first_hop.lock() = hostname
+    }
+
+    pub fn set_exit_selection(&self, reason: Option<String>) {
+        *self.exit_selection.lock() = reason
+    }
+
+    pub fn set_log_lines(&self, lines: usize) {
+        *self.log_lines.lock() = lines
+    }
+
+    pub fn set_window_blocked(&self, conns: usize) {
+        *self.window_blocked.lock() = conns
+    }
+
+    pub fn set_buffer_usage(&self, buffered: usize, copy_buffers: usize) {
+        *self.buffered_bytes.lock() = buffered;
+        *self.copy_buffer_bytes.lock() = copy_buffers
+    }
+
+    pub fn set_keepalive(
+        &self,
+        interval: std::time::Duration,
+        nat_timeout: Option<std::time::Duration>,
+    ) {
+        *self.keepalive_interval.lock() = interval.as_secs_f64();
+        *self.nat_timeout.lock() = nat_timeout.map(|t| t.as_secs_f64())
+    }
+
+    pub fn set_handshakes(
+        &self,
+        udp: crate::kalive::HandshakeCounts,
+        tcp: crate::kalive::HandshakeCounts,
+    ) {
+        *self.udp_handshakes.lock() = udp;
+        *self.tcp_handshakes.lock() = tcp
+    }
+
+    pub fn set_protocol_version(&self, version: u64, downgraded: bool) {
+        *self.protocol_version.lock() = version;
+        *self.protocol_downgraded.lock() = downgraded
+    }
+
+    pub fn set_route(&self, route: Option<crate::kalive::Route>) {
+        *self.route.lock() = route
+    }
+
+    pub fn incr_suspend_reconnects(&self) {
+        *self.suspend_reconnects.lock() += 1;
+    }
+
+    pub fn incr_manual_reconnects(&self) {
+        *self.manual_reconnects.lock() += 1;
+    }
+
+    pub fn set_reconnect_state(&self, attempts: u32, delay: std::time::Duration) {
+        *self.reconnect_attempts.lock() = attempts;
+        *self.reconnect_delay.lock() = delay.as_secs_f64()
+    }
+
+    pub fn set_paths(&self, paths: Vec<PathStat>) {
+        *self.paths.lock() = paths
+    }
+
+    pub fn paths(&self) -> Vec<PathStat> {
+        self.paths.lock().clone()
+    }
+
+    /// UDP and TCP handshake outcomes on the current network, in that order.
+    pub fn handshakes(
+        &self,
+    ) -> (
+        crate::kalive::HandshakeCounts,
+        crate::kalive::HandshakeCounts,
+    ) {
+        (*self.udp_handshakes.lock(), *self.tcp_handshakes.lock())
+    }
+
+    /// Whether there's currently a working tunnel to an exit.
+    pub fn is_connected(&self) -> bool {
+        self.exit_info.lock().is_some()
+    }
+
+    pub fn get_route(&self) -> Option<crate::kalive::Route> {
+        self.route.lock().clone()
+    }
+
+    /// Registers a tunneled connection, which stays listed until the returned handle is dropped.
+    pub fn register_conn(self: &Arc<Self>, destination: &str) -> ConnHandle {
+        let id = self.next_conn_id.fetch_add(1, Ordering::Relaxed);
+        let (send_close, recv_close) = smol::channel::bounded(1);
+        let entry = Arc::new(ConnEntry {
+            destination: destination.to_string(),
+            start: Instant::now(),
+            ttfb_us: AtomicU64::new(0),
+            rx: AtomicU64::new(0),
+            tx: AtomicU64::new(0),
+            send_close,
+        });
+        let mut conns = self.conns.lock();
+        conns.insert(id, entry.clone());
+        *self.open_conns.lock() = conns.len() as u64;
+        ConnHandle {
+            id,
+            entry,
+            recv_close,
+            stats: self.clone(),
+        }
+    }
+
+    /// Lists the tunneled connections that are currently open.
+    pub fn list_conns(&self) -> Vec<ConnInfo> {
+        self.conns
+            .lock()
+            .iter()
+            .map(|(id, entry)| ConnInfo {
+                id: *id,
+                destination: entry.destination.clone(),
+                rx: entry.rx.load(Ordering::Relaxed),
+                tx: entry.tx.load(Ordering::Relaxed),
+                age: entry.start.elapsed().as_secs_f64(),
+                ttfb_ms: entry.ttfb().map(|ttfb| ttfb.as_secs_f64() * 1000.0),
+            })
+            .collect()
+    }
+
+    /// Asks a tunneled connection to close. Returns false if there's no such connection.
+    pub fn close_conn(&self, id: u64) -> bool {
+        if let Some(entry) = self.conns.lock().get(&id) {
+            let _ = entry.send_close.try_send(());
+            true
+        } else {
+            false
+        }
+    }
+}
+
+struct ConnEntry {
+    destination: String,
+    start: Instant,
+    /// Microseconds from the start to the first byte received, or zero before then.
+    ttfb_us: AtomicU64,
+    rx: AtomicU64,
+    tx: AtomicU64,
+    send_close: smol::channel::Sender<()>,
+}
+
+impl ConnEntry {
+    fn ttfb(&self) -> Option<Duration> {
+        match self.ttfb_us.load(Ordering::Relaxed) {
+            0 => None,
+            us => Some(Duration::from_micros(us)),
+        }
+    }
+}
+
+/// A tunneled connection, as listed by the /connections endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnInfo {
+    pub id: u64,
+    pub destination: String,
+    pub rx: u64,
+    pub tx: u64,
+    pub age: f64,
+    /// Milliseconds from the exit accepting the connection to the first byte coming back, if any has.
+    #[serde(default)]
+    pub ttfb_ms: Option<f64>,
+}
+
+/// Handle to a registered connection. Unregisters the connection when dropped.
+pub struct ConnHandle {
+    id: u64,
+    entry: Arc<ConnEntry>,
+    recv_close: smol::channel::Receiver<()>,
+    stats: Arc<StatCollector>,
+}
+
+impl ConnHandle {
+    pub fn incr_rx(&self, bytes: u64) {
+        if self.entry.rx.fetch_add(bytes, Ordering::Relaxed) == 0 && bytes > 0 {
+            let ttfb = self.entry.start.elapsed();
+            self.entry
+                .ttfb_us
+                .store((ttfb.as_micros() as u64).max(1), Ordering::Relaxed);
+            self.stats.add_ttfb(ttfb);
+        }
+        self.stats.incr_total_rx(bytes);
+    }
+
+    /// How long it took for the first byte to come back, if any has.
+    pub fn ttfb(&self) -> Option<Duration> {
+        self.entry.ttfb()
+    }
+
+    pub fn incr_tx(&self, bytes: u64) {
+        self.entry.tx.fetch_add(bytes, Ordering::Relaxed);
+        self.stats.incr_total_tx(bytes);
+    }
+
+    /// Waits until someone asks for this connection to be closed.
+    pub async fn wait_close(&self) {
+        if self.recv_close.recv().await.is_err() {
+            smol::future::pending::<()>().await
+        }
+    }
+}
+
+impl Drop for ConnHandle {
+    fn drop(&mut self) {
+        let mut conns = self.stats.conns.lock();
+        conns.remove(&self.id);
+        *self.stats.open_conns.lock() = conns.len() as u64;
+    }
 }
 
 pub static GLOBAL_LOGGER: Lazy<RwLock<VecDeque<String>>> =
     Lazy::new(|| RwLock::new(VecDeque::new()));
+
+/// Statistics for one of the sessions to the exit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PathStat {
+    /// Identifies the session in the exit's logs too.
+    pub session_id: String,
+    /// 1 for a path to the first exit, 2 for a path tunneled through it to a second exit.
+    pub hop: u8,
+    pub endpoint: SocketAddr,
+    pub via_bridge: bool,
+    pub use_tcp: bool,
+    pub shards: usize,
+    /// Shards sitting out for a while, because they were getting far fewer packets than the others.
+    #[serde(default)]
+    pub benched_shards: Vec<u8>,
+    /// How the path of each shard in use is doing, as the session estimates it.
+    #[serde(default)]
+    pub shard_paths: Vec<ShardPathStat>,
+    pub ping: f64,
+    pub loss: f64,
+    pub upload_loss: f64,
+    /// Packets queued inside the session, waiting to go out or to be read. Persistently high values mean buffer bloat.
+    pub send_queue: usize,
+    pub recv_queue: usize,
+}
+
+/// Statistics for the path of one shard of a session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShardPathStat {
+    pub shard_id: u8,
+    /// Round trip in milliseconds, or 0 if not measured yet.
+    pub ping: f64,
+    /// Loss in percent.
+    pub loss: f64,
+    /// Packets per second the path is estimated to carry. The session's packets are spread over its shards in proportion to this.
+    pub bandwidth: f64,
+}
+
+/// Maximum number of lines kept in `GLOBAL_LOGGER`. Older lines are dropped first.
+pub static GLOBAL_LOGGER_CAPACITY: AtomicUsize = AtomicUsize::new(100000);
+
+/// Appends a line to `GLOBAL_LOGGER`, evicting the oldest lines if it's over capacity.
+pub fn push_log_line(line: String) {
+    let capacity = GLOBAL_LOGGER_CAPACITY.load(Ordering::Relaxed);
+    let mut logger = GLOBAL_LOGGER.write();
+    logger.push_back(line);
+    while logger.len() > capacity {
+        logger.pop_front();
+    }
+}
@@ -0,0 +1,175 @@
+//! Transparent proxying for Linux routers.
+//!
+//! Connections redirected to `--transparent-listen` by iptables are tunneled to wherever they were originally headed, so apps don't need to know about the proxy at all. Traffic from geph4-client itself must be excluded, or it will loop back into itself. For example, running the client as the `geph` user and listening on port 9911:
+//!
+//! ```text
+//! iptables -t nat -N GEPH
+//! iptables -t nat -A GEPH -d 0.0.0.0/8,10.0.0.0/8,127.0.0.0/8,169.254.0.0/16,172.16.0.0/12,192.168.0.0/16,224.0.0.0/4,240.0.0.0/4 -j RETURN
+//! iptables -t nat -A GEPH -p tcp -j REDIRECT --to-ports 9911
+//! # traffic forwarded from the LAN
+//! iptables -t nat -A PREROUTING -p tcp -j GEPH
+//! # traffic from the router itself
+//! iptables -t nat -A OUTPUT -p tcp -m owner ! --uid-owner geph -j GEPH
+//! ```
+//!
+//! The same rules work for IPv6 with `ip6tables`, given an IPv6 `--transparent-listen`. TPROXY rules work too, as long as the client has `CAP_NET_ADMIN` so that the listener can be made transparent.
+use crate::{kalive::Keepalive, main_connect::relay, stats::StatCollector};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+/// Accepts redirected connections from a listener made by [bind_transparent] forever, tunneling each one to its original destination.
+pub async fn transparent_loop(
+    listener: smol::net::TcpListener,
+    stats: Arc<StatCollector>,
+    keepalive: Keepalive,
+    exclude_prc: bool,
+) -> anyhow::Result<()> {
+    let listen = listener.local_addr()?;
+    log::info!("transparent proxy listening on {}", listen);
+    loop {
+        let (client, _) = listener.accept().await?;
+        let stats = stats.clone();
+        let keepalive = keepalive.clone();
+        smolscale::spawn(async move {
+            let _active = crate::power::active();
+            let dest = match original_dst(&client) {
+                Ok(dest) => dest,
+                Err(err) => {
+                    log::warn!("cannot find original destination: {}", err);
+                    return Ok(());
+                }
+            };
+            // connecting to ourselves would loop forever
+            if dest == listen || dest.ip().is_loopback() {
+                log::warn!("dropping transparent connection to {}", dest);
+                return Ok(());
+            }
+            let v4addr = match dest.ip() {
+                IpAddr::V4(v4) => Some(v4),
+                IpAddr::V6(_) => None,
+            };
+            client.set_nodelay(true)?;
+            relay(
+                stats,
+                client,
+                &dest.to_string(),
+                v4addr,
+                &keepalive,
+                exclude_prc,
+            )
+            .await
+        })
+        .detach();
+    }
+}
+
+/// Binds a listener, making it transparent (for TPROXY) if we have the privileges to.
+#[cfg(target_os = "linux")]
+pub fn bind_transparent(listen: SocketAddr) -> anyhow::Result<smol::net::TcpListener> {
+    use socket2::{Domain, Socket, Type};
+    use std::os::unix::io::AsRawFd;
+    let domain = if listen.is_ipv4() {
+        Domain::ipv4()
+    } else {
+        Domain::ipv6()
+    };
+    let socket = Socket::new(domain, Type::stream(), None)?;
+    socket.set_reuse_address(true)?;
+    let one: libc::c_int = 1;
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_IP,
+            libc::IP_TRANSPARENT,
+            &one as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if res != 0 {
+        log::warn!(
+            "cannot set IP_TRANSPARENT ({}), so only REDIRECT rules will work",
+            std::io::Error::last_os_error()
+        );
+    }
+    socket.bind(&listen.into())?;
+    socket.listen(1024)?;
+    Ok(smol::Async::new(socket.into_tcp_listener())?.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind_transparent(_listen: SocketAddr) -> anyhow::Result<smol::net::TcpListener> {
+    anyhow::bail!("transparent proxying is only supported on Linux")
+}
+
+/// Finds where a redirected connection was originally going. REDIRECT rules record this in `SO_ORIGINAL_DST`, or `IP6T_SO_ORIGINAL_DST` for IPv6, while TPROXY leaves it as the local address.
+#[cfg(target_os = "linux")]
+fn original_dst(client: &smol::net::TcpStream) -> std::io::Result<SocketAddr> {
+    use std::os::unix::io::AsRawFd;
+    let local = client.local_addr()?;
+    let fd = client.as_raw_fd();
+    // IPv4 connections to a dual-stack listener are tracked as IPv4
+    let found = if local.is_ipv6() {
+        original_dst_v6(fd).or_else(|_| original_dst_v4(fd))
+    } else {
+        original_dst_v4(fd)
+    };
+    Ok(found.unwrap_or(local))
+}
+
+#[cfg(target_os = "linux")]
+fn original_dst_v4(fd: std::os::unix::io::RawFd) -> std::io::Result<SocketAddr> {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    let mut addr: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_IP,
+            libc::SO_ORIGINAL_DST,
+            &mut addr as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+    Ok(SocketAddr::V4(SocketAddrV4::new(
+        ip,
+        u16::from_be(addr.sin_port),
+    )))
+}
+
+#[cfg(target_os = "linux")]
+fn original_dst_v6(fd: std::os::unix::io::RawFd) -> std::io::Result<SocketAddr> {
+    use std::net::{Ipv6Addr, SocketAddrV6};
+    // from linux/netfilter_ipv6/ip6_tables.h
+    const IP6T_SO_ORIGINAL_DST: libc::c_int = 80;
+    let mut addr: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_IPV6,
+            IP6T_SO_ORIGINAL_DST,
+            &mut addr as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(SocketAddr::V6(SocketAddrV6::new(
+        Ipv6Addr::from(addr.sin6_addr.s6_addr),
+        u16::from_be(addr.sin6_port),
+        0,
+        addr.sin6_scope_id,
+    )))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn original_dst(client: &smol::net::TcpStream) -> std::io::Result<SocketAddr> {
+    client.local_addr()
+}
@@ -0,0 +1,190 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::SystemTime,
+};
+
+use parking_lot::Mutex;
+
+/// How much the audit log records about each connection attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditLevel {
+    /// Nothing is recorded.
+    Off,
+    /// Time, session id and destination. Never where the client connected from.
+    Destination,
+    /// Everything in `Destination`, plus the addresses the session's packets came from.
+    Full,
+}
+
+impl FromStr for AuditLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" | "none" => Ok(AuditLevel::Off),
+            "destination" => Ok(AuditLevel::Destination),
+            "full" => Ok(AuditLevel::Full),
+            _ => anyhow::bail!("audit level must be off, destination, or full"),
+        }
+    }
+}
+
+/// An append-only log of connection attempts, one tab-separated line each, kept apart from the operational log. Once the file grows past its size limit, it's rotated to PATH.1, PATH.2 and so on, and the oldest is deleted.
+pub struct AuditLog {
+    level: AuditLevel,
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: Mutex<(File, u64)>,
+}
+
+impl AuditLog {
+    /// Opens the audit log for appending, or returns None if the level is `Off`.
+    pub fn open(
+        path: &Path,
+        level: AuditLevel,
+        max_bytes: u64,
+        keep: usize,
+    ) -> anyhow::Result<Option<Self>> {
+        if level == AuditLevel::Off {
+            return Ok(None);
+        }
+        let file = open_append(path)?;
+        let size = file.metadata()?.len();
+        Ok(Some(AuditLog {
+            level,
+            path: path.to_owned(),
+            max_bytes,
+            keep,
+            file: Mutex::new((file, size)),
+        }))
+    }
+
+    /// Returns the handle that a session uses to record its connection attempts. The source addresses are only looked at for the `Full` level.
+    pub fn for_session(
+        self: &Arc<Self>,
+        session_id: &str,
+        sources: impl FnOnce() -> Vec<SocketAddr>,
+    ) -> SessionAudit {
+        let sources = if self.level == AuditLevel::Full {
+            Some(
+                sources()
+                    .iter()
+                    .map(|addr| addr.to_string())
+                    .collect::<Vec<_>>()
+                    .join(","),
+            )
+        } else {
+            None
+        };
+        SessionAudit {
+            log: self.clone(),
+            session_id: session_id.to_owned(),
+            sources,
+        }
+    }
+
+    fn write_line(&self, line: &str) {
+        let mut file = self.file.lock();
+        if file.1 + line.len() as u64 > self.max_bytes && file.1 > 0 {
+            match self.rotate() {
+                Ok(new_file) => *file = (new_file, 0),
+                Err(err) => log::warn!("cannot rotate audit log: {}", err),
+            }
+        }
+        match file.0.write_all(line.as_bytes()) {
+            Ok(()) => file.1 += line.len() as u64,
+            Err(err) => log::warn!("cannot write audit log: {}", err),
+        }
+    }
+
+    fn rotate(&self) -> std::io::Result<File> {
+        let numbered = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = numbered(n);
+                if from.exists() {
+                    std::fs::rename(from, numbered(n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, numbered(1))?;
+        }
+        open_append(&self.path)
+    }
+}
+
+/// Records the connection attempts of one session.
+#[derive(Clone)]
+pub struct SessionAudit {
+    log: Arc<AuditLog>,
+    session_id: String,
+    sources: Option<String>,
+}
+
+impl SessionAudit {
+    /// Records an attempt to connect to the given destination, as the client asked for it.
+    pub fn record(&self, destination: &str) {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let line = match &self.sources {
+            Some(sources) => format!(
+                "{}\t{}\t{}\t{}\n",
+                now, self.session_id, destination, sources
+            ),
+            None => format!("{}\t{}\t{}\n", now, self.session_id, destination),
+        };
+        self.log.write_line(&line)
+    }
+}
+
+/// Opens the log for appending. It records where clients connect to, so only the owner may read it.
+fn open_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn destination_level_omits_sources() {
+        let dir = std::env::temp_dir().join(format!("audit-test-{}", fastrand::u64(..)));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+        let log = Arc::new(
+            AuditLog::open(&path, AuditLevel::Destination, 60, 1)
+                .unwrap()
+                .unwrap(),
+        );
+        let sess = log.for_session("abcdef", || panic!("sources looked at"));
+        for _ in 0..4 {
+            sess.record("example.com:443");
+        }
+        let current = std::fs::read_to_string(&path).unwrap();
+        assert!(current.ends_with("\tabcdef\texample.com:443\n"));
+        assert!(path.with_extension("log.1").exists());
+        assert!(!path.with_extension("log.2").exists());
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,51 +1,274 @@
 use std::{
-    net::SocketAddr,
-    sync::{atomic::AtomicUsize, Arc},
-    time::{Duration, Instant},
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
-use crate::vpn;
-use binder_transport::BinderClient;
+use crate::{
+    audit::AuditLog,
+    qos::{self, TierLimit},
+    redirect::RedirectTable,
+    vpn,
+};
+use binder_transport::{BinderClient, BinderRequestData, BinderResponse};
+use dashmap::DashMap;
+use ed25519_dalek::Signer;
+use parking_lot::RwLock;
 
-use jemalloc_ctl::epoch;
 use smol::prelude::*;
 
 use x25519_dalek::StaticSecret;
 
+mod bind;
 mod control;
+mod echo;
+mod health;
+pub use health::AdminAuth;
+mod metrics;
 mod session;
-/// the root context
+
+/// how long a level's sessions may go without their shares of its speed limit being recomputed, as sessions come and go
+const REBALANCE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// the port bridges connect to for the control protocol
+const CONTROL_PORT: u16 = 28080;
+
+/// the root context, shared by all the exits this process serves
 pub struct RootCtx {
     stat_client: Arc<statsd::Client>,
-    exit_hostname: String,
     binder_client: Arc<dyn BinderClient>,
     bridge_secret: String,
-    signing_sk: ed25519_dalek::Keypair,
-    sosistab_sk: x25519_dalek::StaticSecret,
+    /// the first is the main exit, whose hostname process-wide statistics are reported under
+    identities: Vec<Arc<Identity>>,
 
-    session_count: AtomicUsize,
     raw_session_count: AtomicUsize,
     pub conn_count: AtomicUsize,
     pub control_count: AtomicUsize,
 
-    free_limit: u32,
-    port_whitelist: bool,
+    session_timeout: Duration,
+    /// how long sessions of a draining exit get to move elsewhere before they're closed
+    drain_grace: Duration,
+    socks_bind: bool,
+    audit_log: Option<Arc<AuditLog>>,
 
-    pub google_proxy: Option<SocketAddr>,
+    policy: RwLock<Arc<Policy>>,
+    /// addresses of this host, which clients may never connect to, so that they can't reach its management ports
+    own_ips: Vec<IpAddr>,
+
+    sessions: DashMap<u64, Arc<SessionEntry>>,
+    /// every session's multiplex, so that clients can move them onto new sessions without losing their connections
+    rebind_table: sosistab::mux::RebindTable,
+    /// how many of the sessions are of each user level
+    level_counts: DashMap<String, usize>,
     // pub conn_tasks: Mutex<cached::SizedCache<u128, smol::Task<Option<()>>>>,
 }
 
+/// one of the exits this process serves. each has its own hostname, keys, and listeners, and is registered at the binder and known to bridges separately.
+pub struct Identity {
+    hostname: String,
+    signing_sk: ed25519_dalek::Keypair,
+    sosistab_sk: x25519_dalek::StaticSecret,
+    /// where its listeners listen. distinct identities need distinct addresses, since clients and bridges find every exit at the same ports.
+    listen_ip: IpAddr,
+    /// where the control protocol for bridges listens, which is usually the same as listen_ip
+    control_ip: IpAddr,
+    session_count: AtomicUsize,
+    /// whether the binder asked for this exit to be drained
+    draining: AtomicBool,
+    drain_event: event_listener::Event,
+}
+
+impl Identity {
+    pub fn new(hostname: String, signing_sk: ed25519_dalek::Keypair, listen_ip: IpAddr) -> Self {
+        let sosistab_sk = x25519_dalek::StaticSecret::from(*signing_sk.secret.as_bytes());
+        Self {
+            hostname,
+            signing_sk,
+            sosistab_sk,
+            listen_ip,
+            control_ip: listen_ip,
+            session_count: AtomicUsize::new(0),
+            draining: AtomicBool::new(false),
+            drain_event: event_listener::Event::new(),
+        }
+    }
+
+    /// listens for bridge control connections on a separate address, leaving the data plane, which clients and bridges send sessions to, on listen_ip
+    pub fn with_control_ip(mut self, control_ip: IpAddr) -> Self {
+        self.control_ip = control_ip;
+        self
+    }
+
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    pub fn listen_ip(&self) -> IpAddr {
+        self.listen_ip
+    }
+
+    pub fn control_ip(&self) -> IpAddr {
+        self.control_ip
+    }
+
+    /// where bridges should connect for the control protocol, if they can't find it at the hostname, which resolves to listen_ip
+    fn advertised_control_addr(&self) -> Option<SocketAddr> {
+        if self.control_ip == self.listen_ip || self.control_ip.is_unspecified() {
+            None
+        } else {
+            Some(SocketAddr::new(self.control_ip, CONTROL_PORT))
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// starts or stops draining, returning whether that's a change
+    fn set_draining(&self, draining: bool) -> bool {
+        let changed = self.draining.swap(draining, Ordering::SeqCst) != draining;
+        if changed && draining {
+            self.drain_event.notify(usize::MAX);
+        }
+        changed
+    }
+
+    /// waits until the exit starts draining
+    async fn wait_draining(&self) {
+        loop {
+            let listener = self.drain_event.listen();
+            if self.is_draining() {
+                return;
+            }
+            listener.await;
+        }
+    }
+
+    /// the public key clients do sosistab handshakes with
+    pub fn sosistab_pk(&self) -> x25519_dalek::PublicKey {
+        x25519_dalek::PublicKey::from(&self.sosistab_sk)
+    }
+}
+
+/// an additional exit to serve, as given on the command line: HOSTNAME,IP,KEYFILE
+#[derive(Debug, Clone)]
+pub struct ExtraExit {
+    pub hostname: String,
+    pub listen_ip: IpAddr,
+    pub signing_sk: PathBuf,
+}
+
+impl FromStr for ExtraExit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.splitn(3, ',').collect();
+        match parts.as_slice() {
+            [hostname, listen_ip, signing_sk] if !hostname.is_empty() && !signing_sk.is_empty() => {
+                Ok(ExtraExit {
+                    hostname: hostname.to_string(),
+                    listen_ip: listen_ip.parse()?,
+                    signing_sk: PathBuf::from(signing_sk),
+                })
+            }
+            _ => anyhow::bail!("extra exits must be given as HOSTNAME,IP,KEYFILE"),
+        }
+    }
+}
+
+/// the parts of the configuration that can be reloaded without a restart
+pub struct Policy {
+    pub free_limit: u32,
+    pub tier_limits: Vec<TierLimit>,
+    pub port_whitelist: bool,
+    pub redirects: RedirectTable,
+    /// Whether clients may connect to private, link-local and other internal addresses. Loopback is off limits regardless.
+    pub allow_internal: bool,
+}
+
 impl RootCtx {
-    fn new_sess(self: &Arc<Self>, sess: sosistab::Session) -> SessCtx {
+    /// the current policy. connections should hold on to this for their whole life, rather than look it up again.
+    pub fn policy(&self) -> Arc<Policy> {
+        self.policy.read().clone()
+    }
+
+    /// the main exit, as opposed to the extra ones
+    fn main_identity(&self) -> &Identity {
+        &self.identities[0]
+    }
+
+    /// the number of live sessions, across all identities
+    fn session_count(&self) -> usize {
+        self.identities
+            .iter()
+            .map(|id| id.session_count.load(std::sync::atomic::Ordering::Relaxed))
+            .sum()
+    }
+
+    /// replaces the policy. new connections get the new one, and existing sessions get the new speed limits.
+    fn set_policy(&self, policy: Policy) {
+        *self.policy.write() = Arc::new(policy);
+        self.rebalance();
+    }
+
+    /// the speed limit of each session of a user level, given how many sessions it has now
+    fn level_limit(&self, policy: &Policy, level: &str) -> u32 {
+        let sessions = self.level_counts.get(level).map(|c| *c).unwrap_or(1);
+        qos::level_limit(&policy.tier_limits, policy.free_limit, level, sessions)
+    }
+
+    /// registers an authenticated session, which starts out with its share of its level's speed limit. the level's other sessions only get their smaller shares at the next rebalance.
+    fn add_session(&self, sess_id: u64, entry: Arc<SessionEntry>) {
+        *self.level_counts.entry(entry.level.clone()).or_insert(0) += 1;
+        let limit = self.level_limit(&self.policy(), &entry.level);
+        entry.mux.get_session().set_ratelimit(limit);
+        self.sessions.insert(sess_id, entry);
+    }
+
+    fn remove_session(&self, sess_id: u64) {
+        if let Some((_, entry)) = self.sessions.remove(&sess_id) {
+            if let Some(mut count) = self.level_counts.get_mut(&entry.level) {
+                *count -= 1;
+            }
+            self.level_counts
+                .remove_if(&entry.level, |_, count| *count == 0);
+        }
+    }
+
+    /// recomputes the speed limits of all sessions, which share their level's limit equally. this goes through every session, so it runs every REBALANCE_INTERVAL and when the policy changes, rather than whenever a session comes or goes.
+    fn rebalance(&self) {
+        let policy = self.policy();
+        let mut limits: HashMap<String, u32> = HashMap::new();
+        for entry in self.sessions.iter() {
+            let limit = match limits.get(&entry.level) {
+                Some(limit) => *limit,
+                None => {
+                    let limit = self.level_limit(&policy, &entry.level);
+                    limits.insert(entry.level.clone(), limit);
+                    limit
+                }
+            };
+            entry.mux.get_session().set_ratelimit(limit);
+        }
+    }
+
+    fn new_sess(self: &Arc<Self>, identity: &Arc<Identity>, sess: sosistab::Session) -> SessCtx {
         SessCtx {
             root: self.clone(),
+            identity: identity.clone(),
             sess,
         }
     }
 
     async fn listen_udp(
         &self,
-        sk: Option<StaticSecret>,
+        long_sk: StaticSecret,
         addr: SocketAddr,
         flow_key: &str,
     ) -> sosistab::Listener {
@@ -53,12 +276,7 @@ impl RootCtx {
         let stat2 = self.stat_client.clone();
         let flow_key = flow_key.to_owned();
         let fk2 = flow_key.clone();
-        let long_sk = if let Some(sk) = sk {
-            sk
-        } else {
-            self.sosistab_sk.clone()
-        };
-        sosistab::Listener::listen_udp(
+        sosistab::Listener::listen_udp_with_recv_timeout(
             addr,
             long_sk,
             move |len, _| {
@@ -71,13 +289,14 @@ impl RootCtx {
                     stat2.count(&fk2, len as f64 * 20.0)
                 }
             },
+            self.session_timeout,
         )
         .await
     }
 
     async fn listen_tcp(
         &self,
-        sk: Option<StaticSecret>,
+        long_sk: StaticSecret,
         addr: SocketAddr,
         flow_key: &str,
     ) -> sosistab::Listener {
@@ -85,12 +304,7 @@ impl RootCtx {
         let stat2 = self.stat_client.clone();
         let flow_key = flow_key.to_owned();
         let fk2 = flow_key.clone();
-        let long_sk = if let Some(sk) = sk {
-            sk
-        } else {
-            self.sosistab_sk.clone()
-        };
-        sosistab::Listener::listen_tcp(
+        sosistab::Listener::listen_tcp_with_recv_timeout(
             addr,
             long_sk,
             move |len, _| {
@@ -103,13 +317,17 @@ impl RootCtx {
                     stat2.count(&fk2, len as f64 * 20.0)
                 }
             },
+            self.session_timeout,
         )
         .await
     }
 }
 
 async fn idlejitter(ctx: Arc<RootCtx>) {
-    let key = format!("idlejitter.{}", ctx.exit_hostname.replace(".", "-"));
+    let key = format!(
+        "idlejitter.{}",
+        ctx.main_identity().hostname.replace(".", "-")
+    );
     const INTERVAL: Duration = Duration::from_millis(10);
     loop {
         let start = Instant::now();
@@ -121,53 +339,157 @@ async fn idlejitter(ctx: Arc<RootCtx>) {
     }
 }
 
+/// an authenticated session, as seen by the health server
+pub struct SessionEntry {
+    mux: Arc<sosistab::mux::Multiplex>,
+    is_plus: bool,
+    level: String,
+    conn_count: AtomicUsize,
+    start: Instant,
+    /// totals over the session's connections that have closed
+    conns_closed: AtomicU64,
+    conn_retransmits: AtomicU64,
+    /// connections busy sending to the client, which share the session's speed limit
+    flows: qos::FlowShare,
+}
+
+impl SessionEntry {
+    /// adds a closed connection to the session's totals
+    fn add_closed_conn(&self, stats: &sosistab::mux::ConnStats) {
+        self.conns_closed.fetch_add(1, Ordering::Relaxed);
+        self.conn_retransmits
+            .fetch_add(stats.retransmits, Ordering::Relaxed);
+    }
+}
+
 /// per-session context
 pub struct SessCtx {
     root: Arc<RootCtx>,
+    /// the exit the session connected to
+    identity: Arc<Identity>,
     sess: sosistab::Session,
 }
 
-/// the main listening loop
+/// the main listening loop. the first identity is the main exit.
 #[allow(clippy::clippy::too_many_arguments)]
 pub async fn main_loop<'a>(
     stat_client: statsd::Client,
-    exit_hostname: &'a str,
+    identities: Vec<Identity>,
     binder_client: Arc<dyn BinderClient>,
     bridge_secret: &'a str,
-    signing_sk: ed25519_dalek::Keypair,
-    sosistab_sk: x25519_dalek::StaticSecret,
-    free_limit: u32,
-    google_proxy: Option<SocketAddr>,
-    port_whitelist: bool,
+    policy: Policy,
+    policy_reloads: smol::channel::Receiver<Policy>,
+    health_listen: Option<SocketAddr>,
+    admin_auth: AdminAuth,
+    session_timeout: Duration,
+    drain_grace: Duration,
+    socks_bind: bool,
+    audit_log: Option<AuditLog>,
+    metrics_interval: Duration,
 ) -> anyhow::Result<()> {
+    let own_ips = own_ips(&identities).await;
+    log::debug!("clients can't connect to our own addresses {:?}", own_ips);
     let ctx = Arc::new(RootCtx {
         stat_client: Arc::new(stat_client),
-        exit_hostname: exit_hostname.to_string(),
         binder_client,
         bridge_secret: bridge_secret.to_string(),
-        signing_sk,
-        sosistab_sk,
-        session_count: AtomicUsize::new(0),
+        identities: identities.into_iter().map(Arc::new).collect(),
         raw_session_count: AtomicUsize::new(0),
         conn_count: AtomicUsize::new(0),
-        free_limit,
-        port_whitelist,
-        google_proxy,
+        session_timeout,
+        drain_grace,
+        socks_bind,
+        audit_log: audit_log.map(Arc::new),
+        policy: RwLock::new(Arc::new(policy)),
+        own_ips,
         control_count: AtomicUsize::new(0),
+        sessions: DashMap::new(),
+        rebind_table: sosistab::mux::RebindTable::new(),
+        level_counts: DashMap::new(),
     });
 
     let _idlejitter = smolscale::spawn(idlejitter(ctx.clone()));
 
     let _vpn = smolscale::spawn(vpn::transparent_proxy_helper(ctx.clone()));
 
+    let _reload = {
+        let ctx = ctx.clone();
+        smolscale::spawn(async move {
+            while let Ok(policy) = policy_reloads.recv().await {
+                log::info!(
+                    "reloaded policy (free_limit = {}, tier_limits = {:?}, port_whitelist = {})",
+                    policy.free_limit,
+                    policy.tier_limits,
+                    policy.port_whitelist
+                );
+                ctx.set_policy(policy);
+            }
+        })
+    };
+
+    let _rebalance = {
+        let ctx = ctx.clone();
+        smolscale::spawn(async move {
+            loop {
+                smol::Timer::after(REBALANCE_INTERVAL).await;
+                ctx.rebalance();
+            }
+        })
+    };
+
+    let _health =
+        health_listen.map(|addr| smolscale::spawn(health::serve(ctx.clone(), addr, admin_auth)));
+
+    // every identity is served until one of them fails
+    let mut identities_fut = smol::future::pending::<anyhow::Result<()>>().boxed();
+    for identity in ctx.identities.iter() {
+        identities_fut = identities_fut
+            .or(serve_identity(ctx.clone(), identity.clone()))
+            .boxed();
+    }
+    // future that uploads statistics
+    let metrics_fut = metrics::push_loop(ctx.clone(), metrics_interval);
+    // race
+    identities_fut.or(metrics_fut).await
+}
+
+/// the addresses of this host: those the identities listen on, or, for identities listening on every address, those their hostnames resolve to
+async fn own_ips(identities: &[Identity]) -> Vec<IpAddr> {
+    let mut toret = Vec::new();
+    for identity in identities {
+        if !identity.control_ip.is_unspecified() {
+            toret.push(identity.control_ip);
+        }
+        if !identity.listen_ip.is_unspecified() {
+            toret.push(identity.listen_ip);
+            continue;
+        }
+        match crate::resolver::resolve(&format!("{}:0", identity.hostname)).await {
+            Ok(addrs) => toret.extend(addrs.into_iter().map(|addr| addr.ip())),
+            Err(err) => log::warn!(
+                "cannot resolve {} to find our own addresses: {}",
+                identity.hostname,
+                err
+            ),
+        }
+    }
+    toret.sort_unstable();
+    toret.dedup();
+    toret
+}
+
+/// serves one identity: its control protocol for bridges, its own "self bridge" for clients, and its load reports to the binder
+async fn serve_identity(ctx: Arc<RootCtx>, identity: Arc<Identity>) -> anyhow::Result<()> {
+    let exit_hostname = identity.hostname.clone();
     // control protocol listener
-    let control_prot_listen = smol::net::TcpListener::bind("[::0]:28080").await?;
+    let control_prot_listen =
+        smol::net::TcpListener::bind(SocketAddr::new(identity.control_ip, CONTROL_PORT)).await?;
     // future that governs the control protocol
     let control_prot_fut = async {
         loop {
             let ctx = ctx.clone();
             let (client, _) = control_prot_listen.accept().await?;
-            smolscale::spawn(control::handle_control(ctx, client)).detach();
+            smolscale::spawn(control::handle_control(ctx, identity.clone(), client)).detach();
         }
     };
     let exit_hostname2 = exit_hostname.to_string();
@@ -182,57 +504,110 @@ pub async fn main_loop<'a>(
     let ctx1 = ctx.clone();
     let self_bridge_fut = async {
         let flow_key = bridge_pkt_key("SELF");
+        let listen_addr = SocketAddr::new(identity.listen_ip, 19831);
         let udp_listen = ctx
-            .listen_udp(None, "[::0]:19831".parse().unwrap(), &flow_key)
+            .listen_udp(identity.sosistab_sk.clone(), listen_addr, &flow_key)
             .await;
         let tcp_listen = ctx
-            .listen_tcp(None, "[::0]:19831".parse().unwrap(), &flow_key)
+            .listen_tcp(identity.sosistab_sk.clone(), listen_addr, &flow_key)
             .await;
-        log::debug!("sosis_listener initialized");
-        loop {
-            let sess = udp_listen
-                .accept_session()
-                .race(tcp_listen.accept_session())
-                .await
-                .ok_or_else(|| anyhow::anyhow!("can't accept from sosistab"))?;
-            let ctx1 = ctx1.clone();
-            smolscale::spawn(session::handle_session(ctx1.new_sess(sess))).detach();
-        }
+        log::debug!("sosis_listener initialized for {}", exit_hostname);
+        let watch_fut = metrics::watch_sessions(
+            ctx.stat_client.clone(),
+            exit_hostname.clone(),
+            udp_listen.subscribe(),
+        )
+        .or(metrics::watch_sessions(
+            ctx.stat_client.clone(),
+            exit_hostname.clone(),
+            tcp_listen.subscribe(),
+        ));
+        let accept_fut = async {
+            loop {
+                // during connection storms, take all the queued sessions at once
+                let sessions = udp_listen
+                    .accept_many(64)
+                    .race(tcp_listen.accept_many(64))
+                    .await;
+                if sessions.is_empty() {
+                    anyhow::bail!("can't accept from sosistab")
+                }
+                for sess in sessions {
+                    let ctx1 = ctx1.clone();
+                    smolscale::spawn(session::handle_session(ctx1.new_sess(&identity, sess)))
+                        .detach();
+                }
+            }
+        };
+        accept_fut.or(watch_fut).await
     };
-    // future that uploads gauge statistics
-    let stat_client = ctx.stat_client.clone();
-    let gauge_fut = async {
-        let key = format!("session_count.{}", exit_hostname.replace(".", "-"));
-        let rskey = format!("raw_session_count.{}", exit_hostname.replace(".", "-"));
-        let memkey = format!("bytes_allocated.{}", exit_hostname.replace(".", "-"));
-        let connkey = format!("conn_count.{}", exit_hostname.replace(".", "-"));
-        let ctrlkey = format!("control_count.{}", exit_hostname.replace(".", "-"));
-        let taskkey = format!("task_count.{}", exit_hostname.replace(".", "-"));
-        let e = epoch::mib().unwrap();
-        // let allocated = jemalloc_ctl::stats::allocated::mib().unwrap();
-        let resident = jemalloc_ctl::stats::resident::mib().unwrap();
+    // future that reports our load to the binder, for load-based exit selection. the binder answers with whether to drain.
+    let load_report_fut = async {
         loop {
-            e.advance().unwrap();
-
-            let session_count = ctx.session_count.load(std::sync::atomic::Ordering::Relaxed);
-            stat_client.gauge(&key, session_count as f64);
-            let raw_session_count = ctx
-                .raw_session_count
-                .load(std::sync::atomic::Ordering::Relaxed);
-            stat_client.gauge(&rskey, raw_session_count as f64);
-            let memory_usage = resident.read().unwrap();
-            stat_client.gauge(&memkey, memory_usage as f64);
-            let conn_count = ctx.conn_count.load(std::sync::atomic::Ordering::Relaxed);
-            stat_client.gauge(&connkey, conn_count as f64);
-            let control_count = ctx.control_count.load(std::sync::atomic::Ordering::Relaxed);
-            stat_client.gauge(&ctrlkey, control_count as f64);
-            let task_count = smolscale::active_task_count();
-            stat_client.gauge(&taskkey, task_count as f64);
-            smol::Timer::after(Duration::from_secs(10)).await;
+            let session_count = identity
+                .session_count
+                .load(std::sync::atomic::Ordering::Relaxed) as u32;
+            let report_unixtime = SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let to_sign =
+                bincode::serialize(&(&exit_hostname, session_count, report_unixtime)).unwrap();
+            let exit_signature = identity.signing_sk.sign(&to_sign);
+            let resp = ctx
+                .binder_client
+                .request(BinderRequestData::ReportExitLoad {
+                    exit_hostname: exit_hostname.clone(),
+                    session_count,
+                    report_unixtime,
+                    exit_signature,
+                })
+                .await;
+            match resp {
+                Ok(resp) => {
+                    let draining =
+                        matches!(resp, BinderResponse::ReportExitLoadResp { draining: true });
+                    if identity.set_draining(draining) {
+                        if draining {
+                            log::warn!(
+                                "{}: the binder says to drain; refusing new sessions, and moving clients elsewhere over the next {:?}",
+                                exit_hostname,
+                                ctx.drain_grace
+                            );
+                        } else {
+                            log::warn!("{}: the binder says to stop draining", exit_hostname);
+                        }
+                    }
+                }
+                Err(err) => log::warn!("failed to report load to binder: {:?}", err),
+            }
+            // bridges look for the control protocol at our hostname, unless the binder tells them it's elsewhere
+            if let Some(control_addr) = identity.advertised_control_addr() {
+                let to_sign = bincode::serialize(&(
+                    "control_addr",
+                    &exit_hostname,
+                    control_addr,
+                    report_unixtime,
+                ))
+                .unwrap();
+                let exit_signature = identity.signing_sk.sign(&to_sign);
+                let resp = ctx
+                    .binder_client
+                    .request(BinderRequestData::ReportControlAddr {
+                        exit_hostname: exit_hostname.clone(),
+                        control_addr,
+                        report_unixtime,
+                        exit_signature,
+                    })
+                    .await;
+                if let Err(err) = resp {
+                    log::warn!("failed to report control address to binder: {:?}", err)
+                }
+            }
+            smol::Timer::after(Duration::from_secs(60)).await;
         }
     };
-    // race
     smol::future::race(control_prot_fut, self_bridge_fut)
-        .or(gauge_fut)
+        .or(load_report_fut)
         .await
 }
@@ -0,0 +1,90 @@
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use smol_timeout::TimeoutExt;
+use sosistab::mux::CloseReason;
+
+/// Connection labels starting with this ask for a SOCKS5 BIND, followed by the address the inbound connection is expected from.
+pub const BIND_PREFIX: &str = "bind ";
+
+/// How long we wait for the inbound connection before giving up.
+const BIND_ACCEPT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Most listening ports we hold open for binds at once, across all sessions.
+const MAX_BINDS: usize = 256;
+
+static ACTIVE_BINDS: AtomicUsize = AtomicUsize::new(0);
+
+/// Handles a SOCKS5 BIND. We listen on a fresh port and send the client the public address of that port. Once the expected peer connects, we send the client the peer's address and relay the connection. Both addresses are sent as pascalish SocketAddrs, as SOCKS5 needs them for its two replies.
+pub async fn handle_bind(
+    mut client: sosistab::mux::RelConn,
+    expected_peer: &str,
+    exit_hostname: &str,
+    port_whitelist: bool,
+) -> anyhow::Result<()> {
+    if ACTIVE_BINDS.fetch_add(1, Ordering::Relaxed) >= MAX_BINDS {
+        ACTIVE_BINDS.fetch_sub(1, Ordering::Relaxed);
+        client.close_with(CloseReason::Refused);
+        anyhow::bail!("too many binds")
+    }
+    let _guard = scopeguard::guard((), |_| {
+        ACTIVE_BINDS.fetch_sub(1, Ordering::Relaxed);
+    });
+
+    let expected = match crate::resolver::resolve(expected_peer)
+        .await
+        .ok()
+        .and_then(|addrs| addrs.first().cloned())
+    {
+        Some(addr) => addr,
+        None => {
+            client.close_with(CloseReason::Unreachable);
+            anyhow::bail!("cannot resolve expected bind peer")
+        }
+    };
+    // the port the peer connects from is often unknown, in which case it's zero
+    let port = expected.port();
+    if port != 0
+        && (crate::lists::BLACK_PORTS.contains(&port)
+            || (port_whitelist && !crate::lists::WHITE_PORTS.contains(&port)))
+    {
+        client.close_with(CloseReason::Refused);
+        anyhow::bail!("bind peer port not allowed")
+    }
+
+    let listener = smol::net::TcpListener::bind("0.0.0.0:0").await?;
+    let local_port = listener.local_addr()?.port();
+    let public_addr = aioutils::resolve(&format!("{}:{}", exit_hostname, local_port))
+        .await?
+        .into_iter()
+        .find(|addr| addr.is_ipv4())
+        .ok_or_else(|| anyhow::anyhow!("cannot find our own public address"))?;
+    aioutils::write_pascalish(&mut client, &public_addr).await?;
+
+    let (remote, peer): (smol::net::TcpStream, SocketAddr) = loop {
+        let (remote, peer) = match listener.accept().timeout(BIND_ACCEPT_TIMEOUT).await {
+            Some(res) => res?,
+            None => {
+                client.close_with(CloseReason::Timeout);
+                anyhow::bail!("nobody connected to bind port")
+            }
+        };
+        if expected.ip().is_unspecified() || peer.ip() == expected.ip() {
+            break (remote, peer);
+        }
+        log::debug!("dropping unexpected bind connection from {}", peer);
+    };
+    drop(listener);
+    aioutils::write_pascalish(&mut client, &peer).await?;
+
+    remote.set_nodelay(true)?;
+    smol::future::race(
+        aioutils::copy_with_stats(remote.clone(), client.clone(), |_| ()),
+        aioutils::copy_with_stats(client, remote, |_| ()),
+    )
+    .await?;
+    Ok(())
+}
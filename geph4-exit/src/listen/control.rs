@@ -1,4 +1,4 @@
-use super::{session, RootCtx};
+use super::{session, Identity, RootCtx};
 use anyhow::Context;
 use binder_transport::BinderRequestData;
 use ed25519_dalek::Signer;
@@ -11,8 +11,10 @@ use std::{
     sync::Arc,
     time::{Duration, SystemTime},
 };
+/// Handles a control connection from a bridge, for the identity whose address it came to.
 pub async fn handle_control(
     ctx: Arc<RootCtx>,
+    identity: Arc<Identity>,
     mut client: smol::net::TcpStream,
 ) -> anyhow::Result<()> {
     ctx.control_count
@@ -22,7 +24,7 @@ pub async fn handle_control(
             .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
     });
 
-    let exit_hostname = ctx.exit_hostname.clone();
+    let exit_hostname = identity.hostname.clone();
     let bridge_pkt_key = move |bridge_group: &str| {
         format!(
             "raw_flow.{}.{}",
@@ -67,19 +69,20 @@ pub async fn handle_control(
         // create or recall binding
         if info.is_none() {
             let ctx = ctx.clone();
+            let identity = identity.clone();
             log::debug!("redoing binding because info is none");
             let sosis_secret = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
-            // we make TCP first since TCP ephemeral ports are a lot more scarce.
+            // we make TCP first since TCP ephemeral ports are a lot more scarce. the bridge forwards to the port we tell it at our hostname, so listen on the data plane's address, even if it connected to us at another.
             let sosis_listener_tcp = ctx
                 .listen_tcp(
-                    Some(sosis_secret.clone()),
-                    "[::0]:0".parse().unwrap(),
+                    sosis_secret.clone(),
+                    SocketAddr::new(identity.listen_ip(), 0),
                     &flow_key,
                 )
                 .await;
             let sosis_listener_udp = ctx
                 .listen_udp(
-                    Some(sosis_secret.clone()),
+                    sosis_secret.clone(),
                     sosis_listener_tcp.local_addr(),
                     &flow_key,
                 )
@@ -101,7 +104,8 @@ pub async fn handle_control(
                             .await
                             .ok_or_else(|| anyhow::anyhow!("could not accept sosis session"))?;
                         let ctx = ctx.clone();
-                        smolscale::spawn(session::handle_session(ctx.new_sess(sess))).detach();
+                        smolscale::spawn(session::handle_session(ctx.new_sess(&identity, sess)))
+                            .detach();
                     }
                 }
                 .or(async move { Ok(recv.recv().await?) }),
@@ -123,9 +127,9 @@ pub async fn handle_control(
         let to_sign =
             bincode::serialize(&(sosistab_pk, their_addr, their_group.clone(), route_unixtime))
                 .unwrap();
-        let exit_signature = ctx.signing_sk.sign(&to_sign);
+        let exit_signature = identity.signing_sk.sign(&to_sign);
         let binder_client = ctx.binder_client.clone();
-        let exit_hostname = ctx.exit_hostname.to_string();
+        let exit_hostname = identity.hostname.clone();
         while let Err(err) = binder_client
             .request(BinderRequestData::AddBridgeRoute {
                 sosistab_pubkey: *sosistab_pk,
@@ -0,0 +1,60 @@
+use std::{
+    num::NonZeroU32,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use governor::{
+    clock::DefaultClock,
+    state::{InMemoryState, NotKeyed},
+    Quota, RateLimiter,
+};
+use once_cell::sync::Lazy;
+use smol::prelude::*;
+use smol_timeout::TimeoutExt;
+
+/// The reserved connection label that reaches the built-in echo service rather than an upstream host.
+pub const ECHO_LABEL: &str = "echo";
+
+/// Maximum number of bytes echoed back on a single connection. This is enough for a benchmark to stream over one connection, rather than opening connections fast enough to run into [ECHO_LIMITER]. Echoed bytes count against the session's rate limit like any other traffic.
+const ECHO_MAX_BYTES: usize = 256 * 1024 * 1024;
+
+/// Echo connections are closed after being idle for this long.
+const ECHO_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Global limit on how many echo connections we accept, so that the echo service can't be used to burn exit CPU.
+static ECHO_LIMITER: Lazy<RateLimiter<NotKeyed, InMemoryState, DefaultClock>> = Lazy::new(|| {
+    RateLimiter::direct(
+        Quota::per_second(NonZeroU32::new(100u32).unwrap())
+            .allow_burst(NonZeroU32::new(200u32).unwrap()),
+    )
+});
+
+/// Handles a connection to the echo service. The exit first sends its current time as a big-endian u64 of milliseconds since the UNIX epoch, then echoes back everything it receives, up to a limit.
+pub async fn handle_echo(mut client: sosistab::mux::RelConn) -> anyhow::Result<()> {
+    if ECHO_LIMITER.check().is_err() {
+        anyhow::bail!("echo service rate limited")
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    client.write_all(&now.to_be_bytes()).await?;
+    client.flush().await?;
+    let mut buf = [0u8; 16384];
+    let mut total = 0;
+    while total < ECHO_MAX_BYTES {
+        let n = client
+            .read(&mut buf)
+            .timeout(ECHO_IDLE_TIMEOUT)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("echo connection idle"))??;
+        if n == 0 {
+            break;
+        }
+        let n = n.min(ECHO_MAX_BYTES - total);
+        client.write_all(&buf[..n]).await?;
+        client.flush().await?;
+        total += n;
+    }
+    Ok(())
+}
@@ -0,0 +1,271 @@
+use super::RootCtx;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+};
+
+/// Most sessions returned by a single `/sessions` request.
+const MAX_SESSIONS_PAGE: usize = 1000;
+
+/// Paths anyone may request, unless the health server is told to require the admin token everywhere. Every other path, including ones that don't exist, needs the token, so that new endpoints are protected by default.
+const PUBLIC_PATHS: &[&str] = &["/health"];
+
+/// Who may use the health server.
+pub struct AdminAuth {
+    /// bearer token for the protected paths, which are disabled without one
+    token: Option<String>,
+    /// whether the public paths need the token too, for when even load figures shouldn't leak
+    protect_all: bool,
+}
+
+impl AdminAuth {
+    pub fn new(token: Option<String>, protect_all: bool) -> anyhow::Result<Self> {
+        if protect_all && token.is_none() {
+            anyhow::bail!("the health server can't require an admin token without one")
+        }
+        Ok(AdminAuth { token, protect_all })
+    }
+
+    /// Checks a request, returning the status to reject it with, if it's rejected.
+    fn check(&self, req: &http_types::Request) -> Result<(), http_types::StatusCode> {
+        if !self.protect_all && PUBLIC_PATHS.contains(&req.url().path()) {
+            return Ok(());
+        }
+        let token = self
+            .token
+            .as_ref()
+            .ok_or(http_types::StatusCode::Forbidden)?;
+        let presented = req
+            .header("Authorization")
+            .and_then(|header| header.as_str().strip_prefix("Bearer "))
+            .ok_or(http_types::StatusCode::Unauthorized)?;
+        if constant_time_eq(presented.as_bytes(), token.as_bytes()) {
+            Ok(())
+        } else {
+            Err(http_types::StatusCode::Unauthorized)
+        }
+    }
+}
+
+/// Compares without giving away, through timing, how much of a guessed token is right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Serialize)]
+struct HealthResp {
+    session_count: usize,
+    raw_session_count: usize,
+    conn_count: usize,
+    control_count: usize,
+    /// handshakes waiting for a worker
+    handshake_queue: usize,
+    /// handshakes dropped because the queue was full, since the exit started
+    handshakes_shed: u64,
+    /// usage of the sessions of each user level
+    tiers: BTreeMap<String, TierUsage>,
+    /// fraction of the bytes we sent over padded TCP connections that were padding
+    tcp_padding_overhead: f64,
+    /// lookups of the destinations clients connect to
+    resolver: crate::resolver::ResolverStats,
+    /// hostnames of the exits the binder asked to drain
+    draining: Vec<String>,
+}
+
+#[derive(Serialize, Default)]
+struct TierUsage {
+    sessions: usize,
+    conn_count: usize,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+#[derive(Serialize)]
+struct SessionRow {
+    id: u64,
+    remote_addrs: Vec<SocketAddr>,
+    version: Option<u64>,
+    bytes_in: Option<u64>,
+    bytes_out: Option<u64>,
+    conn_count: usize,
+    /// connections that have closed, and how many retransmits they needed in all
+    conns_closed: u64,
+    conn_retransmits: u64,
+    is_plus: bool,
+    age_secs: f64,
+}
+
+#[derive(Serialize)]
+struct SessionsResp {
+    total: usize,
+    offset: usize,
+    sessions: Vec<SessionRow>,
+}
+
+/// Serves the health server, which exposes the state of the exit to operators.
+pub async fn serve(ctx: Arc<RootCtx>, listen: SocketAddr, auth: AdminAuth) -> anyhow::Result<()> {
+    let listener = smol::net::TcpListener::bind(listen).await?;
+    let auth = Arc::new(auth);
+    log::info!("health server listening on {}", listen);
+    loop {
+        let (client, _) = listener.accept().await?;
+        let ctx = ctx.clone();
+        let auth = auth.clone();
+        smolscale::spawn(async move {
+            drop(async_h1::accept(client, |req| handle_health(ctx.clone(), &auth, req)).await)
+        })
+        .detach();
+    }
+}
+
+async fn handle_health(
+    ctx: Arc<RootCtx>,
+    auth: &AdminAuth,
+    req: http_types::Request,
+) -> http_types::Result<http_types::Response> {
+    if let Err(status) = auth.check(&req) {
+        let mut res = http_types::Response::new(status);
+        if status == http_types::StatusCode::Unauthorized {
+            res.insert_header("WWW-Authenticate", "Bearer realm=\"geph4-exit\"");
+        }
+        return Ok(res);
+    }
+    let mut res = http_types::Response::new(http_types::StatusCode::Ok);
+    match req.url().path() {
+        "/health" => {
+            let resp = HealthResp {
+                session_count: ctx.session_count(),
+                raw_session_count: ctx.raw_session_count.load(Ordering::Relaxed),
+                conn_count: ctx.conn_count.load(Ordering::Relaxed),
+                control_count: ctx.control_count.load(Ordering::Relaxed),
+                handshake_queue: sosistab::handshake_queue_depth(),
+                handshakes_shed: sosistab::handshakes_shed(),
+                tiers: tier_usage(&ctx),
+                tcp_padding_overhead: sosistab::tcp_padding_overhead(),
+                resolver: crate::resolver::stats(),
+                draining: ctx
+                    .identities
+                    .iter()
+                    .filter(|id| id.is_draining())
+                    .map(|id| id.hostname().to_string())
+                    .collect(),
+            };
+            res.set_body(serde_json::to_string(&resp)?);
+            res.insert_header("Content-Type", "application/json");
+        }
+        "/sessions" => {
+            let mut offset: usize = 0;
+            let mut limit: usize = 100;
+            for (k, v) in req.url().query_pairs() {
+                match k.as_ref() {
+                    "offset" => offset = v.parse()?,
+                    "limit" => limit = v.parse()?,
+                    _ => {}
+                }
+            }
+            let mut ids: Vec<u64> = ctx.sessions.iter().map(|e| *e.key()).collect();
+            ids.sort_unstable();
+            let total = ids.len();
+            let sessions = ids
+                .into_iter()
+                .skip(offset)
+                .take(limit.min(MAX_SESSIONS_PAGE))
+                .filter_map(|id| {
+                    let entry = ctx.sessions.get(&id)?.clone();
+                    let info = entry.mux.get_session().info();
+                    Some(SessionRow {
+                        id,
+                        remote_addrs: info
+                            .as_ref()
+                            .map(|i| i.remote_addrs.clone())
+                            .unwrap_or_default(),
+                        version: info.as_ref().map(|i| i.version),
+                        bytes_in: info.as_ref().map(|i| i.bytes_in),
+                        bytes_out: info.as_ref().map(|i| i.bytes_out),
+                        conn_count: entry.conn_count.load(Ordering::Relaxed),
+                        conns_closed: entry.conns_closed.load(Ordering::Relaxed),
+                        conn_retransmits: entry.conn_retransmits.load(Ordering::Relaxed),
+                        is_plus: entry.is_plus,
+                        age_secs: entry.start.elapsed().as_secs_f64(),
+                    })
+                })
+                .collect();
+            let resp = SessionsResp {
+                total,
+                offset,
+                sessions,
+            };
+            res.set_body(serde_json::to_string(&resp)?);
+            res.insert_header("Content-Type", "application/json");
+        }
+        _ => res.set_status(http_types::StatusCode::NotFound),
+    }
+    Ok(res)
+}
+
+fn tier_usage(ctx: &RootCtx) -> BTreeMap<String, TierUsage> {
+    let mut tiers: BTreeMap<String, TierUsage> = BTreeMap::new();
+    for entry in ctx.sessions.iter() {
+        let usage = tiers.entry(entry.level.clone()).or_default();
+        usage.sessions += 1;
+        usage.conn_count += entry.conn_count.load(Ordering::Relaxed);
+        if let Some(info) = entry.mux.get_session().info() {
+            usage.bytes_in += info.bytes_in;
+            usage.bytes_out += info.bytes_out;
+        }
+    }
+    tiers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_types::{Method, Request, StatusCode};
+
+    fn get(path: &str, token: Option<&str>) -> Request {
+        let mut req = Request::new(
+            Method::Get,
+            http_types::Url::parse("http://localhost")
+                .unwrap()
+                .join(path)
+                .unwrap(),
+        );
+        if let Some(token) = token {
+            req.insert_header("Authorization", format!("Bearer {}", token));
+        }
+        req
+    }
+
+    #[test]
+    fn admin_paths_need_token() {
+        let auth = AdminAuth::new(Some("secret".into()), false).unwrap();
+        assert_eq!(auth.check(&get("/health", None)), Ok(()));
+        assert_eq!(
+            auth.check(&get("/sessions", None)),
+            Err(StatusCode::Unauthorized)
+        );
+        assert_eq!(
+            auth.check(&get("/sessions", Some("secre"))),
+            Err(StatusCode::Unauthorized)
+        );
+        assert_eq!(auth.check(&get("/sessions", Some("secret"))), Ok(()));
+        assert_eq!(
+            auth.check(&get("/nonexistent", None)),
+            Err(StatusCode::Unauthorized)
+        );
+
+        let strict = AdminAuth::new(Some("secret".into()), true).unwrap();
+        assert_eq!(
+            strict.check(&get("/health", None)),
+            Err(StatusCode::Unauthorized)
+        );
+        let none = AdminAuth::new(None, false).unwrap();
+        assert_eq!(
+            none.check(&get("/sessions", Some("secret"))),
+            Err(StatusCode::Forbidden)
+        );
+        assert!(AdminAuth::new(None, true).is_err());
+    }
+}
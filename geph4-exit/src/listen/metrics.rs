@@ -0,0 +1,206 @@
+//! Pushes the exit's metrics to statsd. Every metric name ends with the main exit's hostname, dots replaced with dashes, written `{host}` below. These names are stable, since dashboards are built on them:
+//!
+//! - gauges
+//!   - `session_count.{host}`: sessions with recent activity, pushed once for every exit this process serves, under its own hostname
+//!   - `raw_session_count.{host}`: all live sessions, authenticated or not
+//!   - `tier_sessions.{host}.{tier}`: authenticated sessions of each tier, such as `free` or `plus`
+//!   - `conn_count.{host}`: open proxied connections
+//!   - `control_count.{host}`: open bridge control connections
+//!   - `bytes_allocated.{host}`: resident memory, only where the exit uses jemalloc
+//!   - `task_count.{host}`: running tasks
+//!   - `window_blocked.{host}`: connections waiting for their peer's receive window
+//!   - `decode_queue.{host}`: packets outside any known session waiting to be decrypted as handshakes
+//! - counts, each covering the time since the last push
+//!   - `handshakes.{host}`: sosistab handshakes answered
+//!   - `handshake_failures.{host}`: handshakes that could be decrypted but not answered, such as ones with an unknown version
+//!   - `handshakes_shed.{host}`: handshakes dropped because the handshake queue was full
+//!   - `decode_shed.{host}`: packets outside any known session dropped undecrypted because the decoding workers were behind
+//!   - `replay_drops.{host}`: handshake packets dropped as replays
+//!   - `undersized_datagrams.{host}` and `oversized_datagrams.{host}`: datagrams dropped unread for being too short to be anything, or so long they were probably truncated. Many oversized ones point to path MTU trouble.
+//!   - `tier_bytes_up.{host}.{tier}` and `tier_bytes_down.{host}.{tier}`: bytes that sessions of each tier sent and received
+//!   - `sessions_closed.{host}.{reason}`: sessions that closed, with `timed_out` for ones whose client went silent and `dropped` for ones the exit ended, such as after failed authentication
+//!   - `conn_port.{host}.{port}`: connections proxied to each well-known port, with the rest counted under `other`
+//!   - `exit_usage.{host}`: a sampled estimate of proxied bytes
+
+use std::{
+    collections::HashMap,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use sosistab::{SessionCloseReason, SessionEvent};
+
+use super::RootCtx;
+
+/// The stable key under which a metric is reported for an exit.
+pub fn key(name: &str, exit_hostname: &str) -> String {
+    format!("{}.{}", name, exit_hostname.replace(".", "-"))
+}
+
+/// Counts one proxied connection towards its destination port.
+pub fn count_port(stat_client: &statsd::Client, exit_hostname: &str, port: u16) {
+    let port = if port < 1024 || crate::lists::WHITE_PORTS.contains(&port) {
+        port.to_string()
+    } else {
+        "other".to_string()
+    };
+    stat_client.incr(&format!("{}.{}", key("conn_port", exit_hostname), port));
+}
+
+/// Counts the sessions of a listener as they close, forever. Only fails if the listener is gone.
+pub async fn watch_sessions(
+    stat_client: Arc<statsd::Client>,
+    exit_hostname: String,
+    events: smol::channel::Receiver<SessionEvent>,
+) -> anyhow::Result<()> {
+    loop {
+        match events.recv().await? {
+            SessionEvent::SessionOpened { id, addr } => {
+                log::trace!("session {} opened from {}", id, addr)
+            }
+            SessionEvent::SessionClosed { id, reason } => {
+                log::debug!("session {} closed ({:?})", id, reason);
+                let reason = match reason {
+                    SessionCloseReason::TimedOut => "timed_out",
+                    SessionCloseReason::Dropped => "dropped",
+                    SessionCloseReason::SaidGoodbye => "said_goodbye",
+                };
+                stat_client.incr(&format!(
+                    "{}.{}",
+                    key("sessions_closed", &exit_hostname),
+                    reason
+                ));
+            }
+            SessionEvent::Lagged { missed } => {
+                log::warn!("missed {} session events", missed)
+            }
+        }
+    }
+}
+
+/// Remembers the last value of a monotonic counter, so that only what's new gets pushed.
+#[derive(Default)]
+struct Delta(u64);
+
+impl Delta {
+    fn next(&mut self, total: u64) -> f64 {
+        let delta = total.saturating_sub(self.0);
+        self.0 = total;
+        delta as f64
+    }
+}
+
+/// How much memory the allocator holds. Only jemalloc can tell.
+#[cfg(not(target_env = "musl"))]
+fn resident_memory() -> anyhow::Result<Option<usize>> {
+    jemalloc_ctl::epoch::advance()?;
+    Ok(Some(jemalloc_ctl::stats::resident::read()?))
+}
+
+#[cfg(target_env = "musl")]
+fn resident_memory() -> anyhow::Result<Option<usize>> {
+    Ok(None)
+}
+
+/// Pushes every metric once per interval, forever.
+pub async fn push_loop(ctx: Arc<RootCtx>, interval: Duration) -> anyhow::Result<()> {
+    let stat_client = ctx.stat_client.clone();
+    let exit_hostname = ctx.main_identity().hostname.clone();
+    let mut handshakes = Delta::default();
+    let mut handshake_failures = Delta::default();
+    let mut handshakes_shed = Delta::default();
+    let mut decode_shed = Delta::default();
+    let mut replay_drops = Delta::default();
+    let mut undersized = Delta::default();
+    let mut oversized = Delta::default();
+    // byte totals of every live session, as of the last push
+    let mut session_bytes: HashMap<u64, (u64, u64)> = HashMap::new();
+    // the first push only learns the counters' current values, rather than reporting everything since startup
+    let mut first = true;
+    loop {
+        for identity in ctx.identities.iter() {
+            let session_count = identity.session_count.load(Ordering::Relaxed);
+            stat_client.gauge(
+                &key("session_count", &identity.hostname),
+                session_count as f64,
+            );
+        }
+        let gauge = |name: &str, value: f64| stat_client.gauge(&key(name, &exit_hostname), value);
+        let count = |name: &str, value: f64| {
+            if !first && value > 0.0 {
+                stat_client.count(&key(name, &exit_hostname), value)
+            }
+        };
+        gauge(
+            "raw_session_count",
+            ctx.raw_session_count.load(Ordering::Relaxed) as f64,
+        );
+        if let Some(resident) = resident_memory()? {
+            gauge("bytes_allocated", resident as f64);
+        }
+        gauge("conn_count", ctx.conn_count.load(Ordering::Relaxed) as f64);
+        gauge(
+            "control_count",
+            ctx.control_count.load(Ordering::Relaxed) as f64,
+        );
+        gauge("task_count", smolscale::active_task_count() as f64);
+        gauge(
+            "window_blocked",
+            sosistab::mux::window_blocked_count() as f64,
+        );
+        gauge("decode_queue", sosistab::decode_queue_depth() as f64);
+
+        count(
+            "handshakes",
+            handshakes.next(sosistab::handshakes_answered()),
+        );
+        count(
+            "handshake_failures",
+            handshake_failures.next(sosistab::handshake_failures()),
+        );
+        count(
+            "handshakes_shed",
+            handshakes_shed.next(sosistab::handshakes_shed()),
+        );
+        count("decode_shed", decode_shed.next(sosistab::decode_shed()));
+        count(
+            "replay_drops",
+            replay_drops.next(sosistab::replays_dropped()),
+        );
+        count(
+            "undersized_datagrams",
+            undersized.next(sosistab::datagrams_undersized()),
+        );
+        count(
+            "oversized_datagrams",
+            oversized.next(sosistab::datagrams_oversized()),
+        );
+
+        // per-tier sessions and traffic. sessions that ended since the last push take their last few seconds of traffic with them.
+        let mut tiers: HashMap<String, (usize, u64, u64)> = HashMap::new();
+        let mut new_session_bytes = HashMap::with_capacity(session_bytes.len());
+        for entry in ctx.sessions.iter() {
+            let tier = tiers.entry(entry.level.clone()).or_default();
+            tier.0 += 1;
+            if let Some(info) = entry.mux.get_session().info() {
+                let (last_in, last_out) =
+                    session_bytes.get(entry.key()).copied().unwrap_or_default();
+                tier.1 += info.bytes_in.saturating_sub(last_in);
+                tier.2 += info.bytes_out.saturating_sub(last_out);
+                new_session_bytes.insert(*entry.key(), (info.bytes_in, info.bytes_out));
+            }
+        }
+        session_bytes = new_session_bytes;
+        for (tier, (sessions, bytes_up, bytes_down)) in tiers {
+            let tier_key =
+                |name: &str| format!("{}.{}", key(name, &exit_hostname), tier.replace(".", "-"));
+            stat_client.gauge(&tier_key("tier_sessions"), sessions as f64);
+            if !first {
+                stat_client.count(&tier_key("tier_bytes_up"), bytes_up as f64);
+                stat_client.count(&tier_key("tier_bytes_down"), bytes_down as f64);
+            }
+        }
+        first = false;
+        smol::Timer::after(interval).await;
+    }
+}
@@ -1,20 +1,36 @@
 use std::{
-    net::SocketAddr,
-    sync::atomic::{AtomicBool, Ordering},
-    time::{Duration, SystemTime},
+    net::IpAddr,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant, SystemTime},
 };
 
-use super::SessCtx;
+use super::{Policy, SessCtx, SessionEntry};
+use crate::audit::SessionAudit;
 use crate::vpn::handle_vpn_session;
-use binder_transport::{BinderClient, BinderRequestData, BinderResponse};
+use anyhow::Context;
+use binder_transport::{
+    BinderClient, BinderRequestData, BinderResponse, ConnIntent, ConnRejection, ExitFeatures,
+};
+use sosistab::mux::CloseReason;
 
 use smol::prelude::*;
 use smol_timeout::TimeoutExt;
 
 use std::sync::Arc;
 
+/// Features this exit advertises to clients.
+const SUPPORTED_FEATURES: ExitFeatures = ExitFeatures::ADDR_PREFERENCE
+    .union(ExitFeatures::ECHO)
+    .union(ExitFeatures::CONN_INTENT)
+    .union(ExitFeatures::PADDING)
+    .union(ExitFeatures::DRAIN_NOTICE);
+
 pub async fn handle_session(ctx: SessCtx) -> anyhow::Result<()> {
-    let SessCtx { root, sess } = ctx;
+    let SessCtx {
+        root,
+        identity,
+        sess,
+    } = ctx;
 
     // raw session count
     root.raw_session_count
@@ -24,28 +40,86 @@ pub async fn handle_session(ctx: SessCtx) -> anyhow::Result<()> {
             .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
     });
 
-    let sess = Arc::new(sosistab::mux::Multiplex::new(sess));
-    let is_plus = authenticate_sess(root.binder_client.clone(), &sess)
-        .timeout(Duration::from_secs(300))
-        .await
-        .ok_or_else(|| anyhow::anyhow!("authentication timeout"))??;
-    log::info!("authenticated a new session (is_plus = {})", is_plus);
-    if !is_plus {
-        if root.free_limit == 0 {
-            anyhow::bail!("not accepting free users here")
-        }
-        sess.get_session().set_ratelimit(root.free_limit);
+    // clients log the same id, so that their logs can be matched up with ours
+    let sess_id = sess.id().to_string();
+    let sess = Arc::new(sosistab::mux::Multiplex::with_rebind_table(
+        sess,
+        &root.rebind_table,
+    ));
+    let features = if root.socks_bind {
+        SUPPORTED_FEATURES.union(ExitFeatures::SOCKS_BIND)
+    } else {
+        SUPPORTED_FEATURES
+    };
+    let (level, mut auth_conn) = authenticate_sess(
+        root.binder_client.clone(),
+        &sess,
+        features,
+        identity.is_draining(),
+    )
+    .timeout(Duration::from_secs(300))
+    .await
+    .ok_or_else(|| anyhow::anyhow!("session {} authentication timeout", sess_id))?
+    .with_context(|| format!("session {} failed to authenticate", sess_id))?;
+    let is_plus = level != "free";
+    log::info!(
+        "authenticated a new session {} (level = {})",
+        sess_id,
+        level
+    );
+    let _end_guard = scopeguard::guard((), |_| log::debug!("session {} ended", sess_id));
+    if !is_plus && root.policy().free_limit == 0 {
+        anyhow::bail!("not accepting free users here")
     }
+    // once the exit starts draining, tell the client to move elsewhere, and close the session some time within the grace period, for clients too old to listen. spreading the closes out keeps clients from all reconnecting at once.
+    let drain_loop = {
+        let identity = identity.clone();
+        let drain_grace = root.drain_grace;
+        let sess_id = sess_id.clone();
+        async move {
+            identity.wait_draining().await;
+            drop(
+                aioutils::write_pascalish(&mut auth_conn, &binder_transport::SESSION_DRAINING)
+                    .await,
+            );
+            smol::Timer::after(drain_grace.mul_f64(fastrand::f64())).await;
+            Err::<(), _>(anyhow::anyhow!("closing session {} to drain", sess_id))
+        }
+    };
+    let audit = root.audit_log.as_ref().map(|log| {
+        log.for_session(&sess_id, || {
+            sess.get_session()
+                .info()
+                .map(|info| info.remote_addrs)
+                .unwrap_or_default()
+        })
+    });
+
+    // register the session for the health server, and share its level's speed limit with it
+    let sess_id: u64 = rand::random();
+    let entry = Arc::new(SessionEntry {
+        mux: sess.clone(),
+        is_plus,
+        level,
+        conn_count: AtomicUsize::new(0),
+        start: Instant::now(),
+        conns_closed: AtomicU64::new(0),
+        conn_retransmits: AtomicU64::new(0),
+        flows: Default::default(),
+    });
+    root.add_session(sess_id, entry.clone());
+    let _sess_guard = scopeguard::guard((), |_| root.remove_session(sess_id));
 
     let (send_sess_alive, recv_sess_alive) = smol::channel::bounded(1);
     let sess_alive_loop = {
         let recv_sess_alive = recv_sess_alive.clone();
-        let root = root.clone();
+        let identity = identity.clone();
         smolscale::spawn(async move {
             let alive = AtomicBool::new(false);
             let guard = scopeguard::guard(alive, |v| {
                 if v.load(Ordering::SeqCst) {
-                    root.session_count
+                    identity
+                        .session_count
                         .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
                 }
             });
@@ -57,11 +131,13 @@ pub async fn handle_session(ctx: SessCtx) -> anyhow::Result<()> {
                 if let Some(sig) = signal {
                     let _ = sig?;
                     if !guard.swap(true, Ordering::SeqCst) {
-                        root.session_count
+                        identity
+                            .session_count
                             .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
                     }
                 } else if guard.swap(false, Ordering::SeqCst) {
-                    root.session_count
+                    identity
+                        .session_count
                         .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
                 }
             }
@@ -71,28 +147,45 @@ pub async fn handle_session(ctx: SessCtx) -> anyhow::Result<()> {
     let proxy_loop = {
         let root = root.clone();
         let sess = sess.clone();
+        let exit_hostname = identity.hostname.clone();
         smolscale::spawn(async move {
             loop {
                 let stream = sess.accept_conn().await?;
                 let ctx = root.clone();
+                let exit_hostname = exit_hostname.clone();
+                let entry = entry.clone();
                 let send_sess_alive = send_sess_alive.clone();
+                let audit = audit.clone();
                 let conn_task = smolscale::spawn(async move {
                     ctx.conn_count
                         .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    entry
+                        .conn_count
+                        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
                     let _deferred = scopeguard::guard((), |_| {
                         ctx.conn_count
                             .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+                        entry
+                            .conn_count
+                            .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
                     });
                     let _ = send_sess_alive.try_send(());
-                    handle_proxy_stream(
+                    let conn = stream.clone();
+                    let res = handle_proxy_stream(
                         ctx.stat_client.clone(),
-                        ctx.exit_hostname.clone(),
-                        ctx.port_whitelist,
+                        &entry,
+                        exit_hostname,
+                        ctx.socks_bind,
                         stream,
-                        ctx.google_proxy,
+                        &ctx.policy(),
+                        &ctx.own_ips,
+                        audit,
                     )
-                    .await
-                    .ok()
+                    .await;
+                    let conn_stats = conn.stats();
+                    log::trace!("conn done: {:?}", conn_stats);
+                    entry.add_closed_conn(&conn_stats);
+                    res.ok()
                 });
                 conn_task.detach();
                 // root.conn_tasks.lock().cache_set(rand::random(), conn_task);
@@ -101,26 +194,35 @@ pub async fn handle_session(ctx: SessCtx) -> anyhow::Result<()> {
     };
     let vpn_loop = smolscale::spawn(handle_vpn_session(
         sess.clone(),
-        root.exit_hostname.clone(),
+        identity.hostname.clone(),
         root.stat_client.clone(),
-        root.port_whitelist,
+        root.clone(),
     ));
-    smol::future::race(proxy_loop.or(sess_alive_loop), vpn_loop).await
+    smol::future::race(proxy_loop.or(sess_alive_loop), vpn_loop)
+        .or(drain_loop)
+        .await
 }
 
+/// Authenticates a session, returning its user level along with the connection it authenticated over, which is kept open to send a drain notice over. Draining exits turn sessions away here.
 async fn authenticate_sess(
     binder_client: Arc<dyn BinderClient>,
     sess: &sosistab::mux::Multiplex,
-) -> anyhow::Result<bool> {
+    features: ExitFeatures,
+    draining: bool,
+) -> anyhow::Result<(String, sosistab::mux::RelConn)> {
     let mut stream = sess.accept_conn().await?;
     log::debug!("authenticating session...");
     // wait for a message containing a blinded signature
     let (auth_tok, auth_sig, level): (Vec<u8>, mizaru::UnblindedSignature, String) =
         aioutils::read_pascalish(&mut stream).await?;
+    if draining {
+        aioutils::write_pascalish(&mut stream, &(binder_transport::SESSION_DRAINING, features))
+            .await?;
+        anyhow::bail!("draining, so not accepting new sessions")
+    }
     if (auth_sig.epoch as i32 - mizaru::time_to_epoch(SystemTime::now()) as i32).abs() > 2 {
         anyhow::bail!("outdated authentication token")
     }
-    let is_plus = level != "free";
     // validate it through the binder
     let res = binder_client
         .request(BinderRequestData::Validate {
@@ -132,49 +234,90 @@ async fn authenticate_sess(
     if res != BinderResponse::ValidateResp(true) {
         anyhow::bail!("unexpected authentication response from binder: {:?}", res)
     }
-    // send response
-    aioutils::write_pascalish(&mut stream, &1u8).await?;
-    Ok(is_plus)
+    // send response, along with our features. old clients only read the first byte.
+    aioutils::write_pascalish(&mut stream, &(1u8, features)).await?;
+    Ok((level, stream))
 }
 
+#[allow(clippy::clippy::too_many_arguments)]
 async fn handle_proxy_stream(
     stat_client: Arc<statsd::Client>,
+    entry: &SessionEntry,
     exit_hostname: String,
-    port_whitelist: bool,
+    socks_bind: bool,
     mut client: sosistab::mux::RelConn,
-    google_proxy: Option<SocketAddr>,
+    policy: &Policy,
+    own_ips: &[IpAddr],
+    audit: Option<SessionAudit>,
 ) -> anyhow::Result<()> {
     // read proxy request
-    let to_prox: String = match client.additional_info() {
+    let label: String = match client.additional_info() {
         Some(s) => s.to_string(),
         None => aioutils::read_pascalish(&mut client).await?,
     };
-    let addr = aioutils::resolve(&to_prox)
-        .await?
-        .first()
-        .cloned()
-        .ok_or_else(|| anyhow::anyhow!("dns failed"))?;
-    let asn = crate::asn::get_asn(addr.ip());
-    // log::debug!("proxying {} ({}, AS{})", to_prox, addr, asn);
-
-    if crate::lists::BLACK_PORTS.contains(&addr.port()) {
-        anyhow::bail!("port blacklisted")
+    // newer clients say what they want in an intent, and get told whether we accept it before we dial
+    let intent: Option<ConnIntent> = if label == binder_transport::CONN_INTENT_LABEL {
+        Some(aioutils::read_pascalish(&mut client).await?)
+    } else {
+        None
+    };
+    // clients may ask for an address family, for names that have both
+    let (to_prox, preference) = match &intent {
+        Some(intent) => (intent.destination.as_str(), intent.preference),
+        None => aioutils::AddrPreference::untag(&label),
+    };
+    if intent.is_none() && to_prox == super::echo::ECHO_LABEL {
+        return super::echo::handle_echo(client).await;
     }
-    if port_whitelist && !crate::lists::WHITE_PORTS.contains(&addr.port()) {
-        anyhow::bail!("port not whitelisted")
+    if let Some(audit) = &audit {
+        audit.record(to_prox);
     }
-
-    // what should we connect to depends on whether or not it's google
-    let to_conn = if let Some(proxy) = google_proxy {
-        if addr.port() == 443 && asn == crate::asn::GOOGLE_ASN {
-            proxy
-        } else {
-            addr
+    if let (None, Some(expected_peer)) = (&intent, to_prox.strip_prefix(super::bind::BIND_PREFIX)) {
+        if !socks_bind {
+            client.close_with(CloseReason::Refused);
+            anyhow::bail!("binds not allowed")
         }
+        return super::bind::handle_bind(
+            client,
+            expected_peer,
+            &exit_hostname,
+            policy.port_whitelist,
+        )
+        .await;
+    }
+    let addr = match crate::resolver::resolve_preferring(to_prox, preference)
+        .await
+        .ok()
+        .and_then(|addrs| addrs.first().cloned())
+    {
+        Some(addr) => addr,
+        None => return Err(refuse(&mut client, &intent, ConnRejection::NameNotResolved).await),
+    };
+    // log::debug!("proxying {} ({})", to_prox, addr);
+
+    if crate::lists::BLACK_PORTS.contains(&addr.port())
+        || (policy.port_whitelist && !crate::lists::WHITE_PORTS.contains(&addr.port()))
+    {
+        return Err(refuse(&mut client, &intent, ConnRejection::PortNotAllowed).await);
+    }
+    // this is fine because just connecting to a local service is not a security problem
+    if to_prox != "127.0.0.1:3128" && !destination_allowed(addr.ip(), policy, own_ips) {
+        return Err(refuse(&mut client, &intent, ConnRejection::AddressNotAllowed).await);
+    }
+    if intent.is_some() {
+        aioutils::write_pascalish(&mut client, &Ok::<(), ConnRejection>(())).await?;
+    }
+    super::metrics::count_port(&stat_client, &exit_hostname, addr.port());
+
+    // what should we connect to depends on the redirect rules, which might need the SNI
+    let (prefix, sni) = if policy.redirects.needs_sni(addr.port()) {
+        crate::redirect::peek_sni(&mut client).await
     } else {
-        addr
+        (Vec::new(), None)
     };
-    let remote = smol::net::TcpStream::connect(&to_conn)
+    let host = to_prox.rsplitn(2, ':').nth(1);
+    let to_conn = policy.redirects.lookup(host, sni.as_deref(), addr);
+    let remote = crate::outbound::connect(to_conn)
         .or(async {
             smol::Timer::after(Duration::from_secs(60)).await;
             Err(std::io::Error::new(
@@ -182,17 +325,24 @@ async fn handle_proxy_stream(
                 "timed out remote",
             ))
         })
-        .await?;
-    // this is fine because just connecting to a local service is not a security problem
-    if &to_prox != "127.0.0.1:3128" && (addr.ip().is_loopback() || addr.ip().is_multicast()) {
-        anyhow::bail!("attempted a connection to a non-global IP address")
-    }
-
+        .await;
+    let mut remote = match remote {
+        Ok(remote) => remote,
+        Err(err) => {
+            client.close_with(if err.kind() == std::io::ErrorKind::TimedOut {
+                CloseReason::Timeout
+            } else {
+                CloseReason::Unreachable
+            });
+            return Err(err.into());
+        }
+    };
     remote.set_nodelay(true)?;
-    let key = format!("exit_usage.{}", exit_hostname.replace(".", "-"));
+    remote.write_all(&prefix).await?;
+    let key = super::metrics::key("exit_usage", &exit_hostname);
     // copy the streams
     smol::future::race(
-        aioutils::copy_with_stats(remote.clone(), client.clone(), |n| {
+        copy_paced(remote.clone(), client.clone(), entry, |n| {
             if fastrand::f32() < 0.05 {
                 stat_client.count(&key, n as f64 * 20.0)
             }
@@ -206,3 +356,85 @@ async fn handle_proxy_stream(
     .await?;
     Ok(())
 }
+
+/// Copies from the destination to the client like [aioutils::copy_with_stats], but while the session is throttled, keeps the connection to its share of the session's limit, so that a bulk download leaves room for the session's other connections.
+async fn copy_paced(
+    mut reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+    entry: &SessionEntry,
+    mut on_write: impl FnMut(usize),
+) -> std::io::Result<()> {
+    let mut buffer = vec![0u8; 16384];
+    let mut flag = entry.flows.flag();
+    let mut next_write = Instant::now();
+    loop {
+        // reads are cancel-safe, so nothing is lost by giving up on one to mark the connection idle
+        let n = match reader
+            .read(&mut buffer)
+            .timeout(crate::qos::BUSY_WINDOW)
+            .await
+        {
+            Some(res) => res?,
+            None => {
+                flag.set(false);
+                reader.read(&mut buffer).await?
+            }
+        };
+        if n == 0 {
+            return Ok(());
+        }
+        flag.set(true);
+        let now = Instant::now();
+        let limit = entry.mux.get_session().ratelimit();
+        if let Some(share) = crate::qos::conn_share(limit, entry.flows.busy()) {
+            let write_at = next_write.max(now);
+            if write_at > now {
+                smol::Timer::at(write_at).await;
+            }
+            next_write = write_at + Duration::from_secs_f64(n as f64 / share as f64);
+        } else {
+            next_write = now;
+        }
+        on_write(n);
+        writer.write_all(&buffer[..n]).await?;
+    }
+}
+
+/// Whether clients may connect to the given address. The exit host itself is always off limits, and so is the rest of its network unless the policy allows internal destinations.
+fn destination_allowed(ip: IpAddr, policy: &Policy, own_ips: &[IpAddr]) -> bool {
+    if ip.is_multicast() || ip.is_loopback() || own_ips.contains(&ip) {
+        return false;
+    }
+    match crate::lists::internal_kind(ip) {
+        Some("loopback") | Some("unspecified") => false,
+        Some(kind) => {
+            if !policy.allow_internal {
+                log::debug!("refusing connection to {} address {}", kind, ip);
+            }
+            policy.allow_internal
+        }
+        None => true,
+    }
+}
+
+/// Refuses a connection before dialing anything, telling clients that sent an intent why.
+async fn refuse(
+    client: &mut sosistab::mux::RelConn,
+    intent: &Option<ConnIntent>,
+    rejection: ConnRejection,
+) -> anyhow::Error {
+    if let Some(intent) = intent {
+        if intent.direct_fallback && rejection == ConnRejection::PortNotAllowed {
+            log::debug!(
+                "refusing {}, which the client will connect to directly",
+                intent.destination
+            );
+        }
+        let _ = aioutils::write_pascalish(client, &Err::<(), _>(rejection)).await;
+    }
+    client.close_with(match rejection {
+        ConnRejection::NameNotResolved => CloseReason::Unreachable,
+        ConnRejection::PortNotAllowed | ConnRejection::AddressNotAllowed => CloseReason::Refused,
+    });
+    anyhow::anyhow!(rejection)
+}
@@ -1,4 +1,7 @@
+use std::net::{IpAddr, Ipv4Addr};
+
 use once_cell::sync::Lazy;
+use rangemap::RangeMap;
 use rustc_hash::FxHashSet;
 
 /// List of whitelisted ports.
@@ -25,3 +28,79 @@ pub static WHITE_PORTS: Lazy<FxHashSet<u16>> = Lazy::new(|| {
 
 /// List of blacklisted ports
 pub static BLACK_PORTS: Lazy<FxHashSet<u16>> = Lazy::new(|| vec![25u16].into_iter().collect());
+
+/// IPv4 ranges that aren't on the public Internet, by what they are. Connecting to these would reach the exit's own host, its LAN, or its cloud provider's metadata service.
+static INTERNAL_V4: Lazy<RangeMap<Ipv4Addr, &'static str>> = Lazy::new(|| {
+    let ranges: [(&str, &str, &'static str); 7] = [
+        ("0.0.0.0", "0.255.255.255", "unspecified"),
+        ("10.0.0.0", "10.255.255.255", "private"),
+        ("100.64.0.0", "100.127.255.255", "carrier-grade NAT"),
+        ("127.0.0.0", "127.255.255.255", "loopback"),
+        ("169.254.0.0", "169.254.255.255", "link-local"),
+        ("172.16.0.0", "172.31.255.255", "private"),
+        ("192.168.0.0", "192.168.255.255", "private"),
+    ];
+    let mut toret = RangeMap::new();
+    for (start, end, kind) in ranges.iter() {
+        let start: Ipv4Addr = start.parse().unwrap();
+        let end = crate::asn::next_ip(end.parse().unwrap());
+        toret.insert(start..end, *kind);
+    }
+    toret
+});
+
+/// What kind of internal address this is, or None if it's on the public Internet. IPv6 addresses are checked for loopback, link-local and unique local addresses, as well as for the IPv4 addresses they embed.
+pub fn internal_kind(ip: IpAddr) -> Option<&'static str> {
+    match ip {
+        IpAddr::V4(ip) => INTERNAL_V4.get(&ip).copied(),
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4() {
+                // ::1 looks like an IPv4-compatible 0.0.0.1, but it's loopback
+                if ip.is_unspecified() || ip == Ipv4Addr::new(0, 0, 0, 1) {
+                    return Some("loopback");
+                }
+                return INTERNAL_V4.get(&ip).copied();
+            }
+            match ip.segments()[0] {
+                0xfe80..=0xfebf => Some("link-local"),
+                0xfc00..=0xfdff => Some("unique local"),
+                _ => None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internal_addresses() {
+        for internal in [
+            "127.0.0.1",
+            "169.254.169.254",
+            "10.1.2.3",
+            "172.31.255.255",
+            "192.168.1.1",
+            "::1",
+            "::ffff:10.0.0.1",
+            "fe80::1",
+            "fd00::1",
+        ]
+        .iter()
+        {
+            assert!(
+                internal_kind(internal.parse().unwrap()).is_some(),
+                "{}",
+                internal
+            );
+        }
+        for public in ["1.1.1.1", "172.32.0.1", "192.169.0.1", "2606:4700::1111"].iter() {
+            assert!(
+                internal_kind(public.parse().unwrap()).is_none(),
+                "{}",
+                public
+            );
+        }
+    }
+}
@@ -1,19 +1,24 @@
 use std::{
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     path::PathBuf,
     sync::Arc,
     time::{Duration, Instant},
 };
 
+use anyhow::Context;
 use binder_transport::{BinderClient, BinderRequestData, BinderResponse};
 use env_logger::Env;
-use jemallocator::Jemalloc;
 use std::os::unix::fs::PermissionsExt;
 use structopt::StructOpt;
 
 mod asn;
+mod audit;
 mod listen;
 mod lists;
+mod outbound;
+mod qos;
+mod redirect;
+mod resolver;
 mod vpn;
 
 #[derive(Debug, StructOpt, Clone)]
@@ -22,114 +27,518 @@ struct Opt {
     /// HTTP address of the binder
     binder_http: String,
 
+    #[structopt(long)]
+    /// SOCKS5 proxy to reach the binder through, such as when testing from an isolated network. It must not require authentication. The exit refuses to start if it's unreachable.
+    binder_socks_proxy: Option<SocketAddr>,
+
     #[structopt(long, default_value = "172.105.28.221:8125")]
     /// UDP address of the statsd daemon
     statsd_addr: SocketAddr,
 
-    #[structopt(
-        long,
-        default_value = "124526f4e692b589511369687498cce57492bf4da20f8d26019c1cc0c80b6e4b"
-    )]
-    /// x25519 master key of the binder
-    binder_master_pk: String,
+    #[structopt(long)]
+    /// x25519 master key of the binder, in hex. Defaults to the key built in at compile time, which is the public binder's unless GEPH_BINDER_MASTER_PK was set then.
+    binder_master_pk: Option<String>,
+
+    #[structopt(long)]
+    /// file containing the binder's x25519 master key in hex, as an alternative to --binder-master-pk for deployments with their own binder.
+    binder_master_pk_file: Option<PathBuf>,
 
     #[structopt(long, default_value = "/var/local/geph4-exit.key")]
     /// signing key location
     signing_sk: PathBuf,
 
-    /// bridge secret. All bridges and exits know this secret, and it's used to prevent random people from spamming the bridge table.
+    /// Encrypt the signing key on disk with a passphrase, taken from the GEPH_PASSPHRASE environment variable or asked for on startup. An existing unencrypted key is encrypted in place. This protects the key if the disk is compromised, but the exit can then only start unattended if the passphrase is in its environment, which largely defeats the point.
+    #[structopt(long)]
+    encrypt_signing_sk: bool,
+
+    /// bridge secret. All bridges and exits know this secret, and it's used to prevent random people from spamming the bridge table. This shows up in process listings, so prefer --bridge-secret-file or the GEPH_BRIDGE_SECRET environment variable.
     #[structopt(long)]
-    bridge_secret: String,
+    bridge_secret: Option<String>,
+
+    /// File containing the bridge secret, as an alternative to --bridge-secret.
+    #[structopt(long)]
+    bridge_secret_file: Option<PathBuf>,
 
     /// Hostname of this exit.
     #[structopt(long)]
     exit_hostname: String,
 
+    /// Address to listen for clients and bridges on, which --exit-hostname must resolve to. Listens on all addresses if not given, which isn't allowed with --extra-exit.
+    #[structopt(long, default_value = "::")]
+    listen_ip: IpAddr,
+
+    /// Address to listen for bridge control connections on, if not --listen-ip, such as a management interface of a multi-homed exit. Client sessions, including those forwarded by bridges, still go to --listen-ip, which --exit-hostname resolves to. The exit reports this address to the binder, which tells bridges where to connect, so it must be one bridges can reach. Bridges or binders too old to know about it look for the control port at --exit-hostname, so for them that name must reach this address as well, through a port forward. Applies to the main exit only.
+    #[structopt(long)]
+    control_listen_ip: Option<IpAddr>,
+
+    /// Another exit to serve from this process, as HOSTNAME,IP,KEYFILE: its hostname, the address it listens on, which its hostname must resolve to, and its signing key file, created if missing. Each is registered at the binder and known to bridges as a separate exit, but they all share one policy, health server and audit log, and connect upstream from the same addresses. Can be given more than once.
+    #[structopt(long)]
+    extra_exit: Vec<listen::ExtraExit>,
+
     /// Speed limit for free users, in KB/s. If zero, completely blocks free users.
     #[structopt(long, default_value = "200")]
     free_limit: u32,
 
+    /// Speed limit, in the same units as --free-limit, that all sessions of a user level share equally, given as LEVEL=LIMIT, such as plus=500000. Can be given more than once. Free sessions are also held to --free-limit each. Levels without a limit aren't throttled.
+    #[structopt(long)]
+    tier_limit: Vec<qos::TierLimit>,
+
     /// Whether or not to use port whitelist.
     #[structopt(long)]
     port_whitelist: bool,
 
-    /// Google proxy server to redirect all port 443 Google requests to.
+    /// Let clients connect to private (RFC 1918), carrier-grade NAT and link-local addresses, such as machines on the exit's LAN. Off by default, since clients could otherwise reach services that trust the exit's network, or its cloud provider's metadata service at 169.254.169.254. Loopback addresses and the exit's own addresses stay off limits either way.
+    #[structopt(long)]
+    allow_internal_destinations: bool,
+
+    /// Google proxy server to redirect all port 443 Google requests to. Shorthand for a redirect rule matching Google's ASN.
     #[structopt(long)]
     google_proxy: Option<SocketAddr>,
+
+    /// Transparently redirect matching destinations, as sni=PATTERN:TARGET, host=PATTERN:TARGET, or asn=NUMBER:TARGET. Patterns may start with "*.". Can be given multiple times; the first matching rule wins.
+    #[structopt(long)]
+    redirect_rule: Vec<redirect::RedirectRule>,
+
+    /// Range, as MIN-MAX, of the padded length of sosistab handshake packets. Clients don't need to use the same range.
+    #[structopt(long, default_value = "0-1000")]
+    handshake_padding: sosistab::HandshakePadding,
+
+    /// How to disguise sosistab UDP traffic: "none", or "dtls" to wrap every packet in a DTLS record header, at 13 bytes of overhead per packet. With "dtls", clients that don't disguise their traffic still work.
+    #[structopt(long, default_value = "none")]
+    udp_obfuscation: sosistab::UdpObfuscation,
+
+    /// How much forward error correction to send to clients: "adaptive" to send parity only as measured loss calls for, "off", or a percentage such as "20%" to always send at least that much parity. Clients decode parity whatever they're set to.
+    #[structopt(long, default_value = "adaptive")]
+    fec: sosistab::FecMode,
+
+    /// Where to listen for the health server, which reports the state of the exit over HTTP. Disabled if not given.
+    #[structopt(long)]
+    health_listen: Option<SocketAddr>,
+
+    /// How long, in seconds, a session can go without receiving anything before it's considered dead and dropped. Sessions whose client says goodbye are dropped right away.
+    #[structopt(long, default_value = "3600")]
+    session_timeout: u64,
+
+    /// How long, in seconds, clients get to move to other exits once the binder asks for this exit to be drained. New sessions are turned away right away, and existing ones are told to move and then closed at random times over this period, so that their clients don't all reconnect at once.
+    #[structopt(long, default_value = "600")]
+    drain_grace: u64,
+
+    /// How many new sosistab handshakes per minute to accept from a single IP address. Bridges put many clients behind one address, so keep this generous. Zero disables the limit.
+    #[structopt(long, default_value = "6000")]
+    handshake_rate_limit: u32,
+
+    /// How many sosistab handshakes to answer at once. Zero means half the CPU cores.
+    #[structopt(long, default_value = "0")]
+    handshake_workers: usize,
+
+    /// How many sosistab handshakes can wait for a worker. Handshakes beyond this are dropped under a flood, and their clients retry later.
+    #[structopt(long, default_value = "1024")]
+    handshake_queue: usize,
+
+    /// Under a flood of handshakes, make clients solve a proof-of-work challenge before their handshakes are answered, once the handshake queue is a quarter full. Difficulty climbs with the queue's length, up to this many bits, each of which doubles the work. Zero, the default, disables this. Clients too old to solve challenges can't connect while they're on.
+    #[structopt(long, default_value = "0")]
+    handshake_pow_max_bits: u8,
+
+    /// Receive window, in KiB, of each tunneled connection. Clients can't send more than this much data that hasn't been forwarded yet.
+    #[structopt(long, default_value = "10240")]
+    recv_window_kb: usize,
+
+    /// For debugging: hash everything sent over each tunneled connection and check it against what arrives, so that data corrupted inside the tunnel resets the connection rather than going unnoticed. This costs CPU on every byte, and only checks anything for clients that turn it on too.
+    #[structopt(long)]
+    debug_integrity: bool,
+
+    /// Range, as MIN-MAX, of local ports to connect to upstream hosts from. If every port tried in the range is taken, an ephemeral port is used instead. Any ephemeral port is used if not given.
+    #[structopt(long)]
+    outbound_port_range: Option<outbound::PortRange>,
+
+    /// Accept SOCKS5 BIND requests from clients, by listening on a fresh port for each one. Each bind holds a port open for up to two minutes, so this is off by default.
+    #[structopt(long)]
+    allow_socks_bind: bool,
+
+    /// Where to resolve the names clients connect to: "system" for the host's resolver, a comma-separated list of DNS servers such as "1.1.1.1,9.9.9.9:53", tried in order, or the https:// URL of a DNS-over-HTTPS server. Answers from servers are cached for their TTL.
+    #[structopt(long, default_value = "system")]
+    dns_upstream: resolver::Upstream,
+
+    /// How often, in seconds, to push metrics to statsd. Counts cover the time since the previous push, so dashboards should sum them over intervals no shorter than this.
+    #[structopt(long, default_value = "10")]
+    metrics_interval: u64,
+
+    /// How much to record about each connection clients ask for, in a separate audit log: "off", "destination" for the time, session id and destination, or "full" to also record the addresses the session came from.
+    #[structopt(long, default_value = "off")]
+    audit_level: audit::AuditLevel,
+
+    /// Where to write the audit log.
+    #[structopt(long, default_value = "/var/log/geph4-exit-audit.log")]
+    audit_log: PathBuf,
+
+    /// Size, in MiB, past which the audit log is rotated.
+    #[structopt(long, default_value = "64")]
+    audit_log_max_mb: u64,
+
+    /// How many rotated audit logs to keep around.
+    #[structopt(long, default_value = "4")]
+    audit_log_keep: usize,
+
+    /// Bearer token required for everything the health server serves except /health, such as /sessions. Requests without it get a 401. Those endpoints are disabled if no token is given. This shows up in process listings, so prefer --admin-token-file or the GEPH_ADMIN_TOKEN environment variable.
+    #[structopt(long)]
+    admin_token: Option<String>,
+
+    /// File containing the admin token, as an alternative to --admin-token.
+    #[structopt(long)]
+    admin_token_file: Option<PathBuf>,
+
+    /// Require the admin token for /health too. Leave this off if an orchestrator probes /health without credentials.
+    #[structopt(long)]
+    health_requires_auth: bool,
+
+    /// TOML or YAML file of flags to use when they're not given on the command line, such as `exit_hostname = "us-hio-01.exits.geph.io"`. On SIGHUP, --free-limit, --tier-limit, --port-whitelist, --google-proxy and --redirect-rule are read again from this file and apply to new connections without dropping sessions, as do changed speed limits to existing sessions. Flags given on the command line still win, and all other flags only change on restart.
+    #[structopt(long)]
+    config: Option<PathBuf>,
+}
+
+impl Opt {
+    /// The parts of the options that can be reloaded without a restart.
+    fn policy(&self) -> listen::Policy {
+        listen::Policy {
+            free_limit: self.free_limit,
+            tier_limits: self.tier_limit.clone(),
+            port_whitelist: self.port_whitelist,
+            allow_internal: self.allow_internal_destinations,
+            redirects: redirect::RedirectTable::new(self.redirect_rule.clone(), self.google_proxy),
+        }
+    }
+}
+
+/// Re-reads the policy from the config file on every SIGHUP.
+fn reload_on_sighup() -> anyhow::Result<smol::channel::Receiver<listen::Policy>> {
+    let (send_reload, recv_reload) = smol::channel::unbounded();
+    let mut signals = signal_hook::iterator::Signals::new(&[signal_hook::consts::SIGHUP])?;
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            match configfile::args().and_then(|args| Ok(Opt::from_iter_safe(args)?)) {
+                Ok(opt) => {
+                    let _ = send_reload.try_send(opt.policy());
+                }
+                Err(err) => log::warn!("cannot reload flags, so keeping the old policy: {}", err),
+            }
+        }
+    });
+    Ok(recv_reload)
+}
+
+/// Saves the signing key, readable only by us, optionally encrypting it with a passphrase.
+fn save_signing_sk(
+    path: &std::path::Path,
+    keypair: &ed25519_dalek::Keypair,
+    encrypt: bool,
+) -> anyhow::Result<()> {
+    let mut contents = bincode::serialize(keypair)?;
+    if encrypt {
+        let passphrase = atrest::passphrase("signing_sk")?;
+        contents = atrest::seal_standalone(&passphrase, &contents)?;
+    }
+    // the old key file is read-only, so replace it rather than writing over it
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, contents)?;
+    let mut perms = std::fs::metadata(&tmp_path)?.permissions();
+    perms.set_readonly(true);
+    perms.set_mode(0o600);
+    std::fs::set_permissions(&tmp_path, perms)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Why the signing key couldn't be loaded. The key is the exit's identity, so none of these can be fixed by quietly making a new one.
+#[derive(Debug, thiserror::Error)]
+enum SigningKeyError {
+    #[error("cannot read signing_sk at {0:?} ({1}). Fix its permissions, or remove it to deliberately give this exit a new identity")]
+    Unreadable(PathBuf, std::io::Error),
+    #[error("signing_sk at {0:?} is corrupt ({1}). Refusing to replace it, since a new key would change this exit's identity and break its registration with the binder. Restore it from a backup, or remove it to deliberately give this exit a new identity")]
+    Corrupt(PathBuf, String),
+}
+
+/// Reads the signing key, creating one only if there's no key file at all.
+fn load_signing_sk(
+    path: &std::path::Path,
+    encrypt: bool,
+) -> anyhow::Result<ed25519_dalek::Keypair> {
+    let corrupt = |err: bincode::Error| SigningKeyError::Corrupt(path.to_owned(), err.to_string());
+    match std::fs::read(path) {
+        // an encrypted key always needs the passphrase, whether or not we're asked to encrypt
+        Ok(vec) if atrest::is_sealed(&vec) => {
+            let passphrase = atrest::passphrase("signing_sk")?;
+            let plain =
+                atrest::open_standalone(&passphrase, &vec).context("cannot decrypt signing_sk")?;
+            Ok(bincode::deserialize(&plain).map_err(corrupt)?)
+        }
+        Ok(vec) => {
+            let keypair = bincode::deserialize(&vec).map_err(corrupt)?;
+            if encrypt {
+                log::info!("encrypting the existing signing_sk");
+                save_signing_sk(path, &keypair, true)?;
+            }
+            Ok(keypair)
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            log::warn!(
+                "no signing_sk at {:?}, so creating one and saving it!",
+                path
+            );
+            let new_keypair = ed25519_dalek::Keypair::generate(&mut rand::rngs::OsRng {});
+            if let Err(err) = save_signing_sk(path, &new_keypair, encrypt) {
+                log::error!("cannot save signing_sk persistently!!! {}", err);
+            }
+            Ok(new_keypair)
+        }
+        Err(err) => Err(SigningKeyError::Unreadable(path.to_owned(), err).into()),
+    }
+}
+
+/// Longest we wait between attempts to reach the binder at startup.
+const MAX_BINDER_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Asks the binder whether this exit is registered.
+async fn check_registration(
+    binder_client: &dyn BinderClient,
+    signing_pk: &ed25519_dalek::PublicKey,
+) -> anyhow::Result<bool> {
+    let resp = binder_client.request(BinderRequestData::GetExits).await?;
+    match resp {
+        BinderResponse::GetExitsResp(exits) => {
+            Ok(exits.iter().any(|e| &e.signing_key == signing_pk))
+        }
+        other => anyhow::bail!("unexpected response to GetExits: {:?}", other),
+    }
+}
+
+/// Remembers whether we're registered, so that we can start up later even if the binder is down.
+fn record_registration(hostname: &str, cache: &std::path::Path, registered: bool) {
+    if registered {
+        if let Err(err) = std::fs::write(cache, b"") {
+            log::warn!("cannot cache registration status: {}", err)
+        }
+    } else {
+        log::warn!(
+            "{} is not found at the binder; you should manually add it first",
+            hostname
+        );
+        let _ = std::fs::remove_file(cache);
+    }
+}
+
+/// Waits until we know whether the exit with this signing key is registered, tolerating the binder being down if it was registered before.
+async fn wait_registration(
+    binder_client: &Arc<dyn BinderClient>,
+    hostname: &str,
+    signing_sk_path: &std::path::Path,
+    signing_pk: ed25519_dalek::PublicKey,
+) {
+    let registered_cache = signing_sk_path.with_extension("registered");
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        match check_registration(binder_client.as_ref(), &signing_pk).await {
+            Ok(registered) => {
+                record_registration(&hostname, &registered_cache, registered);
+                return;
+            }
+            Err(err) if registered_cache.exists() => {
+                log::warn!(
+                    "binder unreachable ({}), but this exit was registered before, so starting anyway",
+                    err
+                );
+                let binder_client = binder_client.clone();
+                let hostname = hostname.to_owned();
+                smolscale::spawn(async move {
+                    let mut backoff = Duration::from_secs(1);
+                    loop {
+                        smol::Timer::after(backoff).await;
+                        match check_registration(binder_client.as_ref(), &signing_pk).await {
+                            Ok(registered) => {
+                                record_registration(&hostname, &registered_cache, registered);
+                                return;
+                            }
+                            Err(err) => {
+                                log::warn!("still cannot reach binder: {}", err);
+                                backoff = (backoff * 2).min(MAX_BINDER_BACKOFF);
+                            }
+                        }
+                    }
+                })
+                .detach();
+                return;
+            }
+            Err(err) => {
+                log::warn!(
+                    "cannot reach binder ({}), retrying in {}s",
+                    err,
+                    backoff.as_secs()
+                );
+                smol::Timer::after(backoff).await;
+                backoff = (backoff * 2).min(MAX_BINDER_BACKOFF);
+            }
+        }
+    }
 }
 
+// jemalloc holds up much better than glibc malloc against the fragmentation from lots of short-lived connections. elsewhere, the system allocator is good enough for development.
+#[cfg(not(target_env = "musl"))]
 #[global_allocator]
-pub static ALLOCATOR: Jemalloc = Jemalloc;
+pub static ALLOCATOR: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
 fn main() -> anyhow::Result<()> {
     // smolscale::permanently_single_threaded();
-    let opt: Opt = Opt::from_args();
+    let opt: Opt = Opt::from_iter(configfile::args()?);
     let stat_client = statsd::Client::new(opt.statsd_addr, "geph4")?;
     env_logger::Builder::from_env(Env::default().default_filter_or("geph4_exit=debug,warn")).init();
+    if let Some(config) = &opt.config {
+        log::info!("using flags from {:?}", config);
+    }
+    // warns right away if session keys are being logged
+    sosistab::keylog_enabled();
+    let policy_reloads = reload_on_sighup()?;
+    opt.handshake_padding.set();
+    opt.udp_obfuscation.set();
+    opt.fec.set();
+    if let Some(range) = opt.outbound_port_range {
+        range.set();
+    }
+    opt.dns_upstream.clone().set();
+    sosistab::mux::set_recv_window(opt.recv_window_kb * 1024);
+    sosistab::mux::set_integrity_check(opt.debug_integrity);
+    sosistab::set_handshake_rate_limit(opt.handshake_rate_limit);
+    sosistab::set_handshake_concurrency(opt.handshake_workers, opt.handshake_queue);
+    sosistab::set_handshake_pow(opt.handshake_pow_max_bits);
+    let audit_log = audit::AuditLog::open(
+        &opt.audit_log,
+        opt.audit_level,
+        opt.audit_log_max_mb * 1024 * 1024,
+        opt.audit_log_keep,
+    )
+    .context("cannot open audit log")?;
+    let binder_master_pk = x25519_dalek::PublicKey::from(configfile::binder_master_pk(
+        "binder-master-pk",
+        opt.binder_master_pk.as_deref(),
+        opt.binder_master_pk_file.as_deref(),
+    )?);
+    let bridge_secret = configfile::secret(
+        "bridge-secret",
+        opt.bridge_secret.as_deref(),
+        opt.bridge_secret_file.as_deref(),
+        "GEPH_BRIDGE_SECRET",
+    )?;
+    let admin_token = if opt.admin_token.is_some()
+        || opt.admin_token_file.is_some()
+        || std::env::var_os("GEPH_ADMIN_TOKEN").is_some()
+    {
+        Some(configfile::secret(
+            "admin-token",
+            opt.admin_token.as_deref(),
+            opt.admin_token_file.as_deref(),
+            "GEPH_ADMIN_TOKEN",
+        )?)
+    } else {
+        None
+    };
+    let admin_auth = listen::AdminAuth::new(admin_token, opt.health_requires_auth)?;
+    if let Some(control_ip) = opt.control_listen_ip {
+        if opt.extra_exit.iter().any(|extra| {
+            extra.listen_ip == control_ip
+                || (control_ip.is_unspecified() && !extra.listen_ip.is_unspecified())
+        }) {
+            anyhow::bail!(
+                "--control-listen-ip must not overlap the addresses of extra exits, which have their own control listeners"
+            )
+        }
+    }
+    if !opt.extra_exit.is_empty() && opt.listen_ip.is_unspecified() {
+        anyhow::bail!(
+            "--listen-ip must be given with --extra-exit, so that each exit has its own address"
+        )
+    }
     smol::future::block_on(smolscale::spawn(async move {
         log::info!("geph4-exit starting...");
-        // read or generate key
-        let signing_sk = {
-            match std::fs::read(&opt.signing_sk) {
-                Ok(vec) => bincode::deserialize(&vec)?,
-                Err(err) => {
-                    log::warn!(
-                        "can't read signing_sk, so creating one and saving it! {}",
-                        err
-                    );
-                    let new_keypair = ed25519_dalek::Keypair::generate(&mut rand::rngs::OsRng {});
-                    if let Err(err) =
-                        std::fs::write(&opt.signing_sk, bincode::serialize(&new_keypair)?)
-                    {
-                        log::error!("cannot save signing_sk persistently!!! {}", err);
-                    } else {
-                        let mut perms = std::fs::metadata(&opt.signing_sk)?.permissions();
-                        perms.set_readonly(true);
-                        perms.set_mode(600);
-                        std::fs::set_permissions(&opt.signing_sk, perms)?;
-                    }
-                    new_keypair
-                }
-            }
-        };
-        let sosistab_sk = x25519_dalek::StaticSecret::from(*signing_sk.secret.as_bytes());
-        log::info!("signing_pk = {}", hex::encode(signing_sk.public.as_bytes()));
-        log::info!(
-            "sosistab_sk = {}",
-            hex::encode(x25519_dalek::PublicKey::from(&sosistab_sk).as_bytes())
-        );
         // create binder client
-        let binder_client = Arc::new(binder_transport::HttpClient::new(
-            bincode::deserialize(&hex::decode(opt.binder_master_pk)?)?,
-            &opt.binder_http,
-            &[],
-        ));
-        let exits = {
-            let resp = binder_client.request(BinderRequestData::GetExits).await?;
-            match resp {
-                BinderResponse::GetExitsResp(exits) => exits,
-                _ => panic!(),
-            }
-        };
-        // warn if not in exits
-        if exits
-            .iter()
-            .find(|e| e.signing_key == signing_sk.public)
-            .is_none()
+        let mut binder_client =
+            binder_transport::HttpClient::new(binder_master_pk, &opt.binder_http, &[]);
+        if let Some(proxy) = opt.binder_socks_proxy {
+            binder_transport::probe_socks5(proxy)
+                .await
+                .context("cannot use the binder SOCKS5 proxy")?;
+            log::info!("reaching the binder through SOCKS5 proxy {}", proxy);
+            binder_client = binder_client.with_socks_proxy(proxy);
+        }
+        let binder_client: Arc<dyn BinderClient> = Arc::new(binder_client);
+        // read or generate keys, and check that every exit is registered
+        let mut identities = Vec::new();
+        let extra_exits = opt.extra_exit.iter().map(|extra| {
+            (
+                extra.hostname.clone(),
+                extra.signing_sk.clone(),
+                extra.listen_ip,
+            )
+        });
+        for (hostname, signing_sk_path, listen_ip) in std::iter::once((
+            opt.exit_hostname.clone(),
+            opt.signing_sk.clone(),
+            opt.listen_ip,
+        ))
+        .chain(extra_exits)
         {
-            log::warn!("this exit is not found at the binder; you should manually add it first")
+            if identities.iter().any(|id: &listen::Identity| {
+                id.hostname() == hostname || id.listen_ip() == listen_ip
+            }) {
+                anyhow::bail!(
+                    "{} shares its hostname or address with another exit",
+                    hostname
+                )
+            }
+            let signing_sk = load_signing_sk(&signing_sk_path, opt.encrypt_signing_sk)?;
+            log::info!(
+                "{}: signing_pk = {}",
+                hostname,
+                hex::encode(signing_sk.public.as_bytes())
+            );
+            wait_registration(
+                &binder_client,
+                &hostname,
+                &signing_sk_path,
+                signing_sk.public,
+            )
+            .await;
+            let mut identity = listen::Identity::new(hostname, signing_sk, listen_ip);
+            if identities.is_empty() {
+                if let Some(control_ip) = opt.control_listen_ip {
+                    identity = identity.with_control_ip(control_ip);
+                }
+            }
+            log::info!(
+                "{}: data plane on {}, control plane on {}",
+                identity.hostname(),
+                identity.listen_ip(),
+                identity.control_ip()
+            );
+            log::info!(
+                "{}: sosistab_pk = {}",
+                identity.hostname(),
+                hex::encode(identity.sosistab_pk().as_bytes())
+            );
+            identities.push(identity);
         }
         // listen
         listen::main_loop(
             stat_client,
-            &opt.exit_hostname,
+            identities,
             binder_client,
-            &opt.bridge_secret,
-            signing_sk,
-            sosistab_sk,
-            opt.free_limit,
-            opt.google_proxy,
-            opt.port_whitelist,
+            &bridge_secret,
+            opt.policy(),
+            policy_reloads,
+            opt.health_listen,
+            admin_auth,
+            Duration::from_secs(opt.session_timeout),
+            Duration::from_secs(opt.drain_grace),
+            opt.allow_socks_bind,
+            audit_log,
+            Duration::from_secs(opt.metrics_interval.max(1)),
         )
         .await?;
         Ok(())
@@ -0,0 +1,111 @@
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use smol::Async;
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// How many random ports in the range we try before falling back to an ephemeral port.
+const BIND_ATTEMPTS: usize = 8;
+
+/// The range, packed as MIN << 16 | MAX, of local ports for outbound connections. Zero means any ephemeral port.
+static PORT_RANGE: AtomicU32 = AtomicU32::new(0);
+
+/// A range of local ports, as MIN-MAX.
+#[derive(Debug, Clone, Copy)]
+pub struct PortRange {
+    pub min: u16,
+    pub max: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '-');
+        let (min, max) = match (parts.next(), parts.next()) {
+            (Some(min), Some(max)) => (min.parse::<u16>()?, max.parse::<u16>()?),
+            _ => anyhow::bail!("port range must be MIN-MAX"),
+        };
+        if min == 0 || min > max {
+            anyhow::bail!("port range must be MIN-MAX with 0 < MIN <= MAX")
+        }
+        Ok(PortRange { min, max })
+    }
+}
+
+impl PortRange {
+    /// Makes outbound connections from now on use local ports in this range.
+    pub fn set(self) {
+        PORT_RANGE.store((self.min as u32) << 16 | self.max as u32, Ordering::Relaxed)
+    }
+
+    fn get() -> Option<Self> {
+        match PORT_RANGE.load(Ordering::Relaxed) {
+            0 => None,
+            packed => Some(PortRange {
+                min: (packed >> 16) as u16,
+                max: packed as u16,
+            }),
+        }
+    }
+}
+
+/// Connects to an upstream address, from a random local port in the configured range if there is one. If several ports in the range are taken, an ephemeral port is used instead, so running out of ports in the range never stops connections.
+pub async fn connect(addr: SocketAddr) -> io::Result<smol::net::TcpStream> {
+    let range = match PortRange::get() {
+        Some(range) => range,
+        None => return smol::net::TcpStream::connect(addr).await,
+    };
+    for _ in 0..BIND_ATTEMPTS {
+        let port = fastrand::u16(range.min..=range.max);
+        match connect_from(port, addr).await {
+            Err(err)
+                if err.kind() == io::ErrorKind::AddrInUse
+                    || err.kind() == io::ErrorKind::AddrNotAvailable =>
+            {
+                continue
+            }
+            res => return res,
+        }
+    }
+    log::debug!(
+        "no free port in {}-{} for {}; using an ephemeral port",
+        range.min,
+        range.max,
+        addr
+    );
+    smol::net::TcpStream::connect(addr).await
+}
+
+async fn connect_from(port: u16, addr: SocketAddr) -> io::Result<smol::net::TcpStream> {
+    let (domain, local) = match addr {
+        SocketAddr::V4(_) => (
+            Domain::ipv4(),
+            SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port),
+        ),
+        SocketAddr::V6(_) => (
+            Domain::ipv6(),
+            SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port),
+        ),
+    };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    // the same local port can then be reused for different destinations
+    socket.set_reuse_address(true)?;
+    socket.bind(&local.into())?;
+    socket.set_nonblocking(true)?;
+    match socket.connect(&addr.into()) {
+        Ok(()) => {}
+        Err(err) if err.raw_os_error() == Some(libc::EINPROGRESS) => {}
+        Err(err) => return Err(err),
+    }
+    let stream = Async::new(socket.into_tcp_stream())?;
+    stream.writable().await?;
+    if let Some(err) = stream.get_ref().take_error()? {
+        return Err(err);
+    }
+    Ok(stream.into())
+}
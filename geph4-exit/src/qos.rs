@@ -0,0 +1,178 @@
+use std::{
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+/// What sosistab sessions are limited to unless told otherwise. Sessions of levels without a limit go back to this when a limit is removed.
+pub const DEFAULT_SESSION_LIMIT: u32 = 25600;
+
+/// However many sessions share a tier's limit, none gets less than this, so that a crowded tier is slow rather than broken.
+const MIN_SHARE: u32 = 50;
+
+/// A speed limit that all the sessions of one user level share, given as LEVEL=LIMIT.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TierLimit {
+    pub level: String,
+    pub limit: u32,
+}
+
+impl FromStr for TierLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '=');
+        let level = parts.next().unwrap_or_default().trim();
+        let limit = parts
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("tier limit must look like LEVEL=LIMIT"))?;
+        if level.is_empty() {
+            anyhow::bail!("tier limit has no level")
+        }
+        Ok(TierLimit {
+            level: level.to_string(),
+            limit: limit.trim().parse()?,
+        })
+    }
+}
+
+/// The limit of each of `sessions` sessions that fairly share `aggregate`, capped by `cap` if it's nonzero.
+pub fn fair_share(aggregate: u32, sessions: usize, cap: u32) -> u32 {
+    let share = (aggregate / sessions.max(1) as u32).max(MIN_SHARE);
+    if cap > 0 {
+        share.min(cap)
+    } else {
+        share
+    }
+}
+
+/// The limit of each session of a user level that has `sessions` sessions, given the tier limits and the limit of each free session.
+pub fn level_limit(
+    tier_limits: &[TierLimit],
+    free_limit: u32,
+    level: &str,
+    sessions: usize,
+) -> u32 {
+    let cap = if level == "free" { free_limit } else { 0 };
+    match tier_limits.iter().find(|t| t.level == level) {
+        Some(tier) => fair_share(tier.limit, sessions, cap),
+        None if cap > 0 => cap,
+        None => DEFAULT_SESSION_LIMIT,
+    }
+}
+
+/// About how much data a sosistab packet carries, for turning limits in packets per second into bytes per second.
+const PACKET_PAYLOAD: u64 = 1100;
+
+/// A connection that has had nothing to send for this long no longer takes a share of its session's limit.
+pub const BUSY_WINDOW: Duration = Duration::from_secs(1);
+
+/// The connections of one session that are busy sending to the client, and so split its speed limit between them.
+#[derive(Debug, Default)]
+pub struct FlowShare {
+    busy: AtomicUsize,
+}
+
+impl FlowShare {
+    /// How many connections are busy.
+    pub fn busy(&self) -> usize {
+        self.busy.load(Ordering::Relaxed)
+    }
+
+    /// A flag for a new connection, which starts out idle.
+    pub fn flag(&self) -> BusyFlag<'_> {
+        BusyFlag {
+            share: self,
+            busy: false,
+        }
+    }
+}
+
+/// Whether one connection counts as busy. Dropping it makes the connection idle.
+pub struct BusyFlag<'a> {
+    share: &'a FlowShare,
+    busy: bool,
+}
+
+impl<'a> BusyFlag<'a> {
+    pub fn set(&mut self, busy: bool) {
+        if busy != self.busy {
+            if busy {
+                self.share.busy.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.share.busy.fetch_sub(1, Ordering::Relaxed);
+            }
+            self.busy = busy;
+        }
+    }
+}
+
+impl<'a> Drop for BusyFlag<'a> {
+    fn drop(&mut self) {
+        self.set(false)
+    }
+}
+
+/// The rate, in bytes per second, that each of `busy` connections may send at, so that one bulk transfer can't take all of a session limited to `session_limit` packets per second. None if sosistab doesn't throttle the session, so its connections needn't be paced either. The session's own limit still applies on top.
+pub fn conn_share(session_limit: u32, busy: usize) -> Option<u64> {
+    if session_limit >= sosistab::THROTTLE_BELOW {
+        return None;
+    }
+    let share = session_limit as u64 * PACKET_PAYLOAD / busy.max(1) as u64;
+    Some(share.max(PACKET_PAYLOAD))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shares_are_fair_and_bounded() {
+        assert_eq!(fair_share(10000, 4, 0), 2500);
+        assert_eq!(fair_share(10000, 0, 0), 10000);
+        assert_eq!(fair_share(10000, 1, 200), 200);
+        assert_eq!(fair_share(1000, 1000, 0), MIN_SHARE);
+        assert_eq!(
+            "plus=100000".parse::<TierLimit>().unwrap(),
+            TierLimit {
+                level: "plus".into(),
+                limit: 100000
+            }
+        );
+        assert!("plus".parse::<TierLimit>().is_err());
+        assert!("=5".parse::<TierLimit>().is_err());
+    }
+
+    #[test]
+    fn tier_limits_throttle() {
+        let tiers = vec!["plus=100000".parse::<TierLimit>().unwrap()];
+        // a crowded tier throttles its sessions, while a lone session gets more than sosistab ever throttles
+        assert_eq!(level_limit(&tiers, 200, "plus", 10), 10000);
+        assert!(level_limit(&tiers, 200, "plus", 10) < sosistab::THROTTLE_BELOW);
+        assert!(level_limit(&tiers, 200, "plus", 1) >= sosistab::THROTTLE_BELOW);
+        // free sessions are held to the free limit, and levels without a limit aren't throttled
+        assert_eq!(level_limit(&tiers, 200, "free", 10), 200);
+        assert!(level_limit(&tiers, 200, "gold", 10) >= sosistab::THROTTLE_BELOW);
+    }
+
+    #[test]
+    fn busy_conns_split_the_session() {
+        let flows = FlowShare::default();
+        assert_eq!(conn_share(DEFAULT_SESSION_LIMIT, 3), None);
+        assert_eq!(conn_share(sosistab::THROTTLE_BELOW, 3), None);
+        assert_eq!(conn_share(500, flows.busy()), Some(550000));
+        {
+            let mut bulk = flows.flag();
+            let mut interactive = flows.flag();
+            bulk.set(true);
+            interactive.set(true);
+            interactive.set(true);
+            assert_eq!(flows.busy(), 2);
+            assert_eq!(conn_share(500, flows.busy()), Some(275000));
+            interactive.set(false);
+            assert_eq!(flows.busy(), 1);
+        }
+        assert_eq!(flows.busy(), 0);
+        assert_eq!(conn_share(10, 100), Some(PACKET_PAYLOAD));
+    }
+}
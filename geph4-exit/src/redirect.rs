@@ -0,0 +1,282 @@
+use std::{net::SocketAddr, str::FromStr, time::Duration};
+
+use smol::prelude::*;
+use smol_timeout::TimeoutExt;
+
+/// How long we wait for the client to send its TLS ClientHello before giving up on finding an SNI.
+const SNI_PEEK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest ClientHello we are willing to buffer while looking for an SNI.
+const MAX_HELLO_LEN: usize = 16384;
+
+/// What a redirect rule matches on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RedirectMatch {
+    /// The server name in the TLS ClientHello, such as `*.googlevideo.com`.
+    Sni(String),
+    /// The hostname the client asked us to connect to.
+    Host(String),
+    /// Port 443 of any address in the given autonomous system.
+    Asn(u32),
+}
+
+/// A rule transparently redirecting some destinations to a fixed target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedirectRule {
+    pub matcher: RedirectMatch,
+    pub target: SocketAddr,
+}
+
+impl FromStr for RedirectRule {
+    type Err = anyhow::Error;
+
+    /// Parses rules of the form `sni=PATTERN:TARGET`, `host=PATTERN:TARGET`, or `asn=NUMBER:TARGET`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut kv = s.splitn(2, '=');
+        let kind = kv.next().unwrap_or_default();
+        let rest = kv
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("redirect rule {:?} has no '='", s))?;
+        let mut pt = rest.splitn(2, ':');
+        let pattern = pt.next().unwrap_or_default().to_ascii_lowercase();
+        let target: SocketAddr = pt
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("redirect rule {:?} has no target", s))?
+            .parse()?;
+        if pattern.is_empty() {
+            anyhow::bail!("redirect rule {:?} has an empty pattern", s)
+        }
+        let matcher = match kind {
+            "sni" => RedirectMatch::Sni(pattern),
+            "host" => RedirectMatch::Host(pattern),
+            "asn" => RedirectMatch::Asn(pattern.parse()?),
+            other => anyhow::bail!(
+                "unknown redirect rule kind {:?} (expected sni, host, or asn)",
+                other
+            ),
+        };
+        Ok(RedirectRule { matcher, target })
+    }
+}
+
+/// An ordered table of redirect rules. The first matching rule wins.
+#[derive(Debug, Clone, Default)]
+pub struct RedirectTable {
+    rules: Vec<RedirectRule>,
+}
+
+impl RedirectTable {
+    /// Creates a table out of the given rules, plus the legacy Google proxy rule if given.
+    pub fn new(mut rules: Vec<RedirectRule>, google_proxy: Option<SocketAddr>) -> Self {
+        if let Some(target) = google_proxy {
+            rules.push(RedirectRule {
+                matcher: RedirectMatch::Asn(crate::asn::GOOGLE_ASN),
+                target,
+            })
+        }
+        Self { rules }
+    }
+
+    /// Whether any rule needs the SNI, and so whether connections to the given port must be peeked at.
+    pub fn needs_sni(&self, port: u16) -> bool {
+        port == 443
+            && self
+                .rules
+                .iter()
+                .any(|r| matches!(r.matcher, RedirectMatch::Sni(_)))
+    }
+
+    /// Finds where a connection should actually go, given what we know about it.
+    pub fn lookup(&self, host: Option<&str>, sni: Option<&str>, addr: SocketAddr) -> SocketAddr {
+        for rule in self.rules.iter() {
+            let matched = match &rule.matcher {
+                RedirectMatch::Sni(pattern) => sni.map(|s| glob_match(pattern, s)).unwrap_or(false),
+                RedirectMatch::Host(pattern) => {
+                    host.map(|h| glob_match(pattern, h)).unwrap_or(false)
+                }
+                RedirectMatch::Asn(asn) => {
+                    addr.port() == 443 && crate::asn::get_asn(addr.ip()) == *asn
+                }
+            };
+            if matched {
+                return rule.target;
+            }
+        }
+        addr
+    }
+}
+
+/// Matches a name against a pattern that's either exact, `*`, or `*.suffix`.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    if pattern == "*" {
+        true
+    } else if let Some(suffix) = pattern.strip_prefix("*.") {
+        name == suffix || name.ends_with(&pattern[1..])
+    } else {
+        name == pattern
+    }
+}
+
+/// Reads the TLS ClientHello off the start of a stream, returning the bytes read (which must be forwarded) and the SNI if there is one.
+pub async fn peek_sni(client: &mut (impl AsyncRead + Unpin)) -> (Vec<u8>, Option<String>) {
+    let mut buf = Vec::new();
+    let fut = async {
+        let mut chunk = [0u8; 4096];
+        loop {
+            let n = client.read(&mut chunk).await.ok()?;
+            if n == 0 {
+                return None;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+            match parse_sni(&buf) {
+                SniParse::Found(sni) => return Some(sni),
+                SniParse::NotTls => return None,
+                SniParse::Incomplete if buf.len() < MAX_HELLO_LEN => continue,
+                SniParse::Incomplete => return None,
+            }
+        }
+    };
+    let sni = fut.timeout(SNI_PEEK_TIMEOUT).await.flatten();
+    (buf, sni)
+}
+
+/// Result of trying to parse an SNI out of a partial stream.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SniParse {
+    Found(String),
+    Incomplete,
+    NotTls,
+}
+
+/// Parses the SNI out of the first TLS record, which must contain the whole ClientHello.
+pub fn parse_sni(buf: &[u8]) -> SniParse {
+    if buf.len() < 5 {
+        return SniParse::Incomplete;
+    }
+    // handshake record
+    if buf[0] != 0x16 {
+        return SniParse::NotTls;
+    }
+    let record_len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    if buf.len() < 5 + record_len {
+        return SniParse::Incomplete;
+    }
+    match parse_hello(&buf[5..5 + record_len]) {
+        Some(sni) => SniParse::Found(sni),
+        None => SniParse::NotTls,
+    }
+}
+
+fn parse_hello(record: &[u8]) -> Option<String> {
+    let mut r = Reader(record);
+    // ClientHello
+    if r.u8()? != 1 {
+        return None;
+    }
+    r.take(3)?;
+    // version and random
+    r.take(2 + 32)?;
+    let session_id_len = r.u8()? as usize;
+    r.take(session_id_len)?;
+    let cipher_suites_len = r.u16()? as usize;
+    r.take(cipher_suites_len)?;
+    let compression_len = r.u8()? as usize;
+    r.take(compression_len)?;
+    let extensions_len = r.u16()? as usize;
+    let mut exts = Reader(r.take(extensions_len)?);
+    while !exts.0.is_empty() {
+        let ext_type = exts.u16()?;
+        let ext_len = exts.u16()? as usize;
+        let ext = exts.take(ext_len)?;
+        if ext_type != 0 {
+            continue;
+        }
+        let mut ext = Reader(ext);
+        let list_len = ext.u16()? as usize;
+        let mut list = Reader(ext.take(list_len)?);
+        while !list.0.is_empty() {
+            let name_type = list.u8()?;
+            let name_len = list.u16()? as usize;
+            let name = list.take(name_len)?;
+            if name_type == 0 {
+                return String::from_utf8(name.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let b = self.take(2)?;
+        Some(u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_hello(sni: &str) -> Vec<u8> {
+        let name = sni.as_bytes();
+        let mut sni_ext = vec![];
+        sni_ext.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+        sni_ext.push(0);
+        sni_ext.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        sni_ext.extend_from_slice(name);
+        let mut exts = vec![0, 0];
+        exts.extend_from_slice(&(sni_ext.len() as u16).to_be_bytes());
+        exts.extend_from_slice(&sni_ext);
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0; 32]);
+        body.push(0);
+        body.extend_from_slice(&[0, 2, 0x13, 0x01]);
+        body.extend_from_slice(&[1, 0]);
+        body.extend_from_slice(&(exts.len() as u16).to_be_bytes());
+        body.extend_from_slice(&exts);
+        let mut hs = vec![1];
+        hs.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        hs.extend_from_slice(&body);
+        let mut record = vec![0x16, 3, 1];
+        record.extend_from_slice(&(hs.len() as u16).to_be_bytes());
+        record.extend_from_slice(&hs);
+        record
+    }
+
+    #[test]
+    fn sni_parsing() {
+        let hello = client_hello("www.example.com");
+        assert_eq!(parse_sni(&hello), SniParse::Found("www.example.com".into()));
+        assert_eq!(parse_sni(&hello[..hello.len() - 1]), SniParse::Incomplete);
+        assert_eq!(parse_sni(b"GET / HTTP/1.1\r\n"), SniParse::NotTls);
+    }
+
+    #[test]
+    fn rule_matching() {
+        let target: SocketAddr = "10.0.0.1:443".parse().unwrap();
+        let rule: RedirectRule = "sni=*.example.com:10.0.0.1:443".parse().unwrap();
+        assert_eq!(rule.matcher, RedirectMatch::Sni("*.example.com".into()));
+        let table = RedirectTable::new(vec![rule], None);
+        let addr: SocketAddr = "1.1.1.1:443".parse().unwrap();
+        assert_eq!(table.lookup(None, Some("cdn.Example.com"), addr), target);
+        assert_eq!(table.lookup(None, Some("example.com"), addr), target);
+        assert_eq!(table.lookup(None, Some("notexample.com"), addr), addr);
+        assert!("foo=bar:1.1.1.1:1".parse::<RedirectRule>().is_err());
+    }
+}
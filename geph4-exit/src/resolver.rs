@@ -0,0 +1,369 @@
+use std::{
+    io::Read,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use aioutils::AddrPreference;
+use anyhow::Context;
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::Mutex;
+use serde::Serialize;
+use smol_timeout::TimeoutExt;
+
+/// How long we wait for each upstream server to answer.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Bounds on how long answers are cached, whatever TTL they came with.
+const MIN_TTL: Duration = Duration::from_secs(10);
+const MAX_TTL: Duration = Duration::from_secs(3600);
+
+/// Most names cached at once.
+const CACHE_SIZE: usize = 65536;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+
+static UPSTREAM: OnceCell<Upstream> = OnceCell::new();
+
+static CACHE: Lazy<Mutex<lru::LruCache<String, (Vec<IpAddr>, Instant)>>> =
+    Lazy::new(|| Mutex::new(lru::LruCache::new(CACHE_SIZE)));
+
+static LOOKUPS: AtomicU64 = AtomicU64::new(0);
+static CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static FAILURES: AtomicU64 = AtomicU64::new(0);
+
+/// Where the names of tunneled destinations are resolved: "system" for the host's resolver, an https:// URL for a DNS-over-HTTPS server, or a comma-separated list of DNS servers, tried in order.
+#[derive(Debug, Clone)]
+pub enum Upstream {
+    System,
+    Servers(Vec<SocketAddr>),
+    Https(String),
+}
+
+impl FromStr for Upstream {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "system" {
+            return Ok(Upstream::System);
+        }
+        if s.starts_with("https://") {
+            return Ok(Upstream::Https(s.to_string()));
+        }
+        let servers = s
+            .split(',')
+            .map(|server| {
+                server
+                    .parse::<SocketAddr>()
+                    .or_else(|_| server.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+                    .with_context(|| format!("{:?} is not a DNS server address", server))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Upstream::Servers(servers))
+    }
+}
+
+impl std::fmt::Display for Upstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Upstream::System => "system".fmt(f),
+            Upstream::Servers(servers) => servers
+                .iter()
+                .map(|server| server.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+                .fmt(f),
+            Upstream::Https(url) => url.fmt(f),
+        }
+    }
+}
+
+impl Upstream {
+    /// Makes tunneled destinations be resolved through this upstream from now on.
+    pub fn set(self) {
+        let _ = UPSTREAM.set(self);
+    }
+
+    fn get() -> &'static Upstream {
+        UPSTREAM.get().unwrap_or(&Upstream::System)
+    }
+
+    async fn query(&self, name: &str, qtype: u16) -> anyhow::Result<(Vec<IpAddr>, u32)> {
+        let id = fastrand::u16(..);
+        let query = build_query(id, name, qtype)?;
+        let response = match self {
+            Upstream::System => unreachable!(),
+            Upstream::Servers(servers) => {
+                let mut last_err = anyhow::anyhow!("no DNS servers");
+                let mut response = None;
+                for server in servers {
+                    match query_udp(*server, &query).timeout(QUERY_TIMEOUT).await {
+                        Some(Ok(resp)) => {
+                            response = Some(resp);
+                            break;
+                        }
+                        Some(Err(err)) => last_err = err.into(),
+                        None => last_err = anyhow::anyhow!("{} timed out", server),
+                    }
+                }
+                response.ok_or(last_err)?
+            }
+            Upstream::Https(url) => query_https(url.clone(), query).await?,
+        };
+        parse_response(&response, id)
+    }
+}
+
+/// Statistics about the resolver, for the health server.
+#[derive(Serialize)]
+pub struct ResolverStats {
+    upstream: String,
+    lookups: u64,
+    cache_hits: u64,
+    failures: u64,
+    cached_names: usize,
+}
+
+/// Returns statistics about tunneled destination lookups since the exit started.
+pub fn stats() -> ResolverStats {
+    ResolverStats {
+        upstream: Upstream::get().to_string(),
+        lookups: LOOKUPS.load(Ordering::Relaxed),
+        cache_hits: CACHE_HITS.load(Ordering::Relaxed),
+        failures: FAILURES.load(Ordering::Relaxed),
+        cached_names: CACHE.lock().len(),
+    }
+}
+
+/// Resolves a tunneled destination, as "host:port", through the configured upstream, with the preferred family first.
+pub async fn resolve_preferring(
+    host_port: &str,
+    preference: AddrPreference,
+) -> std::io::Result<Vec<SocketAddr>> {
+    let mut addrs = resolve(host_port).await?;
+    preference.sort(&mut addrs);
+    Ok(addrs)
+}
+
+/// Resolves a tunneled destination, as "host:port", through the configured upstream.
+pub async fn resolve(host_port: &str) -> std::io::Result<Vec<SocketAddr>> {
+    LOOKUPS.fetch_add(1, Ordering::Relaxed);
+    let res = resolve_inner(host_port).await;
+    if res.is_err() {
+        FAILURES.fetch_add(1, Ordering::Relaxed);
+    }
+    res
+}
+
+async fn resolve_inner(host_port: &str) -> std::io::Result<Vec<SocketAddr>> {
+    let upstream = Upstream::get();
+    if let Upstream::System = upstream {
+        return aioutils::resolve(host_port).await;
+    }
+    let mut parts = host_port.rsplitn(2, ':');
+    let (port, host) = match (parts.next(), parts.next()) {
+        (Some(port), Some(host)) => (port, host.trim_start_matches('[').trim_end_matches(']')),
+        _ => return Err(aioutils::to_ioerror("no port in address")),
+    };
+    let port: u16 = port.parse().map_err(aioutils::to_ioerror)?;
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let name = host.to_ascii_lowercase();
+    let cached = CACHE
+        .lock()
+        .get(&name)
+        .filter(|(_, expiry)| *expiry > Instant::now())
+        .map(|(ips, _)| ips.clone());
+    let ips = match cached {
+        Some(ips) => {
+            CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+            ips
+        }
+        None => {
+            let (v4, v6) = smol::future::zip(
+                upstream.query(&name, TYPE_A),
+                upstream.query(&name, TYPE_AAAA),
+            )
+            .await;
+            let mut ips = Vec::new();
+            let mut ttl = MAX_TTL.as_secs() as u32;
+            for res in [v4, v6].iter() {
+                match res {
+                    Ok((found, found_ttl)) if !found.is_empty() => {
+                        ips.extend_from_slice(found);
+                        ttl = ttl.min(*found_ttl);
+                    }
+                    Ok(_) => {}
+                    Err(err) => log::debug!("cannot resolve {}: {}", name, err),
+                }
+            }
+            if ips.is_empty() {
+                return Err(aioutils::to_ioerror("no addresses found"));
+            }
+            let ttl = Duration::from_secs(ttl as u64).max(MIN_TTL);
+            CACHE.lock().put(name, (ips.clone(), Instant::now() + ttl));
+            ips
+        }
+    };
+    Ok(ips
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect())
+}
+
+async fn query_udp(server: SocketAddr, query: &[u8]) -> std::io::Result<Vec<u8>> {
+    let socket = if server.is_ipv4() {
+        smol::net::UdpSocket::bind("0.0.0.0:0").await?
+    } else {
+        smol::net::UdpSocket::bind("[::]:0").await?
+    };
+    socket.connect(server).await?;
+    socket.send(query).await?;
+    let mut buf = vec![0u8; 4096];
+    let n = socket.recv(&mut buf).await?;
+    buf.truncate(n);
+    Ok(buf)
+}
+
+async fn query_https(url: String, query: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    smol::unblock(move || {
+        let resp = ureq::post(&url)
+            .set("Content-Type", "application/dns-message")
+            .set("Accept", "application/dns-message")
+            .timeout(QUERY_TIMEOUT)
+            .send_bytes(&query);
+        if resp.status() != 200 {
+            anyhow::bail!("DNS-over-HTTPS server returned {}", resp.status())
+        }
+        let mut buf = Vec::new();
+        resp.into_reader().take(65536).read_to_end(&mut buf)?;
+        Ok(buf)
+    })
+    .await
+}
+
+/// Builds a recursive DNS query for one name.
+fn build_query(id: u16, name: &str, qtype: u16) -> anyhow::Result<Vec<u8>> {
+    let mut msg = Vec::with_capacity(name.len() + 18);
+    msg.extend_from_slice(&id.to_be_bytes());
+    // recursion desired, one question
+    msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            anyhow::bail!("{:?} is not a valid name", name)
+        }
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+    msg.extend_from_slice(&qtype.to_be_bytes());
+    msg.extend_from_slice(&[0, 1]);
+    Ok(msg)
+}
+
+/// Returns the position just past a possibly compressed name.
+fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(pos)? as usize;
+        if len == 0 {
+            return Some(pos + 1);
+        }
+        if len & 0xc0 == 0xc0 {
+            return Some(pos + 2);
+        }
+        pos += 1 + len;
+    }
+}
+
+/// Parses the answer to a query, returning the A and AAAA records in it and the smallest TTL among them. A name that doesn't exist has no records.
+fn parse_response(msg: &[u8], id: u16) -> anyhow::Result<(Vec<IpAddr>, u32)> {
+    let truncated = || anyhow::anyhow!("truncated DNS response");
+    let u16_at = |pos: usize| -> anyhow::Result<u16> {
+        let bytes = msg.get(pos..pos + 2).ok_or_else(truncated)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    if msg.len() < 12 || u16_at(0)? != id || msg[2] & 0x80 == 0 {
+        anyhow::bail!("not a response to our query")
+    }
+    match msg[3] & 0x0f {
+        0 => {}
+        3 => return Ok((Vec::new(), 0)),
+        rcode => anyhow::bail!("DNS server returned error {}", rcode),
+    }
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(msg, pos).ok_or_else(truncated)? + 4;
+    }
+    let mut ips = Vec::new();
+    let mut ttl = u32::MAX;
+    for _ in 0..answers {
+        pos = skip_name(msg, pos).ok_or_else(truncated)?;
+        let header = msg.get(pos..pos + 10).ok_or_else(truncated)?;
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let rttl = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        let rdlen = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let rdata = msg.get(pos + 10..pos + 10 + rdlen).ok_or_else(truncated)?;
+        let ip = match (rtype, rdlen) {
+            (TYPE_A, 4) => Some(IpAddr::V4(Ipv4Addr::new(
+                rdata[0], rdata[1], rdata[2], rdata[3],
+            ))),
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rdata);
+                Some(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            _ => None,
+        };
+        if let Some(ip) = ip {
+            ips.push(ip);
+            ttl = ttl.min(rttl);
+        }
+        pos += 10 + rdlen;
+    }
+    Ok((ips, ttl))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_compressed_answers() {
+        let mut msg = build_query(0x1234, "example.com", TYPE_A).unwrap();
+        msg[2] = 0x81;
+        msg[3] = 0x80;
+        msg[7] = 2;
+        // a CNAME, then an A record for it, both named by pointers to the question
+        msg.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 12]);
+        msg.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 1, 44, 0, 4, 93, 184, 216, 34]);
+        let (ips, ttl) = parse_response(&msg, 0x1234).unwrap();
+        assert_eq!(ips, vec![IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34))]);
+        assert_eq!(ttl, 300);
+        assert!(parse_response(&msg, 0x4321).is_err());
+        assert!(parse_response(&msg[..msg.len() - 1], 0x1234).is_err());
+    }
+
+    #[test]
+    fn parses_upstreams() {
+        match "1.1.1.1,[2606:4700::1111]:5353"
+            .parse::<Upstream>()
+            .unwrap()
+        {
+            Upstream::Servers(servers) => assert_eq!(
+                servers,
+                vec![
+                    "1.1.1.1:53".parse().unwrap(),
+                    "[2606:4700::1111]:5353".parse().unwrap()
+                ]
+            ),
+            other => panic!("parsed as {:?}", other),
+        }
+        assert!("dns.example".parse::<Upstream>().is_err());
+    }
+}
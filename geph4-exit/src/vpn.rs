@@ -11,6 +11,7 @@ use pnet_packet::{
 };
 use rand::prelude::*;
 
+use smol::io::AsyncWriteExt;
 use smol::Async;
 use smol_timeout::TimeoutExt;
 use std::os::unix::io::AsRawFd;
@@ -37,7 +38,6 @@ pub async fn transparent_proxy_helper(ctx: Arc<RootCtx>) -> anyhow::Result<()> {
         libc::listen(listener.get_ref().as_raw_fd(), 65536);
     }
 
-    let google_proxy = ctx.google_proxy;
     loop {
         let (client, _) = listener.accept().await.unwrap();
         let root = ctx.clone();
@@ -76,22 +76,20 @@ pub async fn transparent_proxy_helper(ctx: Arc<RootCtx>) -> anyhow::Result<()> {
                 return None;
             }
 
-            let asn = crate::asn::get_asn(addr.ip());
-            // log::debug!("helper got destination {} (AS{})", addr, asn);
-            let to_conn = if let Some(proxy) = google_proxy {
-                if addr.port() == 443 && asn == crate::asn::GOOGLE_ASN {
-                    proxy
-                } else {
-                    addr
-                }
+            let mut client = client;
+            let policy = root.policy();
+            let (prefix, sni) = if policy.redirects.needs_sni(addr.port()) {
+                crate::redirect::peek_sni(&mut client).await
             } else {
-                addr
+                (Vec::new(), None)
             };
+            let to_conn = policy.redirects.lookup(None, sni.as_deref(), addr);
 
-            let remote = smol::Async::<std::net::TcpStream>::connect(to_conn)
+            let mut remote = smol::Async::<std::net::TcpStream>::connect(to_conn)
                 .timeout(Duration::from_secs(60))
                 .await?
                 .ok()?;
+            remote.write_all(&prefix).await.ok()?;
             let remote2 = Async::new(remote.get_ref().try_clone().unwrap()).unwrap();
             let client2 = Async::new(client.get_ref().try_clone().unwrap()).unwrap();
             // remote.set_nodelay(true).ok()?;
@@ -113,7 +111,7 @@ pub async fn handle_vpn_session(
     mux: Arc<sosistab::mux::Multiplex>,
     exit_hostname: String,
     stat_client: Arc<statsd::Client>,
-    port_whitelist: bool,
+    root: Arc<RootCtx>,
 ) -> anyhow::Result<()> {
     Lazy::force(&INCOMING_PKT_HANDLER);
     log::debug!("handle_vpn_session entered");
@@ -189,7 +187,9 @@ pub async fn handle_vpn_session(
                         if crate::lists::BLACK_PORTS.contains(&port) {
                             continue;
                         }
-                        if port_whitelist && !crate::lists::WHITE_PORTS.contains(&port) {
+                        if root.policy().port_whitelist
+                            && !crate::lists::WHITE_PORTS.contains(&port)
+                        {
                             continue;
                         }
                     }
@@ -0,0 +1,170 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Smallest copy buffer, which idle and slow connections use.
+pub const MIN_COPY_BUFFER: usize = 8192;
+
+/// Largest copy buffer, so that even many fast connections have bounded memory use.
+pub const MAX_COPY_BUFFER: usize = 262144;
+
+/// Bytes in all copy buffers right now, and how many there may be before buffers stop growing.
+static COPY_BUFFER_BYTES: AtomicUsize = AtomicUsize::new(0);
+static COPY_BUDGET: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Sets how many bytes all copy buffers together may take. Buffers don't grow past [MIN_COPY_BUFFER] while that would go over the budget, so busy connections then copy in smaller reads, rather than memory use growing with the number of connections. Every copy still gets a buffer of [MIN_COPY_BUFFER], even over the budget.
+pub fn set_copy_budget(bytes: usize) {
+    COPY_BUDGET.store(bytes, Ordering::Relaxed)
+}
+
+/// Bytes in all copy buffers right now.
+pub fn copy_buffer_bytes() -> usize {
+    COPY_BUFFER_BYTES.load(Ordering::Relaxed)
+}
+
+/// A copy buffer, counted against the copy budget for as long as it exists.
+pub(crate) struct CopyBuffer(Vec<u8>);
+
+impl CopyBuffer {
+    pub fn new(size: usize) -> Self {
+        COPY_BUFFER_BYTES.fetch_add(size, Ordering::Relaxed);
+        Self(vec![0u8; size])
+    }
+
+    /// Replaces the buffer with one of the given size, unless it would grow past what the budget allows. Returns the size it ended up.
+    pub fn resize(&mut self, size: usize) -> usize {
+        let grow = size.saturating_sub(self.0.len());
+        if grow > 0 && copy_buffer_bytes() + grow > COPY_BUDGET.load(Ordering::Relaxed) {
+            return self.0.len();
+        }
+        *self = Self::new(size);
+        size
+    }
+}
+
+impl Drop for CopyBuffer {
+    fn drop(&mut self) {
+        COPY_BUFFER_BYTES.fetch_sub(self.0.len(), Ordering::Relaxed);
+    }
+}
+
+impl Deref for CopyBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl DerefMut for CopyBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+/// How much data the buffer should hold, in terms of how long it takes to arrive at the observed throughput.
+const BUFFERED_TIME: Duration = Duration::from_millis(10);
+
+/// How often the buffer size is reconsidered.
+const SIZING_WINDOW: Duration = Duration::from_millis(100);
+
+/// Sizes the buffer of a copy from how fast data has been going through it. The size doubles or halves at most once per window, so that a burst doesn't balloon it.
+pub(crate) struct BufferSizer {
+    size: usize,
+    window_start: Instant,
+    window_bytes: usize,
+}
+
+impl BufferSizer {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            size: MIN_COPY_BUFFER,
+            window_start: now,
+            window_bytes: 0,
+        }
+    }
+
+    /// The size the buffer should be now.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Accounts for a read of the given length, returning the new size.
+    pub fn on_read(&mut self, n: usize, now: Instant) -> usize {
+        self.window_bytes += n;
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed >= SIZING_WINDOW {
+            let target = (self.window_bytes as f64 * BUFFERED_TIME.as_secs_f64()
+                / elapsed.as_secs_f64()) as usize;
+            if target > self.size {
+                self.size = (self.size * 2).min(MAX_COPY_BUFFER);
+            } else if target < self.size / 4 {
+                self.size = (self.size / 2).max(MIN_COPY_BUFFER);
+            }
+            self.window_start = now;
+            self.window_bytes = 0;
+        }
+        self.size
+    }
+
+    /// Holds the size where it is, for when the buffer couldn't grow.
+    pub fn hold(&mut self, size: usize) {
+        self.size = size;
+    }
+
+    /// Goes back to the smallest size, for when the connection has gone idle.
+    pub fn on_idle(&mut self, now: Instant) {
+        self.size = MIN_COPY_BUFFER;
+        self.window_start = now;
+        self.window_bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grows_with_throughput_and_shrinks_when_idle() {
+        let mut now = Instant::now();
+        let mut sizer = BufferSizer::new(now);
+        // a second of 100 MB/s, in full-buffer reads
+        let end = now + Duration::from_secs(1);
+        while now < end {
+            let n = sizer.size();
+            now += Duration::from_secs_f64(n as f64 / 100e6);
+            sizer.on_read(n, now);
+        }
+        assert_eq!(sizer.size(), MAX_COPY_BUFFER);
+        // 1 MB/s wants about 10 KB, so the buffer halves until that's more than a quarter of it
+        for _ in 0..100 {
+            now += Duration::from_millis(10);
+            sizer.on_read(10_000, now);
+        }
+        assert_eq!(sizer.size(), 32768);
+        sizer.on_idle(now);
+        assert_eq!(sizer.size(), MIN_COPY_BUFFER);
+    }
+
+    #[test]
+    fn buffers_stay_within_budget() {
+        set_copy_budget(2 * MIN_COPY_BUFFER);
+        let mut first = CopyBuffer::new(MIN_COPY_BUFFER);
+        let mut second = CopyBuffer::new(MIN_COPY_BUFFER);
+        assert_eq!(copy_buffer_bytes(), 2 * MIN_COPY_BUFFER);
+        // neither can grow while both are around, but shrinking always works
+        assert_eq!(first.resize(MAX_COPY_BUFFER), MIN_COPY_BUFFER);
+        assert_eq!(second.resize(MIN_COPY_BUFFER / 2), MIN_COPY_BUFFER / 2);
+        assert_eq!(
+            first.resize(MIN_COPY_BUFFER * 3 / 2),
+            MIN_COPY_BUFFER * 3 / 2
+        );
+        drop(second);
+        assert_eq!(first.resize(2 * MIN_COPY_BUFFER), 2 * MIN_COPY_BUFFER);
+        drop(first);
+        assert_eq!(copy_buffer_bytes(), 0);
+        set_copy_budget(usize::MAX);
+    }
+}
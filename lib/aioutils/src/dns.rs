@@ -37,3 +37,88 @@ pub async fn resolve(host_port: &str) -> std::io::Result<Vec<SocketAddr>> {
 pub async fn resolve_inner(host_port: String) -> std::io::Result<Vec<SocketAddr>> {
     smol::net::resolve(host_port).await
 }
+
+/// Which address family to connect over when a name resolves to both IPv4 and IPv6 addresses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum AddrPreference {
+    /// Whatever order the resolver returns.
+    Default,
+    /// IPv4 addresses first.
+    Ipv4,
+    /// IPv6 addresses first.
+    Ipv6,
+}
+
+impl AddrPreference {
+    /// Sorts addresses so that the preferred family comes first, keeping the resolver's order otherwise. Addresses of the other family are kept as a fallback.
+    pub fn sort(self, addrs: &mut [SocketAddr]) {
+        match self {
+            AddrPreference::Default => {}
+            AddrPreference::Ipv4 => addrs.sort_by_key(|addr| !addr.is_ipv4()),
+            AddrPreference::Ipv6 => addrs.sort_by_key(|addr| !addr.is_ipv6()),
+        }
+    }
+
+    /// Tags a "host:port" connection label with this preference. Untagged labels mean [AddrPreference::Default], so that exits that don't understand tags see the same labels as before.
+    pub fn tag(self, host_port: &str) -> String {
+        match self {
+            AddrPreference::Default => host_port.to_string(),
+            AddrPreference::Ipv4 => format!("{}#ipv4", host_port),
+            AddrPreference::Ipv6 => format!("{}#ipv6", host_port),
+        }
+    }
+
+    /// Splits a possibly tagged connection label into the "host:port" and the preference.
+    pub fn untag(label: &str) -> (&str, Self) {
+        if let Some(host_port) = label.strip_suffix("#ipv4") {
+            (host_port, AddrPreference::Ipv4)
+        } else if let Some(host_port) = label.strip_suffix("#ipv6") {
+            (host_port, AddrPreference::Ipv6)
+        } else {
+            (label, AddrPreference::Default)
+        }
+    }
+}
+
+/// Resolves a string into a vector of SocketAddrs, with the preferred family first.
+pub async fn resolve_preferring(
+    host_port: &str,
+    preference: AddrPreference,
+) -> std::io::Result<Vec<SocketAddr>> {
+    let mut addrs = resolve(host_port).await?;
+    preference.sort(&mut addrs);
+    Ok(addrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preference_labels() {
+        for pref in [
+            AddrPreference::Default,
+            AddrPreference::Ipv4,
+            AddrPreference::Ipv6,
+        ]
+        .iter()
+        {
+            let label = pref.tag("example.com:443");
+            assert_eq!(AddrPreference::untag(&label), ("example.com:443", *pref));
+        }
+        assert_eq!(AddrPreference::Default.tag("[::1]:80"), "[::1]:80");
+    }
+
+    #[test]
+    fn preference_sort() {
+        let v4: SocketAddr = "1.2.3.4:80".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:80".parse().unwrap();
+        let mut addrs = vec![v4, v6];
+        AddrPreference::Ipv6.sort(&mut addrs);
+        assert_eq!(addrs, vec![v6, v4]);
+        AddrPreference::Default.sort(&mut addrs);
+        assert_eq!(addrs, vec![v6, v4]);
+        AddrPreference::Ipv4.sort(&mut addrs);
+        assert_eq!(addrs, vec![v4, v6]);
+    }
+}
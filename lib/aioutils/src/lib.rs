@@ -1,17 +1,30 @@
-use std::{io::Read, pin::Pin, time::Duration};
+use std::{
+    io::Read,
+    pin::Pin,
+    time::{Duration, Instant},
+};
 
 use concurrent_queue::ConcurrentQueue;
 use once_cell::sync::Lazy;
 use serde::{de::DeserializeOwned, Serialize};
 use smol::{channel::Receiver, prelude::*};
 
+mod copybuf;
 mod dns;
+pub use copybuf::{copy_buffer_bytes, set_copy_budget, MAX_COPY_BUFFER, MIN_COPY_BUFFER};
 pub use dns::*;
 
 /// Reads a bincode-deserializable value with a 16bbe length
 pub async fn read_pascalish<T: DeserializeOwned>(
     reader: &mut (impl AsyncRead + Unpin),
 ) -> anyhow::Result<T> {
+    let true_buf = read_pascalish_raw(reader).await?;
+    // then deserialize
+    Ok(bincode::deserialize(&true_buf)?)
+}
+
+/// Reads the raw bytes of a value with a 16bbe length, for when the type of the value isn't known in advance
+pub async fn read_pascalish_raw(reader: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Vec<u8>> {
     // first read 2 bytes as length
     let mut len_bts = [0u8; 2];
     reader.read_exact(&mut len_bts).await?;
@@ -19,8 +32,7 @@ pub async fn read_pascalish<T: DeserializeOwned>(
     // then read len
     let mut true_buf = vec![0u8; len as usize];
     reader.read_exact(&mut true_buf).await?;
-    // then deserialize
-    Ok(bincode::deserialize(&true_buf)?)
+    Ok(true_buf)
 }
 
 /// Writes a bincode-serializable value with a 16bbe length
@@ -85,27 +97,49 @@ pub async fn copy_socket_to_with_stats(
     }
 }
 
-/// Copies an AsyncRead to an AsyncWrite, with a callback for every write.
-#[inline]
+/// How long a connection can go without anything to read before its copy buffer goes back to the smallest size.
+const BUFFER_IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Copies an AsyncRead to an AsyncWrite, with a callback for every write. The buffer grows with the throughput, between [MIN_COPY_BUFFER] and [MAX_COPY_BUFFER], so that bulk transfers over fast, high-latency paths aren't held back by small reads, and shrinks back once the connection goes idle. It doesn't grow past what's left of the budget set by [set_copy_budget].
 pub async fn copy_with_stats(
     mut reader: impl AsyncRead + Unpin,
     mut writer: impl AsyncWrite + Unpin,
     mut on_write: impl FnMut(usize),
 ) -> std::io::Result<()> {
-    let mut buffer = [0u8; 8192];
+    let mut sizer = copybuf::BufferSizer::new(Instant::now());
+    let mut buffer = copybuf::CopyBuffer::new(sizer.size());
     let mut timeout = smol::Timer::after(IDLE_TIMEOUT);
     loop {
-        // first read into the small buffer
-        let n = reader
-            .read(&mut buffer)
-            .or(async {
-                (&mut timeout).await;
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::TimedOut,
-                    "copy_with_stats timeout",
-                ))
-            })
-            .await?;
+        // a big buffer isn't kept around while waiting on an idle connection. reads are cancel-safe, so nothing is lost by giving up on one.
+        let n = if buffer.len() > MIN_COPY_BUFFER {
+            async { reader.read(&mut buffer).await.map(Some) }
+                .or(async {
+                    smol::Timer::after(BUFFER_IDLE_TIMEOUT).await;
+                    Ok(None)
+                })
+                .await?
+        } else {
+            None
+        };
+        let n = match n {
+            Some(n) => n,
+            None => {
+                if buffer.len() > MIN_COPY_BUFFER {
+                    sizer.on_idle(Instant::now());
+                    buffer.resize(sizer.size());
+                }
+                reader
+                    .read(&mut buffer)
+                    .or(async {
+                        (&mut timeout).await;
+                        Err(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            "copy_with_stats timeout",
+                        ))
+                    })
+                    .await?
+            }
+        };
         if n == 0 {
             return Ok(());
         }
@@ -121,6 +155,11 @@ pub async fn copy_with_stats(
                 ))
             })
             .await?;
+        let size = sizer.on_read(n, Instant::now());
+        if size != buffer.len() {
+            let size = buffer.resize(size);
+            sizer.hold(size);
+        }
     }
 }
 
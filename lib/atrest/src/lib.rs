@@ -0,0 +1,121 @@
+//! Passphrase-based encryption for secrets kept on disk, such as an exit's signing key or a client's cached credentials.
+//!
+//! Keys are derived from the passphrase with Argon2id, and data is sealed with XChaCha20-Poly1305. Encryption protects secrets if the disk is stolen or copied, but someone then has to supply the passphrase every time the program starts, either interactively or through the environment. Unattended machines that keep the passphrase in their environment or a startup script gain little, which is why everything stays unencrypted unless asked for.
+use chacha20poly1305::{
+    aead::{Aead, NewAead},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use rand::prelude::*;
+
+/// Environment variable that supplies the passphrase, instead of prompting for it.
+pub const PASSPHRASE_ENV: &str = "GEPH_PASSPHRASE";
+
+/// Marks sealed data, so that it can be told apart from plaintext left from before encryption was turned on.
+const MAGIC: &[u8; 8] = b"GEPHSEAL";
+const NONCE_LEN: usize = 24;
+const SALT_LEN: usize = 16;
+
+/// Seals and opens data with a key derived from a passphrase.
+pub struct Sealer {
+    cipher: XChaCha20Poly1305,
+}
+
+impl Sealer {
+    /// Derives a key from the passphrase and salt. This is deliberately slow, so do it once and reuse the Sealer.
+    pub fn new(passphrase: &str, salt: &[u8]) -> anyhow::Result<Self> {
+        let config = argon2::Config {
+            variant: argon2::Variant::Argon2id,
+            hash_length: 32,
+            ..Default::default()
+        };
+        let key = argon2::hash_raw(passphrase.as_bytes(), salt, &config)?;
+        Ok(Self {
+            cipher: XChaCha20Poly1305::new(Key::from_slice(&key)),
+        })
+    }
+
+    /// Generates a fresh random salt.
+    pub fn new_salt() -> Vec<u8> {
+        let mut salt = vec![0u8; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        salt
+    }
+
+    /// Seals some data.
+    pub fn seal(&self, plain: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = self
+            .cipher
+            .encrypt(XNonce::from_slice(&nonce), plain)
+            .expect("encryption cannot fail");
+        let mut sealed = Vec::with_capacity(MAGIC.len() + NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(MAGIC);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Opens sealed data, failing if it wasn't sealed with the same passphrase or was tampered with.
+    pub fn open(&self, sealed: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if !is_sealed(sealed) || sealed.len() < MAGIC.len() + NONCE_LEN {
+            anyhow::bail!("data is not sealed")
+        }
+        let (nonce, ciphertext) = sealed[MAGIC.len()..].split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("wrong passphrase, or corrupted data"))
+    }
+}
+
+/// Whether the data looks sealed, rather than plaintext.
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Seals data on its own, storing the salt alongside, as for a single file.
+pub fn seal_standalone(passphrase: &str, plain: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let salt = Sealer::new_salt();
+    let mut out = Sealer::new(passphrase, &salt)?.seal(plain);
+    out.extend_from_slice(&salt);
+    Ok(out)
+}
+
+/// Opens data sealed by [seal_standalone].
+pub fn open_standalone(passphrase: &str, sealed: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if sealed.len() < SALT_LEN {
+        anyhow::bail!("data is not sealed")
+    }
+    let (sealed, salt) = sealed.split_at(sealed.len() - SALT_LEN);
+    Sealer::new(passphrase, salt)?.open(sealed)
+}
+
+/// Gets the passphrase from the environment, or failing that, by prompting on the terminal.
+pub fn passphrase(what: &str) -> anyhow::Result<String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    let passphrase =
+        rpassword::read_password_from_tty(Some(&format!("passphrase for {}: ", what)))?;
+    if passphrase.is_empty() {
+        anyhow::bail!("empty passphrase")
+    }
+    Ok(passphrase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_roundtrip() {
+        let sealed = seal_standalone("hunter2", b"secret key").unwrap();
+        assert!(is_sealed(&sealed));
+        assert_eq!(
+            open_standalone("hunter2", &sealed).unwrap(),
+            b"secret key".to_vec()
+        );
+        assert!(open_standalone("hunter3", &sealed).is_err());
+        assert!(!is_sealed(b"secret key"));
+    }
+}
@@ -5,10 +5,14 @@ use crate::{
 };
 use async_tls::TlsConnector;
 use http_types::{Method, Request, StatusCode, Url};
+use sha2::{Digest, Sha256};
 use smol::channel::{Receiver, Sender};
 use smol_timeout::TimeoutExt;
 use std::{
+    future::Future,
     net::SocketAddr,
+    pin::Pin,
+    str::FromStr,
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -19,6 +23,99 @@ pub struct HttpClient {
     binder_lpk: x25519_dalek::PublicKey,
     endpoint: String,
     headers: Vec<(String, String)>,
+    tls_pins: Vec<[u8; 32]>,
+    tls_fingerprint: TlsFingerprint,
+    dialer: Option<Dialer>,
+    socks_proxy: Option<SocketAddr>,
+}
+
+/// What the TLS ClientHello of connections to the binder looks like, so that it can blend in with browsers rather than stand out as an unusual client.
+///
+/// This only reorders cipher suites and sets ALPN, which is as far as rustls lets a ClientHello be changed. The extensions rustls sends, and their order, still differ from any browser's, so a careful enough fingerprinter (JA3 included) can tell these apart from the real thing. It makes blocking the binder connection by an exact fingerprint harder, nothing more.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlsFingerprint {
+    /// Whatever rustls sends by default.
+    Default,
+    /// Cipher suites in the order Chrome offers them.
+    Chrome,
+    /// Cipher suites in the order Firefox offers them.
+    Firefox,
+    /// Chrome or Firefox, picked afresh for each connection.
+    Rotate,
+}
+
+impl FromStr for TlsFingerprint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(TlsFingerprint::Default),
+            "chrome" => Ok(TlsFingerprint::Chrome),
+            "firefox" => Ok(TlsFingerprint::Firefox),
+            "rotate" => Ok(TlsFingerprint::Rotate),
+            other => Err(format!(
+                "unknown TLS fingerprint {:?} (expected default, chrome, firefox, or rotate)",
+                other
+            )),
+        }
+    }
+}
+
+impl TlsFingerprint {
+    /// Changes the given config to send this fingerprint.
+    fn apply(self, config: &mut rustls::ClientConfig) {
+        use rustls::ciphersuite::*;
+        let suites: Vec<&'static rustls::SupportedCipherSuite> = match self {
+            TlsFingerprint::Default => return,
+            TlsFingerprint::Rotate => {
+                return if rand::random() {
+                    TlsFingerprint::Chrome
+                } else {
+                    TlsFingerprint::Firefox
+                }
+                .apply(config)
+            }
+            TlsFingerprint::Chrome => vec![
+                &TLS13_AES_128_GCM_SHA256,
+                &TLS13_AES_256_GCM_SHA384,
+                &TLS13_CHACHA20_POLY1305_SHA256,
+                &TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+                &TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+                &TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+                &TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+                &TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+                &TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+            ],
+            TlsFingerprint::Firefox => vec![
+                &TLS13_AES_128_GCM_SHA256,
+                &TLS13_CHACHA20_POLY1305_SHA256,
+                &TLS13_AES_256_GCM_SHA384,
+                &TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+                &TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+                &TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+                &TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+                &TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+                &TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+            ],
+        };
+        config.ciphersuites = suites;
+        // browsers would offer h2 first, but we only speak HTTP/1.1, and a front that picked h2 would break the request
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    }
+}
+
+/// A custom way of opening the underlying connection to the binder, given a "host:port" string. Returning `Ok(None)` falls back to connecting directly.
+#[derive(Clone)]
+pub struct Dialer(pub Arc<dyn Fn(String) -> DialFuture + Send + Sync>);
+
+/// The future returned by a `Dialer`.
+pub type DialFuture =
+    Pin<Box<dyn Future<Output = std::io::Result<Option<aioutils::ConnLike>>> + Send>>;
+
+impl std::fmt::Debug for Dialer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Dialer")
+    }
 }
 
 impl HttpClient {
@@ -35,8 +132,93 @@ impl HttpClient {
                 .iter()
                 .map(|(k, v)| (k.to_string(), v.to_string()))
                 .collect(),
+            tls_pins: Vec::new(),
+            tls_fingerprint: TlsFingerprint::Default,
+            dialer: None,
+            socks_proxy: None,
         }
     }
+
+    /// Opens connections to the endpoint with the given dialer, rather than always connecting directly. TLS still happens end-to-end over whatever the dialer returns.
+    pub fn with_dialer(mut self, dialer: Dialer) -> Self {
+        self.dialer = Some(dialer);
+        self
+    }
+
+    /// Connects to the endpoint through the SOCKS5 proxy at the given address, rather than directly, for testing from isolated networks or bootstrapping over another circuit. The proxy must not require authentication. A dialer, if there is one, still goes first, and the proxy replaces only the direct connection it falls back to. If the proxy can't be reached, requests fail rather than going around it.
+    pub fn with_socks_proxy(mut self, proxy: SocketAddr) -> Self {
+        self.socks_proxy = Some(proxy);
+        self
+    }
+
+    /// Pins the TLS certificate of the endpoint, on top of the usual CA validation. The connection only succeeds if the SHA-256 hash of the leaf certificate is one of the given pins. An empty list disables pinning.
+    pub fn with_tls_pins(mut self, pins: &[[u8; 32]]) -> Self {
+        self.tls_pins = pins.to_vec();
+        self
+    }
+
+    /// Makes the TLS ClientHello look like that of the given browser. See [TlsFingerprint] for how far this goes.
+    pub fn with_tls_fingerprint(mut self, fingerprint: TlsFingerprint) -> Self {
+        self.tls_fingerprint = fingerprint;
+        self
+    }
+}
+
+/// A certificate verifier that does normal WebPKI validation, then additionally checks the leaf certificate against a set of pins.
+struct PinnedVerifier {
+    inner: rustls::WebPKIVerifier,
+    pins: Vec<[u8; 32]>,
+}
+
+impl rustls::ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &rustls::RootCertStore,
+        presented_certs: &[rustls::Certificate],
+        dns_name: webpki::DNSNameRef<'_>,
+        ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        let verified =
+            self.inner
+                .verify_server_cert(roots, presented_certs, dns_name, ocsp_response)?;
+        let leaf = presented_certs
+            .first()
+            .ok_or(rustls::TLSError::NoCertificatesPresented)?;
+        let mut leaf_hash = [0u8; 32];
+        leaf_hash.copy_from_slice(&Sha256::digest(&leaf.0));
+        if self.pins.contains(&leaf_hash) {
+            Ok(verified)
+        } else {
+            log::warn!(
+                "TLS certificate {} does not match any pin",
+                hex::encode(&leaf_hash)
+            );
+            Err(rustls::TLSError::General(
+                "certificate does not match any pin".into(),
+            ))
+        }
+    }
+}
+
+/// Creates a TLS connector, pinned to the given certificate hashes if there are any, and sending the given fingerprint.
+fn tls_connector(pins: &[[u8; 32]], fingerprint: TlsFingerprint) -> TlsConnector {
+    if pins.is_empty() && fingerprint == TlsFingerprint::Default {
+        return TlsConnector::default();
+    }
+    let mut config = rustls::ClientConfig::new();
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    if !pins.is_empty() {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(PinnedVerifier {
+                inner: rustls::WebPKIVerifier::new(),
+                pins: pins.to_vec(),
+            }));
+    }
+    fingerprint.apply(&mut config);
+    TlsConnector::from(Arc::new(config))
 }
 
 #[async_trait::async_trait]
@@ -44,9 +226,15 @@ impl BinderClient for HttpClient {
     async fn request(&self, brequest: BinderRequestData) -> BinderResult<BinderResponse> {
         let everything = async move {
             // open connection
-            let conn = endpoint_to_conn(&self.endpoint)
-                .await
-                .map_err(|v| BinderError::Other(v.to_string()))?;
+            let conn = endpoint_to_conn(
+                &self.endpoint,
+                &self.tls_pins,
+                self.tls_fingerprint,
+                self.dialer.as_ref(),
+                self.socks_proxy,
+            )
+            .await
+            .map_err(|v| BinderError::Other(v.to_string()))?;
             // send request
             let mut req = Request::new(Method::Post, Url::parse(&self.endpoint).unwrap());
             for (header, value) in self.headers.iter() {
@@ -112,7 +300,13 @@ impl HttpServer {
 }
 
 /// Returns a connection, given an endpoint. Implements a happy-eyeballs-style thing.
-async fn endpoint_to_conn(endpoint: &str) -> std::io::Result<aioutils::ConnLike> {
+async fn endpoint_to_conn(
+    endpoint: &str,
+    tls_pins: &[[u8; 32]],
+    tls_fingerprint: TlsFingerprint,
+    dialer: Option<&Dialer>,
+    socks_proxy: Option<SocketAddr>,
+) -> std::io::Result<aioutils::ConnLike> {
     let url = Url::parse(endpoint).map_err(aioutils::to_ioerror)?;
     let host_string = url
         .host_str()
@@ -120,6 +314,29 @@ async fn endpoint_to_conn(endpoint: &str) -> std::io::Result<aioutils::ConnLike>
         .ok_or_else(|| aioutils::to_ioerror("no host"))?;
     let port = url.port_or_known_default().unwrap_or(0);
     let composed = format!("{}:{}", host_string, port);
+    if let Some(dialer) = dialer {
+        if let Some(conn) = (dialer.0)(composed.clone()).await? {
+            return match url.scheme() {
+                "https" => {
+                    let connector = tls_connector(tls_pins, tls_fingerprint);
+                    let tls_conn = connector.connect(host_string, conn).await?;
+                    Ok(aioutils::connify(tls_conn))
+                }
+                _ => Ok(conn),
+            };
+        }
+    }
+    if let Some(proxy) = socks_proxy {
+        let tcp_conn = crate::socks::socks5_connect(proxy, &composed).await?;
+        return match url.scheme() {
+            "https" => {
+                let connector = tls_connector(tls_pins, tls_fingerprint);
+                let tls_conn = connector.connect(host_string, tcp_conn).await?;
+                Ok(aioutils::connify(tls_conn))
+            }
+            _ => Ok(aioutils::connify(tcp_conn)),
+        };
+    }
     let (send, recv) = smol::channel::unbounded();
     let mut _tasks: Vec<smol::Task<std::io::Result<()>>> = vec![];
     // race
@@ -136,7 +353,7 @@ async fn endpoint_to_conn(endpoint: &str) -> std::io::Result<aioutils::ConnLike>
     if let Ok(tcp_conn) = recv.recv().await {
         match url.scheme() {
             "https" => {
-                let connector = TlsConnector::default();
+                let connector = tls_connector(tls_pins, tls_fingerprint);
                 let tls_conn = connector.connect(host_string, tcp_conn).await?;
                 Ok(aioutils::connify(tls_conn))
             }
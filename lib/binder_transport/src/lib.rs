@@ -9,7 +9,11 @@ use smol_timeout::TimeoutExt;
 pub use wiretypes::*;
 mod http;
 pub use http::*;
+mod master_sig;
+mod socks;
+pub use master_sig::*;
 use rand::prelude::*;
+pub use socks::probe_socks5;
 
 /// Trait that all binder clients implement.
 #[async_trait::async_trait]
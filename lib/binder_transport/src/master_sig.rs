@@ -0,0 +1,46 @@
+//! Signatures by the binder's x25519 master key.
+//!
+//! Clients know the binder only by its x25519 master public key, which can't check signatures as it is. So, as in XEdDSA, the master secret doubles as an ed25519 signing key. Every x25519 public key corresponds to two ed25519 public keys, and the one whose sign bit is clear is used, with the secret negated where needed to match it.
+use curve25519_dalek::{
+    constants::ED25519_BASEPOINT_TABLE, montgomery::MontgomeryPoint, scalar::Scalar,
+};
+use ed25519_dalek::{ExpandedSecretKey, Signature};
+
+/// Signs a message with the binder's master secret.
+pub fn master_sign(master_sk: &x25519_dalek::StaticSecret, msg: &[u8]) -> Signature {
+    let mut bits = master_sk.to_bytes();
+    // x25519 clamps secrets when using them, so the signing key must be clamped the same way
+    bits[0] &= 248;
+    bits[31] &= 127;
+    bits[31] |= 64;
+    let mut secret = Scalar::from_bits(bits);
+    let mut public = &secret * &ED25519_BASEPOINT_TABLE;
+    if public.compress().as_bytes()[31] & 0x80 != 0 {
+        secret = -secret;
+        public = -public;
+    }
+    // nonces are deterministic like in plain ed25519, but derived from the x25519 secret
+    let nonce_prefix = blake3::keyed_hash(blake3::hash(b"master_sign").as_bytes(), &bits);
+    let mut expanded = [0u8; 64];
+    expanded[..32].copy_from_slice(secret.as_bytes());
+    expanded[32..].copy_from_slice(nonce_prefix.as_bytes());
+    let expanded = ExpandedSecretKey::from_bytes(&expanded).unwrap();
+    let public = ed25519_dalek::PublicKey::from_bytes(public.compress().as_bytes()).unwrap();
+    expanded.sign(msg, &public)
+}
+
+/// Checks a signature made by [master_sign] against the binder's master public key.
+pub fn master_verify(
+    master_pk: &x25519_dalek::PublicKey,
+    msg: &[u8],
+    signature: &Signature,
+) -> bool {
+    let public = match MontgomeryPoint(*master_pk.as_bytes()).to_edwards(0) {
+        Some(public) => public,
+        None => return false,
+    };
+    match ed25519_dalek::PublicKey::from_bytes(public.compress().as_bytes()) {
+        Ok(public) => public.verify_strict(msg, signature).is_ok(),
+        Err(_) => false,
+    }
+}
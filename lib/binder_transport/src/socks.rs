@@ -0,0 +1,126 @@
+use smol::{net::TcpStream, prelude::*};
+use smol_timeout::TimeoutExt;
+use std::{
+    io::{Error, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+/// How long the proxy gets to answer each step of the handshake.
+const PROXY_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn proxy_error(proxy: SocketAddr, msg: impl std::fmt::Display) -> Error {
+    Error::new(ErrorKind::Other, format!("SOCKS5 proxy {}: {}", proxy, msg))
+}
+
+/// Connects to the proxy and agrees on not authenticating, which is all that's supported.
+async fn greet(proxy: SocketAddr) -> std::io::Result<TcpStream> {
+    let mut conn = TcpStream::connect(proxy)
+        .timeout(PROXY_TIMEOUT)
+        .await
+        .ok_or_else(|| proxy_error(proxy, "timed out connecting"))?
+        .map_err(|err| proxy_error(proxy, format!("cannot connect: {}", err)))?;
+    conn.write_all(&[5, 1, 0]).await?;
+    let mut reply = [0u8; 2];
+    conn.read_exact(&mut reply)
+        .timeout(PROXY_TIMEOUT)
+        .await
+        .ok_or_else(|| proxy_error(proxy, "timed out waiting for greeting"))?
+        .map_err(|err| proxy_error(proxy, format!("no greeting: {}", err)))?;
+    match reply {
+        [5, 0] => Ok(conn),
+        [5, _] => Err(proxy_error(
+            proxy,
+            "requires authentication, which isn't supported",
+        )),
+        _ => Err(proxy_error(proxy, "doesn't speak SOCKS5")),
+    }
+}
+
+/// Checks that there's a SOCKS5 proxy at the address that can be used without authentication, so that a wrong address shows up on startup rather than as failing binder requests.
+pub async fn probe_socks5(proxy: SocketAddr) -> std::io::Result<()> {
+    greet(proxy).await?;
+    Ok(())
+}
+
+/// Opens a connection to the "host:port" destination through the SOCKS5 proxy. Host names are resolved by the proxy, so no DNS queries for the destination leave this machine.
+pub(crate) async fn socks5_connect(proxy: SocketAddr, dest: &str) -> std::io::Result<TcpStream> {
+    let mut request = vec![5, 1, 0];
+    if let Ok(addr) = dest.parse::<SocketAddr>() {
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                request.push(1);
+                request.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                request.push(4);
+                request.extend_from_slice(&ip.octets());
+            }
+        }
+        request.extend_from_slice(&addr.port().to_be_bytes());
+    } else {
+        let (host, port) = dest
+            .rfind(':')
+            .and_then(|idx| Some((&dest[..idx], dest[idx + 1..].parse::<u16>().ok()?)))
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "destination isn't host:port"))?;
+        if host.is_empty() || host.len() > 255 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "destination host name is empty or too long",
+            ));
+        }
+        request.push(3);
+        request.push(host.len() as u8);
+        request.extend_from_slice(host.as_bytes());
+        request.extend_from_slice(&port.to_be_bytes());
+    }
+
+    let mut conn = greet(proxy).await?;
+    conn.write_all(&request).await?;
+    let connected = async {
+        let mut header = [0u8; 4];
+        conn.read_exact(&mut header).await?;
+        if header[0] != 5 {
+            return Err(proxy_error(proxy, "doesn't speak SOCKS5"));
+        }
+        if header[1] != 0 {
+            return Err(proxy_error(
+                proxy,
+                format!("cannot connect to {}: {}", dest, reply_reason(header[1])),
+            ));
+        }
+        // skip the address the proxy bound to
+        let addr_len = match header[3] {
+            1 => 4,
+            4 => 16,
+            3 => {
+                let mut len = [0u8; 1];
+                conn.read_exact(&mut len).await?;
+                len[0] as usize
+            }
+            _ => return Err(proxy_error(proxy, "sent a malformed reply")),
+        };
+        let mut bound = vec![0u8; addr_len + 2];
+        conn.read_exact(&mut bound).await?;
+        Ok(())
+    };
+    connected
+        .timeout(PROXY_TIMEOUT)
+        .await
+        .ok_or_else(|| proxy_error(proxy, format!("timed out connecting to {}", dest)))??;
+    Ok(conn)
+}
+
+fn reply_reason(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
@@ -1,4 +1,4 @@
-use std::net::SocketAddr;
+use std::{collections::BTreeMap, net::SocketAddr};
 
 use chacha20poly1305::{
     aead::{Aead, NewAead},
@@ -75,6 +75,57 @@ pub enum BinderRequestData {
 
     /// Get all free exits
     GetFreeExits,
+
+    /// Report the current load of an exit
+    ReportExitLoad {
+        /// Exit hostname
+        exit_hostname: String,
+        /// Number of active sessions
+        session_count: u32,
+        /// Time
+        report_unixtime: u64,
+        /// Signature over a tuple of the rest of the fields, by the exit.
+        exit_signature: ed25519_dalek::Signature,
+    },
+
+    /// Report which ways of reaching exits worked for a client, with its blind-signed token, which doesn't identify the user but lets the binder turn away clients that report too much
+    ReportReachability {
+        level: String,
+        unblinded_digest: Vec<u8>,
+        unblinded_signature: mizaru::UnblindedSignature,
+        /// Coarse region the client is in, such as a country code, as given by the user
+        region: String,
+        reports: Vec<ReachabilityReport>,
+    },
+
+    /// Get bridges, each with the signature its exit made when announcing it, so that they can be checked against the exit's signing key
+    GetSignedBridges {
+        level: String,
+        unblinded_digest: Vec<u8>,
+        unblinded_signature: mizaru::UnblindedSignature,
+        exit_hostname: String,
+    },
+
+    /// Get the number of active sessions last reported by each exit that reported recently
+    GetExitLoads,
+
+    /// Get all exits, or only the free ones, signed by the binder
+    GetSignedExits { only_free: bool },
+
+    /// Report the address an exit takes bridge control connections on, for exits that take them somewhere other than where their hostname resolves to
+    ReportControlAddr {
+        /// Exit hostname
+        exit_hostname: String,
+        /// Address of the control listener
+        control_addr: SocketAddr,
+        /// Time
+        report_unixtime: u64,
+        /// Signature over a tuple of the string "control_addr" and the rest of the fields, by the exit.
+        exit_signature: ed25519_dalek::Signature,
+    },
+
+    /// Get the control addresses last reported by exits that reported recently
+    GetControlAddrs,
 }
 
 impl BinderRequestData {
@@ -111,6 +162,9 @@ impl BinderRequestData {
             BinderRequestData::GetExits { .. } => true,
             BinderRequestData::GetFreeExits { .. } => true,
             BinderRequestData::GetBridges { .. } => true,
+            BinderRequestData::GetSignedBridges { .. } => true,
+            BinderRequestData::GetExitLoads { .. } => true,
+            BinderRequestData::GetSignedExits { .. } => true,
             // BinderRequestData::Authenticate { .. } => true,
             // BinderRequestData::Validate { .. } => true,
             _ => false,
@@ -168,6 +222,16 @@ pub enum BinderResponse {
     GetExitsResp(Vec<ExitDescriptor>),
     /// Response to request for bridges
     GetBridgesResp(Vec<BridgeDescriptor>),
+    /// Response to request for signed bridges
+    GetSignedBridgesResp(Vec<SignedBridgeDescriptor>),
+    /// Response to a load report, telling the exit whether it should drain
+    ReportExitLoadResp { draining: bool },
+    /// Response to request for exit loads, by exit hostname
+    GetExitLoadsResp(BTreeMap<String, u32>),
+    /// Response to request for signed exits
+    GetSignedExitsResp(SignedExitList),
+    /// Response to request for control addresses, by exit hostname
+    GetControlAddrsResp(BTreeMap<String, SocketAddr>),
 }
 
 /// Exit descriptor
@@ -180,6 +244,112 @@ pub struct ExitDescriptor {
     pub sosistab_key: x25519_dalek::PublicKey,
 }
 
+/// A list of exits, signed with the binder's master key, so that clients can check a list that reached them some other way than from the binder, such as in a cache bundle.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct SignedExitList {
+    pub exits: Vec<ExitDescriptor>,
+    /// When the binder signed the list
+    pub signed_unixtime: u64,
+    pub signature: ed25519_dalek::Signature,
+}
+
+impl SignedExitList {
+    /// Signs a list of exits with the binder's master secret.
+    pub fn sign(
+        exits: Vec<ExitDescriptor>,
+        signed_unixtime: u64,
+        master_sk: &x25519_dalek::StaticSecret,
+    ) -> Self {
+        let signature = crate::master_sign(master_sk, &Self::to_sign(&exits, signed_unixtime));
+        SignedExitList {
+            exits,
+            signed_unixtime,
+            signature,
+        }
+    }
+
+    /// Checks the binder's signature.
+    pub fn verify(&self, master_pk: &x25519_dalek::PublicKey) -> bool {
+        crate::master_verify(
+            master_pk,
+            &Self::to_sign(&self.exits, self.signed_unixtime),
+            &self.signature,
+        )
+    }
+
+    fn to_sign(exits: &[ExitDescriptor], signed_unixtime: u64) -> Vec<u8> {
+        bincode::serialize(&("exit-list", exits, signed_unixtime)).unwrap()
+    }
+}
+
+/// Optional features an exit supports, as a bitmap. Exits advertise these to clients when authenticating a session, so that clients only use features the exit understands. Bits a client doesn't know about are ignored, and exits too old to advertise anything support none of them.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, Eq, PartialEq, Hash)]
+pub struct ExitFeatures(pub u64);
+
+impl ExitFeatures {
+    /// Connection labels may carry an address family preference.
+    pub const ADDR_PREFERENCE: ExitFeatures = ExitFeatures(1 << 0);
+    /// Connections labeled "echo" reach an echo service, for measuring the tunnel.
+    pub const ECHO: ExitFeatures = ExitFeatures(1 << 1);
+    /// Connection labels may ask for a SOCKS5 BIND.
+    pub const SOCKS_BIND: ExitFeatures = ExitFeatures(1 << 2);
+    /// Connections may start with a [ConnIntent], which the exit answers before dialing.
+    pub const CONN_INTENT: ExitFeatures = ExitFeatures(1 << 3);
+    /// Sessions may carry cover traffic, which the exit throws away.
+    pub const PADDING: ExitFeatures = ExitFeatures(1 << 4);
+    /// The exit keeps the authentication connection open, and sends [SESSION_DRAINING] over it, pascalish, once it starts draining, so that the client can move to another exit before the session is closed.
+    pub const DRAIN_NOTICE: ExitFeatures = ExitFeatures(1 << 5);
+
+    /// Whether all the given features are supported.
+    pub fn contains(self, other: ExitFeatures) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Combines two sets of features.
+    pub const fn union(self, other: ExitFeatures) -> ExitFeatures {
+        ExitFeatures(self.0 | other.0)
+    }
+}
+
+/// Status an exit answers authentication with, instead of 1, when it's draining and takes no new sessions. Clients too old to know this see a session that gets closed soon after.
+pub const SESSION_DRAINING: u8 = 2;
+
+/// Label of connections that start with a [ConnIntent]. The version is part of the label, so that a future intent with different fields gets a new label and feature bit, and exits keep understanding both.
+pub const CONN_INTENT_LABEL: &str = "intent1";
+
+/// What a client wants from a tunneled connection. Sent pascalish right after the connection is opened with [CONN_INTENT_LABEL], and answered by the exit with a pascalish `Result<(), ConnRejection>` once it has checked its policy, before it dials anything.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct ConnIntent {
+    /// The destination, as "host:port".
+    pub destination: String,
+    /// Which address family to connect over, for names with both.
+    pub preference: aioutils::AddrPreference,
+    /// Whether the client connects directly, bypassing the tunnel, to destinations on ports the exit refuses.
+    pub direct_fallback: bool,
+}
+
+/// Why an exit refused a [ConnIntent].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq, thiserror::Error)]
+pub enum ConnRejection {
+    #[error("the exit doesn't allow connections to this port")]
+    PortNotAllowed,
+    #[error("the exit doesn't allow connections to this address")]
+    AddressNotAllowed,
+    #[error("the exit cannot resolve this name")]
+    NameNotResolved,
+}
+
+/// How often one way of reaching an exit worked for a client. Carries nothing about the client or its user.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct ReachabilityReport {
+    pub exit_hostname: String,
+    /// The bridge used, or None for connecting to the exit directly
+    pub bridge: Option<SocketAddr>,
+    pub use_tcp: bool,
+    pub successes: u32,
+    pub failures: u32,
+}
+
 /// Bridge descriptor
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub struct BridgeDescriptor {
@@ -187,6 +357,31 @@ pub struct BridgeDescriptor {
     pub sosistab_key: x25519_dalek::PublicKey,
 }
 
+/// Bridge descriptor, along with the exit's signature from when it announced the route through the bridge. The binder can't forge these, so they show that the exit, whose signing key clients know from the exit list, really did set up the route. They don't show that it still stands.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct SignedBridgeDescriptor {
+    pub bridge: BridgeDescriptor,
+    pub bridge_group: String,
+    pub route_unixtime: u64,
+    pub exit_signature: ed25519_dalek::Signature,
+}
+
+impl SignedBridgeDescriptor {
+    /// Checks the exit's signature, which is over the same tuple as in [BinderRequestData::AddBridgeRoute].
+    pub fn verify(&self, exit_signing_key: &ed25519_dalek::PublicKey) -> bool {
+        let message = bincode::serialize(&(
+            self.bridge.sosistab_key,
+            self.bridge.endpoint,
+            &self.bridge_group,
+            self.route_unixtime,
+        ))
+        .unwrap();
+        exit_signing_key
+            .verify_strict(&message, &self.exit_signature)
+            .is_ok()
+    }
+}
+
 /// Information for a particular user
 #[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
 pub struct UserInfo {
@@ -0,0 +1,283 @@
+//! Lets binaries read their command-line flags from a TOML or YAML config file too.
+//!
+//! Every key in the file is the name of a flag, with either dashes or underscores, and its value is what would be passed on the command line. Flags that are actually given on the command line win over the file. For example:
+//!
+//! ```toml
+//! exit_server = "sg-sgp-01.exits.geph.io"
+//! use_tcp = true
+//! binder_tls_pins = ["aaaa...", "bbbb..."]
+//! ```
+use anyhow::Context;
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashSet},
+    ffi::OsString,
+    path::Path,
+};
+
+/// The process's arguments, with the flags from the `--config` file, if any, merged in.
+pub fn args() -> anyhow::Result<Vec<OsString>> {
+    merge(std::env::args_os().collect())
+}
+
+/// Merges the flags from the `--config` file named in the given arguments, if any, into them.
+///
+/// The `--config` flag itself is left in place, so the binary can document it like any other flag.
+pub fn merge(mut args: Vec<OsString>) -> anyhow::Result<Vec<OsString>> {
+    let path = match config_path(&args)? {
+        Some(path) => path,
+        None => return Ok(args),
+    };
+    let text = std::fs::read_to_string(&path)
+        .with_context(|| format!("cannot read config file {}", path))?;
+    let flags =
+        parse(Path::new(&path), &text).with_context(|| format!("invalid config file {}", path))?;
+    let given: HashSet<String> = args
+        .iter()
+        .filter_map(|arg| arg.to_str())
+        .filter_map(|arg| arg.strip_prefix("--"))
+        .filter_map(|arg| arg.splitn(2, '=').next())
+        .map(|flag| flag.to_string())
+        .collect();
+    for (flag, values) in flags {
+        if given.contains(&flag) {
+            continue;
+        }
+        for value in values {
+            args.push(value);
+        }
+    }
+    Ok(args)
+}
+
+/// Finds the path given to `--config`, which can be given at most once.
+fn config_path(args: &[OsString]) -> anyhow::Result<Option<String>> {
+    let mut path = None;
+    let mut iter = args.iter().map(|arg| arg.to_string_lossy());
+    while let Some(arg) = iter.next() {
+        let this = if arg == "--config" {
+            Some(iter.next().context("--config needs a path")?.into_owned())
+        } else {
+            arg.strip_prefix("--config=").map(|p| p.to_string())
+        };
+        if let Some(this) = this {
+            if path.is_some() {
+                anyhow::bail!("--config given more than once")
+            }
+            path = Some(this);
+        }
+    }
+    Ok(path)
+}
+
+/// Parses a config file into the command-line arguments it stands for, keyed by flag name.
+fn parse(path: &Path, text: &str) -> anyhow::Result<BTreeMap<String, Vec<OsString>>> {
+    let is_yaml = matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("yaml") | Some("yml")
+    );
+    let table: BTreeMap<String, Value> = if is_yaml {
+        serde_yaml::from_str(text)?
+    } else {
+        toml::from_str(text)?
+    };
+    let mut flags = BTreeMap::new();
+    for (key, value) in table {
+        let flag = key.replace('_', "-");
+        if flag == "config" {
+            anyhow::bail!("config files cannot include other config files")
+        }
+        let values = match value {
+            Value::Null | Value::Bool(false) => vec![],
+            Value::Bool(true) => vec![format!("--{}", flag).into()],
+            Value::Array(values) => values
+                .into_iter()
+                .map(|v| Ok(format!("--{}={}", flag, scalar(&key, v)?).into()))
+                .collect::<anyhow::Result<_>>()?,
+            value => vec![format!("--{}={}", flag, scalar(&key, value)?).into()],
+        };
+        if flags.insert(flag, values).is_some() {
+            anyhow::bail!("{} is given more than once", key)
+        }
+    }
+    Ok(flags)
+}
+
+/// Resolves a secret that can be given as a flag, as a file holding it, or as an environment variable. Exactly one of these must be used. Flags show up in process listings, so the other two are better for real deployments. Whitespace around the file's contents is ignored.
+pub fn secret(
+    name: &str,
+    flag: Option<&str>,
+    file: Option<&Path>,
+    env_var: &str,
+) -> anyhow::Result<String> {
+    let env = std::env::var(env_var).ok();
+    match (flag, file, env) {
+        (Some(secret), None, None) => Ok(secret.to_string()),
+        (None, Some(file), None) => {
+            let secret = std::fs::read_to_string(file)
+                .with_context(|| format!("cannot read {} from {:?}", name, file))?;
+            let secret = secret.trim();
+            if secret.is_empty() {
+                anyhow::bail!("{:?} doesn't contain a {}", file, name)
+            }
+            Ok(secret.to_string())
+        }
+        (None, None, Some(secret)) => Ok(secret),
+        (None, None, None) => anyhow::bail!(
+            "{} must be given, either as --{}, in a file with --{}-file, or in the {} environment variable",
+            name,
+            name,
+            name,
+            env_var
+        ),
+        _ => anyhow::bail!(
+            "{} is given more than one way; use only one of --{}, --{}-file, and {}",
+            name,
+            name,
+            name,
+            env_var
+        ),
+    }
+}
+
+/// The binder master key built into the binaries: whatever was in the `GEPH_BINDER_MASTER_PK` environment variable when they were compiled, or else the public binder's. Private deployments with their own binder can build binaries that trust it this way, rather than passing its key everywhere.
+pub const BUILTIN_BINDER_MASTER_PK: &str = match option_env!("GEPH_BINDER_MASTER_PK") {
+    Some(pk) => pk,
+    None => "124526f4e692b589511369687498cce57492bf4da20f8d26019c1cc0c80b6e4b",
+};
+
+/// Resolves the binder's x25519 master key, given in hex either as the `--{flag_name}` flag or in a file given as `--binder-master-pk-file`, or else built in. At most one of the flag and the file may be given. In the file, whitespace and lines starting with `#` are ignored.
+pub fn binder_master_pk(
+    flag_name: &str,
+    flag: Option<&str>,
+    file: Option<&Path>,
+) -> anyhow::Result<[u8; 32]> {
+    match (flag, file) {
+        (Some(pk), None) => parse_master_pk(&format!("--{}", flag_name), pk),
+        (None, Some(file)) => {
+            let text = std::fs::read_to_string(file)
+                .with_context(|| format!("cannot read binder master key from {:?}", file))?;
+            let pk: String = text
+                .lines()
+                .filter(|line| !line.trim_start().starts_with('#'))
+                .flat_map(|line| line.split_whitespace())
+                .collect();
+            parse_master_pk(&format!("{:?}", file), &pk)
+        }
+        (None, None) => parse_master_pk(
+            "the built-in binder master key (GEPH_BINDER_MASTER_PK at build time)",
+            BUILTIN_BINDER_MASTER_PK,
+        ),
+        (Some(_), Some(_)) => anyhow::bail!(
+            "binder master key is given twice; use only one of --{} and --binder-master-pk-file",
+            flag_name
+        ),
+    }
+}
+
+fn parse_master_pk(source: &str, pk: &str) -> anyhow::Result<[u8; 32]> {
+    let bytes = hex::decode(pk.trim())
+        .map_err(|err| anyhow::anyhow!("{} isn't a hex-encoded key: {}", source, err))?;
+    if bytes.len() != 32 {
+        anyhow::bail!(
+            "{} is {} bytes long, but binder master keys are 32 bytes, or 64 hex digits",
+            source,
+            bytes.len()
+        )
+    }
+    let mut toret = [0u8; 32];
+    toret.copy_from_slice(&bytes);
+    Ok(toret)
+}
+
+fn scalar(key: &str, value: Value) -> anyhow::Result<String> {
+    match value {
+        Value::String(s) => Ok(s),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        _ => anyhow::bail!(
+            "{} must be a string, number, boolean, or list of those",
+            key
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line_wins() {
+        let dir = std::env::temp_dir().join(format!("configfile-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("exit.toml");
+        std::fs::write(
+            &path,
+            "exit_server = \"a.example\"\nuse-tcp = true\nexclude_prc = false\npins = [\"x\", \"y\"]\n",
+        )
+        .unwrap();
+        let args: Vec<OsString> = vec![
+            "geph4-client".into(),
+            "connect".into(),
+            "--exit-server=b.example".into(),
+            "--config".into(),
+            path.clone().into(),
+        ];
+        let merged = merge(args.clone()).unwrap();
+        let mut expected = args;
+        expected.extend(
+            vec!["--pins=x", "--pins=y", "--use-tcp"]
+                .into_iter()
+                .map(OsString::from),
+        );
+        assert_eq!(merged, expected);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn secret_needs_one_source() {
+        let env_var = "CONFIGFILE_TEST_UNSET_SECRET";
+        let path = std::env::temp_dir().join(format!("configfile-secret-{}", std::process::id()));
+        std::fs::write(&path, "hunter2\n").unwrap();
+        assert_eq!(
+            secret("bridge-secret", None, Some(&path), env_var).unwrap(),
+            "hunter2"
+        );
+        assert!(secret("bridge-secret", Some("x"), Some(&path), env_var).is_err());
+        assert!(secret("bridge-secret", None, None, env_var).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert!(secret("bridge-secret", None, Some(&path), env_var).is_err());
+    }
+
+    #[test]
+    fn master_pk_from_file() {
+        let path = std::env::temp_dir().join(format!("configfile-pk-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            format!(
+                "# private binder\n{}\n{}\n",
+                &BUILTIN_BINDER_MASTER_PK[..32],
+                &BUILTIN_BINDER_MASTER_PK[32..]
+            ),
+        )
+        .unwrap();
+        let builtin = binder_master_pk("binder-master-pk", None, None).unwrap();
+        assert_eq!(
+            binder_master_pk("binder-master-pk", None, Some(&path)).unwrap(),
+            builtin
+        );
+        assert!(binder_master_pk("binder-master-pk", Some("00"), Some(&path)).is_err());
+        assert!(binder_master_pk("binder-master-pk", Some("abcd"), None).is_err());
+        assert!(binder_master_pk("binder-master-pk", Some(&"zz".repeat(32)), None).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let path = Path::new("x.toml");
+        assert!(parse(path, "config = \"other.toml\"").is_err());
+        assert!(parse(path, "exit_server = \"a\"\nexit-server = \"b\"").is_err());
+        assert!(parse(path, "[nested]\nkey = 1").is_err());
+        assert!(parse(Path::new("x.yaml"), "use_tcp: true\n").unwrap().len() == 1);
+    }
+}
@@ -18,11 +18,11 @@ use std::net::SocketAddr;
 pub async fn run(listen_addr: SocketAddr, proxy_address: SocketAddr) -> std::io::Result<()> {
     let shared_server: SharedProxyServer = ProxyServer::new_shared(proxy_address);
     let make_service = make_service_fn(|socket: &AddrStream| {
-        let client_addr = socket.remote_addr();
+        let client_addr = socket.remote_addr().to_string();
         let cloned_server = shared_server.clone();
         async move {
             Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
-                server_dispatch(req, client_addr, cloned_server.clone())
+                server_dispatch(req, client_addr.clone(), cloned_server.clone())
             }))
         }
     });
@@ -36,10 +36,40 @@ pub async fn run(listen_addr: SocketAddr, proxy_address: SocketAddr) -> std::io:
     Ok(())
 }
 
+#[cfg(unix)]
+pub async fn run_unix(
+    listener: std::os::unix::net::UnixListener,
+    proxy_address: SocketAddr,
+) -> std::io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::UnixListener::from_std(listener)?;
+    let incoming = futures::stream::unfold(listener, |listener| async move {
+        let conn = listener.accept().await.map(|(conn, _)| conn);
+        Some((conn, listener))
+    });
+    let shared_server: SharedProxyServer = ProxyServer::new_shared(proxy_address);
+    let make_service = make_service_fn(|_: &tokio::net::UnixStream| {
+        let cloned_server = shared_server.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                server_dispatch(req, "unix".to_string(), cloned_server.clone())
+            }))
+        }
+    });
+    let server = hyper::Server::builder(hyper::server::accept::from_stream(incoming))
+        .http1_only(true)
+        .serve(make_service);
+    if let Err(err) = server.await {
+        use std::io::Error;
+        return Err(Error::new(std::io::ErrorKind::Other, err));
+    }
+    Ok(())
+}
+
 use std::str::FromStr;
 async fn server_dispatch(
     mut req: Request<Body>,
-    client_addr: SocketAddr,
+    client_addr: String,
     proxy_server: SharedProxyServer,
 ) -> std::io::Result<Response<Body>> {
     let host = match host_addr(req.uri()) {
@@ -184,7 +214,7 @@ async fn establish_connect_tunnel(
     upgraded: Upgraded,
     mut stream: TcpStream,
     svr_addr: &SocketAddr,
-    client_addr: SocketAddr,
+    client_addr: String,
     addr: Address,
 ) {
     use tokio::io::{copy, split};
@@ -10,3 +10,13 @@ pub async fn run_tokio(local_listen_addr: SocketAddr, proxy_address: SocketAddr)
         .await
         .unwrap()
 }
+
+#[cfg(unix)]
+pub async fn run_tokio_unix(
+    local_listener: std::os::unix::net::UnixListener,
+    proxy_address: SocketAddr,
+) {
+    http_local::run_unix(local_listener, proxy_address)
+        .await
+        .unwrap()
+}
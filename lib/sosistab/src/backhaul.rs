@@ -7,6 +7,14 @@ use std::{
 use bytes::{Bytes, BytesMut};
 use smol::Async;
 
+mod dtls;
+pub use dtls::*;
+mod stream;
+pub use stream::*;
+
+/// Size of the buffer each received datagram is read into. Longer datagrams are cut short, so a datagram that fills the buffer exactly was probably truncated.
+pub const RECV_BUFFER_LEN: usize = 2048;
+
 /// A trait that represents a datagram backhaul. This presents an interface similar to that of "PacketConn" in Go, and it is used to abstract over different kinds of datagram transports.
 #[async_trait::async_trait]
 pub trait Backhaul: Send + Sync {
@@ -88,9 +96,9 @@ impl Backhaul for Async<UdpSocket> {
     }
 
     async fn recv_from(&self) -> io::Result<(Bytes, SocketAddr)> {
-        let mut buf = BytesMut::with_capacity(2048);
+        let mut buf = BytesMut::with_capacity(RECV_BUFFER_LEN);
         unsafe {
-            buf.set_len(2048);
+            buf.set_len(RECV_BUFFER_LEN);
         }
         let (n, origin) = self.recv_from(&mut buf).await?;
         Ok((buf.freeze().slice(0..n), origin))
@@ -146,13 +154,14 @@ impl Backhaul for Async<UdpSocket> {
             let fd: RawFd = sock.as_raw_fd();
             // create a byte buffer
             let mut byte_buffer: Vec<u8> = unsafe {
-                let mut space = Vec::with_capacity(2048 * MAX_LEN);
-                space.set_len(2048 * MAX_LEN);
+                let mut space = Vec::with_capacity(RECV_BUFFER_LEN * MAX_LEN);
+                space.set_len(RECV_BUFFER_LEN * MAX_LEN);
                 space
             };
             // split into slices
             let response: Vec<(usize, Option<nix::sys::socket::SockAddr>)> = {
-                let byte_slices: Vec<&mut [u8]> = byte_buffer.chunks_exact_mut(2048).collect();
+                let byte_slices: Vec<&mut [u8]> =
+                    byte_buffer.chunks_exact_mut(RECV_BUFFER_LEN).collect();
                 let mut iovs: Vec<[IoVec<&mut [u8]>; 1]> = byte_slices
                     .into_iter()
                     .map(|v| [IoVec::from_mut_slice(v)])
@@ -183,7 +192,7 @@ impl Backhaul for Async<UdpSocket> {
                 .into_iter()
                 .enumerate()
                 .filter_map(|(i, rm)| {
-                    let bts = bts.slice(2048 * i..2048 * i + rm.0);
+                    let bts = bts.slice(RECV_BUFFER_LEN * i..RECV_BUFFER_LEN * i + rm.0);
                     let sockaddr = rm.1?;
                     if let nix::sys::socket::SockAddr::Inet(inetaddr) = sockaddr {
                         Some((bts, inetaddr.to_std()))
@@ -0,0 +1,203 @@
+//! Makes UDP backhauls look like DTLS 1.2, as used by WebRTC.
+//!
+//! Raw sosistab packets are uniformly random bytes, which some DPI systems flag precisely because nothing legitimate looks like that. Wrapping every packet in a DTLS application data record header makes the traffic look like an established WebRTC or DTLS VPN flow, which is common enough that blocking it wholesale is costly. This works best where video calls are allowed but unknown UDP is throttled or blocked. It does nothing against censors that insist on seeing a DTLS handshake first, or that block UDP altogether, where TCP is the better choice.
+//!
+//! Each packet grows by a 13-byte record header, which is about 1% of a full-sized packet. Nothing else changes, so throughput drops by about as much.
+use super::Backhaul;
+use bytes::{Bytes, BytesMut};
+use dashmap::DashMap;
+use std::{
+    io,
+    net::SocketAddr,
+    str::FromStr,
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+    time::{Duration, Instant},
+};
+
+const HEADER_LEN: usize = 13;
+const APPLICATION_DATA: u8 = 23;
+const DTLS_1_2: [u8; 2] = [0xfe, 0xfd];
+/// Epoch 1 is the first one after the handshake.
+const EPOCH: [u8; 2] = [0, 1];
+
+/// How servers forget about clients that spoke DTLS, once there are many of them.
+const PEER_EXPIRY: Duration = Duration::from_secs(600);
+const MAX_PEERS: usize = 65536;
+
+static UDP_OBFUSCATION: AtomicU8 = AtomicU8::new(0);
+
+/// How UDP packets are disguised on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpObfuscation {
+    /// Plain sosistab packets.
+    None,
+    /// Packets wrapped in DTLS 1.2 record headers.
+    Dtls,
+}
+
+impl UdpObfuscation {
+    /// Sets the obfuscation used by every UDP client and listener created from now on. Listeners with DTLS enabled still accept plain clients, replying to each client the way it spoke.
+    pub fn set(self) {
+        UDP_OBFUSCATION.store(self as u8, Ordering::Relaxed)
+    }
+
+    /// Gets the obfuscation used in this process.
+    pub fn get() -> Self {
+        match UDP_OBFUSCATION.load(Ordering::Relaxed) {
+            0 => UdpObfuscation::None,
+            _ => UdpObfuscation::Dtls,
+        }
+    }
+}
+
+impl FromStr for UdpObfuscation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(UdpObfuscation::None),
+            "dtls" => Ok(UdpObfuscation::Dtls),
+            other => Err(format!(
+                "unknown UDP obfuscation {:?} (expected none or dtls)",
+                other
+            )),
+        }
+    }
+}
+
+/// A backhaul that wraps packets in DTLS record headers.
+pub struct DtlsBackhaul<B: Backhaul> {
+    haul: B,
+    seqno: AtomicU64,
+    /// For servers, the clients that have spoken DTLS to us. Clients always speak DTLS and have no such table.
+    peers: Option<DashMap<SocketAddr, Instant>>,
+}
+
+impl<B: Backhaul> DtlsBackhaul<B> {
+    /// Wraps a client backhaul, which only sends and accepts DTLS.
+    pub fn client(haul: B) -> Self {
+        Self {
+            haul,
+            seqno: AtomicU64::new(0),
+            peers: None,
+        }
+    }
+
+    /// Wraps a server backhaul, which accepts both DTLS and plain packets and answers each client in kind.
+    pub fn server(haul: B) -> Self {
+        Self {
+            haul,
+            seqno: AtomicU64::new(0),
+            peers: Some(DashMap::new()),
+        }
+    }
+
+    fn wrap_for(&self, to_send: Bytes, dest: SocketAddr) -> Bytes {
+        let speaks_dtls = match &self.peers {
+            None => true,
+            Some(peers) => peers.contains_key(&dest),
+        };
+        if speaks_dtls {
+            wrap(&to_send, self.seqno.fetch_add(1, Ordering::Relaxed))
+        } else {
+            to_send
+        }
+    }
+
+    fn unwrap_from(&self, received: Bytes, origin: SocketAddr) -> Option<Bytes> {
+        match (&self.peers, unwrap(&received)) {
+            (_, Some(body)) => {
+                if let Some(peers) = &self.peers {
+                    if peers.len() > MAX_PEERS {
+                        peers.retain(|_, last| last.elapsed() < PEER_EXPIRY);
+                    }
+                    peers.insert(origin, Instant::now());
+                }
+                Some(body)
+            }
+            (Some(_), None) => Some(received),
+            (None, None) => None,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<B: Backhaul> Backhaul for DtlsBackhaul<B> {
+    async fn send_to(&self, to_send: Bytes, dest: SocketAddr) -> io::Result<()> {
+        self.haul.send_to(self.wrap_for(to_send, dest), dest).await
+    }
+
+    async fn send_to_many(&self, to_send: &[(Bytes, SocketAddr)]) -> io::Result<()> {
+        let wrapped: Vec<_> = to_send
+            .iter()
+            .map(|(bts, dest)| (self.wrap_for(bts.clone(), *dest), *dest))
+            .collect();
+        self.haul.send_to_many(&wrapped).await
+    }
+
+    async fn recv_from(&self) -> io::Result<(Bytes, SocketAddr)> {
+        loop {
+            let (bts, origin) = self.haul.recv_from().await?;
+            if let Some(bts) = self.unwrap_from(bts, origin) {
+                return Ok((bts, origin));
+            }
+        }
+    }
+
+    async fn recv_from_many(&self) -> io::Result<Vec<(Bytes, SocketAddr)>> {
+        loop {
+            let received: Vec<_> = self
+                .haul
+                .recv_from_many()
+                .await?
+                .into_iter()
+                .filter_map(|(bts, origin)| Some((self.unwrap_from(bts, origin)?, origin)))
+                .collect();
+            if !received.is_empty() {
+                return Ok(received);
+            }
+        }
+    }
+}
+
+/// Wraps a packet in a DTLS application data record.
+fn wrap(body: &[u8], seqno: u64) -> Bytes {
+    let mut buf = BytesMut::with_capacity(HEADER_LEN + body.len());
+    buf.extend_from_slice(&[APPLICATION_DATA]);
+    buf.extend_from_slice(&DTLS_1_2);
+    buf.extend_from_slice(&EPOCH);
+    buf.extend_from_slice(&seqno.to_be_bytes()[2..]);
+    buf.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    buf.extend_from_slice(body);
+    buf.freeze()
+}
+
+/// Unwraps a DTLS application data record, if that's what the packet is.
+fn unwrap(packet: &Bytes) -> Option<Bytes> {
+    if packet.len() < HEADER_LEN || packet[0] != APPLICATION_DATA || packet[1..3] != DTLS_1_2 {
+        return None;
+    }
+    let len = u16::from_be_bytes([packet[11], packet[12]]) as usize;
+    if len != packet.len() - HEADER_LEN {
+        return None;
+    }
+    Some(packet.slice(HEADER_LEN..))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_roundtrip() {
+        let body = Bytes::from_static(b"hello world");
+        let wrapped = wrap(&body, 0x0102_0304_0506);
+        assert_eq!(wrapped.len(), body.len() + HEADER_LEN);
+        assert_eq!(&wrapped[..5], &[23, 0xfe, 0xfd, 0, 1]);
+        assert_eq!(&wrapped[5..11], &[1, 2, 3, 4, 5, 6]);
+        assert_eq!(unwrap(&wrapped), Some(body.clone()));
+        // plain packets, and records with the wrong length, aren't mistaken for DTLS
+        assert_eq!(unwrap(&body), None);
+        assert_eq!(unwrap(&wrapped.slice(..wrapped.len() - 1)), None);
+    }
+}
@@ -0,0 +1,157 @@
+//! Carries datagrams over a connected byte stream that the caller supplies, such as a TCP connection through an SSH tunnel, or a stream inside some other transport.
+//!
+//! The stream speaks the same obfuscated protocol as the TCP backhaul: a hello to the server's key, then datagrams under a stream cipher, padded if [TcpFraming](crate::TcpFraming) says so. Whatever the stream ends up connected to must therefore be the TCP port of a sosistab server, directly or through any number of byte-for-byte forwarders.
+use super::{Backhaul, RECV_BUFFER_LEN};
+use crate::{
+    runtime,
+    tcp::{obfs_connect, ObfsReader, ObfsWriter},
+};
+use bytes::Bytes;
+use smol::{
+    channel::{Receiver, Sender, TrySendError},
+    prelude::*,
+};
+use std::{io, net::SocketAddr};
+
+/// How many datagrams may wait to be written to the stream, or read by the session, before more are dropped.
+const QUEUE_LEN: usize = 1024;
+
+/// A backhaul over a single connected stream, talking to a single peer.
+///
+/// Every datagram received is taken to come from the peer address given on creation, and every datagram sent goes down the stream whatever its destination. A stream can't tell shards apart, so a session over one stream should have one shard. Datagrams sent before the stream's own handshake finishes wait for it, and once the handshake or the stream fails, so does the backhaul.
+pub struct StreamBackhaul {
+    peer: SocketAddr,
+    send_outgoing: Sender<Bytes>,
+    recv_incoming: Receiver<Bytes>,
+    _task: smol::Task<()>,
+}
+
+impl StreamBackhaul {
+    /// Creates a backhaul over the given stream, to a server with the given public key. Datagrams are said to come from `peer`, which is only a label, so it can be made up, but it must be the server address the session connects to.
+    pub fn new(
+        stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        peer: SocketAddr,
+        server_pubkey: x25519_dalek::PublicKey,
+    ) -> Self {
+        let (send_outgoing, recv_outgoing) = smol::channel::bounded(QUEUE_LEN);
+        let (send_incoming, recv_incoming) = smol::channel::bounded(QUEUE_LEN);
+        let _task = runtime::spawn(async move {
+            let res: anyhow::Result<()> = async {
+                let (reader, writer) = obfs_connect(stream, server_pubkey).await?;
+                // reading and writing whole frames in futures of their own means a cancelled send or receive can't leave the stream mid-frame
+                read_frames(reader, send_incoming)
+                    .or(write_frames(writer, recv_outgoing))
+                    .await
+            }
+            .await;
+            if let Err(err) = res {
+                tracing::debug!("stream backhaul stopped: {:?}", err)
+            }
+        });
+        StreamBackhaul {
+            peer,
+            send_outgoing,
+            recv_incoming,
+            _task,
+        }
+    }
+}
+
+async fn read_frames(
+    mut reader: ObfsReader<impl AsyncRead + Unpin>,
+    send_incoming: Sender<Bytes>,
+) -> anyhow::Result<()> {
+    let mut buffer = [0u8; 65536];
+    loop {
+        let length = reader.read_datagram(&mut buffer).await?;
+        match send_incoming.try_send(Bytes::copy_from_slice(&buffer[..length])) {
+            Err(TrySendError::Closed(_)) => return Ok(()),
+            Err(TrySendError::Full(_)) => tracing::trace!("dropping datagram from stream"),
+            Ok(()) => (),
+        }
+    }
+}
+
+async fn write_frames(
+    mut writer: ObfsWriter<impl AsyncWrite + Unpin>,
+    recv_outgoing: Receiver<Bytes>,
+) -> anyhow::Result<()> {
+    let mut batch = Vec::new();
+    while let Ok(datagram) = recv_outgoing.recv().await {
+        batch.push(datagram);
+        // whatever else is queued goes in the same writes
+        while let Ok(datagram) = recv_outgoing.try_recv() {
+            batch.push(datagram);
+        }
+        let datagrams: Vec<&[u8]> = batch.iter().map(|datagram| &datagram[..]).collect();
+        writer.write_datagrams(&datagrams).await?;
+        batch.clear();
+    }
+    Ok(())
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "stream closed")
+}
+
+#[async_trait::async_trait]
+impl Backhaul for StreamBackhaul {
+    async fn send_to(&self, to_send: Bytes, _dest: SocketAddr) -> io::Result<()> {
+        if to_send.len() > RECV_BUFFER_LEN {
+            tracing::warn!("refusing to send packet of length {}", to_send.len());
+            return Ok(());
+        }
+        match self.send_outgoing.try_send(to_send) {
+            Err(TrySendError::Closed(_)) => Err(closed()),
+            // like a full socket buffer, this drops the datagram
+            Err(TrySendError::Full(_)) => Ok(()),
+            Ok(()) => Ok(()),
+        }
+    }
+
+    async fn recv_from(&self) -> io::Result<(Bytes, SocketAddr)> {
+        let datagram = self.recv_incoming.recv().await.map_err(|_| closed())?;
+        Ok((datagram, self.peer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{connect_over, Listener};
+    use std::{sync::Arc, time::Duration};
+
+    #[test]
+    fn talks_to_tcp_listeners() {
+        smol::block_on(async {
+            let long_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let pubkey = (&long_sk).into();
+            let listener = Listener::listen_tcp_with_recv_timeout(
+                "127.0.0.1:0",
+                long_sk,
+                |_, _| (),
+                |_, _| (),
+                Duration::from_secs(60),
+            )
+            .await;
+            let server_addr = listener.local_addr();
+            let stream = smol::net::TcpStream::connect(server_addr).await.unwrap();
+            let backhaul: Arc<dyn Backhaul> =
+                Arc::new(StreamBackhaul::new(stream, server_addr, pubkey));
+            let client = connect_over(server_addr, pubkey, move || Ok(backhaul.clone()), 1)
+                .await
+                .unwrap();
+            let server = listener
+                .accept_session_timeout(Duration::from_secs(10))
+                .await
+                .unwrap();
+            client.send_bytes(Bytes::from_static(b"hello over a stream"));
+            assert_eq!(
+                &server.recv_bytes().await.unwrap()[..],
+                b"hello over a stream"
+            );
+            server.send_bytes(Bytes::from_static(b"and back"));
+            assert_eq!(&client.recv_bytes().await.unwrap()[..], b"and back");
+        })
+    }
+}
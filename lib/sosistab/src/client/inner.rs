@@ -1,15 +1,22 @@
 use crate::{
     crypt::{self, LegacyAEAD, NgAEAD},
-    protocol, runtime, Backhaul, Session, SessionConfig,
+    keylog,
+    listener::HandshakeRng,
+    protocol, runtime,
+    session::FrameSealer,
+    Backhaul, ConnectError, Session, SessionConfig, ShardStats,
 };
 use bytes::Bytes;
+use event_listener::Event;
 use governor::{Quota, RateLimiter};
+use parking_lot::Mutex;
 use rand::prelude::*;
-use smol::channel::{Receiver, Sender};
+use smol::channel::{Receiver, Sender, TrySendError};
 use smol::prelude::*;
 use std::{
     net::SocketAddr,
     num::NonZeroU32,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -19,28 +26,62 @@ use std::{
 pub struct ClientConfig {
     pub server_addr: SocketAddr,
     pub server_pubkey: x25519_dalek::PublicKey,
-    pub backhaul_gen: Arc<dyn Fn() -> Arc<dyn Backhaul> + 'static + Send + Sync>,
+    pub backhaul_gen: Arc<dyn Fn() -> std::io::Result<Arc<dyn Backhaul>> + 'static + Send + Sync>,
     pub num_shards: usize,
+    /// If given, the session starts with `num_shards` shards, then adds or removes shards as it measures the link, up to this many.
+    pub max_shards: Option<usize>,
     pub reset_interval: Option<Duration>,
 }
 
 /// Connects to a remote server, given a closure that generates socket addresses.
-pub async fn connect_custom(cfg: ClientConfig) -> std::io::Result<Session> {
-    let backhaul = (cfg.backhaul_gen)();
-    let my_long_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
-    let my_eph_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+pub async fn connect_custom(cfg: ClientConfig) -> Result<Session, ConnectError> {
+    connect_with_rng(cfg, &HandshakeRng::Os).await
+}
+
+/// Connects to a remote server, taking the handshake's keys from the given RNG.
+pub(crate) async fn connect_with_rng(
+    cfg: ClientConfig,
+    rng: &HandshakeRng,
+) -> Result<Session, ConnectError> {
+    let backhaul = (cfg.backhaul_gen)().map_err(ConnectError::Bind)?;
+    let my_long_sk = rng.x25519_secret();
+    let my_eph_sk = rng.x25519_secret();
     // do the handshake
+    let max_version = max_version();
+    let legacy_version = VERSION.min(max_version);
     let cookie = crypt::Cookie::new(cfg.server_pubkey);
     let init_hello = protocol::HandshakeFrame::ClientHello {
         long_pk: (&my_long_sk).into(),
         eph_pk: (&my_eph_sk).into(),
-        version: VERSION,
+        version: legacy_version,
     };
-    for timeout_factor in (0u32..).map(|x| 2u64.pow(x)) {
-        // send hello
+    // every resend starts a new Noise handshake, but a late reply to an earlier one is still fine
+    let mut noise_initiators = Vec::new();
+    // a busy server may want proof of work before answering
+    let mut pow_solution: Option<protocol::HandshakeFrame> = None;
+    'attempts: for timeout_factor in (0u32..HANDSHAKE_ATTEMPTS).map(|x| 2u64.pow(x)) {
+        // send hello. servers that don't know about Noise will stop decoding after the ClientHello.
+        let mut initiator = crypt::NoiseInitiator::new(&cfg.server_pubkey);
+        let noise_hello = protocol::HandshakeFrame::ClientHelloNoise {
+            noise: initiator.hello(),
+            version: NOISE_VERSION,
+        };
+        noise_initiators.push(initiator);
+        let mut frames = if max_version >= NOISE_VERSION {
+            vec![init_hello.clone(), noise_hello]
+        } else {
+            vec![init_hello.clone()]
+        };
+        frames.push(protocol::HandshakeFrame::ClientPowSupport {
+            max_bits: crate::pow::MAX_BITS,
+        });
+        frames.extend(pow_solution.clone());
         let init_hello = crypt::LegacyAEAD::new(&cookie.generate_c2s().next().unwrap())
-            .pad_encrypt_v1(&std::slice::from_ref(&init_hello), 1000);
-        backhaul.send_to(init_hello, cfg.server_addr).await?;
+            .pad_encrypt_handshake(&frames);
+        backhaul
+            .send_to(init_hello, cfg.server_addr)
+            .await
+            .map_err(ConnectError::Io)?;
         tracing::trace!("sent client hello");
         // wait for response
         let res = backhaul
@@ -59,23 +100,75 @@ pub async fn connect_custom(cfg: ClientConfig) -> std::io::Result<Session> {
                     let decrypter = crypt::LegacyAEAD::new(&possible_key);
                     let response = decrypter.pad_decrypt_v1(&buf);
                     for response in response.unwrap_or_default() {
-                        if let protocol::HandshakeFrame::ServerHello {
-                            long_pk,
-                            eph_pk,
-                            resume_token,
-                        } = response
-                        {
-                            tracing::trace!("obtained response from server");
-                            if long_pk.as_bytes() != cfg.server_pubkey.as_bytes() {
-                                return Err(std::io::Error::new(
-                                    std::io::ErrorKind::ConnectionRefused,
-                                    "bad pubkey",
-                                ));
-                            }
-                            let shared_sec =
-                                crypt::triple_ecdh(&my_long_sk, &my_eph_sk, &long_pk, &eph_pk);
-                            return init_session(cookie, resume_token, shared_sec, cfg.clone())
+                        match response {
+                            protocol::HandshakeFrame::ServerHello {
+                                long_pk,
+                                eph_pk,
+                                resume_token,
+                            } => {
+                                tracing::trace!("obtained response from server");
+                                if long_pk.as_bytes() != cfg.server_pubkey.as_bytes() {
+                                    return Err(ConnectError::BadServerKey);
+                                }
+                                let shared_sec =
+                                    crypt::triple_ecdh(&my_long_sk, &my_eph_sk, &long_pk, &eph_pk);
+                                return init_session(
+                                    cookie,
+                                    resume_token,
+                                    shared_sec,
+                                    legacy_version,
+                                    cfg.clone(),
+                                )
                                 .await;
+                            }
+                            protocol::HandshakeFrame::ServerHelloNoise {
+                                noise,
+                                resume_token,
+                            } => {
+                                tracing::trace!("obtained Noise response from server");
+                                // the Noise handshake itself authenticates the server's long-term key
+                                let shared_sec = noise_initiators
+                                    .iter_mut()
+                                    .find_map(|initiator| initiator.finish(&noise));
+                                if let Some(shared_sec) = shared_sec {
+                                    return init_session(
+                                        cookie,
+                                        resume_token,
+                                        shared_sec,
+                                        NOISE_VERSION,
+                                        cfg.clone(),
+                                    )
+                                    .await;
+                                }
+                                tracing::warn!("Noise response from server did not verify");
+                            }
+                            protocol::HandshakeFrame::ServerPowChallenge {
+                                challenge,
+                                difficulty,
+                            } => {
+                                if difficulty > crate::pow::MAX_BITS {
+                                    tracing::warn!(
+                                        "ignoring proof-of-work challenge of difficulty {}",
+                                        difficulty
+                                    );
+                                    continue;
+                                }
+                                tracing::debug!(
+                                    "server is busy, solving challenge of difficulty {}",
+                                    difficulty
+                                );
+                                let nonce = {
+                                    let challenge = challenge.clone();
+                                    smol::unblock(move || crate::pow::solve(&challenge, difficulty))
+                                        .await
+                                };
+                                pow_solution = Some(protocol::HandshakeFrame::ClientPowSolution {
+                                    challenge,
+                                    nonce,
+                                });
+                                continue 'attempts;
+                            }
+                            _ => continue,
                         }
                     }
                 }
@@ -89,59 +182,611 @@ pub async fn connect_custom(cfg: ClientConfig) -> std::io::Result<Session> {
                     );
                     continue;
                 }
-                return Err(err);
+                return Err(ConnectError::Io(err));
+            }
+        }
+    }
+    Err(ConnectError::HandshakeTimeout)
+}
+
+/// Sends hellos to the server, doubling the wait each time, until it answers one with the right key. Unlike a real connection, no session is created on the server. Returns how long the answering hello took.
+pub async fn probe(
+    backhaul: Arc<dyn Backhaul>,
+    server_addr: SocketAddr,
+    server_pubkey: x25519_dalek::PublicKey,
+    attempts: u32,
+) -> Result<Duration, ConnectError> {
+    let cookie = crypt::Cookie::new(server_pubkey);
+    let hello = throwaway_hello(&HandshakeRng::Os);
+    for timeout_factor in (0..attempts).map(|x| 2u64.pow(x)) {
+        let start = Instant::now();
+        let packet = crypt::LegacyAEAD::new(&cookie.generate_c2s().next().unwrap())
+            .pad_encrypt_handshake(&[hello.clone()]);
+        backhaul
+            .send_to(packet, server_addr)
+            .await
+            .map_err(ConnectError::Io)?;
+        let deadline = start + Duration::from_secs(timeout_factor);
+        // anything that isn't an answer to us is ignored until the deadline
+        loop {
+            let res = backhaul
+                .recv_from()
+                .or(async {
+                    smol::Timer::at(deadline).await;
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "timed out",
+                    ))
+                })
+                .await;
+            let buf = match res {
+                Ok((buf, _)) => buf,
+                Err(err) if err.kind() == std::io::ErrorKind::TimedOut => break,
+                Err(err) => return Err(ConnectError::Io(err)),
+            };
+            for possible_key in cookie.generate_s2c() {
+                let frames = crypt::LegacyAEAD::new(&possible_key)
+                    .pad_decrypt_v1::<protocol::HandshakeFrame>(&buf)
+                    .unwrap_or_default();
+                for frame in frames {
+                    if let protocol::HandshakeFrame::ServerHello { long_pk, .. } = frame {
+                        if long_pk.as_bytes() != server_pubkey.as_bytes() {
+                            return Err(ConnectError::BadServerKey);
+                        }
+                        return Ok(start.elapsed());
+                    }
+                }
             }
         }
     }
-    unimplemented!()
+    Err(ConnectError::HandshakeTimeout)
 }
+
+/// A hello with fresh keys, for probing the server. Nothing comes of it beyond the server's answer.
+fn throwaway_hello(rng: &HandshakeRng) -> protocol::HandshakeFrame {
+    let my_long_sk = rng.x25519_secret();
+    let my_eph_sk = rng.x25519_secret();
+    protocol::HandshakeFrame::ClientHello {
+        long_pk: (&my_long_sk).into(),
+        eph_pk: (&my_eph_sk).into(),
+        version: VERSION.min(max_version()),
+    }
+}
+
+/// How many times we send the hello, doubling the wait each time, before giving up. This adds up to a bit over two minutes.
+const HANDSHAKE_ATTEMPTS: u32 = 7;
 const VERSION: u64 = 3;
+const NOISE_VERSION: u64 = 4;
+
+/// The newest protocol version, which clients use unless told otherwise.
+pub const LATEST_VERSION: u64 = NOISE_VERSION;
+
+static MAX_VERSION: AtomicU64 = AtomicU64::new(LATEST_VERSION);
+
+/// Caps the protocol version of sessions created from now on. This is a workaround for servers that negotiate a version they then don't handle properly, and shouldn't otherwise be used, since older versions are weaker.
+pub fn set_max_version(version: u64) {
+    MAX_VERSION.store(version.max(1).min(LATEST_VERSION), Ordering::Relaxed)
+}
+
+/// Gets the highest protocol version that new sessions may use.
+pub fn max_version() -> u64 {
+    MAX_VERSION.load(Ordering::Relaxed)
+}
+
+/// How often an adaptive session reconsiders its shard count.
+const SHARD_ADJUST_INTERVAL: Duration = Duration::from_secs(5);
+/// Upload packets per second a single shard should carry, past which we spread out over more shards.
+const SHARD_PPS: f64 = 2000.0;
+/// How often a shard that's no longer in use sends something anyway, so that its NAT mapping stays alive for downstream traffic.
+const IDLE_SHARD_KEEPALIVE: Duration = Duration::from_secs(10);
+
+/// How often a session checks the health of its shards.
+const SHARD_HEALTH_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a shard with a bad path sits out before it gets another chance.
+const SHARD_BENCH_TIME: Duration = Duration::from_secs(15);
+/// Fewer downstream packets than this over a health check interval say too little about the shards to judge them.
+const SHARD_HEALTH_MIN_PACKETS: u64 = 200;
+/// A shard receiving less than this fraction of the average shard's downstream packets is considered to have a bad path.
+const BAD_SHARD_SHARE: f64 = 0.25;
+
+/// How often each shard of a multi-shard session sends an echo request through its own socket, to time the round trip of its path. An echo not answered by the time the next one goes out counts for nothing.
+const SHARD_ECHO_INTERVAL: Duration = Duration::from_secs(2);
+/// Rate a shard's congestion controller starts out at, in packets per second.
+const SHARD_INITIAL_RATE: f64 = SHARD_PPS;
+/// Rate a shard's congestion controller never goes below, so that a shard that recovers gets traffic again.
+const SHARD_MIN_RATE: f64 = 50.0;
+/// Rate a shard's congestion controller never goes above.
+const SHARD_MAX_RATE: f64 = SHARD_PPS * 50.0;
+/// What a shard's rate grows by after an interval without congestion.
+const SHARD_RATE_INCREASE: f64 = SHARD_PPS / 10.0;
+/// What a shard's rate is multiplied by after an interval with congestion.
+const SHARD_RATE_DECREASE: f64 = 0.7;
+/// Loss over an interval past which a shard's path counts as congested.
+const SHARD_CONGESTION_LOSS: f64 = 0.1;
+/// Queueing delay, on top of a quarter of the smallest round trip seen, past which a shard's path counts as congested.
+const SHARD_QUEUE_DELAY: Duration = Duration::from_millis(10);
+/// Packets queued for a single shard, past which the scheduler hands packets to other shards instead.
+const SHARD_QUEUE: usize = 64;
+
+/// Tracks how many of a session's shards are in use, and how each is doing.
+struct ShardState {
+    active: AtomicUsize,
+    changed: Event,
+    packets_out: AtomicU64,
+    /// The nonce the next echo request goes out with. Nonces only ever increase, since the server answers each only once.
+    next_echo: AtomicU64,
+    health: Vec<ShardHealth>,
+}
+
+#[derive(Default)]
+struct ShardHealth {
+    packets_in: AtomicU64,
+    packets_out: AtomicU64,
+    /// Whether the shard is sitting out because its path looks bad.
+    benched: AtomicBool,
+    controller: Mutex<ShardController>,
+    /// The controller's latest rate, as the bits of an f64, so that the scheduler can read it without locking.
+    rate: AtomicU64,
+    /// The nonce of the echo request last sent through the shard, and when it was sent, until it's answered.
+    echo_sent: Mutex<Option<(u64, Instant)>>,
+}
+
+/// Congestion control for one shard's path, by additive increase and multiplicative decrease of a rate in packets per second. Queueing delay, seen in the shard's echoes, and loss both count as congestion. The rates only decide how the session's traffic is split between its shards: a shard whose path congests gets a smaller share, and the session's own rate limit still caps the total.
+#[derive(Clone, Copy, Debug)]
+struct ShardController {
+    srtt: Option<Duration>,
+    min_rtt: Option<Duration>,
+    loss: f64,
+    rate: f64,
+    /// Whether the path showed congestion since the rate was last adjusted.
+    congested: bool,
+}
+
+impl Default for ShardController {
+    fn default() -> Self {
+        ShardController {
+            srtt: None,
+            min_rtt: None,
+            loss: 0.0,
+            rate: SHARD_INITIAL_RATE,
+            congested: false,
+        }
+    }
+}
+
+impl ShardController {
+    /// Takes a round trip timed by an echo. One well above the smallest seen means packets are queueing somewhere on the path.
+    fn on_rtt(&mut self, sample: Duration) {
+        self.srtt = Some(match self.srtt {
+            Some(srtt) => (srtt * 3 + sample) / 4,
+            None => sample,
+        });
+        let min_rtt = self.min_rtt.map(|m| m.min(sample)).unwrap_or(sample);
+        self.min_rtt = Some(min_rtt);
+        if sample > min_rtt + min_rtt / 4 + SHARD_QUEUE_DELAY {
+            self.congested = true;
+        }
+    }
+
+    /// Takes the loss measured over an interval.
+    fn on_loss(&mut self, sample: f64) {
+        self.loss = (self.loss * 3.0 + sample) / 4.0;
+        if sample > SHARD_CONGESTION_LOSS {
+            self.congested = true;
+        }
+    }
+
+    /// Adjusts the rate at the end of an interval: down by a factor if the path congested during it, and up by a step otherwise.
+    fn adjust(&mut self) {
+        self.rate = if self.congested {
+            (self.rate * SHARD_RATE_DECREASE).max(SHARD_MIN_RATE)
+        } else {
+            (self.rate + SHARD_RATE_INCREASE).min(SHARD_MAX_RATE)
+        };
+        self.congested = false;
+    }
+}
+
+/// The controller of a session with a single shard, which carries everything and so sees exactly what the session sees. Its rate never matters, since there's nothing to split.
+fn session_controller(statg: &crate::session::StatGatherer) -> ShardController {
+    let ping = statg.ping();
+    ShardController {
+        // the session reports an absurd ping until it has measured one
+        srtt: Some(ping).filter(|ping| *ping < Duration::from_secs(1000)),
+        loss: statg.loss(),
+        ..Default::default()
+    }
+}
+
+impl ShardState {
+    /// Whether the given shard should be taking packets.
+    fn in_use(&self, shard_id: u8) -> bool {
+        (shard_id as usize) < self.active.load(Ordering::Relaxed)
+            && !self.health[shard_id as usize]
+                .benched
+                .load(Ordering::Relaxed)
+    }
+
+    /// Waits until the given shard is in use, or until the timeout passes. Returns whether it's in use.
+    async fn wait_active(&self, shard_id: u8, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let listener = self.changed.listen();
+            if self.in_use(shard_id) {
+                return true;
+            }
+            let changed = async {
+                listener.await;
+                true
+            }
+            .or(async {
+                smol::Timer::at(deadline).await;
+                false
+            })
+            .await;
+            if !changed {
+                return false;
+            }
+        }
+    }
+
+    /// How much of the traffic each shard should take, or None for shards not in use.
+    fn weights(&self) -> Vec<Option<f64>> {
+        (0..self.health.len())
+            .map(|i| {
+                if self.in_use(i as u8) {
+                    Some(f64::from_bits(self.health[i].rate.load(Ordering::Relaxed)))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn stats(&self, statg: &crate::session::StatGatherer) -> Vec<ShardStats> {
+        let active = self.active.load(Ordering::Relaxed);
+        self.health
+            .iter()
+            .enumerate()
+            .map(|(i, health)| {
+                let controller = if self.health.len() == 1 {
+                    session_controller(statg)
+                } else {
+                    *health.controller.lock()
+                };
+                ShardStats {
+                    shard_id: i as u8,
+                    active: i < active,
+                    benched: health.benched.load(Ordering::Relaxed),
+                    packets_in: health.packets_in.load(Ordering::Relaxed),
+                    packets_out: health.packets_out.load(Ordering::Relaxed),
+                    rtt: controller.srtt,
+                    loss: controller.loss,
+                    bandwidth: controller.rate,
+                }
+            })
+            .collect()
+    }
+
+    /// Times the round trip of whichever shard sent the echo request this answers. Late answers, to requests since replaced by newer ones, are ignored.
+    fn on_echo_reply(&self, nonce: u64) {
+        for health in self.health.iter() {
+            let mut echo_sent = health.echo_sent.lock();
+            if let Some((sent_nonce, sent)) = *echo_sent {
+                if sent_nonce == nonce {
+                    *echo_sent = None;
+                    health.controller.lock().on_rtt(sent.elapsed());
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Estimates the downstream loss of each shard in service over the last interval from how far it fell short of the busiest shard, or None where there's nothing to compare with. Like [bad_shards], this relies on the server spreading its packets evenly.
+fn shard_losses(received: &[Option<u64>]) -> Vec<Option<f64>> {
+    let counted: Vec<u64> = received.iter().flatten().copied().collect();
+    let total: u64 = counted.iter().sum();
+    if counted.len() < 2 || total < SHARD_HEALTH_MIN_PACKETS {
+        return vec![None; received.len()];
+    }
+    let busiest = counted.iter().copied().max().unwrap_or_default() as f64;
+    received
+        .iter()
+        .map(|n| n.map(|n| 1.0 - n as f64 / busiest))
+        .collect()
+}
+
+/// Picks the shard for the next packet by smooth weighted round robin, so that over time each shard in use gets packets in proportion to its weight, without long runs on any one. Shards without an estimate yet, with a weight of zero, count as average ones. Returns None if no shard is in use.
+fn next_shard(credit: &mut [f64], weights: &[Option<f64>]) -> Option<usize> {
+    let (known, known_total) = weights
+        .iter()
+        .flatten()
+        .filter(|w| **w > 0.0)
+        .fold((0, 0.0), |(n, total), w| (n + 1, total + w));
+    let average = if known == 0 {
+        1.0
+    } else {
+        known_total / known as f64
+    };
+    let mut total = 0.0;
+    let mut best: Option<usize> = None;
+    for (i, weight) in weights.iter().enumerate() {
+        match weight {
+            Some(weight) => {
+                let weight = if *weight > 0.0 { *weight } else { average };
+                credit[i] += weight;
+                total += weight;
+                if best.map(|best| credit[i] > credit[best]).unwrap_or(true) {
+                    best = Some(i);
+                }
+            }
+            None => credit[i] = 0.0,
+        }
+    }
+    let best = best?;
+    credit[best] -= total;
+    Some(best)
+}
+
+/// Hands the session's outgoing packets to the shards in use, in proportion to the rates their congestion controllers allow. A shard whose queue is full is passed over, so that one slow path doesn't hold up the others.
+async fn shard_scheduler(
+    state: Arc<ShardState>,
+    recv_packet_out: Receiver<Bytes>,
+    queues: Vec<Sender<Bytes>>,
+) -> Option<()> {
+    let mut credit = vec![0.0; queues.len()];
+    loop {
+        let mut packet = Some(recv_packet_out.recv().await.ok()?);
+        let weights = state.weights();
+        let pick = next_shard(&mut credit, &weights).unwrap_or_default();
+        let fallbacks = (0..queues.len()).filter(|i| weights[*i].is_some());
+        for i in std::iter::once(pick).chain(fallbacks) {
+            match queues[i].try_send(packet.take()?) {
+                Ok(()) => break,
+                Err(TrySendError::Full(bts)) | Err(TrySendError::Closed(bts)) => packet = Some(bts),
+            }
+        }
+        if let Some(packet) = packet {
+            // every shard is backed up, so we wait for the one picked
+            drop(queues[pick].send(packet).await);
+        }
+    }
+}
+
+/// Picks out shards whose paths look bad, given how many downstream packets each shard in service received over the last interval, or None for shards that weren't in service throughout. The server spreads its packets evenly over the shards it hears from, so a shard getting far less than its share is losing packets. Too few packets say nothing, so no shard is picked then. The busiest shard is never picked.
+fn bad_shards(received: &[Option<u64>]) -> Vec<bool> {
+    let counted: Vec<u64> = received.iter().flatten().copied().collect();
+    let total: u64 = counted.iter().sum();
+    if counted.len() < 2 || total < SHARD_HEALTH_MIN_PACKETS {
+        return vec![false; received.len()];
+    }
+    let mean = total as f64 / counted.len() as f64;
+    received
+        .iter()
+        .map(|n| matches!(n, Some(n) if (*n as f64) < mean * BAD_SHARD_SHARE))
+        .collect()
+}
+
+/// Periodically benches shards whose paths look bad, so that traffic concentrates on the healthy ones, and gives benched shards another chance once they've sat out for a while. Also feeds each shard's loss to its congestion controller, and publishes the rates the controllers settle on.
+async fn shard_health_monitor(
+    state: Arc<ShardState>,
+    statg: Arc<crate::session::StatGatherer>,
+) -> Option<()> {
+    let count = state.health.len();
+    let mut last_in = vec![0u64; count];
+    let mut benched_at: Vec<Option<Instant>> = vec![None; count];
+    // shards in service for the whole of the last interval, which are the only ones judged
+    let mut judged = vec![false; count];
+    loop {
+        smol::Timer::after(SHARD_HEALTH_INTERVAL).await;
+        let active = state.active.load(Ordering::Relaxed);
+        let received: Vec<Option<u64>> = (0..count)
+            .map(|i| {
+                let total = state.health[i].packets_in.load(Ordering::Relaxed);
+                let delta = total - last_in[i];
+                last_in[i] = total;
+                if judged[i] && i < active {
+                    Some(delta)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let losses = shard_losses(&received);
+        let counted = received.iter().flatten().count();
+        for (i, health) in state.health.iter().enumerate() {
+            let mut controller = health.controller.lock();
+            match losses[i] {
+                Some(loss) => controller.on_loss(loss),
+                // a lone shard in service carries everything, so it sees the session's own loss
+                None if counted == 1 && received[i].is_some() => controller.on_loss(statg.loss()),
+                None => {}
+            }
+            controller.adjust();
+            health
+                .rate
+                .store(controller.rate.to_bits(), Ordering::Relaxed);
+        }
+        let mut changed = false;
+        for (i, bad) in bad_shards(&received).into_iter().enumerate() {
+            if bad {
+                tracing::debug!(
+                    "benching shard {}, which got {:?} of {} packets",
+                    i,
+                    received[i],
+                    received.iter().flatten().sum::<u64>()
+                );
+                benched_at[i] = Some(Instant::now());
+                state.health[i].benched.store(true, Ordering::Relaxed);
+                changed = true;
+            } else if matches!(benched_at[i], Some(at) if at.elapsed() >= SHARD_BENCH_TIME) {
+                tracing::debug!("giving shard {} another chance", i);
+                benched_at[i] = None;
+                state.health[i].benched.store(false, Ordering::Relaxed);
+                changed = true;
+            }
+        }
+        judged = (0..count)
+            .map(|i| i < active && benched_at[i].is_none())
+            .collect();
+        if changed {
+            state.changed.notify(usize::MAX);
+        }
+    }
+}
+
+/// Decides how many shards to use next, given the measured loss and upload packets per second. Loss and heavy traffic call for more shards, while a clean, quiet link gets by with fewer.
+fn next_shard_count(active: usize, max_shards: usize, loss: f64, pps: f64) -> usize {
+    if (loss > 0.05 || pps / active as f64 > SHARD_PPS) && active < max_shards {
+        active + 1
+    } else if loss < 0.01 && active > 1 && pps / ((active - 1) as f64) < SHARD_PPS / 2.0 {
+        active - 1
+    } else {
+        active
+    }
+}
+
+/// Periodically adjusts the shard count of an adaptive session.
+async fn shard_controller(
+    state: Arc<ShardState>,
+    statg: Arc<crate::session::StatGatherer>,
+    max_shards: usize,
+) -> Option<()> {
+    let mut last_packets = 0;
+    loop {
+        smol::Timer::after(SHARD_ADJUST_INTERVAL).await;
+        let packets = state.packets_out.load(Ordering::Relaxed);
+        let pps = (packets - last_packets) as f64 / SHARD_ADJUST_INTERVAL.as_secs_f64();
+        last_packets = packets;
+        let active = state.active.load(Ordering::Relaxed);
+        let next = next_shard_count(active, max_shards, statg.loss(), pps);
+        if next != active {
+            tracing::debug!(
+                "shards {} => {} (loss {:.2}%, {:.0} pps)",
+                active,
+                next,
+                statg.loss() * 100.0,
+                pps
+            );
+            state.active.store(next, Ordering::Relaxed);
+            state.changed.notify(usize::MAX);
+        }
+    }
+}
 
 async fn init_session(
     cookie: crypt::Cookie,
     resume_token: Bytes,
     shared_sec: blake3::Hash,
+    version: u64,
     cfg: ClientConfig,
-) -> std::io::Result<Session> {
+) -> Result<Session, ConnectError> {
     let remind_ratelimit = Arc::new(RateLimiter::direct(Quota::per_second(
         NonZeroU32::new(3).unwrap(),
     )));
     let (send_frame_out, recv_frame_out) = smol::channel::bounded(5000);
     let (send_frame_in, recv_frame_in) = smol::channel::bounded(5000);
-    let backhaul_tasks: Vec<_> = (0..cfg.num_shards)
-        .map(|i| {
-            runtime::spawn_local(client_backhaul_once(
-                remind_ratelimit.clone(),
-                cookie.clone(),
-                resume_token.clone(),
-                send_frame_in.clone(),
-                recv_frame_out.clone(),
-                i as u8,
-                cfg.clone(),
-            ))
-        })
-        .collect();
+    let max_shards = cfg
+        .max_shards
+        .unwrap_or_default()
+        .max(cfg.num_shards)
+        .min(u8::MAX as usize);
+    let shards = Arc::new(ShardState {
+        active: AtomicUsize::new(cfg.num_shards),
+        changed: Event::new(),
+        packets_out: AtomicU64::new(0),
+        next_echo: AtomicU64::new(1),
+        health: (0..max_shards).map(|_| ShardHealth::default()).collect(),
+    });
     let up_key = blake3::keyed_hash(crypt::UP_KEY, shared_sec.as_bytes());
     let dn_key = blake3::keyed_hash(crypt::DN_KEY, shared_sec.as_bytes());
+    keylog::log_session(
+        &crypt::session_id(&resume_token),
+        up_key.as_bytes(),
+        dn_key.as_bytes(),
+    );
     let mut session = Session::new(SessionConfig {
+        id: crypt::session_id(&resume_token),
         send_packet: send_frame_out,
         recv_packet: recv_frame_in,
         send_crypt_legacy: LegacyAEAD::new(up_key.as_bytes()),
         recv_crypt_legacy: LegacyAEAD::new(dn_key.as_bytes()),
         send_crypt_ng: NgAEAD::new(up_key.as_bytes()),
         recv_crypt_ng: NgAEAD::new(dn_key.as_bytes()),
+        rebind_secret: crypt::rebind_secret(&up_key, &dn_key),
         recv_timeout: Duration::from_secs(300),
         statistics: 8000,
-        version: VERSION,
+        version,
     });
+    let mut backhaul_tasks = Vec::new();
+    // a single shard takes everything straight from the session, while several get their packets from the scheduler
+    let shard_queues = if max_shards > 1 {
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..max_shards)
+            .map(|_| smol::channel::bounded(SHARD_QUEUE))
+            .unzip();
+        backhaul_tasks.push(runtime::spawn_local(shard_scheduler(
+            shards.clone(),
+            recv_frame_out,
+            senders,
+        )));
+        receivers
+    } else {
+        vec![recv_frame_out]
+    };
+    backhaul_tasks.extend(
+        shard_queues
+            .into_iter()
+            .enumerate()
+            .map(|(i, recv_packet_out)| {
+                runtime::spawn_local(client_backhaul_once(
+                    shards.clone(),
+                    remind_ratelimit.clone(),
+                    cookie.clone(),
+                    resume_token.clone(),
+                    send_frame_in.clone(),
+                    recv_packet_out,
+                    i as u8,
+                    session.sealer(),
+                    cfg.clone(),
+                ))
+            }),
+    );
+    if max_shards > cfg.num_shards {
+        backhaul_tasks.push(runtime::spawn_local(shard_controller(
+            shards.clone(),
+            session.stat_gatherer(),
+            max_shards,
+        )));
+    }
+    if max_shards > 1 {
+        backhaul_tasks.push(runtime::spawn_local(shard_health_monitor(
+            shards.clone(),
+            session.stat_gatherer(),
+        )));
+        let shards = shards.clone();
+        session.set_echo_handler(move |nonce| shards.on_echo_reply(nonce));
+    }
+    let statg = session.stat_gatherer();
+    session.set_shard_source(move || shards.stats(&statg));
     session.on_drop(move || {
-        drop(backhaul_tasks);
+        // the shards stop once they have sent everything the session left behind, goodbye included
+        runtime::spawn(smol_timeout::TimeoutExt::timeout(
+            async move {
+                for task in backhaul_tasks {
+                    task.await;
+                }
+            },
+            crate::session::GOODBYE_GRACE,
+        ))
+        .detach();
     });
     Ok(session)
 }
 
 #[allow(clippy::all)]
 async fn client_backhaul_once(
+    shards: Arc<ShardState>,
     remind_ratelimit: Arc<
         RateLimiter<
             governor::state::NotKeyed,
@@ -154,19 +799,33 @@ async fn client_backhaul_once(
     send_packet_in: Sender<Bytes>,
     recv_packet_out: Receiver<Bytes>,
     shard_id: u8,
+    sealer: FrameSealer,
     cfg: ClientConfig,
 ) -> Option<()> {
+    // shards past the initial count aren't even bound until they're needed
+    while !shards
+        .wait_active(shard_id, Duration::from_secs(3600))
+        .await
+    {}
     let mut last_reset = Instant::now();
     let mut updated = false;
-    let mut socket: Arc<dyn Backhaul> = (cfg.backhaul_gen)();
+    let reject_check = RateLimiter::direct(Quota::per_second(NonZeroU32::new(4).unwrap()));
+    let token_hash = blake3::hash(&resume_token);
+    let mut socket: Arc<dyn Backhaul> = (cfg.backhaul_gen)().ok()?;
     // let mut _old_cleanup: Option<smol::Task<Option<()>>> = None;
 
     #[derive(Debug)]
     enum Evt {
         Incoming(Vec<Bytes>),
         Outgoing(Bytes),
+        Keepalive,
+        Echo,
     };
 
+    // shards of a multi-shard session time their own paths, so that the scheduler can tell them apart. the server answers through whichever shard it likes, so mostly the upstream half of the path gets timed, but that's the half the scheduler decides about.
+    let echoing = shards.health.len() > 1;
+    let mut next_echo = Instant::now() + Duration::from_secs(1);
+
     let mut my_reset_millis = cfg.reset_interval.map(|interval| {
         rand::thread_rng().gen_range(interval.as_millis() / 2, interval.as_millis())
     });
@@ -180,13 +839,46 @@ async fn client_backhaul_once(
             }
         };
         let up = async {
-            let raw_upload = recv_packet_out.recv().await.ok()?;
+            // packets handed to the shard before it went out of use still go out
+            let raw_upload = match recv_packet_out.try_recv() {
+                Ok(raw_upload) => raw_upload,
+                Err(_) => {
+                    // shards no longer in use stop taking packets, but keep the server able to reach them
+                    if !shards.wait_active(shard_id, IDLE_SHARD_KEEPALIVE).await {
+                        return Some(Evt::Keepalive);
+                    }
+                    recv_packet_out.recv().await.ok()?
+                }
+            };
+            shards.packets_out.fetch_add(1, Ordering::Relaxed);
+            shards.health[shard_id as usize]
+                .packets_out
+                .fetch_add(1, Ordering::Relaxed);
             Some(Evt::Outgoing(raw_upload))
         };
+        let echo = async move {
+            if !echoing {
+                smol::future::pending::<()>().await;
+            }
+            smol::Timer::at(next_echo).await;
+            Some(Evt::Echo)
+        };
 
-        match smol::future::race(down, up).await {
+        match smol::future::race(down, smol::future::race(up, echo)).await {
             Some(Evt::Incoming(bts)) => {
+                shards.health[shard_id as usize]
+                    .packets_in
+                    .fetch_add(bts.len() as u64, Ordering::Relaxed);
                 for bts in bts {
+                    // checking every packet for a rejection would double the cost of decryption, so we only check a few a second. once the server has forgotten us, a rejection is all it sends.
+                    if reject_check.check().is_ok() && is_rejection(&cookie, &token_hash, &bts) {
+                        tracing::warn!(
+                            "server no longer knows session {}; giving up on it",
+                            crypt::session_id(&resume_token)
+                        );
+                        send_packet_in.close();
+                        return None;
+                    }
                     let _ = send_packet_in.try_send(bts);
                 }
             }
@@ -204,37 +896,41 @@ async fn client_backhaul_once(
                             });
                             last_reset = now;
                             // also replace the UDP socket!
-                            let old_socket = socket.clone();
-                            let send_packet_in = send_packet_in.clone();
-                            // spawn a task to clean up the UDP socket
-                            let tata: smol::Task<Option<()>> = runtime::spawn_local(
-                                async move {
-                                    loop {
-                                        let bufs = old_socket.recv_from_many().await.ok()?;
-                                        for (buf, _) in bufs {
-                                            drop(send_packet_in.send(buf).await)
+                            match (cfg.backhaul_gen)() {
+                                Ok(new_socket) => {
+                                    let old_socket = std::mem::replace(&mut socket, new_socket);
+                                    let send_packet_in = send_packet_in.clone();
+                                    // spawn a task to clean up the UDP socket
+                                    let tata: smol::Task<Option<()>> = runtime::spawn_local(
+                                        async move {
+                                            loop {
+                                                let bufs =
+                                                    old_socket.recv_from_many().await.ok()?;
+                                                for (buf, _) in bufs {
+                                                    drop(send_packet_in.send(buf).await)
+                                                }
+                                            }
                                         }
-                                    }
+                                        .or(async {
+                                            smol::Timer::after(Duration::from_secs(60)).await;
+                                            None
+                                        }),
+                                    );
+                                    tata.detach();
                                 }
-                                .or(async {
-                                    smol::Timer::after(Duration::from_secs(60)).await;
-                                    None
-                                }),
-                            );
-                            tata.detach();
-                            socket = (cfg.backhaul_gen)()
+                                Err(err) => tracing::warn!("cannot replace backhaul: {}", err),
+                            }
                         }
                     }
                     drop(
                         socket
                             .send_to(
-                                g_encrypt.pad_encrypt_v1(
-                                    &[protocol::HandshakeFrame::ClientResume {
+                                g_encrypt.pad_encrypt_handshake(&[
+                                    protocol::HandshakeFrame::ClientResume {
                                         resume_token: resume_token.clone(),
                                         shard_id,
-                                    }],
-                                    1000,
-                                ),
+                                    },
+                                ]),
                                 cfg.server_addr,
                             )
                             .await,
@@ -242,7 +938,148 @@ async fn client_backhaul_once(
                 }
                 drop(socket.send_to(bts, cfg.server_addr).await);
             }
+            Some(Evt::Keepalive) => {
+                let g_encrypt = crypt::LegacyAEAD::new(&cookie.generate_c2s().next().unwrap());
+                drop(
+                    socket
+                        .send_to(
+                            g_encrypt.pad_encrypt_handshake(&[
+                                protocol::HandshakeFrame::ClientResume {
+                                    resume_token: resume_token.clone(),
+                                    shard_id,
+                                },
+                            ]),
+                            cfg.server_addr,
+                        )
+                        .await,
+                );
+            }
+            Some(Evt::Echo) => {
+                if shards.in_use(shard_id) {
+                    let nonce = shards.next_echo.fetch_add(1, Ordering::Relaxed);
+                    // the first version has no frames to echo with
+                    if let Some(request) =
+                        sealer.seal(&protocol::DataFrameV2::EchoRequest { nonce })
+                    {
+                        *shards.health[shard_id as usize].echo_sent.lock() =
+                            Some((nonce, Instant::now()));
+                        drop(socket.send_to(request, cfg.server_addr).await);
+                    }
+                }
+                next_echo = Instant::now() + SHARD_ECHO_INTERVAL;
+            }
             None => return None,
         }
     }
 }
+
+/// Whether the packet is the server telling us it rejected our resume token.
+fn is_rejection(cookie: &crypt::Cookie, token_hash: &blake3::Hash, packet: &[u8]) -> bool {
+    cookie.generate_s2c().any(|key| {
+        crypt::LegacyAEAD::new(&key)
+            .pad_decrypt_v1::<protocol::HandshakeFrame>(packet)
+            .unwrap_or_default()
+            .into_iter()
+            .any(|frame| {
+                matches!(frame, protocol::HandshakeFrame::ServerResumeRejected { token_hash: hash } if hash.as_ref() == token_hash.as_bytes())
+            })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starved_shards_are_bad() {
+        // the third shard gets a fraction of its share, while one not in service is left alone
+        assert_eq!(
+            bad_shards(&[Some(400), Some(350), Some(30), None]),
+            vec![false, false, true, false]
+        );
+        // too few packets to tell
+        assert_eq!(bad_shards(&[Some(100), Some(0)]), vec![false, false]);
+        // a single shard in service has nothing to be compared with
+        assert_eq!(bad_shards(&[Some(1000), None]), vec![false, false]);
+    }
+
+    #[test]
+    fn shard_count_adapts() {
+        // loss adds shards, up to the cap
+        assert_eq!(next_shard_count(2, 8, 0.1, 100.0), 3);
+        assert_eq!(next_shard_count(8, 8, 0.1, 100.0), 8);
+        // so does heavy traffic
+        assert_eq!(next_shard_count(2, 8, 0.0, 5000.0), 3);
+        // a clean, quiet link sheds shards, but never the last one
+        assert_eq!(next_shard_count(4, 8, 0.0, 100.0), 3);
+        assert_eq!(next_shard_count(1, 8, 0.0, 0.0), 1);
+        // in between, nothing changes
+        assert_eq!(next_shard_count(4, 8, 0.02, 100.0), 4);
+    }
+
+    #[test]
+    fn shards_lose_what_they_fall_short_by() {
+        assert_eq!(
+            shard_losses(&[Some(400), Some(300), None]),
+            vec![Some(0.0), Some(0.25), None]
+        );
+        // too few packets to tell
+        assert_eq!(shard_losses(&[Some(100), Some(50)]), vec![None, None]);
+    }
+
+    #[test]
+    fn congested_paths_back_off() {
+        let mut clean = ShardController::default();
+        let mut queueing = ShardController::default();
+        let mut lossy = ShardController::default();
+        for _ in 0..10 {
+            clean.on_rtt(Duration::from_millis(50));
+            clean.adjust();
+            // the first echo sets the baseline, so only later ones can look congested
+            queueing.on_rtt(Duration::from_millis(50));
+            queueing.on_rtt(Duration::from_millis(200));
+            queueing.adjust();
+            lossy.on_loss(0.2);
+            lossy.adjust();
+        }
+        assert_eq!(clean.rate, SHARD_INITIAL_RATE + SHARD_RATE_INCREASE * 10.0);
+        assert!(queueing.rate < SHARD_INITIAL_RATE * 0.1);
+        assert!(lossy.rate < SHARD_INITIAL_RATE * 0.1);
+        // jitter within the slack isn't congestion
+        let mut jittery = ShardController::default();
+        jittery.on_rtt(Duration::from_millis(50));
+        jittery.on_rtt(Duration::from_millis(70));
+        jittery.adjust();
+        assert!(jittery.rate > SHARD_INITIAL_RATE);
+        // rates stay within bounds
+        for _ in 0..100 {
+            lossy.on_loss(0.5);
+            lossy.adjust();
+        }
+        assert_eq!(lossy.rate, SHARD_MIN_RATE);
+        // and a path that clears up gets its traffic back
+        lossy.adjust();
+        assert!(lossy.rate > SHARD_MIN_RATE);
+    }
+
+    #[test]
+    fn traffic_follows_bandwidth() {
+        // a path that carries three times as much gets three times the packets, and shards not in use get none
+        let weights = [Some(300.0), Some(100.0), None];
+        let mut credit = vec![0.0; 3];
+        let mut picked = [0; 3];
+        for _ in 0..40 {
+            picked[next_shard(&mut credit, &weights).unwrap()] += 1;
+        }
+        assert_eq!(picked, [30, 10, 0]);
+        // a shard without an estimate yet counts as an average one
+        let weights = [Some(100.0), Some(0.0)];
+        let mut credit = vec![0.0; 2];
+        let mut picked = [0; 2];
+        for _ in 0..40 {
+            picked[next_shard(&mut credit, &weights).unwrap()] += 1;
+        }
+        assert_eq!(picked, [20, 20]);
+        assert_eq!(next_shard(&mut [0.0], &[None]), None);
+    }
+}
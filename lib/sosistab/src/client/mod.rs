@@ -5,37 +5,173 @@ use crate::*;
 use std::{net::SocketAddr, sync::Arc};
 
 mod inner;
+pub use inner::{max_version, set_max_version, LATEST_VERSION};
+
+/// Why connecting to a server failed.
+#[derive(Debug)]
+pub enum ConnectError {
+    /// Couldn't bind a local socket.
+    Bind(std::io::Error),
+    /// The server never answered the handshake.
+    HandshakeTimeout,
+    /// The server answered with a different long-term key than expected.
+    BadServerKey,
+    /// Some other I/O error.
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectError::Bind(err) => write!(f, "cannot bind local socket: {}", err),
+            ConnectError::HandshakeTimeout => write!(f, "handshake timed out"),
+            ConnectError::BadServerKey => write!(f, "server has the wrong public key"),
+            ConnectError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+impl From<ConnectError> for std::io::Error {
+    fn from(err: ConnectError) -> Self {
+        match err {
+            ConnectError::Bind(err) | ConnectError::Io(err) => err,
+            ConnectError::HandshakeTimeout => {
+                std::io::Error::new(std::io::ErrorKind::TimedOut, "handshake timed out")
+            }
+            ConnectError::BadServerKey => {
+                std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "bad pubkey")
+            }
+        }
+    }
+}
 
 /// Connects to a remote server over UDP.
 pub async fn connect_udp(
     server_addr: SocketAddr,
     pubkey: x25519_dalek::PublicKey,
 ) -> std::io::Result<Session> {
+    Ok(try_connect_udp(server_addr, pubkey).await?)
+}
+
+/// Connects to a remote server over TCP.
+pub async fn connect_tcp(
+    server_addr: SocketAddr,
+    pubkey: x25519_dalek::PublicKey,
+) -> std::io::Result<Session> {
+    Ok(try_connect_tcp(server_addr, pubkey).await?)
+}
+
+/// Connects to a remote server over UDP, returning a typed error on failure.
+pub async fn try_connect_udp(
+    server_addr: SocketAddr,
+    pubkey: x25519_dalek::PublicKey,
+) -> Result<Session, ConnectError> {
     inner::connect_custom(inner::ClientConfig {
         server_addr,
         server_pubkey: pubkey,
-        backhaul_gen: Arc::new(|| {
-            Arc::new(smol::future::block_on(runtime::new_udp_socket_bind("0.0.0.0:0")).unwrap())
-        }),
+        backhaul_gen: Arc::new(udp_backhaul),
         num_shards: 8,
+        max_shards: None,
         reset_interval: Some(Duration::from_secs(20)),
     })
     .await
 }
 
-/// Connects to a remote server over UDP.
-pub async fn connect_tcp(
+/// Connects to a remote server over UDP, starting with a couple of shards and adapting the shard count to the link, up to `max_shards`.
+pub async fn try_connect_udp_adaptive(
     server_addr: SocketAddr,
     pubkey: x25519_dalek::PublicKey,
-) -> std::io::Result<Session> {
+    max_shards: usize,
+) -> Result<Session, ConnectError> {
+    inner::connect_custom(inner::ClientConfig {
+        server_addr,
+        server_pubkey: pubkey,
+        backhaul_gen: Arc::new(udp_backhaul),
+        num_shards: 2.min(max_shards.max(1)),
+        max_shards: Some(max_shards),
+        reset_interval: Some(Duration::from_secs(20)),
+    })
+    .await
+}
+
+/// Connects to a remote server over TCP, starting with a few shards and adapting the shard count to the link, up to `max_shards`.
+pub async fn try_connect_tcp_adaptive(
+    server_addr: SocketAddr,
+    pubkey: x25519_dalek::PublicKey,
+    max_shards: usize,
+) -> Result<Session, ConnectError> {
+    try_connect_tcp_tuned(server_addr, pubkey, Some(max_shards), TcpOptions::default()).await
+}
+
+/// Connects to a remote server over TCP, with its connections tuned as given. With `max_shards`, the session starts with a few shards and adapts the shard count to the link, up to that many. Otherwise, it always uses 16.
+pub async fn try_connect_tcp_tuned(
+    server_addr: SocketAddr,
+    pubkey: x25519_dalek::PublicKey,
+    max_shards: Option<usize>,
+    options: TcpOptions,
+) -> Result<Session, ConnectError> {
     inner::connect_custom(inner::ClientConfig {
         server_addr,
         server_pubkey: pubkey,
         backhaul_gen: Arc::new(move || {
-            Arc::new(TcpClientBackhaul::new().add_remote_key(server_addr, pubkey))
+            Ok(Arc::new(
+                TcpClientBackhaul::new()
+                    .add_remote_key(server_addr, pubkey)
+                    .with_options(options),
+            ))
         }),
-        num_shards: 16,
+        num_shards: max_shards.map(|max| 4.min(max.max(1))).unwrap_or(16),
+        max_shards,
+        reset_interval: None,
+    })
+    .await
+}
+
+/// Connects to a remote server over backhauls the caller provides, rather than over the built-in UDP and TCP ones. This lets sessions run over other transports, such as a [StreamBackhaul] over a stream through an SSH tunnel, or another session's channel.
+///
+/// `backhaul_gen` is called once for the handshake and once for every shard, and each backhaul it returns must deliver datagrams to and from `server_addr`, which is only used as a label if the backhaul doesn't need a real address. The session has `num_shards` shards, which is best left at 1 if every call returns the same backhaul.
+pub async fn connect_over(
+    server_addr: SocketAddr,
+    pubkey: x25519_dalek::PublicKey,
+    backhaul_gen: impl Fn() -> std::io::Result<Arc<dyn Backhaul>> + 'static + Send + Sync,
+    num_shards: usize,
+) -> Result<Session, ConnectError> {
+    inner::connect_custom(inner::ClientConfig {
+        server_addr,
+        server_pubkey: pubkey,
+        backhaul_gen: Arc::new(backhaul_gen),
+        num_shards: num_shards.max(1),
+        max_shards: None,
         reset_interval: None,
     })
     .await
 }
+
+/// Checks that a server answers handshakes over UDP, without creating a session on it, returning the round-trip time. Gives up after about `attempts` doublings of a one-second wait.
+pub async fn probe_udp(
+    server_addr: SocketAddr,
+    pubkey: x25519_dalek::PublicKey,
+    attempts: u32,
+) -> Result<Duration, ConnectError> {
+    let backhaul = udp_backhaul().map_err(ConnectError::Bind)?;
+    inner::probe(backhaul, server_addr, pubkey, attempts).await
+}
+
+/// Binds a fresh UDP backhaul, disguised as configured by [UdpObfuscation].
+fn udp_backhaul() -> std::io::Result<Arc<dyn Backhaul>> {
+    let socket = smol::future::block_on(runtime::new_udp_socket_bind("0.0.0.0:0"))?;
+    Ok(match UdpObfuscation::get() {
+        UdpObfuscation::None => Arc::new(socket),
+        UdpObfuscation::Dtls => Arc::new(DtlsBackhaul::client(socket)),
+    })
+}
+
+/// Connects to a remote server over TCP, returning a typed error on failure.
+pub async fn try_connect_tcp(
+    server_addr: SocketAddr,
+    pubkey: x25519_dalek::PublicKey,
+) -> Result<Session, ConnectError> {
+    try_connect_tcp_tuned(server_addr, pubkey, None, TcpOptions::default()).await
+}
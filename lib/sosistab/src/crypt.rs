@@ -6,10 +6,35 @@ use rand::prelude::*;
 use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use std::{sync::Arc, time::SystemTime};
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
 
 pub const UP_KEY: &[u8; 32] = b"upload--------------------------";
 pub const DN_KEY: &[u8; 32] = b"download------------------------";
+pub const ID_KEY: &[u8; 32] = b"session-id----------------------";
+pub const REBIND_KEY: &[u8; 32] = b"rebind--------------------------";
+
+/// Derives a secret from a session's keys, which only the two sides of the session know. A multiplex moving off the session shows it to the other side to say which multiplex it is.
+pub fn rebind_secret(up_key: &blake3::Hash, dn_key: &blake3::Hash) -> [u8; 32] {
+    let mut keys = [0u8; 64];
+    keys[..32].copy_from_slice(up_key.as_bytes());
+    keys[32..].copy_from_slice(dn_key.as_bytes());
+    *blake3::keyed_hash(REBIND_KEY, &keys).as_bytes()
+}
+
+/// Derives a short identifier for a session from its resume token. Both sides have the token, so they agree on the identifier, but since it's a hash, the identifier can be logged without revealing anything that helps hijack the session.
+pub fn session_id(resume_token: &[u8]) -> String {
+    blake3::keyed_hash(ID_KEY, resume_token).as_bytes()[..6]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
 /// A structure for encrypting or decrypting Chacha12/Blake3-64.
 #[derive(Debug, Copy, Clone)]
 pub struct LegacyAEAD {
@@ -78,7 +103,23 @@ impl LegacyAEAD {
 
     /// Pad and encrypt.
     pub fn pad_encrypt_v1(&self, msgs: &[impl Serialize], target_len: usize) -> Bytes {
-        let mut target_len = rand::thread_rng().gen_range(0, target_len);
+        self.pad_encrypt_range(msgs, 0, target_len)
+    }
+
+    /// Pad and encrypt a handshake, using the process-wide handshake padding distribution.
+    pub fn pad_encrypt_handshake(&self, msgs: &[impl Serialize]) -> Bytes {
+        let padding = HandshakePadding::get();
+        self.pad_encrypt_range(msgs, padding.min, padding.max)
+    }
+
+    /// Pad and encrypt, picking a padded length uniformly between the two bounds.
+    pub fn pad_encrypt_range(
+        &self,
+        msgs: &[impl Serialize],
+        min_len: usize,
+        max_len: usize,
+    ) -> Bytes {
+        let mut target_len = rand::thread_rng().gen_range(min_len, max_len.max(min_len + 1));
         let mut plain = Vec::with_capacity(1500);
         for msg in msgs {
             bincode::serialize_into(&mut plain, &msg).unwrap();
@@ -119,6 +160,58 @@ impl LegacyAEAD {
     }
 }
 
+static HANDSHAKE_PADDING_MIN: AtomicUsize = AtomicUsize::new(0);
+static HANDSHAKE_PADDING_MAX: AtomicUsize = AtomicUsize::new(1000);
+
+/// The distribution of handshake packet lengths: every handshake is padded to a length picked uniformly between `min` and `max`.
+///
+/// Padding is self-delimiting, so the two sides do not need to use the same distribution. Every deployment sharing the default makes it a fingerprint of its own, but an unusual distribution is even easier to single out, so only change it to mimic some specific other protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakePadding {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl Default for HandshakePadding {
+    fn default() -> Self {
+        Self { min: 0, max: 1000 }
+    }
+}
+
+impl HandshakePadding {
+    /// Sets the handshake padding used by every client and listener in this process.
+    pub fn set(self) {
+        HANDSHAKE_PADDING_MIN.store(self.min, Ordering::Relaxed);
+        HANDSHAKE_PADDING_MAX.store(self.max, Ordering::Relaxed);
+    }
+
+    /// Gets the handshake padding used in this process.
+    pub fn get() -> Self {
+        Self {
+            min: HANDSHAKE_PADDING_MIN.load(Ordering::Relaxed),
+            max: HANDSHAKE_PADDING_MAX.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl FromStr for HandshakePadding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '-');
+        let (min, max) = match (parts.next(), parts.next()) {
+            (Some(min), Some(max)) => (min, max),
+            _ => return Err(format!("padding {:?} is not of the form MIN-MAX", s)),
+        };
+        let min: usize = min.parse().map_err(|e| format!("bad minimum: {}", e))?;
+        let max: usize = max.parse().map_err(|e| format!("bad maximum: {}", e))?;
+        if min > max || max > 1300 {
+            return Err(format!("padding {:?} must satisfy MIN <= MAX <= 1300", s));
+        }
+        Ok(Self { min, max })
+    }
+}
+
 /// Next generation AEAD, based on `ring`'s ChaCha20/Poly1305, used in versions 3 and above
 #[derive(Debug, Clone)]
 pub struct NgAEAD {
@@ -251,3 +344,66 @@ pub fn triple_ecdh(
     };
     blake3::hash(&to_hash)
 }
+
+/// Noise pattern used by the version-4 handshake.
+const NOISE_PARAMS: &str = "Noise_IK_25519_ChaChaPoly_BLAKE2s";
+
+/// Client side of the version-4 handshake, which is Noise IK with the server's long-term key as the responder's static key.
+pub struct NoiseInitiator {
+    state: snow::HandshakeState,
+}
+
+impl NoiseInitiator {
+    /// Starts a handshake to the server with the given long-term public key.
+    pub fn new(server_pk: &x25519_dalek::PublicKey) -> Self {
+        let builder = snow::Builder::new(NOISE_PARAMS.parse().unwrap());
+        let keypair = builder.generate_keypair().unwrap();
+        let state = builder
+            .local_private_key(&keypair.private)
+            .remote_public_key(server_pk.as_bytes())
+            .build_initiator()
+            .unwrap();
+        Self { state }
+    }
+
+    /// Produces the first handshake message.
+    pub fn hello(&mut self) -> Bytes {
+        let mut buf = vec![0u8; 1024];
+        let len = self.state.write_message(&[], &mut buf).unwrap();
+        buf.truncate(len);
+        buf.into()
+    }
+
+    /// Finishes the handshake with the server's reply, returning the shared secret. Returns None if the reply doesn't belong to this handshake.
+    pub fn finish(&mut self, reply: &[u8]) -> Option<blake3::Hash> {
+        let mut buf = vec![0u8; 1024];
+        self.state.read_message(reply, &mut buf).ok()?;
+        Some(noise_secret(&self.state))
+    }
+}
+
+/// Server side of the version-4 handshake. Returns the reply to send to the client and the shared secret.
+pub fn noise_respond(
+    long_sk: &x25519_dalek::StaticSecret,
+    hello: &[u8],
+) -> Option<(Bytes, blake3::Hash)> {
+    let mut state = snow::Builder::new(NOISE_PARAMS.parse().unwrap())
+        .local_private_key(&long_sk.to_bytes())
+        .build_responder()
+        .ok()?;
+    let mut buf = vec![0u8; 1024];
+    state.read_message(hello, &mut buf).ok()?;
+    let len = state.write_message(&[], &mut buf).ok()?;
+    buf.truncate(len);
+    Some((buf.into(), noise_secret(&state)))
+}
+
+/// Derives the session secret from a finished Noise handshake. Sosistab does its own transport encryption, so we only take the keys out of Noise.
+fn noise_secret(state: &snow::HandshakeState) -> blake3::Hash {
+    let (a, b) = state.dangerously_get_raw_split();
+    let (a, b) = if a < b { (a, b) } else { (b, a) };
+    let mut to_hash = Vec::with_capacity(64);
+    to_hash.extend_from_slice(&a);
+    to_hash.extend_from_slice(&b);
+    blake3::hash(&to_hash)
+}
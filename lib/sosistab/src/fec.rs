@@ -1,4 +1,8 @@
 use bytes::{Bytes, BytesMut};
+use std::{
+    str::FromStr,
+    sync::atomic::{AtomicU16, Ordering},
+};
 
 mod decoder;
 mod encoder;
@@ -6,6 +10,74 @@ mod wrapped;
 pub use decoder::*;
 pub use encoder::*;
 
+/// 0 is adaptive, 1 is off, and 2 + N is at least N percent.
+static FEC_MODE: AtomicU16 = AtomicU16::new(0);
+
+/// How much forward error correction sessions send. Parity packets describe themselves, so receivers decode them whatever mode they use, and the two ends don't have to agree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FecMode {
+    /// Enough parity to keep the loss after correction around the target, based on the measured loss. Nothing is sent while there's no measured loss.
+    Adaptive,
+    /// No parity at all, leaving lost packets to retransmissions.
+    Off,
+    /// At least this percentage of the data packets as parity, or more if the measured loss calls for it. This helps on links where loss comes in bursts too short for the loss measurement to notice, at the cost of the bandwidth used by the parity.
+    AtLeast(u8),
+}
+
+impl FecMode {
+    /// Sets the mode used by every session from now on.
+    pub fn set(self) {
+        let packed = match self {
+            FecMode::Adaptive => 0,
+            FecMode::Off => 1,
+            FecMode::AtLeast(pct) => 2 + pct as u16,
+        };
+        FEC_MODE.store(packed, Ordering::Relaxed)
+    }
+
+    /// Gets the mode used in this process.
+    pub fn get() -> Self {
+        match FEC_MODE.load(Ordering::Relaxed) {
+            0 => FecMode::Adaptive,
+            1 => FecMode::Off,
+            packed => FecMode::AtLeast((packed - 2) as u8),
+        }
+    }
+
+    /// How many parity packets to send for a run of data packets, given how many the measured loss calls for.
+    fn parity_len(self, adaptive_len: usize, run_len: usize) -> usize {
+        match self {
+            FecMode::Adaptive => adaptive_len,
+            FecMode::Off => 0,
+            FecMode::AtLeast(pct) => {
+                let floor = (run_len * pct as usize + 99) / 100;
+                adaptive_len.max(floor).min(run_len).min(255 - run_len)
+            }
+        }
+    }
+}
+
+impl FromStr for FecMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "adaptive" => Ok(FecMode::Adaptive),
+            "off" => Ok(FecMode::Off),
+            other => match other
+                .strip_suffix('%')
+                .and_then(|pct| pct.parse::<u8>().ok())
+            {
+                Some(pct) if pct > 0 && pct <= 100 => Ok(FecMode::AtLeast(pct)),
+                _ => Err(format!(
+                    "unknown FEC mode {:?} (expected adaptive, off, or a percentage such as 20%)",
+                    other
+                )),
+            },
+        }
+    }
+}
+
 pub fn pre_encode(pkt: &[u8], len: usize) -> BytesMut {
     assert!(pkt.len() <= 65535);
     assert!(pkt.len() + 2 <= len);
@@ -25,3 +97,20 @@ fn post_decode(raw: Bytes) -> Option<Bytes> {
     let body_len = u16::from_le_bytes([raw[0], raw[1]]);
     Some(raw.slice(2..2 + body_len as usize))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fec_mode_parity() {
+        assert_eq!("20%".parse::<FecMode>(), Ok(FecMode::AtLeast(20)));
+        assert!("0%".parse::<FecMode>().is_err());
+        assert!("150%".parse::<FecMode>().is_err());
+        assert_eq!(FecMode::Adaptive.parity_len(0, 16), 0);
+        assert_eq!(FecMode::Off.parity_len(3, 16), 0);
+        assert_eq!(FecMode::AtLeast(20).parity_len(0, 16), 4);
+        assert_eq!(FecMode::AtLeast(20).parity_len(6, 16), 6);
+        assert_eq!(FecMode::AtLeast(100).parity_len(0, 1), 1);
+    }
+}
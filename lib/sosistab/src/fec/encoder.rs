@@ -3,7 +3,7 @@ use probability::distribution::Distribution;
 
 use rustc_hash::FxHashMap;
 
-use super::{pre_encode, wrapped::WrappedReedSolomon};
+use super::{pre_encode, wrapped::WrappedReedSolomon, FecMode};
 
 /// A forward error correction encoder. Retains internal state for memoization, memory pooling etc.
 #[derive(Debug)]
@@ -34,7 +34,7 @@ impl FrameEncoder {
             pkts.iter().map(|p| pre_encode(p, max_length + 2)).collect();
         // then we get an encoder for this size
         let data_shards = pkts.len();
-        let parity_shards = self.repair_len(measured_loss, pkts.len());
+        let parity_shards = self.parity_len(measured_loss, pkts.len());
         // then we encode
         // prepare the space for in-place mutation
         let mut parity_shard_space = vec![vec![0u8; max_length + 2]; parity_shards];
@@ -62,6 +62,16 @@ impl FrameEncoder {
         toret
     }
 
+    /// Calculates the number of parity packets to send for a run of packets, following the process-wide FEC mode.
+    pub fn parity_len(&mut self, measured_loss: u8, run_len: usize) -> usize {
+        let adaptive_len = if measured_loss == 0 {
+            0
+        } else {
+            self.repair_len(measured_loss, run_len)
+        };
+        FecMode::get().parity_len(adaptive_len, run_len)
+    }
+
     /// Calculates the number of repair blocks needed to properly reconstruct a run of packets.
     fn repair_len(&mut self, measured_loss: u8, run_len: usize) -> usize {
         let target_loss = self.target_loss;
@@ -0,0 +1,86 @@
+//! Logging of session keys, so that developers can decrypt captures of their own traffic, like browsers do with `SSLKEYLOGFILE`. Anyone who gets hold of the key log can read everything the logged sessions carried, so this is off unless [KEYLOG_ENV] is set, and loudly complains when it's on.
+//!
+//! The key log is a text file that keys are appended to, one line each, with hex keys:
+//!
+//! ```text
+//! SESSION <session id> <upload key> <download key>
+//! TCP <client address> <server address> <upload key> <download key>
+//! ```
+//!
+//! `SESSION` lines have the keys of a session, which encrypt the body of every packet after the handshake, with the cipher the session's protocol version calls for. Packets don't say which session they're from, so try each key until one decrypts, much as a listener would. The session id is the one [crate::Session::id] returns, and that both sides log.
+//!
+//! `TCP` lines have the keys of the ChaCha8 stream cipher that obfuscates a TCP connection, which has to be removed before the session's packets inside can be read. Upload means from the client to the server.
+//!
+//! Handshakes aren't covered, since anyone who knows the server's public key can already decrypt them.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+/// Environment variable with the path of the key log. Keys are only logged if it's set.
+pub const KEYLOG_ENV: &str = "SOSISTAB_KEYLOGFILE";
+
+static KEYLOG: Lazy<Option<Mutex<File>>> = Lazy::new(|| {
+    let path = std::env::var_os(KEYLOG_ENV)?;
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    match options.open(&path) {
+        Ok(file) => {
+            let warning = format!(
+                "WARNING: {} is set, so sosistab session keys are being written to {:?}. Anyone who can read that file can decrypt this traffic. Never do this outside of debugging.",
+                KEYLOG_ENV, path
+            );
+            tracing::warn!("{}", warning);
+            eprintln!("{}", warning);
+            Some(Mutex::new(file))
+        }
+        Err(err) => {
+            tracing::error!("can't open key log {:?}: {}", path, err);
+            None
+        }
+    }
+});
+
+/// Whether keys are being logged. Calling this at startup makes the warning appear right away, rather than with the first session.
+pub fn keylog_enabled() -> bool {
+    KEYLOG.is_some()
+}
+
+fn hex(key: &[u8]) -> String {
+    key.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn write_line(line: String) {
+    if let Some(file) = KEYLOG.as_ref() {
+        if let Err(err) = writeln!(file.lock(), "{}", line) {
+            tracing::error!("can't write to key log: {}", err);
+        }
+    }
+}
+
+/// Logs the keys of a session.
+pub(crate) fn log_session(id: &str, up_key: &[u8], dn_key: &[u8]) {
+    if KEYLOG.is_some() {
+        write_line(format!("SESSION {} {} {}", id, hex(up_key), hex(dn_key)))
+    }
+}
+
+/// Logs the keys of an obfuscated TCP connection.
+pub(crate) fn log_tcp(client: SocketAddr, server: SocketAddr, up_key: &[u8], dn_key: &[u8]) {
+    if KEYLOG.is_some() {
+        write_line(format!(
+            "TCP {} {} {} {}",
+            client,
+            server,
+            hex(up_key),
+            hex(dn_key)
+        ))
+    }
+}
@@ -3,9 +3,16 @@ mod crypt;
 mod fec;
 mod listener;
 pub use client::*;
+pub use crypt::HandshakePadding;
 use crypt::{LegacyAEAD, NgAEAD};
+pub use fec::FecMode;
+mod keylog;
+pub use keylog::{keylog_enabled, KEYLOG_ENV};
 pub use listener::*;
 use std::time::{Duration, Instant};
+mod pow;
+mod priority;
+pub use priority::Priority;
 mod protocol;
 pub mod runtime;
 mod session;
@@ -14,6 +21,9 @@ mod backhaul;
 pub mod mux;
 mod tcp;
 pub use backhaul::*;
+pub use tcp::{tcp_padding_overhead, TcpFraming, TcpOptions};
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
 mod recfilter;
 
 #[cfg(test)]
@@ -8,7 +8,6 @@ use bytes::Bytes;
 use governor::{Quota, RateLimiter};
 use parking_lot::RwLock;
 use protocol::HandshakeFrame::*;
-use rand::prelude::*;
 use serde::{Deserialize, Serialize};
 use smol::net::AsyncToSocketAddrs;
 use smol::{
@@ -16,17 +15,115 @@ use smol::{
     net::TcpListener,
 };
 use std::net::SocketAddr;
-use std::{num::NonZeroU32, sync::Arc};
+use std::{
+    hash::{Hash, Hasher},
+    num::NonZeroU32,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
 use table::ShardedAddrs;
 use tcp::TcpServerBackhaul;
 
 use self::table::SessionTable;
 
+mod events;
+mod rng;
 mod table;
+use events::EventHub;
+pub use events::{SessionCloseReason, SessionEvent, SESSION_EVENT_BUFFER};
+pub(crate) use rng::HandshakeRng;
+
+/// How long a listener's sessions can receive nothing before they're considered dead and dropped, unless the listener was given another timeout. Sessions whose client says goodbye are dropped right away.
+pub const DEFAULT_RECV_TIMEOUT: Duration = Duration::from_secs(3600);
+
+static HANDSHAKE_RATE_LIMIT: AtomicU32 = AtomicU32::new(6000);
+static HANDSHAKE_WORKERS: AtomicUsize = AtomicUsize::new(0);
+static HANDSHAKE_QUEUE_LEN: AtomicUsize = AtomicUsize::new(1024);
+static HANDSHAKE_QUEUE_DEPTH: AtomicUsize = AtomicUsize::new(0);
+static HANDSHAKES_SHED: AtomicU64 = AtomicU64::new(0);
+static HANDSHAKES_ANSWERED: AtomicU64 = AtomicU64::new(0);
+static HANDSHAKE_FAILURES: AtomicU64 = AtomicU64::new(0);
+static DATAGRAMS_UNDERSIZED: AtomicU64 = AtomicU64::new(0);
+static DATAGRAMS_OVERSIZED: AtomicU64 = AtomicU64::new(0);
+static HANDSHAKE_POW_MAX_BITS: AtomicU32 = AtomicU32::new(0);
+static DECODE_QUEUE_DEPTH: AtomicUsize = AtomicUsize::new(0);
+static DECODE_SHED: AtomicU64 = AtomicU64::new(0);
+
+/// How many packets that might be handshakes each decoding worker holds, waiting to be decrypted.
+const DECODE_QUEUE_LEN: usize = 256;
+
+/// Datagrams shorter than this can't be anything, since even the lightest AEAD we use adds this much.
+const MIN_DATAGRAM_LEN: usize = 24;
+
+/// Sets how many new handshakes per minute listeners created from now on accept from any single IP address. Excess handshakes are dropped before doing any expensive cryptography. Zero disables the limit.
+///
+/// Resuming shards doesn't count, but everything behind a NAT or a bridge shares one address, so this shouldn't be set too low.
+pub fn set_handshake_rate_limit(per_minute: u32) {
+    HANDSHAKE_RATE_LIMIT.store(per_minute, Ordering::Relaxed)
+}
+
+/// Sets how many handshakes listeners created from now on answer at once, and how many more wait in a queue for their turn. Hellos arriving when the queue is full are dropped, and their clients retry later. Zero workers means half the CPU cores, so that a flood of handshakes can't starve established sessions.
+pub fn set_handshake_concurrency(workers: usize, queue_len: usize) {
+    HANDSHAKE_WORKERS.store(workers, Ordering::Relaxed);
+    HANDSHAKE_QUEUE_LEN.store(queue_len.max(1), Ordering::Relaxed)
+}
+
+/// Makes listeners created from now on ask clients to solve a proof-of-work challenge before answering their hellos, once the handshake queue is a quarter full. The challenge gets harder as the queue fills, up to the given number of bits, each of which doubles the work. Zero disables this. Clients that don't know about proof of work can't connect while the challenge is on, but are unaffected otherwise.
+pub fn set_handshake_pow(max_bits: u8) {
+    HANDSHAKE_POW_MAX_BITS.store(max_bits.min(pow::MAX_BITS) as u32, Ordering::Relaxed)
+}
+
+/// How many handshakes are currently waiting for a worker, across all listeners.
+pub fn handshake_queue_depth() -> usize {
+    HANDSHAKE_QUEUE_DEPTH.load(Ordering::Relaxed)
+}
+
+/// How many handshakes have been dropped because the queue was full, across all listeners.
+pub fn handshakes_shed() -> u64 {
+    HANDSHAKES_SHED.load(Ordering::Relaxed)
+}
+
+/// How many hellos have been answered, each starting a new session unless the client gives up, across all listeners.
+pub fn handshakes_answered() -> u64 {
+    HANDSHAKES_ANSWERED.load(Ordering::Relaxed)
+}
+
+/// How many hellos were decrypted but then turned out to be unusable, such as ones with an unknown version or a bad Noise message, across all listeners.
+pub fn handshake_failures() -> u64 {
+    HANDSHAKE_FAILURES.load(Ordering::Relaxed)
+}
+
+/// How many incoming datagrams have been dropped, before any decryption, for being too short to be a sosistab packet, across all listeners. Many of these point to a flood of junk.
+pub fn datagrams_undersized() -> u64 {
+    DATAGRAMS_UNDERSIZED.load(Ordering::Relaxed)
+}
+
+/// How many incoming datagrams have been dropped, before any decryption, for filling the whole receive buffer, which means they were most likely truncated, across all listeners. Many of these point to something on the path mangling packets, or to clients sending packets bigger than the path can carry.
+pub fn datagrams_oversized() -> u64 {
+    DATAGRAMS_OVERSIZED.load(Ordering::Relaxed)
+}
+
+/// How many packets that might be handshakes are waiting to be decrypted, across all listeners. These are the packets that don't belong to a known session, so a lot of them means a flood of junk or of hellos.
+pub fn decode_queue_depth() -> usize {
+    DECODE_QUEUE_DEPTH.load(Ordering::Relaxed)
+}
+
+/// How many packets that might have been handshakes were dropped undecrypted because the decoding queues were full, across all listeners.
+pub fn decode_shed() -> u64 {
+    DECODE_SHED.load(Ordering::Relaxed)
+}
+
+/// How many handshake packets have been dropped as replays of earlier ones, across all listeners.
+pub fn replays_dropped() -> u64 {
+    crate::recfilter::REPLAYS_DROPPED.load(Ordering::Relaxed)
+}
 
 pub struct Listener {
     accepted: Receiver<Session>,
     local_addr: SocketAddr,
+    events: Arc<EventHub>,
     _task: smol::Task<Option<()>>,
 }
 
@@ -36,39 +133,138 @@ impl Listener {
     pub async fn accept_session(&self) -> Option<Session> {
         self.accepted.recv().await.ok()
     }
-    /// Creates a new listener given the parameters.
+
+    /// Accepts a session, giving up after the given timeout. Returns None if no session arrived in time, or if the listener has stopped.
+    pub async fn accept_session_timeout(&self, timeout: Duration) -> Option<Session> {
+        smol_timeout::TimeoutExt::timeout(self.accept_session(), timeout)
+            .await
+            .flatten()
+    }
+
+    /// Accepts at least one and at most `max` sessions, draining any that are already queued. Returns an empty vector only if the listener has stopped.
+    pub async fn accept_many(&self, max: usize) -> Vec<Session> {
+        let mut toret = Vec::new();
+        if let Some(first) = self.accept_session().await {
+            toret.push(first);
+            while toret.len() < max {
+                match self.accepted.try_recv() {
+                    Ok(sess) => toret.push(sess),
+                    Err(_) => break,
+                }
+            }
+        }
+        toret
+    }
+    /// Subscribes to the opening and closing of this listener's sessions, from now on. Every subscriber gets every event, but each can only fall [SESSION_EVENT_BUFFER] events behind. Past that, events are thrown away rather than holding up the listener, and the subscriber gets a [SessionEvent::Lagged] before the next event that does fit.
+    pub fn subscribe(&self) -> Receiver<SessionEvent> {
+        self.events.subscribe()
+    }
+
+    /// Creates a new listener given the parameters. Sessions that receive nothing for [DEFAULT_RECV_TIMEOUT] are considered dead and dropped.
     pub async fn listen_udp(
         addr: impl AsyncToSocketAddrs,
         long_sk: x25519_dalek::StaticSecret,
         on_recv: impl Fn(usize, SocketAddr) + 'static + Send + Sync,
         on_send: impl Fn(usize, SocketAddr) + 'static + Send + Sync,
+    ) -> Self {
+        Self::listen_udp_with_recv_timeout(addr, long_sk, on_recv, on_send, DEFAULT_RECV_TIMEOUT)
+            .await
+    }
+
+    /// Like `listen_udp`, but sessions are considered dead and dropped after receiving nothing for `recv_timeout` instead.
+    pub async fn listen_udp_with_recv_timeout(
+        addr: impl AsyncToSocketAddrs,
+        long_sk: x25519_dalek::StaticSecret,
+        on_recv: impl Fn(usize, SocketAddr) + 'static + Send + Sync,
+        on_send: impl Fn(usize, SocketAddr) + 'static + Send + Sync,
+        recv_timeout: Duration,
+    ) -> Self {
+        Self::listen_udp_with_rng(
+            addr,
+            long_sk,
+            on_recv,
+            on_send,
+            recv_timeout,
+            HandshakeRng::Os,
+        )
+        .await
+    }
+
+    /// Creates a new UDP listener whose handshakes and resume tokens are entirely determined by the seed, so that they can be reproduced when fuzzing. Never use this for real traffic.
+    #[cfg(feature = "fuzzing")]
+    pub async fn listen_udp_seeded(
+        addr: impl AsyncToSocketAddrs,
+        long_sk: x25519_dalek::StaticSecret,
+        seed: u64,
+        recv_timeout: Duration,
+    ) -> Self {
+        Self::listen_udp_with_rng(
+            addr,
+            long_sk,
+            |_, _| (),
+            |_, _| (),
+            recv_timeout,
+            HandshakeRng::seeded(seed),
+        )
+        .await
+    }
+
+    async fn listen_udp_with_rng(
+        addr: impl AsyncToSocketAddrs,
+        long_sk: x25519_dalek::StaticSecret,
+        on_recv: impl Fn(usize, SocketAddr) + 'static + Send + Sync,
+        on_send: impl Fn(usize, SocketAddr) + 'static + Send + Sync,
+        recv_timeout: Duration,
+        rng: HandshakeRng,
     ) -> Self {
         // let addr = async_net::resolve(addr).await;
         let socket = runtime::new_udp_socket_bind(addr).await.unwrap();
         let local_addr = socket.get_ref().local_addr().unwrap();
         let cookie = crypt::Cookie::new((&long_sk).into());
         let (send, recv) = smol::channel::unbounded();
+        let events = Arc::new(EventHub::default());
+        let socket = StatsBackhaul::new(socket, on_recv, on_send);
+        let socket: Arc<dyn Backhaul> = match UdpObfuscation::get() {
+            UdpObfuscation::None => Arc::new(socket),
+            UdpObfuscation::Dtls => Arc::new(DtlsBackhaul::server(socket)),
+        };
         let task = runtime::spawn_local(
             ListenerActor {
-                socket: Arc::new(StatsBackhaul::new(socket, on_recv, on_send)),
+                socket,
                 cookie,
                 long_sk,
+                recv_timeout,
+                rng: Arc::new(rng),
+                events: events.clone(),
             }
             .run(send),
         );
         Listener {
             accepted: recv,
             local_addr,
+            events,
             _task: task,
         }
     }
 
-    /// Creates a new listener given the parameters.
+    /// Creates a new listener given the parameters. Sessions that receive nothing for [DEFAULT_RECV_TIMEOUT] are considered dead and dropped.
     pub async fn listen_tcp(
         addr: impl AsyncToSocketAddrs,
         long_sk: x25519_dalek::StaticSecret,
         on_recv: impl Fn(usize, SocketAddr) + 'static + Send + Sync,
         on_send: impl Fn(usize, SocketAddr) + 'static + Send + Sync,
+    ) -> Self {
+        Self::listen_tcp_with_recv_timeout(addr, long_sk, on_recv, on_send, DEFAULT_RECV_TIMEOUT)
+            .await
+    }
+
+    /// Like `listen_tcp`, but sessions are considered dead and dropped after receiving nothing for `recv_timeout` instead.
+    pub async fn listen_tcp_with_recv_timeout(
+        addr: impl AsyncToSocketAddrs,
+        long_sk: x25519_dalek::StaticSecret,
+        on_recv: impl Fn(usize, SocketAddr) + 'static + Send + Sync,
+        on_send: impl Fn(usize, SocketAddr) + 'static + Send + Sync,
+        recv_timeout: Duration,
     ) -> Self {
         // let addr = async_net::resolve(addr).await;
         let listener = TcpListener::bind(addr).await.unwrap();
@@ -76,17 +272,22 @@ impl Listener {
         let cookie = crypt::Cookie::new((&long_sk).into());
         let socket = TcpServerBackhaul::new(listener, long_sk.clone());
         let (send, recv) = smol::channel::unbounded();
+        let events = Arc::new(EventHub::default());
         let task = runtime::spawn_local(
             ListenerActor {
                 socket: Arc::new(StatsBackhaul::new(socket, on_recv, on_send)),
                 cookie,
                 long_sk,
+                recv_timeout,
+                rng: Arc::new(HandshakeRng::Os),
+                events: events.clone(),
             }
             .run(send),
         );
         Listener {
             accepted: recv,
             local_addr,
+            events,
             _task: task,
         }
     }
@@ -101,253 +302,440 @@ struct ListenerActor {
     socket: Arc<dyn Backhaul>,
     cookie: crypt::Cookie,
     long_sk: x25519_dalek::StaticSecret,
+    recv_timeout: Duration,
+    rng: Arc<HandshakeRng>,
+    events: Arc<EventHub>,
 }
 impl ListenerActor {
     #[allow(clippy::mutable_key_type)]
     #[tracing::instrument(skip(self), level = "trace")]
     async fn run(self, accepted: Sender<Session>) -> Option<()> {
+        let recv_timeout = self.recv_timeout;
         // session table
-        let mut session_table = SessionTable::default();
+        let session_table = Arc::new(SessionTable::default());
         // channel for dropping sessions
         let (send_dead, recv_dead) = smol::channel::unbounded();
 
+        // resume tokens are only good for this run of the listener. we could persist or derive the key, so that sessions survive a restart at this layer, but whatever runs on top of sessions generally doesn't survive one, and clients would then sit on a session that's half-dead rather than reconnect. instead, we tell clients with tokens we can't decrypt to handshake again.
         let token_key = {
             let mut buf = [0u8; 32];
-            rand::thread_rng().fill_bytes(&mut buf);
+            self.rng.fill(&mut buf);
             buf
         };
 
-        let read_socket = self.socket.clone();
         let write_socket = self.socket.clone();
 
         // "fallthrough" rate limit. the idea is that new sessions on the same addr are infrequent, so we don't need to check constantly.
-        let fallthrough_limiter = RateLimiter::dashmap_with_clock(
+        let fallthrough_limiter = Arc::new(RateLimiter::dashmap_with_clock(
             Quota::per_minute(NonZeroU32::new(5u32).unwrap()),
             &governor::clock::MonotonicClock,
-        );
+        ));
+
+        // new handshakes cost a Diffie-Hellman each, so we limit how many a single address can make us do
+        let hello_limiter =
+            NonZeroU32::new(HANDSHAKE_RATE_LIMIT.load(Ordering::Relaxed)).map(|rate| {
+                RateLimiter::dashmap_with_clock(
+                    Quota::per_minute(rate),
+                    &governor::clock::MonotonicClock,
+                )
+            });
+        let hello_allowed = |addr: SocketAddr| {
+            hello_limiter
+                .as_ref()
+                .map(|limiter| limiter.check_key(&addr.ip()).is_ok())
+                .unwrap_or(true)
+        };
+
+        // packets that might be handshakes are trial-decrypted by a pool of workers, so that neither junk nor decryption holds up the actor, and only packets that decrypt reach it. packets from one address always go to the same worker, so that they reach the actor in the order they arrived. seeded listeners must see handshakes in a reproducible order, so they get one worker.
+        let (send_decoded, recv_decoded) = smol::channel::bounded(1000);
+        let decoders = if matches!(*self.rng, HandshakeRng::Seeded(_)) {
+            1
+        } else {
+            num_cpus::get()
+        };
+        let mut decode_queues = Vec::with_capacity(decoders);
+        let mut _decode_workers: Vec<smol::Task<Option<()>>> = Vec::with_capacity(decoders);
+        for _ in 0..decoders {
+            let (send_undecoded, recv_undecoded) = smol::channel::bounded(DECODE_QUEUE_LEN);
+            let cookie = self.cookie.clone();
+            let send_decoded = send_decoded.clone();
+            decode_queues.push(send_undecoded);
+            _decode_workers.push(runtime::spawn(async move {
+                while let Ok((buffer, addr)) = recv_undecoded.recv().await {
+                    DECODE_QUEUE_DEPTH.fetch_sub(1, Ordering::Relaxed);
+                    if let Some(decoded) = decode_handshake(&cookie, &buffer, addr) {
+                        send_decoded.send(decoded).await.ok()?;
+                    }
+                }
+                Some(())
+            }));
+        }
+        let decode_queues = Arc::new(decode_queues);
+
+        // packets belonging to existing sessions are demultiplexed in parallel by several workers. they never wait on the decoding workers, which shed packets instead when they fall behind.
+        let _demux_workers: Vec<smol::Task<()>> = (0..num_cpus::get())
+            .map(|_| {
+                let read_socket = self.socket.clone();
+                let session_table = session_table.clone();
+                let fallthrough_limiter = fallthrough_limiter.clone();
+                let decode_queues = decode_queues.clone();
+                runtime::spawn(async move {
+                    loop {
+                        let items = read_socket.recv_from_many().await.unwrap();
+                        if rand::random::<f32>() < 0.001 {
+                            fallthrough_limiter.retain_recent();
+                        }
+                        for (buffer, addr) in items {
+                            // junk is dropped before it costs any decryption attempts
+                            if buffer.len() < MIN_DATAGRAM_LEN {
+                                DATAGRAMS_UNDERSIZED.fetch_add(1, Ordering::Relaxed);
+                                tracing::trace!(
+                                    "dropping {}-byte datagram from {}",
+                                    buffer.len(),
+                                    addr
+                                );
+                                continue;
+                            }
+                            if buffer.len() >= RECV_BUFFER_LEN {
+                                DATAGRAMS_OVERSIZED.fetch_add(1, Ordering::Relaxed);
+                                tracing::trace!("dropping truncated datagram from {}", addr);
+                                continue;
+                            }
+                            // first we attempt to map this to an existing session
+                            if let Some(handle) = session_table.lookup(addr) {
+                                handle
+                                    .bytes_in
+                                    .fetch_add(buffer.len() as u64, Ordering::Relaxed);
+                                handle.mark_seen(addr);
+                                let _ = handle.sender.try_send(buffer.clone());
+                                if fallthrough_limiter.check_key(&addr).is_err() {
+                                    continue;
+                                }
+                                // TODO figure out a way to decide whether to continue
+                            }
+                            enqueue_decode(&decode_queues, buffer, addr);
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        // hellos cost a Diffie-Hellman each, so a fixed number of workers answer them off a bounded queue, and hellos that don't fit are shed
+        let hello_ctx = Arc::new(HelloCtx {
+            long_sk: self.long_sk.clone(),
+            token_key,
+            rng: self.rng.clone(),
+            socket: write_socket.clone(),
+        });
+        let (send_hello, recv_hello) =
+            smol::channel::bounded::<HelloJob>(HANDSHAKE_QUEUE_LEN.load(Ordering::Relaxed));
+        // seeded listeners must draw from their RNG in a reproducible order
+        let workers = match HANDSHAKE_WORKERS.load(Ordering::Relaxed) {
+            _ if matches!(*self.rng, HandshakeRng::Seeded(_)) => 1,
+            0 => (num_cpus::get() / 2).max(1),
+            n => n,
+        };
+        let _hello_workers: Vec<smol::Task<()>> = (0..workers)
+            .map(|_| {
+                let hello_ctx = hello_ctx.clone();
+                let recv_hello = recv_hello.clone();
+                runtime::spawn(async move {
+                    while let Ok(job) = recv_hello.recv().await {
+                        HANDSHAKE_QUEUE_DEPTH.fetch_sub(1, Ordering::Relaxed);
+                        hello_ctx.respond(job).await;
+                    }
+                })
+            })
+            .collect();
+        let enqueue_hello = |job: HelloJob| {
+            HANDSHAKE_QUEUE_DEPTH.fetch_add(1, Ordering::Relaxed);
+            if send_hello.try_send(job).is_err() {
+                HANDSHAKE_QUEUE_DEPTH.fetch_sub(1, Ordering::Relaxed);
+                HANDSHAKES_SHED.fetch_add(1, Ordering::Relaxed);
+                tracing::debug!("handshake queue full, dropping a hello");
+            }
+        };
+
+        // under load, hellos must come with a solved challenge, which is cheap to check unlike the hello itself
+        let pow_issuer = pow::PowIssuer::new(self.rng.gen());
+        let pow_difficulty = || {
+            let load = HANDSHAKE_QUEUE_DEPTH.load(Ordering::Relaxed) as f64
+                / HANDSHAKE_QUEUE_LEN.load(Ordering::Relaxed) as f64;
+            pow::difficulty(load, HANDSHAKE_POW_MAX_BITS.load(Ordering::Relaxed) as u8)
+        };
 
         // two possible events
         enum Evt {
-            NewRecv(Vec<(Bytes, SocketAddr)>),
-            DeadSess(Bytes),
+            NewRecv(DecodedHandshake),
+            DeadSess((Bytes, String, SessionCloseReason)),
         }
 
         for trace_id in 0u64.. {
             let event = smol::future::race(
-                async { Some(Evt::NewRecv(read_socket.recv_from_many().await.unwrap())) },
+                async { Some(Evt::NewRecv(recv_decoded.recv().await.ok()?)) },
                 async { Some(Evt::DeadSess(recv_dead.recv().await.ok()?)) },
             );
-            if rand::random::<f32>() < 0.001 {
-                fallthrough_limiter.retain_recent();
-            }
             smol::future::yield_now().await;
             match event.await? {
-                Evt::DeadSess(resume_token) => {
+                Evt::DeadSess((resume_token, id, reason)) => {
                     tracing::trace!("removing existing session!");
                     session_table.delete(resume_token);
+                    self.events
+                        .publish(SessionEvent::SessionClosed { id, reason });
+                    if let Some(limiter) = hello_limiter.as_ref() {
+                        limiter.retain_recent();
+                    }
                 }
-                Evt::NewRecv(items) => {
-                    let items: Vec<(Bytes, SocketAddr)> = items;
-                    for (buffer, addr) in items {
-                        // first we attempt to map this to an existing session
-                        if let Some(handle) = session_table.lookup(addr) {
-                            let _ = handle.try_send(buffer.clone());
-                            if fallthrough_limiter.check_key(&addr).is_err() {
+                Evt::NewRecv(DecodedHandshake {
+                    addr,
+                    s2c_key,
+                    frames: handshake,
+                }) => {
+                    tracing::debug!(
+                        "[{}] decoded some sort of handshake: {:?}",
+                        trace_id,
+                        handshake
+                    );
+                    let pow_solution = handshake.iter().find_map(|frame| match frame {
+                        ClientPowSolution { challenge, nonce } => Some((challenge.clone(), *nonce)),
+                        _ => None,
+                    });
+                    // clients that can't solve challenges are left to the per-address limit, since challenging them would only lock them out
+                    let pow_max_bits = handshake.iter().find_map(|frame| match frame {
+                        ClientPowSupport { max_bits } => Some(*max_bits),
+                        _ => None,
+                    });
+                    // prefer the Noise handshake when the client offers one
+                    let handshake = handshake
+                        .iter()
+                        .find(|frame| matches!(frame, ClientHelloNoise { .. }))
+                        .unwrap_or(&handshake[0])
+                        .clone();
+                    let pow_max_bits = pow_max_bits.filter(|_| {
+                        matches!(handshake, ClientHello { .. } | ClientHelloNoise { .. })
+                    });
+                    if let Some(max_bits) = pow_max_bits {
+                        let difficulty = pow_difficulty().min(max_bits);
+                        let solved = pow_solution
+                            .map(|(challenge, nonce)| pow_issuer.verify(addr, &challenge, nonce))
+                            .unwrap_or(false);
+                        if difficulty > 0 && !solved {
+                            if hello_allowed(addr) {
+                                tracing::debug!(
+                                    "[{}] challenging {} at difficulty {}",
+                                    trace_id,
+                                    addr,
+                                    difficulty
+                                );
+                                let reply = protocol::HandshakeFrame::ServerPowChallenge {
+                                    challenge: pow_issuer.challenge(addr, difficulty),
+                                    difficulty,
+                                };
+                                let reply = crypt::LegacyAEAD::new(&s2c_key)
+                                    .pad_encrypt_handshake(&[reply]);
+                                let _ = write_socket.send_to(reply, addr).await;
+                            }
+                            continue;
+                        }
+                    }
+                    match handshake {
+                        ClientHelloNoise { noise, version } => {
+                            if !hello_allowed(addr) {
+                                tracing::debug!("[{}] too many handshakes from {}", trace_id, addr);
+                                continue;
+                            }
+                            if version != 4 {
+                                HANDSHAKE_FAILURES.fetch_add(1, Ordering::Relaxed);
+                                tracing::warn!(
+                                    "got Noise packet with incorrect version {}",
+                                    version
+                                );
                                 continue;
                             }
-                            // TODO figure out a way to decide whether to continue
+                            enqueue_hello(HelloJob {
+                                trace_id,
+                                addr,
+                                s2c_key,
+                                frame: ClientHelloNoise { noise, version },
+                            });
                         }
-                        // we know it's not part of an existing session then. we decrypt it under the current key
-                        let s2c_key = self.cookie.generate_s2c().next().unwrap();
-                        for possible_key in self.cookie.generate_c2s() {
-                            smol::future::yield_now().await;
-                            let crypter = crypt::LegacyAEAD::new(&possible_key);
-                            if let Some(handshake) =
-                                crypter.pad_decrypt_v1::<protocol::HandshakeFrame>(&buffer)
-                            {
-                                if !RECENT_FILTER.lock().check(&buffer) {
-                                    tracing::debug!(
-                                        "discarding replay attempt with len {}",
-                                        buffer.len()
-                                    );
-                                    continue;
-                                }
+                        ClientHello {
+                            long_pk,
+                            eph_pk,
+                            version,
+                        } => {
+                            if !hello_allowed(addr) {
+                                tracing::debug!("[{}] too many handshakes from {}", trace_id, addr);
+                                continue;
+                            }
+                            if version != 1 && version != 2 && version != 3 {
+                                HANDSHAKE_FAILURES.fetch_add(1, Ordering::Relaxed);
+                                tracing::warn!("got packet with incorrect version {}", version);
+                                continue;
+                            }
+                            enqueue_hello(HelloJob {
+                                trace_id,
+                                addr,
+                                s2c_key,
+                                frame: ClientHello {
+                                    long_pk,
+                                    eph_pk,
+                                    version,
+                                },
+                            });
+                        }
+                        ClientResume {
+                            resume_token,
+                            shard_id,
+                        } => {
+                            tracing::trace!("Got ClientResume-{} from {}!", shard_id, addr);
+                            let tokinfo = TokenInfo::decrypt(&token_key, &resume_token);
+                            if tokinfo.is_none() && hello_allowed(addr) {
                                 tracing::debug!(
-                                    "[{}] decoded some sort of handshake: {:?}",
+                                    "[{}] rejecting unknown resume token from {}",
                                     trace_id,
-                                    handshake
+                                    addr
                                 );
-                                match handshake[0].clone() {
-                                    ClientHello {
-                                        long_pk,
-                                        eph_pk,
-                                        version,
-                                    } => {
-                                        if version != 1 && version != 2 && version != 3 {
-                                            tracing::warn!(
-                                                "got packet with incorrect version {}",
-                                                version
-                                            );
-                                            break;
-                                        }
-                                        // generate session key
-                                        let my_eph_sk = x25519_dalek::StaticSecret::new(
-                                            &mut rand::thread_rng(),
-                                        );
-                                        let token = TokenInfo {
-                                            sess_key: crypt::triple_ecdh(
-                                                &self.long_sk,
-                                                &my_eph_sk,
-                                                &long_pk,
-                                                &eph_pk,
-                                            )
-                                            .as_bytes()
-                                            .to_vec()
-                                            .into(),
-                                            init_time_ms: std::time::SystemTime::now()
-                                                .duration_since(std::time::UNIX_EPOCH)
-                                                .unwrap()
-                                                .as_millis()
-                                                as u64,
+                                let reply = protocol::HandshakeFrame::ServerResumeRejected {
+                                    token_hash: blake3::hash(&resume_token)
+                                        .as_bytes()
+                                        .to_vec()
+                                        .into(),
+                                };
+                                let reply = crypt::LegacyAEAD::new(&s2c_key)
+                                    .pad_encrypt_handshake(&[reply]);
+                                let _ = write_socket.send_to(reply, addr).await;
+                            }
+                            if let Some(tokinfo) = tokinfo {
+                                // first check whether we know about the resume token
+                                if !session_table.rebind(addr, shard_id, resume_token.clone()) {
+                                    tracing::debug!(
+                                        "[{}] ClientResume from {} is new!",
+                                        trace_id,
+                                        addr
+                                    );
+
+                                    let up_key =
+                                        blake3::keyed_hash(crypt::UP_KEY, &tokinfo.sess_key);
+                                    let dn_key =
+                                        blake3::keyed_hash(crypt::DN_KEY, &tokinfo.sess_key);
+                                    keylog::log_session(
+                                        &crypt::session_id(&resume_token),
+                                        up_key.as_bytes(),
+                                        dn_key.as_bytes(),
+                                    );
+                                    let write_socket = write_socket.clone();
+                                    let (session_input, session_input_recv) =
+                                        smol::channel::bounded(1000);
+                                    // create session
+                                    let (session_output_send, session_output_recv) =
+                                        smol::channel::bounded(1000);
+                                    let locked_addrs = ShardedAddrs::new(shard_id, addr);
+                                    let locked_addrs = Arc::new(RwLock::new(locked_addrs));
+                                    let bytes_in = Arc::new(AtomicU64::new(0));
+                                    let bytes_out = Arc::new(AtomicU64::new(0));
+                                    let output_poller = {
+                                        let locked_addrs = locked_addrs.clone();
+                                        let bytes_out = bytes_out.clone();
+                                        runtime::spawn(async move {
+                                            loop {
+                                                match session_output_recv.recv().await {
+                                                    Ok(data) => {
+                                                        bytes_out.fetch_add(
+                                                            data.len() as u64,
+                                                            Ordering::Relaxed,
+                                                        );
+                                                        // let start = Instant::now();
+                                                        let remote_addr =
+                                                            locked_addrs.write().get_addr();
+                                                        drop(
+                                                            write_socket
+                                                                .send_to(data, remote_addr)
+                                                                .await,
+                                                        );
+                                                    }
+                                                    Err(_) => return,
+                                                }
+                                            }
+                                        })
+                                    };
+                                    let mut session = Session::new(SessionConfig {
+                                        id: crypt::session_id(&resume_token),
+                                        send_packet: session_output_send,
+                                        recv_packet: session_input_recv,
+                                        recv_timeout,
+                                        statistics: 128,
+
+                                        send_crypt_legacy: crypt::LegacyAEAD::new(
+                                            dn_key.as_bytes(),
+                                        ),
+                                        recv_crypt_legacy: crypt::LegacyAEAD::new(
+                                            up_key.as_bytes(),
+                                        ),
+
+                                        send_crypt_ng: crypt::NgAEAD::new(dn_key.as_bytes()),
+                                        recv_crypt_ng: crypt::NgAEAD::new(up_key.as_bytes()),
+                                        rebind_secret: crypt::rebind_secret(&up_key, &dn_key),
+                                        version: tokinfo.version,
+                                    });
+                                    session.set_info_source({
+                                        let locked_addrs = locked_addrs.clone();
+                                        let bytes_in = bytes_in.clone();
+                                        let version = tokinfo.version;
+                                        let start = Instant::now();
+                                        move || SessionInfo {
+                                            remote_addrs: locked_addrs.read().addrs(),
                                             version,
+                                            bytes_in: bytes_in.load(Ordering::Relaxed),
+                                            bytes_out: bytes_out.load(Ordering::Relaxed),
+                                            age: start.elapsed(),
                                         }
-                                        .encrypt(&token_key);
-                                        let reply = protocol::HandshakeFrame::ServerHello {
-                                            long_pk: (&self.long_sk).into(),
-                                            eph_pk: (&my_eph_sk).into(),
-                                            resume_token: token,
+                                    });
+                                    let send_dead_clo = send_dead.clone();
+                                    let resume_token_clo = resume_token.clone();
+                                    let id = session.id().to_string();
+                                    let timed_out = session.timeout_check();
+                                    let said_goodbye = session.goodbye_check();
+                                    session.on_drop(move || {
+                                        // the poller stops once it has sent everything the session left behind, goodbye included
+                                        runtime::spawn(smol_timeout::TimeoutExt::timeout(
+                                            output_poller,
+                                            crate::session::GOODBYE_GRACE,
+                                        ))
+                                        .detach();
+                                        let reason = if said_goodbye() {
+                                            SessionCloseReason::SaidGoodbye
+                                        } else if timed_out() {
+                                            SessionCloseReason::TimedOut
+                                        } else {
+                                            SessionCloseReason::Dropped
                                         };
-                                        let reply = crypt::LegacyAEAD::new(&s2c_key)
-                                            .pad_encrypt_v1(&[reply], 1000);
-                                        tracing::debug!(
-                                            "[{}] GONNA reply to ClientHello from {}",
-                                            trace_id,
-                                            addr
-                                        );
-                                        let _ = write_socket.send_to(reply, addr).await;
-                                        tracing::debug!(
-                                            "[{}] replied to ClientHello from {}",
-                                            trace_id,
-                                            addr
-                                        );
-                                    }
-                                    ClientResume {
-                                        resume_token,
-                                        shard_id,
-                                    } => {
-                                        tracing::trace!(
-                                            "Got ClientResume-{} from {}!",
-                                            shard_id,
-                                            addr
-                                        );
-                                        let tokinfo = TokenInfo::decrypt(&token_key, &resume_token);
-                                        if let Some(tokinfo) = tokinfo {
-                                            // first check whether we know about the resume token
-                                            if !session_table.rebind(
-                                                addr,
-                                                shard_id,
-                                                resume_token.clone(),
-                                            ) {
-                                                tracing::debug!(
-                                                    "[{}] ClientResume from {} is new!",
-                                                    trace_id,
-                                                    addr
-                                                );
-
-                                                let up_key = blake3::keyed_hash(
-                                                    crypt::UP_KEY,
-                                                    &tokinfo.sess_key,
-                                                );
-                                                let dn_key = blake3::keyed_hash(
-                                                    crypt::DN_KEY,
-                                                    &tokinfo.sess_key,
-                                                );
-                                                let write_socket = write_socket.clone();
-                                                let (session_input, session_input_recv) =
-                                                    smol::channel::bounded(1000);
-                                                // create session
-                                                let (session_output_send, session_output_recv) =
-                                                    smol::channel::bounded(1000);
-                                                let locked_addrs =
-                                                    ShardedAddrs::new(shard_id, addr);
-                                                let locked_addrs =
-                                                    Arc::new(RwLock::new(locked_addrs));
-                                                let output_poller = {
-                                                    let locked_addrs = locked_addrs.clone();
-                                                    runtime::spawn(async move {
-                                                        loop {
-                                                            match session_output_recv.recv().await {
-                                                                Ok(data) => {
-                                                                    // let start = Instant::now();
-                                                                    let remote_addr = locked_addrs
-                                                                        .write()
-                                                                        .get_addr();
-                                                                    drop(
-                                                                        write_socket
-                                                                            .send_to(
-                                                                                data,
-                                                                                remote_addr,
-                                                                            )
-                                                                            .await,
-                                                                    );
-                                                                }
-                                                                Err(_) => {
-                                                                    smol::future::pending::<()>()
-                                                                        .await
-                                                                }
-                                                            }
-                                                        }
-                                                    })
-                                                };
-                                                let mut session = Session::new(SessionConfig {
-                                                    send_packet: session_output_send,
-                                                    recv_packet: session_input_recv,
-                                                    recv_timeout: Duration::from_secs(3600),
-                                                    statistics: 128,
-
-                                                    send_crypt_legacy: crypt::LegacyAEAD::new(
-                                                        dn_key.as_bytes(),
-                                                    ),
-                                                    recv_crypt_legacy: crypt::LegacyAEAD::new(
-                                                        up_key.as_bytes(),
-                                                    ),
-
-                                                    send_crypt_ng: crypt::NgAEAD::new(
-                                                        dn_key.as_bytes(),
-                                                    ),
-                                                    recv_crypt_ng: crypt::NgAEAD::new(
-                                                        up_key.as_bytes(),
-                                                    ),
-                                                    version: tokinfo.version,
-                                                });
-                                                let send_dead_clo = send_dead.clone();
-                                                let resume_token_clo = resume_token.clone();
-                                                session.on_drop(move || {
-                                                    drop(output_poller);
-                                                    drop(send_dead_clo.try_send(resume_token_clo))
-                                                });
-                                                // spawn a task that writes to the socket.
-                                                session_table.new_sess(
-                                                    resume_token.clone(),
-                                                    session_input,
-                                                    locked_addrs,
-                                                );
-                                                session_table.rebind(addr, shard_id, resume_token);
-                                                tracing::debug!("[{}] accept {}", trace_id, addr);
-                                                accepted.try_send(session).ok()?;
-                                            } else {
-                                                tracing::debug!(
-                                                    "[{}] ClientResume from {} rebound",
-                                                    trace_id,
-                                                    addr
-                                                );
-                                            }
-                                        }
-                                    }
-                                    _ => continue,
+                                        drop(send_dead_clo.try_send((resume_token_clo, id, reason)))
+                                    });
+                                    // spawn a task that writes to the socket.
+                                    session_table.new_sess(
+                                        resume_token.clone(),
+                                        session_input,
+                                        bytes_in,
+                                        locked_addrs,
+                                    );
+                                    session_table.rebind(addr, shard_id, resume_token);
+                                    tracing::debug!("[{}] accept {}", trace_id, addr);
+                                    self.events.publish(SessionEvent::SessionOpened {
+                                        id: session.id().to_string(),
+                                        addr,
+                                    });
+                                    accepted.try_send(session).ok()?;
+                                } else {
+                                    tracing::debug!(
+                                        "[{}] ClientResume from {} rebound",
+                                        trace_id,
+                                        addr
+                                    );
                                 }
                             }
                         }
+                        _ => continue,
                     }
                 }
             }
@@ -356,27 +744,178 @@ impl ListenerActor {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct TokenInfo {
+/// A packet that decrypted as a handshake, on its way to the actor.
+struct DecodedHandshake {
+    addr: SocketAddr,
+    s2c_key: [u8; 32],
+    frames: Vec<protocol::HandshakeFrame>,
+}
+
+/// Hands a packet to the decoding worker for its address, or drops it if that worker is too far behind.
+fn enqueue_decode(queues: &[Sender<(Bytes, SocketAddr)>], buffer: Bytes, addr: SocketAddr) {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    addr.hash(&mut hasher);
+    let queue = &queues[hasher.finish() as usize % queues.len()];
+    DECODE_QUEUE_DEPTH.fetch_add(1, Ordering::Relaxed);
+    if queue.try_send((buffer, addr)).is_err() {
+        DECODE_QUEUE_DEPTH.fetch_sub(1, Ordering::Relaxed);
+        DECODE_SHED.fetch_add(1, Ordering::Relaxed);
+        tracing::trace!("decoding queue full, dropping a packet from {}", addr);
+    }
+}
+
+/// Tries to decrypt a packet as a handshake under each of the current keys, dropping replays of earlier handshakes.
+fn decode_handshake(
+    cookie: &crypt::Cookie,
+    buffer: &[u8],
+    addr: SocketAddr,
+) -> Option<DecodedHandshake> {
+    let s2c_key = cookie.generate_s2c().next().unwrap();
+    for possible_key in cookie.generate_c2s() {
+        let crypter = crypt::LegacyAEAD::new(&possible_key);
+        if let Some(frames) = crypter.pad_decrypt_v1::<protocol::HandshakeFrame>(buffer) {
+            let scope = frames.first().map(|f| f.replay_scope()).unwrap_or_default();
+            if !RECENT_FILTER.check(scope, buffer) {
+                tracing::debug!("discarding replay attempt with len {}", buffer.len());
+                return None;
+            }
+            return Some(DecodedHandshake {
+                addr,
+                s2c_key,
+                frames,
+            });
+        }
+    }
+    None
+}
+
+/// A hello waiting to be answered.
+struct HelloJob {
+    trace_id: u64,
+    addr: SocketAddr,
+    s2c_key: [u8; 32],
+    frame: protocol::HandshakeFrame,
+}
+
+/// What handshake workers need to answer hellos.
+struct HelloCtx {
+    long_sk: x25519_dalek::StaticSecret,
+    token_key: [u8; 32],
+    rng: Arc<HandshakeRng>,
+    socket: Arc<dyn Backhaul>,
+}
+
+impl HelloCtx {
+    async fn respond(&self, job: HelloJob) {
+        let HelloJob {
+            trace_id,
+            addr,
+            s2c_key,
+            frame,
+        } = job;
+        match frame {
+            ClientHelloNoise { noise, version } => {
+                let (reply_noise, sess_key) = match crypt::noise_respond(&self.long_sk, &noise) {
+                    Some(v) => v,
+                    None => {
+                        HANDSHAKE_FAILURES.fetch_add(1, Ordering::Relaxed);
+                        tracing::debug!("[{}] bad Noise handshake from {}", trace_id, addr);
+                        return;
+                    }
+                };
+                let token = TokenInfo {
+                    sess_key: sess_key.as_bytes().to_vec().into(),
+                    init_time_ms: std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .unwrap()
+                        .as_millis() as u64,
+                    version,
+                }
+                .encrypt(&self.token_key, &self.rng);
+                let reply = protocol::HandshakeFrame::ServerHelloNoise {
+                    noise: reply_noise,
+                    resume_token: token,
+                };
+                let reply = crypt::LegacyAEAD::new(&s2c_key).pad_encrypt_handshake(&[reply]);
+                let _ = self.socket.send_to(reply, addr).await;
+                HANDSHAKES_ANSWERED.fetch_add(1, Ordering::Relaxed);
+                tracing::debug!("[{}] replied to ClientHelloNoise from {}", trace_id, addr);
+            }
+            ClientHello {
+                long_pk,
+                eph_pk,
+                version,
+            } => {
+                // generate session key
+                let my_eph_sk = self.rng.x25519_secret();
+                let token = TokenInfo {
+                    sess_key: crypt::triple_ecdh(&self.long_sk, &my_eph_sk, &long_pk, &eph_pk)
+                        .as_bytes()
+                        .to_vec()
+                        .into(),
+                    init_time_ms: std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .unwrap()
+                        .as_millis() as u64,
+                    version,
+                }
+                .encrypt(&self.token_key, &self.rng);
+                let reply = protocol::HandshakeFrame::ServerHello {
+                    long_pk: (&self.long_sk).into(),
+                    eph_pk: (&my_eph_sk).into(),
+                    resume_token: token,
+                };
+                let reply = crypt::LegacyAEAD::new(&s2c_key).pad_encrypt_handshake(&[reply]);
+                tracing::debug!("[{}] GONNA reply to ClientHello from {}", trace_id, addr);
+                let _ = self.socket.send_to(reply, addr).await;
+                HANDSHAKES_ANSWERED.fetch_add(1, Ordering::Relaxed);
+                tracing::debug!("[{}] replied to ClientHello from {}", trace_id, addr);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct TokenInfo {
     sess_key: Bytes,
     init_time_ms: u64,
     version: u64,
 }
 
 impl TokenInfo {
-    fn decrypt(key: &[u8], encrypted: &[u8]) -> Option<Self> {
+    pub(crate) fn decrypt(key: &[u8], encrypted: &[u8]) -> Option<Self> {
         // first we decrypt
         let crypter = crypt::LegacyAEAD::new(key);
         let plain = crypter.decrypt(encrypted)?;
         bincode::deserialize(&plain).ok()
     }
 
-    fn encrypt(&self, key: &[u8]) -> Bytes {
+    pub(crate) fn encrypt(&self, key: &[u8], rng: &HandshakeRng) -> Bytes {
         let crypter = crypt::LegacyAEAD::new(key);
-        let mut rng = rand::thread_rng();
         crypter.encrypt(
             &bincode::serialize(self).expect("must serialize"),
             rng.gen(),
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_tokens_reproduce() {
+        let token = TokenInfo {
+            sess_key: Bytes::from_static(&[1; 32]),
+            init_time_ms: 1234,
+            version: 3,
+        };
+        let key = [7u8; 32];
+        let first = token.encrypt(&key, &HandshakeRng::seeded(0));
+        let second = token.encrypt(&key, &HandshakeRng::seeded(0));
+        assert_eq!(first, second);
+        assert_eq!(TokenInfo::decrypt(&key, &first), Some(token));
+        assert_eq!(TokenInfo::decrypt(&[8u8; 32], &first), None);
+    }
+}
@@ -0,0 +1,123 @@
+use std::net::SocketAddr;
+
+use parking_lot::Mutex;
+use smol::channel::{Receiver, Sender, TrySendError};
+
+/// How many events a subscriber can fall behind by before it starts missing them.
+pub const SESSION_EVENT_BUFFER: usize = 1024;
+
+/// Something that happened to a session of a [crate::Listener].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionEvent {
+    /// A session was established, and is about to be returned by `accept_session`. The id is the same one [crate::Session::id] returns.
+    SessionOpened { id: String, addr: SocketAddr },
+    /// A session was dropped, and the listener has forgotten about it.
+    SessionClosed {
+        id: String,
+        reason: SessionCloseReason,
+    },
+    /// The subscriber fell behind and this many events were thrown away since the last one it got. Anything built from the events, such as a count of live sessions, should be rebuilt from scratch.
+    Lagged { missed: u64 },
+}
+
+/// Why a session closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionCloseReason {
+    /// Nothing arrived from the client for the listener's receive timeout.
+    TimedOut,
+    /// Whatever accepted the session dropped it while the client was still around.
+    Dropped,
+    /// The client said goodbye, closing the session.
+    SaidGoodbye,
+}
+
+struct Subscriber {
+    send: Sender<SessionEvent>,
+    missed: u64,
+}
+
+/// Hands out session events to every subscriber. Subscribers that don't keep up lose events instead of slowing down the listener.
+#[derive(Default)]
+pub(crate) struct EventHub {
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+impl EventHub {
+    pub fn subscribe(&self) -> Receiver<SessionEvent> {
+        let (send, recv) = smol::channel::bounded(SESSION_EVENT_BUFFER);
+        self.subscribers.lock().push(Subscriber { send, missed: 0 });
+        recv
+    }
+
+    pub fn publish(&self, event: SessionEvent) {
+        let mut subscribers = self.subscribers.lock();
+        let mut i = 0;
+        while i < subscribers.len() {
+            if subscribers[i].offer(&event) {
+                i += 1;
+            } else {
+                subscribers.swap_remove(i);
+            }
+        }
+    }
+}
+
+impl Subscriber {
+    /// Offers the event, first telling the subscriber about anything it missed. Returns false if the subscriber is gone.
+    fn offer(&mut self, event: &SessionEvent) -> bool {
+        if self.missed > 0 {
+            match self.send.try_send(SessionEvent::Lagged {
+                missed: self.missed,
+            }) {
+                Ok(()) => self.missed = 0,
+                Err(TrySendError::Full(_)) => {
+                    self.missed += 1;
+                    return true;
+                }
+                Err(TrySendError::Closed(_)) => return false,
+            }
+        }
+        match self.send.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.missed += 1;
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opened(n: u16) -> SessionEvent {
+        SessionEvent::SessionOpened {
+            id: n.to_string(),
+            addr: ([10, 0, 0, 1], n).into(),
+        }
+    }
+
+    #[test]
+    fn lagging_subscriber_is_told() {
+        let hub = EventHub::default();
+        let slow = hub.subscribe();
+        let dropped = hub.subscribe();
+        drop(dropped);
+        for n in 0..SESSION_EVENT_BUFFER as u16 + 10 {
+            hub.publish(opened(n));
+        }
+        assert_eq!(hub.subscribers.lock().len(), 1);
+        // drain the full buffer, then the next event is preceded by how many were missed
+        for n in 0..SESSION_EVENT_BUFFER as u16 {
+            assert_eq!(slow.try_recv().unwrap(), opened(n));
+        }
+        hub.publish(opened(0));
+        assert_eq!(
+            slow.try_recv().unwrap(),
+            SessionEvent::Lagged { missed: 10 }
+        );
+        assert_eq!(slow.try_recv().unwrap(), opened(0));
+    }
+}
@@ -0,0 +1,44 @@
+use parking_lot::Mutex;
+use rand::{distributions::Standard, prelude::*};
+use rand_chacha::ChaCha8Rng;
+
+/// Where both ends of a handshake get their keys, and a listener its resume tokens. Everything but tests and fuzzing should use the OS.
+pub enum HandshakeRng {
+    Os,
+    #[cfg_attr(not(any(test, feature = "fuzzing")), allow(dead_code))]
+    Seeded(Mutex<ChaCha8Rng>),
+}
+
+impl HandshakeRng {
+    /// Creates a reproducible RNG from the given seed.
+    #[cfg(any(test, feature = "fuzzing"))]
+    pub fn seeded(seed: u64) -> Self {
+        HandshakeRng::Seeded(Mutex::new(ChaCha8Rng::seed_from_u64(seed)))
+    }
+
+    /// Fills the buffer with random bytes.
+    pub fn fill(&self, buf: &mut [u8]) {
+        match self {
+            HandshakeRng::Os => rand::thread_rng().fill_bytes(buf),
+            HandshakeRng::Seeded(rng) => rng.lock().fill_bytes(buf),
+        }
+    }
+
+    /// Generates a random value.
+    pub fn gen<T>(&self) -> T
+    where
+        Standard: Distribution<T>,
+    {
+        match self {
+            HandshakeRng::Os => rand::thread_rng().gen(),
+            HandshakeRng::Seeded(rng) => rng.lock().gen(),
+        }
+    }
+
+    /// Generates a fresh x25519 secret.
+    pub fn x25519_secret(&self) -> x25519_dalek::StaticSecret {
+        let mut buf = [0u8; 32];
+        self.fill(&mut buf);
+        x25519_dalek::StaticSecret::from(buf)
+    }
+}
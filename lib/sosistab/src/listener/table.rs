@@ -1,70 +1,252 @@
-use std::{collections::BTreeMap, net::SocketAddr, sync::Arc, time::Instant};
+use rustc_hash::FxHasher;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    hash::{Hash, Hasher},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use bytes::Bytes;
 use indexmap::IndexMap;
 use parking_lot::RwLock;
 use smol::channel::Sender;
 
+/// How long a shard can go unheard from before downstream packets stop going to it, as long as some other shard has been heard from since. A shard whose path went bad, or that the client stopped using, then stops taking a share of the traffic.
+const STALE_SHARD: Duration = Duration::from_secs(3);
+
+/// A session whose shards move to new addresses this many times within [CHURN_WINDOW] is taken to be behind a NAT that keeps picking new ports, such as some carrier-grade NATs, rather than one that rebinds now and then.
+const CHURN_MOVES: usize = 6;
+const CHURN_WINDOW: Duration = Duration::from_secs(30);
+/// Addresses a churning session still accepts packets from after its shards moved on from them.
+const RETIRED_ADDRS: usize = 32;
+
+/// The address of one shard, and when a packet last came in from it.
+struct ShardAddr {
+    addr: SocketAddr,
+    /// Milliseconds since the ShardedAddrs was created.
+    last_seen: AtomicU64,
+}
+
 pub struct ShardedAddrs {
-    map: IndexMap<u8, SocketAddr>,
+    map: IndexMap<u8, ShardAddr>,
     index: usize,
     last_time: Instant,
+    created: Instant,
+    /// When shards moved to new addresses, within the churn window.
+    moves: VecDeque<Instant>,
+    /// Addresses that shards moved away from while the session was churning, oldest first.
+    retired: VecDeque<SocketAddr>,
 }
 
 impl ShardedAddrs {
     pub fn new(initial_shard: u8, initial_addr: SocketAddr) -> Self {
-        let mut map = IndexMap::new();
-        map.insert(initial_shard, initial_addr);
-        Self {
-            map,
+        let mut toret = Self {
+            map: IndexMap::new(),
             index: 0,
             last_time: Instant::now(),
+            created: Instant::now(),
+            moves: VecDeque::new(),
+            retired: VecDeque::new(),
+        };
+        toret.insert(initial_shard, initial_addr);
+        toret
+    }
+
+    fn millis(&self) -> u64 {
+        self.created.elapsed().as_millis() as u64
+    }
+
+    fn insert(&mut self, shard_id: u8, addr: SocketAddr) -> Option<SocketAddr> {
+        let last_seen = AtomicU64::new(self.millis());
+        self.map
+            .insert(shard_id, ShardAddr { addr, last_seen })
+            .map(|old| old.addr)
+    }
+
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.map.values().map(|shard| shard.addr).collect()
+    }
+
+    /// Notes that a shard moved to a new address. Returns whether the session has been moving often enough that it looks to be behind a NAT that keeps picking new ports.
+    fn note_move(&mut self) -> bool {
+        let now = Instant::now();
+        self.moves.push_back(now);
+        while matches!(self.moves.front(), Some(at) if now.saturating_duration_since(*at) > CHURN_WINDOW)
+        {
+            self.moves.pop_front();
         }
+        self.moves.len() >= CHURN_MOVES
     }
 
+    /// Notes that a packet just came in from the given address.
+    pub fn mark_seen(&self, addr: SocketAddr) {
+        if let Some(shard) = self.map.values().find(|shard| shard.addr == addr) {
+            shard.last_seen.store(self.millis(), Ordering::Relaxed);
+        }
+    }
+
+    /// Picks the address to send the next packet to. Bursts of packets are spread over the shards in turn, skipping shards that have gone stale.
     pub fn get_addr(&mut self) -> SocketAddr {
-        if self.last_time.elapsed().as_millis() > 100 {
-            self.last_time = Instant::now();
-            *self.map.get_index(self.index).unwrap().1
-        } else {
-            loop {
-                self.last_time = Instant::now();
+        let now = self.millis();
+        let stale = STALE_SHARD.as_millis() as u64;
+        let freshest = self
+            .map
+            .values()
+            .map(|shard| shard.last_seen.load(Ordering::Relaxed))
+            .max()
+            .unwrap_or_default();
+        let usable = |shard: &ShardAddr| {
+            now.saturating_sub(shard.last_seen.load(Ordering::Relaxed)) <= stale
+                || now.saturating_sub(freshest) > stale
+        };
+        let advance = self.last_time.elapsed().as_millis() <= 100;
+        self.last_time = Instant::now();
+        if advance || !usable(self.current()) {
+            for _ in 0..self.map.len() {
                 self.index = self.index.wrapping_add(1) % self.map.len();
-                if let Some(val) = self.map.get_index(self.index) {
-                    return *val.1;
+                if usable(self.current()) {
+                    break;
                 }
             }
         }
+        self.current().addr
+    }
+
+    /// When a packet last came in from any of the shards.
+    fn last_heard(&self) -> Instant {
+        let last_seen = self
+            .map
+            .values()
+            .map(|shard| shard.last_seen.load(Ordering::Relaxed))
+            .max()
+            .unwrap_or_default();
+        self.created + Duration::from_millis(last_seen)
+    }
+
+    fn current(&self) -> &ShardAddr {
+        self.map.get_index(self.index).unwrap().1
     }
 }
 
 #[derive(Clone)]
-struct SessEntry {
-    sender: Sender<Bytes>,
+pub struct SessEntry {
+    pub sender: Sender<Bytes>,
+    pub bytes_in: Arc<AtomicU64>,
     addrs: Arc<RwLock<ShardedAddrs>>,
 }
 
-#[derive(Default)]
+impl SessEntry {
+    /// Notes that a packet just came in from the given address, so that its shard keeps getting downstream packets.
+    pub fn mark_seen(&self, addr: SocketAddr) {
+        self.addrs.read().mark_seen(addr)
+    }
+}
+
+/// Number of independently-locked shards in a SessionTable.
+const TABLE_SHARDS: usize = 16;
+
+/// Most sessions each shard of a SessionTable holds by default.
+const SHARD_CAPACITY: usize = 8192;
+
+/// A table mapping remote addresses to sessions. The table is split into shards, each behind its own lock, so that packets for different sessions can be demultiplexed in parallel. Each shard holds a limited number of sessions. Past that, the session heard from least recently is evicted to make room, which cuts it off from its packets and so closes it. Every session is bound to at most one address per shard plus [RETIRED_ADDRS], so the table's memory is bounded too.
 pub struct SessionTable {
-    token_to_sess: BTreeMap<Bytes, SessEntry>,
-    addr_to_token: BTreeMap<SocketAddr, Bytes>,
+    token_to_sess: Vec<RwLock<BTreeMap<Bytes, SessEntry>>>,
+    addr_to_token: Vec<RwLock<BTreeMap<SocketAddr, Bytes>>>,
+    /// Sessions whose shards keep moving to new ports, by IP.
+    churning_by_ip: Vec<RwLock<BTreeMap<IpAddr, Vec<Bytes>>>>,
+    shard_capacity: usize,
+}
+
+impl Default for SessionTable {
+    fn default() -> Self {
+        Self::with_shard_capacity(SHARD_CAPACITY)
+    }
+}
+
+fn shard_of<T: Hash + ?Sized>(val: &T) -> usize {
+    let mut hasher = FxHasher::default();
+    val.hash(&mut hasher);
+    hasher.finish() as usize % TABLE_SHARDS
 }
 
 impl SessionTable {
+    /// Creates a table whose shards each hold at most the given number of sessions.
+    pub fn with_shard_capacity(shard_capacity: usize) -> Self {
+        Self {
+            token_to_sess: (0..TABLE_SHARDS).map(|_| Default::default()).collect(),
+            addr_to_token: (0..TABLE_SHARDS).map(|_| Default::default()).collect(),
+            churning_by_ip: (0..TABLE_SHARDS).map(|_| Default::default()).collect(),
+            shard_capacity: shard_capacity.max(1),
+        }
+    }
+
+    fn token_shard(&self, token: &[u8]) -> &RwLock<BTreeMap<Bytes, SessEntry>> {
+        &self.token_to_sess[shard_of(token)]
+    }
+
+    fn addr_shard(&self, addr: &SocketAddr) -> &RwLock<BTreeMap<SocketAddr, Bytes>> {
+        &self.addr_to_token[shard_of(addr)]
+    }
+
+    fn ip_shard(&self, ip: &IpAddr) -> &RwLock<BTreeMap<IpAddr, Vec<Bytes>>> {
+        &self.churning_by_ip[shard_of(ip)]
+    }
+
+    /// Forgets the address, unless it has since been taken by another session.
+    fn unbind(&self, addr: SocketAddr, token: &[u8]) {
+        let mut addr_to_token = self.addr_shard(&addr).write();
+        if matches!(addr_to_token.get(&addr), Some(bound) if bound.as_ref() == token) {
+            addr_to_token.remove(&addr);
+        }
+    }
+
+    /// Binds the shard of a session to the address. Usually the shard's old address is forgotten, but a session that keeps moving to new addresses is behind a NAT that picks new ports all the time, so its old addresses stay bound for a while, and packets from its IP are matched to it even from ports it hasn't resumed from. Returns false if the token is unknown.
     #[tracing::instrument(skip(self), level = "trace")]
-    pub fn rebind(&mut self, addr: SocketAddr, shard_id: u8, token: Bytes) -> bool {
-        if let Some(entry) = self.token_to_sess.get(&token) {
-            let old = {
+    pub fn rebind(&self, addr: SocketAddr, shard_id: u8, token: Bytes) -> bool {
+        let entry = self.token_shard(&token).read().get(&token).cloned();
+        if let Some(entry) = entry {
+            let (forget, churning) = {
                 let mut addrs = entry.addrs.write();
-                let old = addrs.map.insert(shard_id, addr);
+                let old = addrs.insert(shard_id, addr);
                 addrs.index = addrs.map.get_index_of(&shard_id).unwrap();
-                old
+                addrs.retired.retain(|retired| *retired != addr);
+                match old {
+                    Some(old) if old != addr => {
+                        if addrs.note_move() {
+                            addrs.retired.push_back(old);
+                            let evicted = if addrs.retired.len() > RETIRED_ADDRS {
+                                addrs.retired.pop_front()
+                            } else {
+                                None
+                            };
+                            (evicted, true)
+                        } else {
+                            (Some(old), false)
+                        }
+                    }
+                    _ => (None, false),
+                }
             };
             tracing::trace!("binding {}=>{}", shard_id, addr);
-            if let Some(old) = old {
-                self.addr_to_token.remove(&old);
+            if let Some(forget) = forget {
+                self.unbind(forget, &token);
+            }
+            self.addr_shard(&addr).write().insert(addr, token.clone());
+            if churning {
+                let mut churning_by_ip = self.ip_shard(&addr.ip()).write();
+                let tokens = churning_by_ip.entry(addr.ip()).or_default();
+                if !tokens.contains(&token) {
+                    tracing::debug!(
+                        "session from {} keeps changing ports; matching it by IP",
+                        addr.ip()
+                    );
+                    tokens.push(token);
+                }
             }
-            self.addr_to_token.insert(addr, token);
             true
         } else {
             false
@@ -72,32 +254,185 @@ impl SessionTable {
     }
 
     #[tracing::instrument(skip(self), level = "trace")]
-    pub fn delete(&mut self, token: Bytes) {
-        if let Some(entry) = self.token_to_sess.remove(&token) {
-            for (_, addr) in entry.addrs.read().map.iter() {
-                self.addr_to_token.remove(addr);
+    pub fn delete(&self, token: Bytes) {
+        let entry = self.token_shard(&token).write().remove(&token);
+        if let Some(entry) = entry {
+            self.unbind_all(&token, &entry);
+        }
+    }
+
+    /// Forgets every address of a session already removed from its shard.
+    fn unbind_all(&self, token: &[u8], entry: &SessEntry) {
+        let addrs = entry.addrs.read();
+        for addr in addrs
+            .addrs()
+            .into_iter()
+            .chain(addrs.retired.iter().copied())
+        {
+            self.unbind(addr, token);
+            let mut churning_by_ip = self.ip_shard(&addr.ip()).write();
+            if let Some(tokens) = churning_by_ip.get_mut(&addr.ip()) {
+                tokens.retain(|t| t.as_ref() != token);
+                if tokens.is_empty() {
+                    churning_by_ip.remove(&addr.ip());
+                }
             }
         }
     }
 
     #[tracing::instrument(skip(self), level = "trace")]
-    pub fn lookup(&self, addr: SocketAddr) -> Option<&Sender<Bytes>> {
-        let token = self.addr_to_token.get(&addr)?;
-        let entry = self.token_to_sess.get(token)?;
-        Some(&entry.sender)
+    pub fn lookup(&self, addr: SocketAddr) -> Option<SessEntry> {
+        let token = self
+            .addr_shard(&addr)
+            .read()
+            .get(&addr)
+            .cloned()
+            .or_else(|| {
+                // only a lone churning session can be told apart by IP; several behind the same NAT have to resume from the ports they use
+                match self.ip_shard(&addr.ip()).read().get(&addr.ip())?.as_slice() {
+                    [token] => Some(token.clone()),
+                    _ => None,
+                }
+            })?;
+        self.token_shard(&token).read().get(&token).cloned()
     }
 
-    #[tracing::instrument(skip(self, sender, locked_addrs), level = "trace")]
+    #[tracing::instrument(skip(self, sender, bytes_in, locked_addrs), level = "trace")]
     pub fn new_sess(
-        &mut self,
+        &self,
         token: Bytes,
         sender: Sender<Bytes>,
+        bytes_in: Arc<AtomicU64>,
         locked_addrs: Arc<RwLock<ShardedAddrs>>,
     ) {
         let entry = SessEntry {
             sender,
+            bytes_in,
             addrs: locked_addrs,
         };
-        self.token_to_sess.insert(token, entry);
+        let evicted = {
+            let mut shard = self.token_shard(&token).write();
+            shard.insert(token.clone(), entry);
+            if shard.len() > self.shard_capacity {
+                let quietest = shard
+                    .iter()
+                    .filter(|(t, _)| **t != token)
+                    .min_by_key(|(_, entry)| entry.addrs.read().last_heard())
+                    .map(|(t, _)| t.clone());
+                quietest.and_then(|t| shard.remove(&t).map(|entry| (t, entry)))
+            } else {
+                None
+            }
+        };
+        if let Some((token, entry)) = evicted {
+            tracing::debug!("session table shard full; evicting the quietest session");
+            self.unbind_all(&token, &entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table_with_sessions(count: u16) -> SessionTable {
+        let table = SessionTable::default();
+        for i in 0..count {
+            let addr: SocketAddr = ([10, 0, (i >> 8) as u8, i as u8], 1000).into();
+            let token = Bytes::from(i.to_be_bytes().to_vec());
+            let (send, _) = smol::channel::unbounded();
+            table.new_sess(
+                token.clone(),
+                send,
+                Default::default(),
+                Arc::new(RwLock::new(ShardedAddrs::new(0, addr))),
+            );
+            assert!(table.rebind(addr, 0, token));
+        }
+        table
+    }
+
+    #[test]
+    fn stale_shards_skipped() {
+        let first: SocketAddr = ([10, 0, 0, 1], 1000).into();
+        let second: SocketAddr = ([10, 0, 0, 1], 2000).into();
+        let mut addrs = ShardedAddrs::new(0, first);
+        // the first shard was last heard from ten seconds ago
+        addrs.created -= Duration::from_secs(10);
+        addrs.insert(1, second);
+        for _ in 0..10 {
+            assert_eq!(addrs.get_addr(), second);
+        }
+        addrs.mark_seen(first);
+        let picked: std::collections::BTreeSet<_> = (0..10).map(|_| addrs.get_addr()).collect();
+        assert_eq!(picked.len(), 2);
+    }
+
+    #[test]
+    fn rebind_and_delete() {
+        let table = table_with_sessions(10);
+        let old_addr: SocketAddr = ([10, 0, 0, 3], 1000).into();
+        let new_addr: SocketAddr = ([10, 0, 0, 3], 2000).into();
+        let token = Bytes::from(3u16.to_be_bytes().to_vec());
+        assert!(table.lookup(old_addr).is_some());
+        // rebinding the same shard replaces the old address
+        assert!(table.rebind(new_addr, 0, token.clone()));
+        assert!(table.lookup(old_addr).is_none());
+        assert!(table.lookup(new_addr).is_some());
+        // unknown tokens can't be rebound
+        assert!(!table.rebind(old_addr, 0, Bytes::from_static(b"nope")));
+        table.delete(token);
+        assert!(table.lookup(new_addr).is_none());
+        assert!(table.lookup(([10, 0, 0, 4], 1000).into()).is_some());
+    }
+
+    #[test]
+    fn churning_sessions_matched_by_ip() {
+        let table = table_with_sessions(2);
+        let token = Bytes::from(0u16.to_be_bytes().to_vec());
+        let port = |port: u16| -> SocketAddr { ([10, 0, 0, 0], port).into() };
+        for i in 1..=8 {
+            assert!(table.rebind(port(2000 + i), 0, token.clone()));
+        }
+        // the first few moves look like ordinary rebinding, after which old ports stay bound
+        assert!(table.lookup(port(2004)).is_none());
+        assert!(table.lookup(port(2005)).is_some());
+        assert!(table.lookup(port(2008)).is_some());
+        // a port the session hasn't resumed from still finds it by IP, unlike one from another IP
+        assert!(table.lookup(port(9999)).is_some());
+        assert!(table.lookup(([10, 0, 0, 9], 9999).into()).is_none());
+        table.delete(token);
+        assert!(table.lookup(port(2005)).is_none());
+        assert!(table.lookup(port(9999)).is_none());
+        // the other session is untouched
+        assert!(table.lookup(([10, 0, 0, 1], 1000).into()).is_some());
+    }
+
+    #[test]
+    fn full_shards_evict_the_quietest_session() {
+        const SESSIONS: u16 = 100;
+        let table = SessionTable::with_shard_capacity(1);
+        let addr = |i: u16| -> SocketAddr { ([10, 0, 0, i as u8], 1000).into() };
+        let mut receivers = Vec::new();
+        for i in 0..SESSIONS {
+            let token = Bytes::from(i.to_be_bytes().to_vec());
+            let (send, recv) = smol::channel::unbounded();
+            table.new_sess(
+                token.clone(),
+                send,
+                Default::default(),
+                Arc::new(RwLock::new(ShardedAddrs::new(0, addr(i)))),
+            );
+            assert!(table.rebind(addr(i), 0, token));
+            receivers.push(recv);
+        }
+        let live = (0..SESSIONS)
+            .filter(|i| table.lookup(addr(*i)).is_some())
+            .count();
+        assert!(live <= TABLE_SHARDS);
+        assert!(table.lookup(addr(SESSIONS - 1)).is_some());
+        // evicted sessions are cut off from their packets
+        let closed = receivers.iter().filter(|recv| recv.is_closed()).count();
+        assert_eq!(closed + live, SESSIONS as usize);
     }
 }
@@ -2,24 +2,38 @@ use crate::*;
 use bytes::Bytes;
 use parking_lot::RwLock;
 use smol::channel::{Receiver, Sender};
-use std::{ops::Deref, sync::Arc};
+use smol_timeout::TimeoutExt;
+use std::{ops::Deref, sync::Arc, time::Duration};
 mod multiplex_actor;
+mod rebind;
 mod relconn;
 mod structs;
-pub use relconn::RelConn;
+pub use rebind::RebindTable;
+use rebind::{MultiplexHandle, SessionSwap};
+pub use relconn::{
+    buffer_budget, buffered_bytes, integrity_check, recv_window, set_buffer_budget,
+    set_integrity_check, set_recv_window, window_blocked_count, CloseReason, ConnStats, RelConn,
+};
 
 use self::structs::Message;
 
 /// A multiplex session over a sosistab session, implementing both reliable "streams" and unreliable messages.
 pub struct Multiplex {
     urel_recv: Receiver<Bytes>,
-    conn_open: Sender<(Option<String>, Sender<RelConn>)>,
+    conn_open: Sender<(Option<String>, Priority, Sender<RelConn>)>,
     conn_accept: Receiver<RelConn>,
-    sess_ref: RwLock<Arc<Session>>,
-    send_session: Sender<Arc<Session>>,
+    sess_ref: Arc<RwLock<Arc<Session>>>,
+    send_session: Sender<(Arc<Session>, SessionSwap)>,
+    rebind_table: Option<(RebindTable, Arc<MultiplexHandle>)>,
     _task: smol::Task<()>,
 }
 
+/// How long to wait for the other side to answer a rebind before asking again.
+const REBIND_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How many times to ask the other side to take a rebind before giving up.
+const REBIND_ATTEMPTS: u32 = 20;
+
 fn to_ioerror<T: Into<Box<dyn std::error::Error + Send + Sync>>>(val: T) -> std::io::Error {
     std::io::Error::new(std::io::ErrorKind::ConnectionReset, val)
 }
@@ -27,28 +41,50 @@ fn to_ioerror<T: Into<Box<dyn std::error::Error + Send + Sync>>>(val: T) -> std:
 impl Multiplex {
     /// Creates a new multiplexed session
     pub fn new(session: Session) -> Self {
+        Self::new_inner(session, None)
+    }
+
+    /// Creates a new multiplexed session on the server side, keeping it in the given table so that the client can move it onto a new session with [Multiplex::rebind]. If this session is itself such a move, the multiplex hands it over to the one the client moved and shuts down.
+    pub fn with_rebind_table(session: Session, table: &RebindTable) -> Self {
+        Self::new_inner(session, Some(table.clone()))
+    }
+
+    fn new_inner(session: Session, table: Option<RebindTable>) -> Self {
         let (send_session, recv_session) = smol::channel::unbounded();
         let (urel_recv_send, urel_recv) = smol::channel::unbounded();
         let (conn_open, conn_open_recv) = smol::channel::unbounded();
         let (conn_accept_send, conn_accept) = smol::channel::bounded(100);
         let session = Arc::new(session);
-        send_session.try_send(session.clone()).unwrap();
-        let _task = runtime::spawn(async move {
-            let retval = multiplex_actor::multiplex(
-                recv_session,
-                urel_recv_send,
-                conn_open_recv,
-                conn_accept_send,
-            )
-            .await;
-            tracing::debug!("multiplex actor returned {:?}", retval);
+        send_session
+            .try_send((session.clone(), SessionSwap::Replace))
+            .unwrap();
+        let sess_ref = Arc::new(RwLock::new(session.clone()));
+        let rebind_table = table.map(|table| {
+            let handle = Arc::new(MultiplexHandle::new(sess_ref.clone(), send_session.clone()));
+            table.insert(session.rebind_secret(), &handle);
+            (table, handle)
+        });
+        let _task = runtime::spawn({
+            let rebind_table = rebind_table.clone();
+            async move {
+                let retval = multiplex_actor::multiplex(
+                    recv_session,
+                    urel_recv_send,
+                    conn_open_recv,
+                    conn_accept_send,
+                    rebind_table,
+                )
+                .await;
+                tracing::debug!("multiplex actor returned {:?}", retval);
+            }
         });
         Multiplex {
             send_session,
             urel_recv,
             conn_open,
             conn_accept,
-            sess_ref: RwLock::new(session),
+            sess_ref,
+            rebind_table,
             _task,
         }
     }
@@ -63,6 +99,16 @@ impl Multiplex {
         Ok(())
     }
 
+    /// Sends a message of the given length that the other side throws away, as cover traffic. It's sent at the lowest priority, so that it never holds up real traffic. Multiplexes from before cover traffic existed die when they get one.
+    pub fn send_padding(&self, len: usize) {
+        self.sess_ref.read().send_bytes_with_priority(
+            bincode::serialize(&Message::Padding(Bytes::from(vec![0u8; len])))
+                .unwrap()
+                .into(),
+            Priority::Low,
+        );
+    }
+
     /// Receive an unreliable message
     #[tracing::instrument(skip(self), level = "trace")]
     pub async fn recv_urel(&self) -> std::io::Result<Bytes> {
@@ -84,14 +130,64 @@ impl Multiplex {
         let sess = Arc::new(sess);
         let mut sess_ref = self.sess_ref.write();
         *sess_ref = sess.clone();
-        let _ = self.send_session.try_send(sess);
+        let _ = self.send_session.try_send((sess, SessionSwap::Replace));
+    }
+
+    /// Moves the multiplex onto a new session, such as one from a fresh handshake after the old one died, keeping its connections open. Unlike [Multiplex::replace_session], the other side moves its multiplex onto the new session too, so this only works with servers that create their multiplexes with [Multiplex::with_rebind_table]. If the other side doesn't take the rebind within ten seconds, this fails and the multiplex stays on its old session.
+    ///
+    /// Which connections survive depends on their state:
+    /// - Established connections survive, resending right away whatever they sent that wasn't acknowledged, and carry on where they left off.
+    /// - Connections still being opened survive, since their opening message is resent every half second, for up to 50 seconds, until it's answered.
+    /// - Connections that were closing finish closing as they would have.
+    /// - Connections that gave up while the old session was down, and connections that the other side reset in the meantime, are gone.
+    /// - Unreliable messages sent on the old session but not yet delivered are lost.
+    pub async fn rebind(&self, sess: Session) -> std::io::Result<()> {
+        let request: Bytes =
+            bincode::serialize(&Message::Rebind(self.get_session().rebind_secret()))
+                .unwrap()
+                .into();
+        for _ in 0..REBIND_ATTEMPTS {
+            sess.send_bytes(request.clone());
+            let answer = async {
+                loop {
+                    let msg = sess.recv_bytes().await?;
+                    if let Ok(Message::RebindAck) = bincode::deserialize(&msg) {
+                        return Some(());
+                    }
+                }
+            };
+            match answer.timeout(REBIND_INTERVAL).await {
+                Some(Some(())) => {
+                    let sess = Arc::new(sess);
+                    *self.sess_ref.write() = sess.clone();
+                    let _ = self.send_session.try_send((sess, SessionSwap::Rebind));
+                    return Ok(());
+                }
+                Some(None) => return Err(to_ioerror("new session died while rebinding")),
+                None => continue,
+            }
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "other side didn't take the rebind",
+        ))
     }
 
     /// Open a reliable conn to the other end.
     pub async fn open_conn(&self, additional: Option<String>) -> std::io::Result<RelConn> {
+        self.open_conn_with_priority(additional, Priority::Normal)
+            .await
+    }
+
+    /// Open a reliable conn to the other end, with both ends sending at the given priority. Older peers send at normal priority whatever is asked.
+    pub async fn open_conn_with_priority(
+        &self,
+        additional: Option<String>,
+        priority: Priority,
+    ) -> std::io::Result<RelConn> {
         let (send, recv) = smol::channel::unbounded();
         self.conn_open
-            .send((additional.clone(), send))
+            .send((additional.clone(), priority, send))
             .await
             .map_err(to_ioerror)?;
         if let Ok(s) = recv.recv().await {
@@ -106,3 +202,123 @@ impl Multiplex {
         self.conn_accept.recv().await.map_err(to_ioerror)
     }
 }
+
+impl Drop for Multiplex {
+    fn drop(&mut self) {
+        if let Some((table, handle)) = &self.rebind_table {
+            table.remove(handle);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smol::prelude::*;
+
+    #[test]
+    fn priorities_reach_the_other_side() {
+        smol::block_on(async {
+            let long_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let pubkey = (&long_sk).into();
+            let listener = Listener::listen_tcp_with_recv_timeout(
+                "127.0.0.1:0",
+                long_sk,
+                |_, _| (),
+                |_, _| (),
+                Duration::from_secs(60),
+            )
+            .await;
+            let client = Multiplex::new(connect_tcp(listener.local_addr(), pubkey).await.unwrap());
+            let server = Multiplex::new(
+                listener
+                    .accept_session_timeout(Duration::from_secs(10))
+                    .await
+                    .unwrap(),
+            );
+            for priority in [Priority::High, Priority::Normal, Priority::Low]
+                .iter()
+                .copied()
+            {
+                let (opened, accepted) = smol::future::zip(
+                    client.open_conn_with_priority(None, priority),
+                    server.accept_conn(),
+                )
+                .await;
+                assert_eq!(opened.unwrap().priority(), priority);
+                assert_eq!(accepted.unwrap().priority(), priority);
+            }
+        })
+    }
+
+    #[test]
+    fn dropped_sessions_say_goodbye() {
+        smol::block_on(async {
+            let long_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let pubkey = (&long_sk).into();
+            let listener = Listener::listen_tcp("127.0.0.1:0", long_sk, |_, _| (), |_, _| ()).await;
+            let client = connect_tcp(listener.local_addr(), pubkey).await.unwrap();
+            let server = listener
+                .accept_session_timeout(Duration::from_secs(10))
+                .await
+                .unwrap();
+            drop(client);
+            // well before the listener's receive timeout
+            let ended = server.recv_bytes().timeout(Duration::from_secs(10)).await;
+            assert!(matches!(ended, Some(None)));
+        })
+    }
+
+    #[test]
+    fn connections_survive_a_rebind() {
+        smol::block_on(async {
+            let long_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let pubkey: x25519_dalek::PublicKey = (&long_sk).into();
+            let listener = Listener::listen_tcp_with_recv_timeout(
+                "127.0.0.1:0",
+                long_sk,
+                |_, _| (),
+                |_, _| (),
+                Duration::from_secs(60),
+            )
+            .await;
+            let table = RebindTable::new();
+            let client = Multiplex::new(connect_tcp(listener.local_addr(), pubkey).await.unwrap());
+            let server = Multiplex::with_rebind_table(
+                listener
+                    .accept_session_timeout(Duration::from_secs(10))
+                    .await
+                    .unwrap(),
+                &table,
+            );
+            let (opened, accepted) =
+                smol::future::zip(client.open_conn(None), server.accept_conn()).await;
+            let (mut opened, mut accepted) = (opened.unwrap(), accepted.unwrap());
+            let mut buf = [0u8; 6];
+            opened.write_all(b"before").await.unwrap();
+            accepted.read_exact(&mut buf).await.unwrap();
+            // the multiplex of the new session hands it over to the old one and shuts down
+            let (rebound, handed_over) = smol::future::zip(
+                client.rebind(connect_tcp(listener.local_addr(), pubkey).await.unwrap()),
+                async {
+                    let session = listener
+                        .accept_session_timeout(Duration::from_secs(10))
+                        .await
+                        .unwrap();
+                    Multiplex::with_rebind_table(session, &table)
+                        .accept_conn()
+                        .await
+                },
+            )
+            .await;
+            rebound.unwrap();
+            assert!(handed_over.is_err());
+            opened.write_all(b"after!").await.unwrap();
+            accepted.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"after!");
+            accepted.write_all(b"reply!").await.unwrap();
+            opened.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"reply!");
+        })
+    }
+}
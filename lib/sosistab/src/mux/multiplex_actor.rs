@@ -1,6 +1,7 @@
 use crate::*;
 use bytes::Bytes;
 use dashmap::DashMap;
+use mux::rebind::{MultiplexHandle, RebindTable, SessionSwap};
 use mux::relconn::{RelConn, RelConnBack, RelConnState};
 use mux::structs::*;
 use rand::prelude::*;
@@ -9,22 +10,23 @@ use smol::prelude::*;
 use std::sync::Arc;
 
 pub async fn multiplex(
-    recv_session: Receiver<Arc<Session>>,
+    recv_session: Receiver<(Arc<Session>, SessionSwap)>,
     urel_recv_send: Sender<Bytes>,
-    conn_open_recv: Receiver<(Option<String>, Sender<RelConn>)>,
+    conn_open_recv: Receiver<(Option<String>, Priority, Sender<RelConn>)>,
     conn_accept_send: Sender<RelConn>,
+    rebind_table: Option<(RebindTable, Arc<MultiplexHandle>)>,
 ) -> anyhow::Result<()> {
     let conn_tab = Arc::new(ConnTable::default());
-    let (glob_send, glob_recv) = smol::channel::bounded(100);
+    let (glob_send, glob_recv) = priority::bounded(100);
     let (dead_send, dead_recv) = smol::channel::unbounded();
-    let mut session = recv_session.recv().await?;
+    let (mut session, _) = recv_session.recv().await?;
 
     // enum of possible events
     enum Event {
-        SessionReplace(Arc<Session>),
+        SessionReplace(Arc<Session>, SessionSwap),
         RecvMsg(Message),
-        SendMsg(Message),
-        ConnOpen(Option<String>, Sender<RelConn>),
+        SendMsg(Priority, Message),
+        ConnOpen(Option<String>, Priority, Sender<RelConn>),
         Dead(u16),
     };
 
@@ -32,27 +34,32 @@ pub async fn multiplex(
         smol::future::yield_now().await;
         // fires on session replacement
         let sess_replace = async {
-            let new_session = recv_session.recv().await?;
-            Ok::<_, anyhow::Error>(Event::SessionReplace(new_session))
+            let (new_session, swap) = recv_session.recv().await?;
+            Ok::<_, anyhow::Error>(Event::SessionReplace(new_session, swap))
         };
         // fires on receiving messages
         let recv_msg = async {
-            let msg = session
-                .recv_bytes()
-                .await
-                .ok_or_else(|| anyhow::anyhow!("underlying session is dead"))?;
-            let msg = bincode::deserialize::<Message>(&msg)?;
-            Ok::<_, anyhow::Error>(Event::RecvMsg(msg))
+            loop {
+                let msg = session
+                    .recv_bytes()
+                    .await
+                    .ok_or_else(|| anyhow::anyhow!("underlying session is dead"))?;
+                // newer peers may send kinds of messages we don't know about
+                match bincode::deserialize::<Message>(&msg) {
+                    Ok(msg) => return Ok::<_, anyhow::Error>(Event::RecvMsg(msg)),
+                    Err(err) => tracing::debug!("dropping message we can't decode: {}", err),
+                }
+            }
         };
         // fires on sending messages
         let send_msg = async {
-            let to_send = glob_recv.recv().await?;
-            Ok::<_, anyhow::Error>(Event::SendMsg(to_send))
+            let (priority, to_send) = glob_recv.recv().await?;
+            Ok::<_, anyhow::Error>(Event::SendMsg(priority, to_send))
         };
         // fires on stream open events
         let conn_open = async {
-            let (additional_data, result_chan) = conn_open_recv.recv().await?;
-            Ok::<_, anyhow::Error>(Event::ConnOpen(additional_data, result_chan))
+            let (additional_data, priority, result_chan) = conn_open_recv.recv().await?;
+            Ok::<_, anyhow::Error>(Event::ConnOpen(additional_data, priority, result_chan))
         };
         // fires on death
         let death = async {
@@ -64,9 +71,24 @@ pub async fn multiplex(
             .or(recv_msg.or(send_msg.or(sess_replace.or(death))))
             .await?
         {
-            Event::SessionReplace(new_sess) => session = new_sess,
+            Event::SessionReplace(new_sess, swap) => {
+                // after a rebind, the other side has moved off the old session too, so there's no one to say goodbye to
+                if !matches!(swap, SessionSwap::Replace) {
+                    session.skip_goodbye();
+                }
+                session = new_sess;
+                match swap {
+                    SessionSwap::Replace => {}
+                    SessionSwap::Rebind => conn_tab.rebind_all(),
+                    SessionSwap::AcceptRebind => {
+                        tracing::debug!("other side moved us onto session {}", session.id());
+                        session.send_bytes(bincode::serialize(&Message::RebindAck).unwrap().into());
+                        conn_tab.rebind_all();
+                    }
+                }
+            }
             Event::Dead(id) => conn_tab.del_stream(id),
-            Event::ConnOpen(additional_data, result_chan) => {
+            Event::ConnOpen(additional_data, priority, result_chan) => {
                 let conn_tab = conn_tab.clone();
                 let glob_send = glob_send.clone();
                 let dead_send = dead_send.clone();
@@ -87,6 +109,7 @@ pub async fn multiplex(
                                 },
                                 additional_data.clone(),
                             );
+                            conn.set_priority(priority);
                             runtime::spawn_local(async move {
                                 recv_sig.recv().await.ok()?;
                                 result_chan.send(conn).await.ok()?;
@@ -102,25 +125,51 @@ pub async fn multiplex(
                     tracing::trace!("conn open send {}", stream_id);
                     drop(
                         glob_send
-                            .send(Message::Rel {
-                                kind: RelKind::Syn,
-                                stream_id,
-                                seqno: 0,
-                                payload: Bytes::copy_from_slice(
-                                    additional_data.clone().unwrap_or_default().as_bytes(),
-                                ),
-                            })
+                            .send(
+                                Message::Rel {
+                                    kind: RelKind::Syn,
+                                    stream_id,
+                                    seqno: priority.to_syn_seqno(),
+                                    payload: Bytes::copy_from_slice(
+                                        additional_data.clone().unwrap_or_default().as_bytes(),
+                                    ),
+                                },
+                                priority,
+                            )
                             .await,
                     );
                 })
                 .detach();
             }
-            Event::SendMsg(msg) => {
+            Event::SendMsg(priority, msg) => {
                 let msg = bincode::serialize(&msg).unwrap();
-                session.send_bytes(msg.into());
+                session.send_bytes_with_priority(msg.into(), priority);
             }
             Event::RecvMsg(msg) => {
                 match msg {
+                    Message::Padding(bts) => {
+                        tracing::trace!("padding recv {}B", bts.len());
+                    }
+                    Message::Rebind(secret) => match &rebind_table {
+                        Some((table, handle)) => match table.get(&secret) {
+                            // a resend of a rebind we already took
+                            Some(other) if Arc::ptr_eq(&other, handle) => session.send_bytes(
+                                bincode::serialize(&Message::RebindAck).unwrap().into(),
+                            ),
+                            Some(other) => {
+                                tracing::debug!(
+                                    "handing session {} over to the multiplex it rebinds",
+                                    session.id()
+                                );
+                                table.insert(session.rebind_secret(), &other);
+                                other.accept_rebind(session.clone());
+                                return Ok(());
+                            }
+                            None => tracing::debug!("ignoring rebind of an unknown multiplex"),
+                        },
+                        None => tracing::debug!("ignoring rebind without a rebind table"),
+                    },
+                    Message::RebindAck => tracing::trace!("ignoring stray rebind ack"),
                     // unreliable
                     Message::Urel(bts) => {
                         tracing::trace!("urel recv {}B", bts.len());
@@ -132,8 +181,8 @@ pub async fn multiplex(
                     Message::Rel {
                         kind: RelKind::Syn,
                         stream_id,
+                        seqno,
                         payload,
-                        ..
                     } => {
                         if conn_tab.get_stream(stream_id).is_some() {
                             tracing::trace!("syn recv {} REACCEPT", stream_id);
@@ -160,6 +209,7 @@ pub async fn multiplex(
                                 },
                                 additional_info,
                             );
+                            new_conn.set_priority(Priority::from_syn_seqno(seqno));
                             // the RelConn itself is responsible for sending the SynAck. Here we just store the connection into the table, accept it, and be done with it.
                             conn_tab.set_stream(stream_id, new_conn_back);
                             drop(conn_accept_send.send(new_conn).await);
@@ -284,6 +334,12 @@ impl ConnTable {
         self.sid_to_stream.remove(&id);
     }
 
+    fn rebind_all(&self) {
+        for entry in self.sid_to_stream.iter() {
+            entry.value().rebind();
+        }
+    }
+
     fn find_id(&self) -> Option<u16> {
         if self.sid_to_stream.len() >= 65535 {
             tracing::warn!("ran out of descriptors ({})", self.sid_to_stream.len());
@@ -0,0 +1,78 @@
+use crate::Session;
+use dashmap::DashMap;
+use parking_lot::{Mutex, RwLock};
+use smol::channel::Sender;
+use std::sync::Arc;
+
+/// What a multiplex does with a session handed to it.
+pub(crate) enum SessionSwap {
+    /// Moves onto the session, leaving connections to find out on their own.
+    Replace,
+    /// Moves onto the session, which the other side moved onto too, and has connections resend what they have in flight.
+    Rebind,
+    /// Like `Rebind`, but first tells the other side, which is waiting to hear that its rebind was taken.
+    AcceptRebind,
+}
+
+/// What another multiplex needs to hand a multiplex a session.
+pub(crate) struct MultiplexHandle {
+    sess_ref: Arc<RwLock<Arc<Session>>>,
+    send_session: Sender<(Arc<Session>, SessionSwap)>,
+    /// Secrets of every session the multiplex has been on.
+    secrets: Mutex<Vec<[u8; 32]>>,
+}
+
+impl MultiplexHandle {
+    pub fn new(
+        sess_ref: Arc<RwLock<Arc<Session>>>,
+        send_session: Sender<(Arc<Session>, SessionSwap)>,
+    ) -> Self {
+        MultiplexHandle {
+            sess_ref,
+            send_session,
+            secrets: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Hands the multiplex the session its other side moved onto.
+    pub fn accept_rebind(&self, sess: Arc<Session>) {
+        *self.sess_ref.write() = sess.clone();
+        let _ = self
+            .send_session
+            .try_send((sess, SessionSwap::AcceptRebind));
+    }
+}
+
+/// Server-side multiplexes, by the secrets of the sessions they've been on, so that a client that moves its multiplex onto a new session with [super::Multiplex::rebind] finds its connections still there.
+///
+/// A rebinding client's new session is accepted like any other, and the multiplex created for it with [super::Multiplex::with_rebind_table] finds the client's old multiplex here, hands it the session, and shuts down, so that `accept_conn` on it fails.
+#[derive(Clone, Default)]
+pub struct RebindTable {
+    handles: Arc<DashMap<[u8; 32], Arc<MultiplexHandle>>>,
+}
+
+impl RebindTable {
+    /// Creates an empty table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts knowing the multiplex by the given session secret.
+    pub(crate) fn insert(&self, secret: [u8; 32], handle: &Arc<MultiplexHandle>) {
+        handle.secrets.lock().push(secret);
+        self.handles.insert(secret, handle.clone());
+    }
+
+    /// Finds the multiplex known by the given session secret.
+    pub(crate) fn get(&self, secret: &[u8; 32]) -> Option<Arc<MultiplexHandle>> {
+        self.handles.get(secret).map(|handle| handle.clone())
+    }
+
+    /// Forgets a multiplex that's going away.
+    pub(crate) fn remove(&self, handle: &Arc<MultiplexHandle>) {
+        for secret in handle.secrets.lock().drain(..) {
+            self.handles
+                .remove_if(&secret, |_, other| Arc::ptr_eq(other, handle));
+        }
+    }
+}
@@ -1,39 +1,126 @@
+use crate::priority::PrioritySender;
 use crate::*;
 use async_dup::Arc as DArc;
 use async_dup::Mutex as DMutex;
 use bipe::{BipeReader, BipeWriter};
 use bytes::Bytes;
-use connvars::ConnVars;
+use connvars::{ConnVars, PeerReset};
+use integrity::IntegrityMismatch;
 use mux::structs::{Message, RelKind};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
 
 use smol::channel::{Receiver, Sender};
 use smol::prelude::*;
-use std::{pin::Pin, sync::Arc, task::Context, task::Poll, time::Duration};
+use std::{
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+    sync::Arc,
+    task::Context,
+    task::Poll,
+    time::Duration,
+};
 mod bipe;
 mod connvars;
 mod inflight;
+mod integrity;
+mod stats;
+use stats::ConnCounters;
+pub use stats::ConnStats;
 
 pub const MSS: usize = 1100;
 const MAX_WAIT_SECS: u64 = 60;
 
+static RECV_WINDOW: AtomicUsize = AtomicUsize::new(10 * 1024 * 1024);
+static WINDOW_BLOCKED: AtomicUsize = AtomicUsize::new(0);
+static INTEGRITY_CHECK: AtomicBool = AtomicBool::new(false);
+
+/// Sets the receive window, in bytes, of connections opened from now on. This is how much data the other side may send before the application reads it.
+pub fn set_recv_window(bytes: usize) {
+    RECV_WINDOW.store(bytes.max(MSS), Ordering::Relaxed)
+}
+
+/// Gets the receive window, in bytes.
+pub fn recv_window() -> usize {
+    RECV_WINDOW.load(Ordering::Relaxed)
+}
+
+/// Sets how many bytes all connections together may hold, received but not yet read by the application. Past that, receive windows shrink, so that the other side slows down rather than buffers growing without bound. Each connection's window is also capped at its share of whatever is left, so that a burst of new connections can't overcommit the budget, though an empty connection always gets at least a packet.
+pub fn set_buffer_budget(bytes: usize) {
+    bipe::BUFFER_BUDGET.set_limit(bytes)
+}
+
+/// Gets the buffer budget, in bytes.
+pub fn buffer_budget() -> usize {
+    bipe::BUFFER_BUDGET.limit()
+}
+
+/// How many bytes all connections together hold right now, received but not yet read by the application.
+pub fn buffered_bytes() -> usize {
+    bipe::BUFFER_BUDGET.bytes()
+}
+
+/// Turns end-to-end integrity checks on or off for connections opened from now on. With checks on, each side keeps a running hash of the stream it sends and tells the other side about it every megabyte and when it closes, so that data corrupted anywhere between the two applications, even though every packet was authenticated, fails reads with an [std::io::ErrorKind::InvalidData] error rather than going unnoticed. Hashing everything costs CPU, so this is meant for debugging. Both sides must turn it on for anything to be checked, though either side can safely have it on alone, as long as the other side's multiplex is recent enough to recognize checkpoints.
+pub fn set_integrity_check(on: bool) {
+    INTEGRITY_CHECK.store(on, Ordering::Relaxed)
+}
+
+/// Whether integrity checks are on.
+pub fn integrity_check() -> bool {
+    INTEGRITY_CHECK.load(Ordering::Relaxed)
+}
+
+/// How many connections can't send right now because the other side's receive window is full.
+pub fn window_blocked_count() -> usize {
+    WINDOW_BLOCKED.load(Ordering::Relaxed)
+}
+
+/// Why a connection was closed, as told to the other side.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub enum CloseReason {
+    /// Nothing in particular went wrong.
+    Normal,
+    /// Something took too long.
+    Timeout,
+    /// The destination isn't allowed, such as a port that isn't whitelisted.
+    Refused,
+    /// The destination couldn't be reached.
+    Unreachable,
+    /// The destination reset the connection.
+    Reset,
+}
+
 #[derive(Clone)]
 pub struct RelConn {
     send_write: DArc<DMutex<BipeWriter>>,
     recv_read: DArc<DMutex<BipeReader>>,
     additional_info: Option<String>,
+    send_close: Sender<CloseReason>,
+    peer_reason: Arc<Mutex<Option<CloseReason>>>,
+    counters: Arc<ConnCounters>,
+    priority: Arc<AtomicU8>,
 }
 
 impl RelConn {
     pub(crate) fn new(
         state: RelConnState,
-        output: Sender<Message>,
+        output: PrioritySender<Message>,
         dropper: impl FnOnce() + Send + 'static,
         additional_info: Option<String>,
     ) -> (Self, RelConnBack) {
         let (send_write, recv_write) = bipe::bipe(1024 * 1024);
-        let (send_read, recv_read) = bipe::bipe(10 * 1024 * 1024);
+        let (send_read, recv_read) =
+            bipe::budgeted_bipe(recv_window(), bipe::BUFFER_BUDGET.clone());
         let (send_wire_read, recv_wire_read) = smol::channel::bounded(1024);
+        let (send_close, recv_close) = smol::channel::bounded(1);
+        let (send_rebind, recv_rebind) = smol::channel::bounded(1);
+        let peer_reason = Arc::new(Mutex::new(None));
         let aic = additional_info.clone();
+        let pr = peer_reason.clone();
+        let counters = Arc::new(ConnCounters::new());
+        let ctrs = counters.clone();
+        let priority = Arc::new(AtomicU8::new(Priority::Normal.to_u8()));
+        let prio = priority.clone();
         let _task = runtime::spawn_local(async move {
             if let Err(e) = relconn_actor(
                 state,
@@ -42,6 +129,11 @@ impl RelConn {
                 recv_wire_read,
                 output,
                 aic,
+                recv_close,
+                recv_rebind,
+                pr,
+                ctrs,
+                prio,
                 dropper,
             )
             .await
@@ -54,9 +146,14 @@ impl RelConn {
                 send_write: DArc::new(DMutex::new(send_write)),
                 recv_read: DArc::new(DMutex::new(recv_read)),
                 additional_info,
+                send_close,
+                peer_reason,
+                counters,
+                priority,
             },
             RelConnBack {
                 send_wire_read,
+                send_rebind,
                 _task: Arc::new(_task),
             },
         )
@@ -69,6 +166,31 @@ impl RelConn {
     pub async fn shutdown(&mut self) {
         drop(self.send_write.close().await)
     }
+
+    /// Resets the connection right away, telling the other side why. Dropping the connection instead closes it without a reason.
+    pub fn close_with(&self, reason: CloseReason) {
+        let _ = self.send_close.try_send(reason);
+    }
+
+    /// Why the other side closed the connection, if it reset the connection and said why. Older peers never do.
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        *self.peer_reason.lock()
+    }
+
+    /// Gets a summary of the connection so far. Once the connection has closed, the summary no longer changes, so call this after the copy is done to log or account for the whole connection.
+    pub fn stats(&self) -> ConnStats {
+        self.counters.snapshot()
+    }
+
+    /// Sets the priority of what the connection sends from now on, including acknowledgements of what it receives. Connections start at the priority they were opened with, which is normal unless the opener asked otherwise.
+    pub fn set_priority(&self, priority: Priority) {
+        self.priority.store(priority.to_u8(), Ordering::Relaxed)
+    }
+
+    /// Gets the priority of what the connection sends.
+    pub fn priority(&self) -> Priority {
+        Priority::from_u8(self.priority.load(Ordering::Relaxed))
+    }
 }
 
 impl AsyncRead for RelConn {
@@ -79,7 +201,18 @@ impl AsyncRead for RelConn {
     ) -> Poll<std::io::Result<usize>> {
         let recv_read = &mut self.recv_read;
         smol::pin!(recv_read);
-        recv_read.poll_read(cx, buf)
+        let res = recv_read.poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            // a corrupted stream must not look like one that ended normally
+            if n == 0 && !buf.is_empty() && self.counters.corrupted() {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "connection failed an integrity check",
+                )));
+            }
+            self.counters.add_received(n);
+        }
+        res
     }
 }
 
@@ -91,7 +224,11 @@ impl AsyncWrite for RelConn {
     ) -> Poll<std::io::Result<usize>> {
         let send_write = &mut self.send_write;
         smol::pin!(send_write);
-        send_write.poll_write(cx, buf)
+        let res = send_write.poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.counters.add_sent(n);
+        }
+        res
     }
 
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
@@ -123,6 +260,7 @@ pub(crate) enum RelConnState {
     Reset {
         stream_id: u16,
         death: smol::Timer,
+        reason: Option<CloseReason>,
     },
 }
 use RelConnState::*;
@@ -134,18 +272,25 @@ async fn relconn_actor(
     mut recv_write: BipeReader,
     mut send_read: BipeWriter,
     recv_wire_read: Receiver<Message>,
-    send_wire_write: Sender<Message>,
+    send_wire_write: PrioritySender<Message>,
     additional_info: Option<String>,
+    recv_close: Receiver<CloseReason>,
+    recv_rebind: Receiver<()>,
+    peer_reason: Arc<Mutex<Option<CloseReason>>>,
+    counters: Arc<ConnCounters>,
+    priority: Arc<AtomicU8>,
     dropper: impl FnOnce(),
 ) -> anyhow::Result<()> {
     // dbg!(RELCONN_COUNT.fetch_add(1, Ordering::Relaxed));
 
     let _guard = scopeguard::guard((), |_| {
         // dbg!(RELCONN_COUNT.fetch_sub(1, Ordering::Relaxed));
+        counters.close();
         dropper()
     });
     let transmit = |msg| {
-        let _ = send_wire_write.try_send(msg);
+        let priority = Priority::from_u8(priority.load(Ordering::Relaxed));
+        let _ = send_wire_write.try_send(msg, priority);
     };
     loop {
         smol::future::yield_now().await;
@@ -200,7 +345,7 @@ async fn relconn_actor(
                     transmit(Message::Rel {
                         kind: RelKind::Syn,
                         stream_id,
-                        seqno: 0,
+                        seqno: Priority::from_u8(priority.load(Ordering::Relaxed)).to_syn_seqno(),
                         payload: Bytes::copy_from_slice(
                             additional_info
                                 .as_ref()
@@ -219,20 +364,50 @@ async fn relconn_actor(
                 stream_id,
                 mut conn_vars,
             } => {
-                if let Err(err) = conn_vars
-                    .process_one(
+                let close_requested = async {
+                    match recv_close.recv().await {
+                        Ok(reason) => Ok::<_, anyhow::Error>(Some(reason)),
+                        Err(_) => smol::future::pending().await,
+                    }
+                };
+                let processed = async {
+                    conn_vars
+                        .process_one(
+                            stream_id,
+                            &mut recv_write,
+                            &mut send_read,
+                            &recv_wire_read,
+                            &recv_rebind,
+                            transmit,
+                        )
+                        .await
+                        .map(|_| None)
+                };
+                let res = processed.or(close_requested).await;
+                counters.update(&conn_vars);
+                if !matches!(res, Ok(None)) {
+                    counters.close();
+                }
+                if let Ok(Some(reason)) = res {
+                    tracing::debug!("C={} closing with {:?}", stream_id, reason);
+                    Reset {
                         stream_id,
-                        &mut recv_write,
-                        &mut send_read,
-                        &recv_wire_read,
-                        transmit,
-                    )
-                    .await
-                {
+                        death: smol::Timer::after(Duration::from_secs(MAX_WAIT_SECS)),
+                        reason: Some(reason),
+                    }
+                } else if let Err(err) = res {
                     tracing::debug!("connection reset: {:?}", err);
+                    if let Some(PeerReset(reason)) = err.downcast_ref::<PeerReset>() {
+                        *peer_reason.lock() = *reason;
+                    }
+                    if let Some(mismatch) = err.downcast_ref::<IntegrityMismatch>() {
+                        tracing::warn!("C={} {}", stream_id, mismatch);
+                        counters.mark_corrupted();
+                    }
                     Reset {
                         stream_id,
                         death: smol::Timer::after(Duration::from_secs(MAX_WAIT_SECS)),
+                        reason: None,
                     }
                 } else {
                     SteadyState {
@@ -244,6 +419,7 @@ async fn relconn_actor(
             Reset {
                 stream_id,
                 mut death,
+                reason,
             } => {
                 drop(send_read.close().await);
                 tracing::trace!("C={} RESET", stream_id);
@@ -251,7 +427,9 @@ async fn relconn_actor(
                     kind: RelKind::Rst,
                     stream_id,
                     seqno: 0,
-                    payload: Bytes::new(),
+                    payload: reason
+                        .map(|reason| bincode::serialize(&reason).unwrap().into())
+                        .unwrap_or_default(),
                 });
                 let die = smol::future::race(
                     async {
@@ -270,7 +448,11 @@ async fn relconn_actor(
                 if die {
                     anyhow::bail!("exiting from reset")
                 }
-                Reset { stream_id, death }
+                Reset {
+                    stream_id,
+                    death,
+                    reason,
+                }
             }
         }
     }
@@ -279,6 +461,7 @@ async fn relconn_actor(
 #[derive(Clone)]
 pub(crate) struct RelConnBack {
     send_wire_read: Sender<Message>,
+    send_rebind: Sender<()>,
     _task: Arc<smol::Task<()>>,
 }
 
@@ -289,4 +472,9 @@ impl RelConnBack {
             tracing::trace!("relconn failed to accept pkt: {}", e)
         }
     }
+
+    /// Tells the connection that the multiplex moved to a new session, so that it resends whatever it has in flight.
+    pub fn rebind(&self) {
+        let _ = self.send_rebind.try_send(());
+    }
 }
@@ -1,12 +1,87 @@
 use bytes::Bytes;
+use once_cell::sync::Lazy;
 use parking_lot::Mutex;
 use smol::future::Future;
 use smol::prelude::*;
-use std::{collections::VecDeque, pin::Pin, sync::Arc, task::Context, task::Poll};
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::Arc,
+    task::Context,
+    task::Poll,
+};
+
+use super::MSS;
+
+/// The budget that the pipes of all connections share.
+pub static BUFFER_BUDGET: Lazy<Arc<Budget>> = Lazy::new(|| Arc::new(Budget::new(usize::MAX)));
+
+/// A limit on how many bytes a set of pipes may hold together.
+pub struct Budget {
+    limit: AtomicUsize,
+    /// Bytes waiting in the budgeted pipes, and how many budgeted pipes there are.
+    bytes: AtomicUsize,
+    pipes: AtomicUsize,
+    /// Notified when the budgeted pipes go from holding the whole budget to holding less, so that writers held back by it can look again. Writers are otherwise woken by reads from their own pipes, so this doesn't fire on every read.
+    drained: event_listener::Event,
+}
+
+impl Budget {
+    pub fn new(limit: usize) -> Self {
+        Budget {
+            limit: AtomicUsize::new(limit),
+            bytes: AtomicUsize::new(0),
+            pipes: AtomicUsize::new(0),
+            drained: event_listener::Event::new(),
+        }
+    }
+
+    /// Sets the limit, in bytes.
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.store(limit, Ordering::Relaxed);
+        self.drained.notify(usize::MAX);
+    }
+
+    /// Gets the limit, in bytes.
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    /// Bytes waiting in the budgeted pipes right now.
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    fn add(&self, n: usize) {
+        self.bytes.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn sub(&self, n: usize) {
+        let before = self.bytes.fetch_sub(n, Ordering::Relaxed);
+        let limit = self.limit();
+        if before >= limit && before - n < limit {
+            self.drained.notify(usize::MAX);
+        }
+    }
+}
 
 /// Create a "bipe". Use async_dup's methods if you want something cloneable/shareable
 pub fn bipe(capacity: usize) -> (BipeWriter, BipeReader) {
-    let info = Arc::new(Mutex::new(BipeQueue::default()));
+    bipe_inner(capacity, None)
+}
+
+/// Create a "bipe" whose contents count against the given budget.
+pub fn budgeted_bipe(capacity: usize, budget: Arc<Budget>) -> (BipeWriter, BipeReader) {
+    budget.pipes.fetch_add(1, Ordering::Relaxed);
+    bipe_inner(capacity, Some(budget))
+}
+
+fn bipe_inner(capacity: usize, budget: Option<Arc<Budget>>) -> (BipeWriter, BipeReader) {
+    let info = Arc::new(Mutex::new(BipeQueue {
+        budget,
+        ..Default::default()
+    }));
     let event = Arc::new(event_listener::Event::new());
     (
         BipeWriter {
@@ -28,15 +103,39 @@ struct BipeQueue {
     inner: VecDeque<Bytes>,
     closed: bool,
     counter: usize,
+    budget: Option<Arc<Budget>>,
+}
+
+impl Drop for BipeQueue {
+    fn drop(&mut self) {
+        if let Some(budget) = &self.budget {
+            budget.sub(self.counter);
+            budget.pipes.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
 }
 
 impl BipeQueue {
     fn push(&mut self, bts: &[u8]) {
         self.inner.push_front(Bytes::copy_from_slice(bts));
-        self.counter += bts.len()
+        self.counter += bts.len();
+        if let Some(budget) = &self.budget {
+            budget.add(bts.len());
+        }
     }
 
+    /// Takes up to `fill.len()` bytes out of the queue.
     fn pop_fill(&mut self, fill: &mut [u8]) -> usize {
+        let n = self.pop_fill_inner(fill);
+        if let Some(budget) = &self.budget {
+            if n > 0 {
+                budget.sub(n);
+            }
+        }
+        n
+    }
+
+    fn pop_fill_inner(&mut self, fill: &mut [u8]) -> usize {
         let tentative = self.inner.pop_back();
         if let Some(tentative) = tentative {
             if tentative.len() <= fill.len() {
@@ -63,6 +162,54 @@ pub struct BipeWriter {
     listener: event_listener::EventListener,
 }
 
+impl BipeWriter {
+    /// How many bytes are in the pipe, waiting to be read.
+    pub fn buffered(&self) -> usize {
+        self.queue.lock().counter
+    }
+
+    /// How many more bytes the pipe should take, out of the given window. A budgeted pipe also takes no more than its share of what's left of the buffer budget, split evenly between all budgeted pipes, though always at least a packet while it's empty, so that it never gets stuck.
+    pub fn free(&self, window: usize) -> usize {
+        let (buffered, budget) = {
+            let queue = self.queue.lock();
+            (queue.counter, queue.budget.clone())
+        };
+        let free = window.saturating_sub(buffered);
+        let budget = match budget {
+            Some(budget) => budget,
+            None => return free,
+        };
+        let left = budget.limit().saturating_sub(budget.bytes());
+        let share = left / budget.pipes.load(Ordering::Relaxed).max(1);
+        let floor = if buffered == 0 { MSS } else { 0 };
+        free.min(share).max(floor)
+    }
+
+    /// How big the window of the pipe effectively is right now, given what's left of the buffer budget.
+    pub fn effective_window(&self, window: usize) -> usize {
+        (self.buffered() + self.free(window)).max(MSS)
+    }
+
+    /// Waits until at least half the effective window is free, or the pipe is closed.
+    pub async fn wait_half_free(&self, window: usize) {
+        loop {
+            let listener = self.signal.listen();
+            let (closed, budget) = {
+                let queue = self.queue.lock();
+                (queue.closed, queue.budget.clone())
+            };
+            let drained = budget.map(|budget| budget.drained.listen());
+            if closed || self.free(window) * 2 >= self.effective_window(window) {
+                return;
+            }
+            match drained {
+                Some(drained) => listener.or(drained).await,
+                None => listener.await,
+            }
+        }
+    }
+}
+
 impl Drop for BipeWriter {
     fn drop(&mut self) {
         self.queue.lock().closed = true;
@@ -146,3 +293,61 @@ impl AsyncRead for BipeReader {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smol_timeout::TimeoutExt;
+    use std::time::Duration;
+
+    const WINDOW: usize = 100 * MSS;
+
+    #[test]
+    fn free_is_capped_by_the_budget() {
+        smol::block_on(async {
+            let budget = Arc::new(Budget::new(10 * MSS));
+            let (mut full, _full_reader) = budgeted_bipe(WINDOW, budget.clone());
+            let (empty, _empty_reader) = budgeted_bipe(WINDOW, budget.clone());
+            // the pipes split the budget evenly
+            assert_eq!(full.free(WINDOW), 5 * MSS);
+            full.write_all(&[0u8; 4 * MSS]).await.unwrap();
+            assert_eq!(full.free(WINDOW), 3 * MSS);
+            assert_eq!(empty.free(WINDOW), 3 * MSS);
+            // once the budget is used up, only an empty pipe takes anything, and only a packet
+            full.write_all(&[0u8; 6 * MSS]).await.unwrap();
+            assert_eq!(full.free(WINDOW), 0);
+            assert_eq!(empty.free(WINDOW), MSS);
+            // unbudgeted pipes only go by the window
+            let (unbudgeted, _unbudgeted_reader) = bipe(WINDOW);
+            assert_eq!(unbudgeted.free(WINDOW), WINDOW);
+        })
+    }
+
+    #[test]
+    fn draining_the_budget_wakes_other_writers() {
+        smol::block_on(async {
+            let budget = Arc::new(Budget::new(10 * MSS));
+            let (mut big, mut big_reader) = budgeted_bipe(WINDOW, budget.clone());
+            let (mut small, _small_reader) = budgeted_bipe(WINDOW, budget.clone());
+            big.write_all(&[0u8; 9 * MSS]).await.unwrap();
+            small.write_all(&[0u8; MSS]).await.unwrap();
+            assert_eq!(small.free(WINDOW), 0);
+            assert!(small
+                .wait_half_free(WINDOW)
+                .timeout(Duration::from_millis(100))
+                .await
+                .is_none());
+            // nobody reads from the small pipe, so only the budget draining can wake its writer
+            let (woken, _) = smol::future::zip(
+                small.wait_half_free(WINDOW).timeout(Duration::from_secs(1)),
+                async {
+                    let mut buf = vec![0u8; 9 * MSS];
+                    big_reader.read_exact(&mut buf).await.unwrap();
+                },
+            )
+            .await;
+            assert!(woken.is_some());
+            assert_eq!(budget.bytes(), MSS);
+        })
+    }
+}
@@ -1,5 +1,6 @@
 use std::{
     collections::VecDeque,
+    sync::atomic::Ordering,
     time::{Duration, Instant},
 };
 
@@ -12,7 +13,8 @@ use crate::{mux::structs::*, VarRateLimit};
 use super::{
     bipe::{BipeReader, BipeWriter},
     inflight::Inflight,
-    MSS,
+    integrity::Integrity,
+    MSS, WINDOW_BLOCKED,
 };
 use smol::prelude::*;
 
@@ -43,6 +45,21 @@ pub(crate) struct ConnVars {
     write_fragments: VecDeque<Bytes>,
 
     limiter: VarRateLimit,
+
+    recv_window: usize,
+    /// One past the highest seqno the other side will take, if it told us. Older peers don't.
+    peer_limit: Option<Seqno>,
+    window_blocked: bool,
+    /// The limit we last told the other side about.
+    advertised_limit: Seqno,
+    /// When to resend a window update, in case the last one was lost.
+    window_retry_timer: Option<Instant>,
+    window_retries: u8,
+    /// Where the stream was when we last sent a window update.
+    window_update_seqno: Seqno,
+
+    /// Only there when integrity checks were on when the connection opened.
+    integrity: Option<Integrity>,
 }
 
 impl Default for ConnVars {
@@ -74,11 +91,48 @@ impl Default for ConnVars {
             write_fragments: VecDeque::new(),
 
             limiter: VarRateLimit::new(),
+
+            recv_window: super::recv_window(),
+            peer_limit: None,
+            window_blocked: false,
+            advertised_limit: 0,
+            window_retry_timer: None,
+            window_retries: 0,
+            window_update_seqno: 0,
+
+            integrity: if super::integrity_check() {
+                Some(Integrity::new())
+            } else {
+                None
+            },
+        }
+    }
+}
+
+impl Drop for ConnVars {
+    fn drop(&mut self) {
+        self.set_window_blocked(false)
+    }
+}
+
+/// The other side reset the connection, perhaps saying why.
+#[derive(Debug)]
+pub(crate) struct PeerReset(pub Option<super::CloseReason>);
+
+impl std::fmt::Display for PeerReset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(reason) => write!(f, "received RST ({:?})", reason),
+            None => write!(f, "received RST"),
         }
     }
 }
 
+impl std::error::Error for PeerReset {}
+
 const ACK_BATCH: usize = 64;
+/// How many times we resend a window update if the other side doesn't start sending again.
+const WINDOW_RETRIES: u8 = 3;
 
 impl ConnVars {
     /// Process a *single* event. Returns false when the thing should be closed.
@@ -88,6 +142,7 @@ impl ConnVars {
         recv_write: &mut BipeReader,
         send_read: &mut BipeWriter,
         recv_wire_read: &Receiver<Message>,
+        recv_rebind: &Receiver<()>,
         transmit: impl Fn(Message),
     ) -> anyhow::Result<()> {
         // match on our current state repeatedly
@@ -98,11 +153,38 @@ impl ConnVars {
             NewWrite(Bytes),
             NewPkt(Message),
             Closing,
+            WindowOpened,
+            WindowRetry,
+            Rebind,
         }
+        let window_open = self
+            .peer_limit
+            .map(|limit| self.next_free_seqno < limit)
+            .unwrap_or(true);
+        self.set_window_blocked(!window_open);
         let event = {
             let writeable = self.inflight.inflight() <= self.cwnd as usize
                 && self.inflight.len() < 10000
-                && !self.closing;
+                && !self.closing
+                && window_open;
+            // if the other side thinks we're almost full, tell it once the application has caught up
+            let window_stalled =
+                (self.advertised_limit.saturating_sub(self.lowest_unseen) as usize) * MSS
+                    < send_read.effective_window(self.recv_window) / 4;
+            let recv_window = self.recv_window;
+            let window_retry_timer = self.window_retry_timer;
+            let send_read_ref = &*send_read;
+            let window_update = async move {
+                if window_stalled {
+                    send_read_ref.wait_half_free(recv_window).await;
+                    Ok::<Evt, anyhow::Error>(Evt::WindowOpened)
+                } else if let Some(time) = window_retry_timer {
+                    smol::Timer::at(time).await;
+                    Ok(Evt::WindowRetry)
+                } else {
+                    smol::future::pending().await
+                }
+            };
             let force_ack = self.ack_seqnos.len() >= ACK_BATCH;
             assert!(self.ack_seqnos.len() <= ACK_BATCH);
 
@@ -158,12 +240,19 @@ impl ConnVars {
             };
             let new_pkt =
                 async { Ok::<Evt, anyhow::Error>(Evt::NewPkt(recv_wire_read.recv().await?)) };
+            let rebind = async {
+                match recv_rebind.recv().await {
+                    Ok(()) => Ok::<Evt, anyhow::Error>(Evt::Rebind),
+                    Err(_) => smol::future::pending().await,
+                }
+            };
             let final_timeout = async {
                 smol::Timer::after(Duration::from_secs(600)).await;
                 anyhow::bail!("final timeout within relconn actor")
             };
             ack_timer
-                .or(new_pkt.or(rto_timeout.or(new_write.or(final_timeout))))
+                .or(new_pkt
+                    .or(rto_timeout.or(new_write.or(window_update.or(rebind.or(final_timeout))))))
                 .await
         };
         let implied_rate = self.pacing_rate() as u32;
@@ -172,6 +261,9 @@ impl ConnVars {
         match event {
             Ok(Evt::Closing) => {
                 self.closing = true;
+                if let Some(integrity) = self.integrity.as_mut() {
+                    transmit(integrity.checkpoint(stream_id, self.next_free_seqno));
+                }
                 if self.inflight.len() > 0 {
                     Ok(())
                 } else {
@@ -188,15 +280,36 @@ impl ConnVars {
                 Ok(())
             }
             Ok(Evt::NewPkt(Message::Rel {
-                kind: RelKind::Rst, ..
-            })) => anyhow::bail!("received RST"),
+                kind: RelKind::Rst,
+                payload,
+                ..
+            })) => Err(PeerReset(bincode::deserialize(&payload).ok()).into()),
+            Ok(Evt::NewPkt(Message::Rel {
+                kind: RelKind::Checkpoint,
+                seqno,
+                payload,
+                ..
+            })) => {
+                if let Some(integrity) = self.integrity.as_mut() {
+                    integrity.on_checkpoint(seqno, &payload, self.lowest_unseen)?;
+                }
+                Ok(())
+            }
             Ok(Evt::NewPkt(Message::Rel {
                 kind: RelKind::DataAck,
                 payload,
                 seqno,
                 ..
             })) => {
-                let seqnos = bincode::deserialize::<Vec<Seqno>>(&payload)?;
+                // newer peers append their receive limit
+                let (seqnos, limit) = match bincode::deserialize::<(Vec<Seqno>, Seqno)>(&payload) {
+                    Ok((seqnos, limit)) => (seqnos, Some(limit)),
+                    Err(_) => (bincode::deserialize::<Vec<Seqno>>(&payload)?, None),
+                };
+                if let Some(limit) = limit {
+                    // acks can be reordered, and the limit never legitimately goes down
+                    self.peer_limit = Some(self.peer_limit.unwrap_or(0).max(limit));
+                }
                 tracing::trace!("new ACK pkt with {} seqnos", seqnos.len());
                 for seqno in seqnos {
                     if self.inflight.mark_acked(seqno) {
@@ -225,10 +338,13 @@ impl ConnVars {
                     self.ack_seqnos.insert(seqno);
                 }
                 let times = self.reorderer.take();
-                self.lowest_unseen += times.len() as u64;
                 let mut success = true;
                 for pkt in times {
                     success |= send_read.write(&pkt).await.is_ok();
+                    self.lowest_unseen += 1;
+                    if let Some(integrity) = self.integrity.as_mut() {
+                        integrity.on_deliver(&pkt, self.lowest_unseen)?;
+                    }
                 }
                 if success {
                     Ok(())
@@ -241,6 +357,11 @@ impl ConnVars {
                 self.limiter.wait(implied_rate).await;
                 let seqno = self.next_free_seqno;
                 self.next_free_seqno += 1;
+                let checkpoint_due = self
+                    .integrity
+                    .as_mut()
+                    .map(|integrity| integrity.on_send(&bts))
+                    .unwrap_or(false);
                 let msg = Message::Rel {
                     kind: RelKind::Data,
                     stream_id,
@@ -251,26 +372,50 @@ impl ConnVars {
                 self.inflight.insert(seqno, msg.clone());
 
                 transmit(msg);
+                if checkpoint_due {
+                    if let Some(integrity) = self.integrity.as_mut() {
+                        transmit(integrity.checkpoint(stream_id, self.next_free_seqno));
+                    }
+                }
 
                 Ok(())
             }
             Ok(Evt::AckTimer) => {
-                // eprintln!("acking {} seqnos", conn_vars.ack_seqnos.len());
-                let mut ack_seqnos: Vec<_> = self.ack_seqnos.iter().collect();
-                assert!(ack_seqnos.len() <= ACK_BATCH);
-                ack_seqnos.sort_unstable();
-                let encoded_acks = bincode::serialize(&ack_seqnos).unwrap();
-                if encoded_acks.len() > 1000 {
-                    tracing::warn!("encoded_acks {} bytes", encoded_acks.len());
+                self.send_ack(stream_id, send_read, &transmit);
+                Ok(())
+            }
+            Ok(Evt::WindowOpened) => {
+                tracing::trace!("C={} window reopened", stream_id);
+                // the very first update just announces the window, so the other side isn't waiting on it
+                let was_stalled = self.advertised_limit > 0;
+                self.send_ack(stream_id, send_read, &transmit);
+                if was_stalled {
+                    self.window_retries = WINDOW_RETRIES;
+                    self.window_update_seqno = self.lowest_unseen;
+                    self.window_retry_timer = Instant::now().checked_add(Duration::from_secs(1));
+                }
+                Ok(())
+            }
+            Ok(Evt::WindowRetry) => {
+                // stop once data is flowing again, since its acks carry the window anyway
+                if self.window_retries > 0 && self.lowest_unseen == self.window_update_seqno {
+                    self.send_ack(stream_id, send_read, &transmit);
+                    self.window_retries -= 1;
+                    self.window_retry_timer = Instant::now().checked_add(Duration::from_secs(1));
+                } else {
+                    self.window_retry_timer = None;
                 }
-                transmit(Message::Rel {
-                    kind: RelKind::DataAck,
+                Ok(())
+            }
+            Ok(Evt::Rebind) => {
+                tracing::debug!(
+                    "C={} moved to a new session, resending {} packets",
                     stream_id,
-                    seqno: self.lowest_unseen,
-                    payload: Bytes::copy_from_slice(&encoded_acks),
-                });
-                self.ack_seqnos.clear();
-                self.delayed_ack_timer = None;
+                    self.inflight.len()
+                );
+                // whatever was in flight on the old session is probably lost, and the other side should hear where we are
+                self.inflight.rearm();
+                self.send_ack(stream_id, send_read, &transmit);
                 Ok(())
             }
             Err(err) => {
@@ -284,6 +429,40 @@ impl ConnVars {
         }
     }
 
+    /// Sends an ack for everything we've received, along with how much more we're willing to receive.
+    fn send_ack(&mut self, stream_id: u16, send_read: &BipeWriter, transmit: &impl Fn(Message)) {
+        // eprintln!("acking {} seqnos", conn_vars.ack_seqnos.len());
+        let mut ack_seqnos: Vec<_> = self.ack_seqnos.iter().copied().collect();
+        assert!(ack_seqnos.len() <= ACK_BATCH);
+        ack_seqnos.sort_unstable();
+        let free = send_read.free(self.recv_window);
+        let limit = self.lowest_unseen + (free / MSS) as Seqno;
+        let encoded_acks = bincode::serialize(&(ack_seqnos, limit)).unwrap();
+        if encoded_acks.len() > 1000 {
+            tracing::warn!("encoded_acks {} bytes", encoded_acks.len());
+        }
+        transmit(Message::Rel {
+            kind: RelKind::DataAck,
+            stream_id,
+            seqno: self.lowest_unseen,
+            payload: Bytes::copy_from_slice(&encoded_acks),
+        });
+        self.advertised_limit = limit;
+        self.ack_seqnos.clear();
+        self.delayed_ack_timer = None;
+    }
+
+    fn set_window_blocked(&mut self, blocked: bool) {
+        if blocked != self.window_blocked {
+            self.window_blocked = blocked;
+            if blocked {
+                WINDOW_BLOCKED.fetch_add(1, Ordering::Relaxed);
+            } else {
+                WINDOW_BLOCKED.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+
     pub fn pacing_rate(&self) -> f64 {
         // calculate implicit rate
         self.cwnd / self.inflight.min_rtt().as_secs_f64()
@@ -59,6 +59,11 @@ impl Inflight {
         self.rtt.rto()
     }
 
+    /// How many RTT samples acks have given us. Before the first, the RTT is a guess.
+    pub fn rtt_samples(&self) -> u64 {
+        self.rtt.samples()
+    }
+
     pub fn rtt_var(&self) -> Duration {
         self.rtt.rtt_var()
     }
@@ -138,6 +143,19 @@ impl Inflight {
             .map(|v| (*v.0, v.1.retrans_time()))
     }
 
+    /// Makes every packet due for retransmission right away, with a fresh timeout, such as after moving to a new session. Acks of these packets aren't used as RTT samples, as their send times are made up.
+    pub fn rearm(&mut self) {
+        let now = Instant::now();
+        let rto_duration = self.rtt.rto();
+        let send_time = now.checked_sub(rto_duration).unwrap_or(now);
+        for entry in self.segments.values_mut() {
+            entry.send_time = send_time;
+            entry.rto_duration = rto_duration;
+            entry.retrans = entry.retrans.max(1);
+        }
+        self.recalc_first_rto();
+    }
+
     /// Retransmits a particular seqno.
     pub fn retransmit(&mut self, seqno: Seqno) -> Option<Message> {
         let payload = {
@@ -9,6 +9,9 @@ pub struct RttCalculator {
     // rate estimation
     min_rtt: u64,
     rtt_update_time: Instant,
+
+    // how many real samples there were, since the measurements start with a guess
+    samples: u64,
 }
 
 impl Default for RttCalculator {
@@ -17,6 +20,7 @@ impl Default for RttCalculator {
             rtt_measurements: vec![300],
             min_rtt: 300,
             rtt_update_time: Instant::now(),
+            samples: 0,
         }
     }
 }
@@ -24,6 +28,7 @@ impl Default for RttCalculator {
 impl RttCalculator {
     pub fn record_sample(&mut self, sample: Duration) {
         let sample = (sample.as_millis() as u64).max(1);
+        self.samples += 1;
         self.rtt_measurements.push(sample);
         self.rtt_measurements.sort_unstable();
         // if over limit, decimate
@@ -65,4 +70,8 @@ impl RttCalculator {
     pub fn min_rtt(&self) -> Duration {
         Duration::from_millis(self.min_rtt)
     }
+
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
 }
@@ -0,0 +1,146 @@
+use std::collections::BTreeMap;
+
+use bytes::Bytes;
+
+use crate::mux::structs::{Message, RelKind, Seqno};
+
+/// How many bytes are sent between checkpoints.
+const CHECKPOINT_INTERVAL: usize = 1024 * 1024;
+/// How many checkpoints ahead of the data we hold on to.
+const MAX_PENDING: usize = 64;
+
+/// Running hashes of what a connection sent and delivered, for catching streams that get corrupted despite every packet being authenticated, such as by reassembly bugs.
+///
+/// Every [CHECKPOINT_INTERVAL] bytes, and once more when the application closes its side, the sender tells the other side the hash of everything it sent so far. The receiver compares that against the hash of everything it delivered to the application up to the same point. Checkpoints are sent once and never resent, so a lost checkpoint just goes unchecked.
+pub(crate) struct Integrity {
+    sent: blake3::Hasher,
+    since_checkpoint: usize,
+    delivered: blake3::Hasher,
+    /// Checkpoints for data not yet delivered, by the seqno they end before.
+    pending: BTreeMap<Seqno, [u8; 32]>,
+}
+
+/// The stream the application read isn't the stream the other side wrote.
+#[derive(Debug)]
+pub(crate) struct IntegrityMismatch(pub Seqno);
+
+impl std::fmt::Display for IntegrityMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "stream corrupted before seqno {}", self.0)
+    }
+}
+
+impl std::error::Error for IntegrityMismatch {}
+
+impl Integrity {
+    pub fn new() -> Self {
+        Integrity {
+            sent: blake3::Hasher::new(),
+            since_checkpoint: 0,
+            delivered: blake3::Hasher::new(),
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Adds data about to be sent. Returns true if a checkpoint is due once it's sent.
+    pub fn on_send(&mut self, bts: &[u8]) -> bool {
+        self.sent.update(bts);
+        self.since_checkpoint += bts.len();
+        self.since_checkpoint >= CHECKPOINT_INTERVAL
+    }
+
+    /// A checkpoint of everything sent so far, which is everything before `next_seqno`.
+    pub fn checkpoint(&mut self, stream_id: u16, next_seqno: Seqno) -> Message {
+        self.since_checkpoint = 0;
+        Message::Rel {
+            kind: RelKind::Checkpoint,
+            stream_id,
+            seqno: next_seqno,
+            payload: Bytes::copy_from_slice(self.sent.finalize().as_bytes()),
+        }
+    }
+
+    /// Adds data delivered to the application, which is everything before `next_seqno` once it's delivered.
+    pub fn on_deliver(&mut self, bts: &[u8], next_seqno: Seqno) -> Result<(), IntegrityMismatch> {
+        self.delivered.update(bts);
+        match self.pending.remove(&next_seqno) {
+            Some(digest) => self.compare(next_seqno, &digest),
+            None => Ok(()),
+        }
+    }
+
+    /// Handles a checkpoint from the other side, given that everything before `lowest_unseen` was delivered.
+    pub fn on_checkpoint(
+        &mut self,
+        seqno: Seqno,
+        payload: &[u8],
+        lowest_unseen: Seqno,
+    ) -> Result<(), IntegrityMismatch> {
+        let mut digest = [0u8; 32];
+        if payload.len() != digest.len() {
+            tracing::debug!("ignoring malformed checkpoint of {} bytes", payload.len());
+            return Ok(());
+        }
+        digest.copy_from_slice(payload);
+        if seqno == lowest_unseen {
+            self.compare(seqno, &digest)
+        } else if seqno > lowest_unseen {
+            self.pending.insert(seqno, digest);
+            while self.pending.len() > MAX_PENDING {
+                let first = *self.pending.keys().next().unwrap();
+                self.pending.remove(&first);
+            }
+            Ok(())
+        } else {
+            // arrived after the data it covers was delivered, so there's nothing left to compare against
+            Ok(())
+        }
+    }
+
+    fn compare(&self, seqno: Seqno, digest: &[u8; 32]) -> Result<(), IntegrityMismatch> {
+        if self.delivered.finalize() == blake3::Hash::from(*digest) {
+            tracing::trace!("checkpoint before seqno {} matches", seqno);
+            Ok(())
+        } else {
+            Err(IntegrityMismatch(seqno))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkpoint_payload(msg: Message) -> (Seqno, Bytes) {
+        match msg {
+            Message::Rel { seqno, payload, .. } => (seqno, payload),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn checkpoints_match_intact_streams() {
+        let mut sender = Integrity::new();
+        let mut receiver = Integrity::new();
+        sender.on_send(b"hello ");
+        sender.on_send(b"world");
+        let (seqno, digest) = checkpoint_payload(sender.checkpoint(0, 2));
+        // the checkpoint overtakes the data
+        receiver.on_checkpoint(seqno, &digest, 0).unwrap();
+        receiver.on_deliver(b"hello ", 1).unwrap();
+        receiver.on_deliver(b"world", 2).unwrap();
+        assert!(receiver.pending.is_empty());
+    }
+
+    #[test]
+    fn checkpoints_catch_corruption() {
+        let mut sender = Integrity::new();
+        let mut receiver = Integrity::new();
+        sender.on_send(b"hello ");
+        sender.on_send(b"world");
+        let (seqno, digest) = checkpoint_payload(sender.checkpoint(0, 2));
+        receiver.on_deliver(b"hello ", 1).unwrap();
+        receiver.on_deliver(b"wrold", 2).unwrap();
+        assert!(receiver.on_checkpoint(seqno, &digest, 2).is_err());
+    }
+}
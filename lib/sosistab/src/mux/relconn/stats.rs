@@ -0,0 +1,111 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::connvars::ConnVars;
+
+/// A summary of a connection, as seen from this side.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct ConnStats {
+    /// Bytes the application wrote to the connection.
+    pub bytes_sent: u64,
+    /// Bytes the application read from the connection.
+    pub bytes_received: u64,
+    /// How long the connection has been open, or was open if it has closed.
+    pub duration: Duration,
+    /// How many times data had to be sent again.
+    pub retransmits: u64,
+    /// Smoothed round-trip time, as of the last ack. None if this side never sent any data to be acked.
+    pub rtt: Option<Duration>,
+    /// Whether the connection has closed, so that the summary is final.
+    pub closed: bool,
+}
+
+/// Statistics the connection and its actor keep up to date.
+pub(crate) struct ConnCounters {
+    start: Instant,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    retransmits: AtomicU64,
+    // zero until there's a sample
+    rtt_ms: AtomicU64,
+    // zero while the connection is open
+    closed_after_us: AtomicU64,
+    corrupted: AtomicBool,
+}
+
+impl ConnCounters {
+    pub fn new() -> Self {
+        ConnCounters {
+            start: Instant::now(),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            retransmits: AtomicU64::new(0),
+            rtt_ms: AtomicU64::new(0),
+            closed_after_us: AtomicU64::new(0),
+            corrupted: AtomicBool::new(false),
+        }
+    }
+
+    pub fn add_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Copies what the actor knows about the connection.
+    pub fn update(&self, conn_vars: &ConnVars) {
+        self.retransmits
+            .store(conn_vars.retrans_count, Ordering::Relaxed);
+        if conn_vars.inflight.rtt_samples() > 0 {
+            self.rtt_ms.store(
+                (conn_vars.inflight.srtt().as_millis() as u64).max(1),
+                Ordering::Relaxed,
+            );
+        }
+    }
+
+    /// Marks the connection closed. Only the first call counts.
+    pub fn close(&self) {
+        let elapsed = (self.start.elapsed().as_micros() as u64).max(1);
+        let _ =
+            self.closed_after_us
+                .compare_exchange(0, elapsed, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    /// Marks the connection as having failed an integrity check.
+    pub fn mark_corrupted(&self) {
+        self.corrupted.store(true, Ordering::Relaxed)
+    }
+
+    pub fn corrupted(&self) -> bool {
+        self.corrupted.load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> ConnStats {
+        let closed_after_us = self.closed_after_us.load(Ordering::Relaxed);
+        let rtt_ms = self.rtt_ms.load(Ordering::Relaxed);
+        ConnStats {
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            duration: if closed_after_us > 0 {
+                Duration::from_micros(closed_after_us)
+            } else {
+                self.start.elapsed()
+            },
+            retransmits: self.retransmits.load(Ordering::Relaxed),
+            rtt: if rtt_ms > 0 {
+                Some(Duration::from_millis(rtt_ms))
+            } else {
+                None
+            },
+            closed: closed_after_us > 0,
+        }
+    }
+}
@@ -15,10 +15,17 @@ pub enum Message {
         seqno: Seqno,
         payload: Bytes,
     },
+    /// Cover traffic, which the other side throws away. Older multiplexes can't decode it and die, so only send it to peers known to understand it.
+    Padding(Bytes),
+    /// Sent on a new session by a multiplex moving onto it, carrying the secret of the session it's moving off, until answered with `RebindAck`. Older multiplexes either drop it or can't decode it and die, so it's only sent when asked to rebind.
+    Rebind([u8; 32]),
+    /// Tells the other side that the multiplex its `Rebind` named moved onto this session.
+    RebindAck,
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
 pub enum RelKind {
+    /// Opens a connection. `seqno` carries the priority the opener wants both sides to send at, which older peers leave at 0, meaning normal.
     Syn,
     SynAck,
     Data,
@@ -26,6 +33,8 @@ pub enum RelKind {
     Fin,
     FinAck,
     Rst,
+    /// An integrity checkpoint: the hash of everything sent before `seqno`. Multiplexes older than this can't decode it and die, so it's only sent with integrity checks on.
+    Checkpoint,
 }
 
 #[derive(Clone)]
@@ -0,0 +1,157 @@
+use bytes::Bytes;
+use parking_lot::Mutex;
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::SocketAddr,
+    time::SystemTime,
+};
+
+/// The least difficulty, in bits, of a challenge that's issued at all. Anything easier wouldn't slow anyone down.
+const MIN_BITS: u8 = 8;
+
+/// The most difficulty clients agree to solve, so that a forged challenge can't make them spin forever.
+pub const MAX_BITS: u8 = 24;
+
+/// How many minutes a challenge stays good for.
+const VALID_MINUTES: u64 = 2;
+
+/// How hard a challenge should be, given how full the handshake queue is, as a fraction. Nothing is asked of clients until the queue is a quarter full, after which difficulty climbs to `max_bits` as the queue fills.
+pub fn difficulty(load: f64, max_bits: u8) -> u8 {
+    if max_bits == 0 || load < 0.25 {
+        return 0;
+    }
+    let min_bits = MIN_BITS.min(max_bits);
+    let extra = (max_bits - min_bits) as f64 * ((load - 0.25) / 0.75).min(1.0);
+    min_bits + extra.round() as u8
+}
+
+/// Issues and checks proof-of-work challenges. A challenge names its difficulty and when it was issued, authenticated with a key only we know and bound to the address it was sent to, so that checking a solution only costs two hashes. The only state is the solutions already used, which are kept until their challenges expire, so that each solution buys a single hello.
+pub struct PowIssuer {
+    key: [u8; 32],
+    /// Solutions already used, by the minute their challenges were issued.
+    used: Mutex<BTreeMap<u64, HashSet<(Bytes, u64)>>>,
+}
+
+impl PowIssuer {
+    pub fn new(key: [u8; 32]) -> Self {
+        PowIssuer {
+            key,
+            used: Default::default(),
+        }
+    }
+
+    /// Creates a challenge of the given difficulty for the given address.
+    pub fn challenge(&self, addr: SocketAddr, difficulty: u8) -> Bytes {
+        self.challenge_at(addr, difficulty, current_minute())
+    }
+
+    fn challenge_at(&self, addr: SocketAddr, difficulty: u8, minute: u64) -> Bytes {
+        let mut challenge = minute.to_be_bytes().to_vec();
+        challenge.push(difficulty);
+        let mac = blake3::keyed_hash(
+            &self.key,
+            &[&challenge[..], addr.to_string().as_bytes()].concat(),
+        );
+        challenge.extend_from_slice(&mac.as_bytes()[..16]);
+        challenge.into()
+    }
+
+    /// Checks a solution to a challenge sent to the given address. A solution is only good once.
+    pub fn verify(&self, addr: SocketAddr, challenge: &[u8], nonce: u64) -> bool {
+        if challenge.len() != 25 {
+            return false;
+        }
+        let mut minute = [0u8; 8];
+        minute.copy_from_slice(&challenge[..8]);
+        let minute = u64::from_be_bytes(minute);
+        let difficulty = challenge[8];
+        let now = current_minute();
+        if now.saturating_sub(minute) >= VALID_MINUTES || difficulty == 0 {
+            return false;
+        }
+        if self.challenge_at(addr, difficulty, minute).as_ref() != challenge
+            || !is_solution(challenge, difficulty, nonce)
+        {
+            return false;
+        }
+        let mut used = self.used.lock();
+        // solutions to expired challenges can't verify anyway
+        *used = used.split_off(&(now + 1).saturating_sub(VALID_MINUTES));
+        used.entry(minute)
+            .or_default()
+            .insert((Bytes::copy_from_slice(challenge), nonce))
+    }
+}
+
+/// Finds a nonce that solves the challenge. Takes about 2^difficulty hashes.
+pub fn solve(challenge: &[u8], difficulty: u8) -> u64 {
+    (0u64..)
+        .find(|nonce| is_solution(challenge, difficulty, *nonce))
+        .unwrap()
+}
+
+fn is_solution(challenge: &[u8], difficulty: u8, nonce: u64) -> bool {
+    let hash = blake3::hash(&[challenge, &nonce.to_be_bytes()[..]].concat());
+    leading_zero_bits(hash.as_bytes()) >= difficulty as u32
+}
+
+fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut zeros = 0;
+    for byte in bytes {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    zeros
+}
+
+fn current_minute() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 60
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn solutions_verify_only_for_their_address() {
+        let issuer = PowIssuer::new([7; 32]);
+        let addr: SocketAddr = "1.2.3.4:5678".parse().unwrap();
+        let challenge = issuer.challenge(addr, 10);
+        let nonce = solve(&challenge, 10);
+        assert!(!issuer.verify("1.2.3.5:5678".parse().unwrap(), &challenge, nonce));
+        assert!(issuer.verify(addr, &challenge, nonce));
+        let mut forged = challenge.to_vec();
+        forged[8] = 1;
+        assert!(!issuer.verify(addr, &forged, solve(&forged, 1)));
+    }
+
+    #[test]
+    fn solutions_verify_once() {
+        let issuer = PowIssuer::new([7; 32]);
+        let addr: SocketAddr = "1.2.3.4:5678".parse().unwrap();
+        let challenge = issuer.challenge(addr, 4);
+        let nonce = solve(&challenge, 4);
+        assert!(issuer.verify(addr, &challenge, nonce));
+        assert!(!issuer.verify(addr, &challenge, nonce));
+        // another solution to the same challenge is more work, so it buys another hello
+        let other = (nonce + 1..)
+            .find(|nonce| is_solution(&challenge, 4, *nonce))
+            .unwrap();
+        assert!(issuer.verify(addr, &challenge, other));
+    }
+
+    #[test]
+    fn difficulty_follows_load() {
+        assert_eq!(difficulty(0.9, 0), 0);
+        assert_eq!(difficulty(0.1, 20), 0);
+        assert_eq!(difficulty(0.25, 20), MIN_BITS);
+        assert_eq!(difficulty(1.0, 20), 20);
+        assert_eq!(difficulty(1.0, 4), 4);
+    }
+}
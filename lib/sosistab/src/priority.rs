@@ -0,0 +1,230 @@
+use std::str::FromStr;
+
+use smol::channel::{Receiver, RecvError, SendError, Sender, TrySendError};
+use smol::prelude::*;
+
+/// How urgently packets should be sent, compared to others going out over the same session.
+///
+/// Whatever is queued at a higher priority goes out before anything queued at a lower one, so a connection that's only sometimes busy, such as DNS or SSH, doesn't wait behind a bulk download. Priority is strict: lower priorities only get what's left over, so only make connections high priority if they don't send much.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+impl Priority {
+    /// Every priority, in the order queues are served.
+    const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    fn index(self) -> usize {
+        match self {
+            Priority::High => 0,
+            Priority::Normal => 1,
+            Priority::Low => 2,
+        }
+    }
+
+    pub(crate) fn to_u8(self) -> u8 {
+        self.index() as u8
+    }
+
+    pub(crate) fn from_u8(val: u8) -> Self {
+        Priority::ALL.get(val as usize).copied().unwrap_or_default()
+    }
+
+    /// Encodes the priority as the seqno of the Syn opening a connection, so that the other side sends at the same priority. Older peers always put 0 there, so 0 has to mean normal.
+    pub(crate) fn to_syn_seqno(self) -> u64 {
+        match self {
+            Priority::Normal => 0,
+            Priority::Low => 1,
+            Priority::High => 2,
+        }
+    }
+
+    pub(crate) fn from_syn_seqno(seqno: u64) -> Self {
+        match seqno {
+            1 => Priority::Low,
+            2 => Priority::High,
+            _ => Priority::Normal,
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            other => Err(format!(
+                "unknown priority {:?} (expected low, normal or high)",
+                other
+            )),
+        }
+    }
+}
+
+/// Creates a channel with a queue for every priority, each of which can hold `cap` items.
+pub(crate) fn bounded<T>(cap: usize) -> (PrioritySender<T>, PriorityReceiver<T>) {
+    let (high_send, high_recv) = smol::channel::bounded(cap);
+    let (normal_send, normal_recv) = smol::channel::bounded(cap);
+    let (low_send, low_recv) = smol::channel::bounded(cap);
+    (
+        PrioritySender {
+            queues: [high_send, normal_send, low_send],
+        },
+        PriorityReceiver {
+            queues: [high_recv, normal_recv, low_recv],
+        },
+    )
+}
+
+/// Creates a channel with a queue for every priority, with no limit on how much they hold.
+pub(crate) fn unbounded<T>() -> (PrioritySender<T>, PriorityReceiver<T>) {
+    let (high_send, high_recv) = smol::channel::unbounded();
+    let (normal_send, normal_recv) = smol::channel::unbounded();
+    let (low_send, low_recv) = smol::channel::unbounded();
+    (
+        PrioritySender {
+            queues: [high_send, normal_send, low_send],
+        },
+        PriorityReceiver {
+            queues: [high_recv, normal_recv, low_recv],
+        },
+    )
+}
+
+/// Sending side of a channel whose items come out highest priority first.
+pub(crate) struct PrioritySender<T> {
+    queues: [Sender<T>; 3],
+}
+
+impl<T> Clone for PrioritySender<T> {
+    fn clone(&self) -> Self {
+        PrioritySender {
+            queues: self.queues.clone(),
+        }
+    }
+}
+
+impl<T> PrioritySender<T> {
+    pub fn try_send(&self, item: T, priority: Priority) -> Result<(), TrySendError<T>> {
+        self.queues[priority.index()].try_send(item)
+    }
+
+    pub async fn send(&self, item: T, priority: Priority) -> Result<(), SendError<T>> {
+        self.queues[priority.index()].send(item).await
+    }
+
+    /// Items queued, of every priority.
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(|q| q.len()).sum()
+    }
+
+    /// Items queued that go out before, or together with, an item of the given priority.
+    pub fn len_ahead_of(&self, priority: Priority) -> usize {
+        self.queues[..=priority.index()]
+            .iter()
+            .map(|q| q.len())
+            .sum()
+    }
+}
+
+/// Receiving side of a channel whose items come out highest priority first.
+pub(crate) struct PriorityReceiver<T> {
+    queues: [Receiver<T>; 3],
+}
+
+impl<T> PriorityReceiver<T> {
+    /// Takes the highest-priority item queued, if any.
+    pub fn try_recv(&self) -> Option<(Priority, T)> {
+        Priority::ALL.iter().find_map(|&priority| {
+            self.queues[priority.index()]
+                .try_recv()
+                .ok()
+                .map(|item| (priority, item))
+        })
+    }
+
+    /// Waits for an item, returning the highest-priority one queued. Fails once the senders are gone and nothing is left.
+    pub async fn recv(&self) -> Result<(Priority, T), RecvError> {
+        loop {
+            if let Some(item) = self.try_recv() {
+                return Ok(item);
+            }
+            if self.queues.iter().all(|q| q.is_closed()) {
+                return Err(RecvError);
+            }
+            // a queue that's closed but not empty can't make this fail, since the loop goes back to try_recv
+            let [high, normal, low] = &self.queues;
+            let next = async { high.recv().await.map(|item| (Priority::High, item)) }
+                .or(async { normal.recv().await.map(|item| (Priority::Normal, item)) })
+                .or(async { low.recv().await.map(|item| (Priority::Low, item)) })
+                .await;
+            if let Ok(item) = next {
+                return Ok(item);
+            }
+        }
+    }
+
+    /// Items queued, of every priority.
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(|q| q.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn higher_priority_first() {
+        let (send, recv) = unbounded();
+        send.try_send(1, Priority::Low).unwrap();
+        send.try_send(2, Priority::Normal).unwrap();
+        send.try_send(3, Priority::High).unwrap();
+        send.try_send(4, Priority::Low).unwrap();
+        assert_eq!(send.len(), 4);
+        assert_eq!(send.len_ahead_of(Priority::High), 1);
+        assert_eq!(send.len_ahead_of(Priority::Normal), 2);
+        let order: Vec<_> = std::iter::from_fn(|| recv.try_recv()).collect();
+        assert_eq!(
+            order,
+            vec![
+                (Priority::High, 3),
+                (Priority::Normal, 2),
+                (Priority::Low, 1),
+                (Priority::Low, 4)
+            ]
+        );
+    }
+
+    #[test]
+    fn syn_seqnos_round_trip() {
+        for priority in Priority::ALL.iter().copied() {
+            assert_eq!(Priority::from_syn_seqno(priority.to_syn_seqno()), priority);
+        }
+        // what older peers send
+        assert_eq!(Priority::from_syn_seqno(0), Priority::Normal);
+    }
+
+    #[test]
+    fn recv_drains_before_failing() {
+        let (send, recv) = unbounded();
+        send.try_send(1, Priority::Low).unwrap();
+        drop(send);
+        smol::block_on(async {
+            assert_eq!(recv.recv().await, Ok((Priority::Low, 1)));
+            assert_eq!(recv.recv().await, Err(RecvError));
+        });
+    }
+}
@@ -26,6 +26,19 @@ pub enum HandshakeFrame {
         /// Which shard is this
         shard_id: u8,
     },
+
+    /// Version-4 equivalent of ClientHello, carrying the first message of a Noise IK handshake. Clients send it alongside a ClientHello, so servers that don't understand it still answer the ClientHello.
+    ClientHelloNoise { noise: Bytes, version: u64 },
+    /// Version-4 equivalent of ServerHello, carrying the second message of the Noise IK handshake.
+    ServerHelloNoise { noise: Bytes, resume_token: Bytes },
+    /// Frame sent from server to client in reply to a ClientResume whose token it can't decrypt, usually because the server restarted since. The client should give up on the session and handshake again, rather than keep resuming a dead token. Identifies the token by its hash, so that it's clear which session is meant.
+    ServerResumeRejected { token_hash: Bytes },
+    /// Frame sent from server to client in reply to a hello, when the server is too busy to answer hellos for free. The client should send its hello again, along with a ClientPowSolution. Only sent to clients that announced support with a ClientPowSupport.
+    ServerPowChallenge { challenge: Bytes, difficulty: u8 },
+    /// Frame sent from client to server alongside a hello, solving a ServerPowChallenge.
+    ClientPowSolution { challenge: Bytes, nonce: u64 },
+    /// Frame sent from client to server after its hellos, saying that it solves ServerPowChallenges of up to the given difficulty. Servers that don't know about it stop decoding there, and keep the hellos before it.
+    ClientPowSupport { max_bits: u8 },
 }
 
 impl HandshakeFrame {
@@ -33,6 +46,17 @@ impl HandshakeFrame {
         bincode::serialize(self).unwrap()
     }
 
+    /// What replays of a packet starting with this frame are checked against: the session it resumes, or the ephemeral key of the handshake it starts.
+    pub fn replay_scope(&self) -> &[u8] {
+        match self {
+            HandshakeFrame::ClientHello { eph_pk, .. } => eph_pk.as_bytes(),
+            HandshakeFrame::ClientResume { resume_token, .. } => resume_token,
+            // the first message of a Noise IK handshake starts with the ephemeral key
+            HandshakeFrame::ClientHelloNoise { noise, .. } => &noise[..noise.len().min(32)],
+            _ => &[],
+        }
+    }
+
     pub fn from_bytes(bts: &[u8]) -> anyhow::Result<Self> {
         Ok(bincode::DefaultOptions::new()
             .with_fixint_encoding()
@@ -63,6 +87,12 @@ pub enum DataFrameV2 {
         pad_size: usize,
         body: Bytes,
     },
+    /// Asks the other side to send back an EchoReply with the same nonce, so that the asker can time the path the request went out on. Nonces only ever go up, so replayed requests go unanswered. Peers too old to know this drop it.
+    EchoRequest { nonce: u64 },
+    /// Answers an EchoRequest.
+    EchoReply { nonce: u64 },
+    /// Tells the other side that the session is closing, so that it can drop the session right away rather than wait out its receive timeout. Peers too old to know this drop it, and time out as before.
+    Goodbye,
 }
 
 impl DataFrameV2 {
@@ -1,34 +1,166 @@
-use std::time::Instant;
+use std::{
+    collections::{hash_map::RandomState, HashSet},
+    hash::{BuildHasher, Hash, Hasher},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
 
 use once_cell::sync::Lazy;
 use parking_lot::Mutex;
 
-// recently seen tracker
-pub struct RecentFilter {
-    curr_bloom: bloomfilter::Bloom<[u8]>,
-    last_bloom: bloomfilter::Bloom<[u8]>,
+/// How many packets the global filter has turned away as replays.
+pub static REPLAYS_DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// How long packets are remembered for, unless a shard fills up first.
+const FILTER_WINDOW: Duration = Duration::from_secs(300);
+
+/// How many independently locked shards the global filter is split into.
+const FILTER_SHARDS: usize = 16;
+
+/// How many packets each shard of the global filter remembers per window. A flood of packets fills a shard sooner, which starts a new window early rather than using more memory, so floods shorten how long packets are remembered.
+const MAX_ENTRIES_PER_SHARD: usize = 32768;
+
+/// Remembers recent packets by scope, such as the session a packet belongs to, to turn away replays.
+///
+/// A packet is only ever compared with earlier packets of the same scope, and is only taken to be a replay if one of them has the same 64-bit keyed hash, so however busy the server is, packets of other sessions can't get a new packet mistaken for a replay. Replays of a packet always have the same scope as the original, so they're still caught. The hashes are keyed at random when the filter is created, so that nobody can make up packets that collide.
+pub struct ScopedFilter {
+    shards: Vec<Mutex<FilterShard>>,
+    hash_keys: RandomState,
+}
+
+/// Packets seen in the current and the last window, by the hashes of their scope and contents.
+struct FilterShard {
+    curr: HashSet<(u64, u64)>,
+    last: HashSet<(u64, u64)>,
     curr_time: Instant,
+    max_entries: usize,
 }
 
-impl RecentFilter {
-    fn new() -> Self {
-        RecentFilter {
-            curr_bloom: bloomfilter::Bloom::new_for_fp_rate(1000000, 0.01),
-            last_bloom: bloomfilter::Bloom::new_for_fp_rate(1000000, 0.01),
+impl FilterShard {
+    fn new(max_entries: usize) -> Self {
+        FilterShard {
+            curr: HashSet::new(),
+            last: HashSet::new(),
             curr_time: Instant::now(),
+            max_entries,
         }
     }
 
-    pub fn check(&mut self, val: &[u8]) -> bool {
-        let start = Instant::now();
-        if start.saturating_duration_since(self.curr_time).as_secs() > 300 {
-            std::mem::swap(&mut self.curr_bloom, &mut self.last_bloom);
-            self.curr_bloom.clear();
-            self.curr_time = start
+    /// Remembers a packet, returning whether it was already remembered.
+    fn check_and_set(&mut self, entry: (u64, u64)) -> bool {
+        let now = Instant::now();
+        if now.saturating_duration_since(self.curr_time) > FILTER_WINDOW
+            || self.curr.len() >= self.max_entries
+        {
+            self.last = std::mem::take(&mut self.curr);
+            self.curr_time = now
         }
-        !(self.curr_bloom.check_and_set(val) || self.last_bloom.check(val))
+        self.last.contains(&entry) || !self.curr.insert(entry)
+    }
+}
+
+impl ScopedFilter {
+    fn new(shards: usize, max_entries_per_shard: usize) -> Self {
+        ScopedFilter {
+            shards: (0..shards)
+                .map(|_| Mutex::new(FilterShard::new(max_entries_per_shard)))
+                .collect(),
+            hash_keys: RandomState::new(),
+        }
+    }
+
+    fn hash(&self, bts: &[u8]) -> u64 {
+        let mut hasher = self.hash_keys.build_hasher();
+        bts.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Checks that a packet in the given scope hasn't been seen recently, remembering it.
+    pub fn check(&self, scope: &[u8], val: &[u8]) -> bool {
+        let scope_hash = self.hash(scope);
+        let seen = self.shards[scope_hash as usize % self.shards.len()]
+            .lock()
+            .check_and_set((scope_hash, self.hash(val)));
+        if seen {
+            REPLAYS_DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+        !seen
     }
 }
 
 /// A global recent filter.
-pub static RECENT_FILTER: Lazy<Mutex<RecentFilter>> = Lazy::new(|| Mutex::new(RecentFilter::new()));
+pub static RECENT_FILTER: Lazy<ScopedFilter> =
+    Lazy::new(|| ScopedFilter::new(FILTER_SHARDS, MAX_ENTRIES_PER_SHARD));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catches_replays_within_scope() {
+        let filter = ScopedFilter::new(4, 1000);
+        assert!(filter.check(b"session", b"packet"));
+        assert!(!filter.check(b"session", b"packet"));
+        assert!(filter.check(b"session", b"another packet"));
+        // the same contents in another session aren't a replay
+        assert!(filter.check(b"other session", b"packet"));
+    }
+
+    #[test]
+    fn remembers_the_last_window_when_full() {
+        let filter = ScopedFilter::new(1, 10);
+        for i in 0u8..15 {
+            assert!(filter.check(b"session", &[i]));
+        }
+        // the first ten moved to the last window when the shard filled up, so they're still caught
+        for i in 0u8..15 {
+            assert!(!filter.check(b"session", &[i]));
+        }
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture` to compare the false positive rates of the Bloom filter this replaced and the scoped filter, both remembering the same number of packets, over five minutes of a busy server's handshakes.
+    #[test]
+    #[ignore]
+    fn false_positive_rates() {
+        const PACKETS: usize = 3_000_000;
+        const REMEMBERED: usize = FILTER_SHARDS * MAX_ENTRIES_PER_SHARD;
+        let packets: Vec<([u8; 32], [u8; 64])> = (0..PACKETS)
+            .map(|_| {
+                (rand::random(), {
+                    let mut packet = [0u8; 64];
+                    packet.iter_mut().for_each(|b| *b = rand::random());
+                    packet
+                })
+            })
+            .collect();
+        // the filter this replaced: one Bloom filter for every session, swapped out as it fills up
+        let mut bloom_curr = bloomfilter::Bloom::new_for_fp_rate(REMEMBERED, 0.01);
+        let mut bloom_last = bloomfilter::Bloom::new_for_fp_rate(REMEMBERED, 0.01);
+        let mut bloom_len = 0;
+        let bloom_fp = packets
+            .iter()
+            .filter(|(_, packet)| {
+                if bloom_len >= REMEMBERED {
+                    std::mem::swap(&mut bloom_curr, &mut bloom_last);
+                    bloom_curr.clear();
+                    bloom_len = 0;
+                }
+                bloom_len += 1;
+                bloom_curr.check_and_set(&packet[..]) || bloom_last.check(&packet[..])
+            })
+            .count();
+        let scoped = ScopedFilter::new(FILTER_SHARDS, MAX_ENTRIES_PER_SHARD);
+        let scoped_fp = packets
+            .iter()
+            .filter(|(scope, packet)| !scoped.check(scope, packet))
+            .count();
+        eprintln!(
+            "false positives over {} packets, remembering {} at a time: Bloom {:.4}%, scoped {:.4}%",
+            PACKETS,
+            REMEMBERED,
+            bloom_fp as f64 / PACKETS as f64 * 100.0,
+            scoped_fp as f64 / PACKETS as f64 * 100.0
+        );
+        assert_eq!(scoped_fp, 0);
+    }
+}
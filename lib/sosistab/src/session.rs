@@ -1,9 +1,10 @@
-use crate::runtime;
+use crate::priority::{self, PriorityReceiver, PrioritySender};
 use crate::{crypt::LegacyAEAD, fec::FrameEncoder};
 use crate::{
     crypt::NgAEAD,
     protocol::{DataFrameV1, DataFrameV2},
 };
+use crate::{runtime, Priority};
 use bytes::Bytes;
 use concurrent_queue::ConcurrentQueue;
 use governor::{NegativeMultiDecision, Quota, RateLimiter};
@@ -13,10 +14,11 @@ use rand::prelude::*;
 use smol::channel::{Receiver, Sender, TrySendError};
 use smol::prelude::*;
 use smol_timeout::TimeoutExt;
-use stats::StatGatherer;
+pub(crate) use stats::StatGatherer;
 use std::{
+    net::SocketAddr,
     num::NonZeroU32,
-    sync::atomic::{AtomicU32, Ordering},
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
     time::{Instant, SystemTime},
 };
 use std::{sync::Arc, time::Duration};
@@ -26,8 +28,26 @@ use self::stats::TimeSeries;
 mod machine;
 mod stats;
 
+/// Sessions whose rate limit, in packets per second, is below this are throttled. At or above it, the limit has no effect. This is also the limit sessions start out with.
+pub const THROTTLE_BELOW: u32 = 25600;
+
+/// Units per second that the send loops' pacers refill at. Each packet costs this divided by the rate limit, so the finer the units, the closer the pace is to the limit.
+const PACING_UNITS: u32 = THROTTLE_BELOW * 16;
+
+/// How long the tasks carrying a dropped session's packets are kept around, so that its goodbye still gets out.
+pub(crate) const GOODBYE_GRACE: Duration = Duration::from_secs(1);
+
+/// Lowest rate limit that the pacers honor exactly. Lower limits pace at this rate, since a single packet would otherwise cost more than the pacer's burst.
+const MIN_PACED_LIMIT: u32 = 20;
+
+/// What sending one packet costs at a pacer, under the given rate limit.
+fn pacing_cost(limit: u32) -> NonZeroU32 {
+    NonZeroU32::new(PACING_UNITS / limit.max(MIN_PACED_LIMIT)).unwrap()
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct SessionConfig {
+    pub id: String,
     pub send_packet: Sender<Bytes>,
     pub recv_packet: Receiver<Bytes>,
     pub recv_timeout: Duration,
@@ -37,11 +57,16 @@ pub(crate) struct SessionConfig {
     pub recv_crypt_legacy: LegacyAEAD,
     pub send_crypt_ng: NgAEAD,
     pub recv_crypt_ng: NgAEAD,
+    pub rebind_secret: [u8; 32],
 }
 
 /// Representation of an isolated session that deals only in DataFrames and abstracts away all I/O concerns. It's the user's responsibility to poll the session. Otherwise, it might not make progress and will drop packets.
 pub struct Session {
-    send_tosend: Sender<Bytes>,
+    id: String,
+    version: u64,
+    rebind_secret: [u8; 32],
+    send_tosend: PrioritySender<Bytes>,
+    send_packet: Sender<Bytes>,
     recv_packet: Receiver<Bytes>,
     statistics: Arc<Mutex<TimeSeries<SessionStat>>>,
     machine: Mutex<RecvMachine>,
@@ -49,6 +74,12 @@ pub struct Session {
     rate_limit: Arc<AtomicU32>,
     last_recv: Arc<Mutex<SystemTime>>,
     recv_timeout: Duration,
+    say_goodbye: AtomicBool,
+    heard_goodbye: Arc<AtomicBool>,
+    info_source: Option<Box<dyn Fn() -> SessionInfo + Send + Sync + 'static>>,
+    shard_source: Option<Box<dyn Fn() -> Vec<ShardStats> + Send + Sync + 'static>>,
+    sealer: FrameSealer,
+    echo_handler: Option<Box<dyn Fn(u64) + Send + Sync + 'static>>,
     _dropper: Vec<Box<dyn FnOnce() + Send + Sync + 'static>>,
     _task: smol::Task<()>,
 }
@@ -56,8 +87,8 @@ pub struct Session {
 impl Session {
     /// Creates a Session.
     pub(crate) fn new(cfg: SessionConfig) -> Self {
-        let (send_tosend, recv_tosend) = smol::channel::unbounded();
-        let rate_limit = Arc::new(AtomicU32::new(25600));
+        let (send_tosend, recv_tosend) = priority::unbounded();
+        let rate_limit = Arc::new(AtomicU32::new(THROTTLE_BELOW));
         let recv_timeout = cfg.recv_timeout;
         let statistics = Arc::new(Mutex::new(TimeSeries::new(cfg.statistics)));
         let machine = Mutex::new(RecvMachine::new(
@@ -67,9 +98,12 @@ impl Session {
         ));
         let last_recv = Arc::new(Mutex::new(SystemTime::now()));
         let recv_packet = cfg.recv_packet.clone();
+        let send_packet = cfg.send_packet.clone();
+        let sealer = FrameSealer::new(&cfg);
 
         let ctx = SessionSendCtx {
             cfg,
+            sealer: sealer.clone(),
             statg: machine.lock().get_gather(),
             recv_tosend,
             rate_limit: rate_limit.clone(),
@@ -77,9 +111,16 @@ impl Session {
             last_recv: last_recv.clone(),
         };
 
+        let id = ctx.cfg.id.clone();
+        let version = ctx.cfg.version;
+        let rebind_secret = ctx.cfg.rebind_secret;
         let task = runtime::spawn(session_send_loop(ctx));
         Session {
+            id,
+            version,
+            rebind_secret,
             send_tosend,
+            send_packet,
             rate_limit,
             recv_packet,
             machine,
@@ -87,29 +128,133 @@ impl Session {
             last_recv,
             statistics,
             recv_timeout,
+            say_goodbye: AtomicBool::new(true),
+            heard_goodbye: Arc::new(AtomicBool::new(false)),
+            info_source: None,
+            shard_source: None,
+            sealer,
+            echo_handler: None,
             _dropper: Vec::new(),
             _task: task,
         }
     }
 
+    /// Gets a short identifier for the session, which both sides of the session agree on. Log this so that logs on both sides can be matched up.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Gets the secret that a multiplex moving off this session shows the other side.
+    pub(crate) fn rebind_secret(&self) -> [u8; 32] {
+        self.rebind_secret
+    }
+
+    /// Gets the protocol version the session negotiated.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
     /// Adds a closure to be run when the Session is dropped. Use this to manage associated "worker" resources.
     pub fn on_drop<T: FnOnce() + Send + Sync + 'static>(&mut self, thing: T) {
         self._dropper.push(Box::new(thing))
     }
 
+    /// Gets a check for whether nothing has arrived for longer than the receive timeout, which is when the session gives up on its peer. The check outlives the session, so that drop handlers can tell why it went away.
+    pub(crate) fn timeout_check(&self) -> impl Fn() -> bool + Send + Sync + 'static {
+        let last_recv = self.last_recv.clone();
+        let recv_timeout = self.recv_timeout;
+        move || {
+            SystemTime::now()
+                .duration_since(*last_recv.lock())
+                .map(|elapsed| elapsed > recv_timeout)
+                .unwrap_or(false)
+        }
+    }
+
+    /// Gets a check for whether the other side said goodbye. Like `timeout_check`, the check outlives the session.
+    pub(crate) fn goodbye_check(&self) -> impl Fn() -> bool + Send + Sync + 'static {
+        let heard_goodbye = self.heard_goodbye.clone();
+        move || heard_goodbye.load(Ordering::Relaxed)
+    }
+
+    /// Keeps the session from saying goodbye when it's dropped, for when the other side is moving off it too rather than closing it.
+    pub(crate) fn skip_goodbye(&self) {
+        self.say_goodbye.store(false, Ordering::Relaxed)
+    }
+
+    /// Sets where the session gets its transport-level metadata from.
+    pub(crate) fn set_info_source<T: Fn() -> SessionInfo + Send + Sync + 'static>(
+        &mut self,
+        source: T,
+    ) {
+        self.info_source = Some(Box::new(source))
+    }
+
+    /// Gets transport-level metadata about the session. Only available for sessions accepted by a Listener.
+    pub fn info(&self) -> Option<SessionInfo> {
+        self.info_source.as_ref().map(|source| source())
+    }
+
+    /// Sets where the session gets the state of its shards from.
+    pub(crate) fn set_shard_source<T: Fn() -> Vec<ShardStats> + Send + Sync + 'static>(
+        &mut self,
+        source: T,
+    ) {
+        self.shard_source = Some(Box::new(source))
+    }
+
+    /// Sets what is told the nonce of every echo reply that comes in.
+    pub(crate) fn set_echo_handler<T: Fn(u64) + Send + Sync + 'static>(&mut self, handler: T) {
+        self.echo_handler = Some(Box::new(handler))
+    }
+
+    /// Gets something that seals frames for this session, for sending them past the session, such as through a particular shard.
+    pub(crate) fn sealer(&self) -> FrameSealer {
+        self.sealer.clone()
+    }
+
+    /// Gets how many shards the session is currently sending through, leaving out those sitting out with a bad path. Only available for sessions created by a client.
+    pub fn active_shards(&self) -> Option<usize> {
+        self.shard_stats()
+            .map(|shards| shards.iter().filter(|s| s.active && !s.benched).count())
+    }
+
+    /// Gets how each of the session's shards is doing. Only available for sessions created by a client.
+    pub fn shard_stats(&self) -> Option<Vec<ShardStats>> {
+        self.shard_source.as_ref().map(|source| source())
+    }
+
+    /// Gets how many packets are currently queued inside the session, in each direction. This only reads a few counters, so it's cheap enough to call on every packet.
+    pub fn queue_occupancy(&self) -> QueueOccupancy {
+        QueueOccupancy {
+            send: self.send_tosend.len() + self.send_packet.len(),
+            recv: self.recv_packet.len() + self.machine_output.len(),
+        }
+    }
+
+    /// Gets the statistics gatherer, which tracks the loss of what we send.
+    pub(crate) fn stat_gatherer(&self) -> Arc<StatGatherer> {
+        self.machine.lock().get_gather()
+    }
+
     /// Takes a Bytes to be sent and stuffs it into the session.
     pub fn send_bytes(&self, to_send: Bytes) {
+        self.send_bytes_with_priority(to_send, Priority::Normal)
+    }
+
+    /// Like `send_bytes`, but the packet jumps ahead of any lower-priority packets still waiting to be sent. Only packets of the same or higher priority count towards how full the queue is when deciding whether to drop it.
+    pub fn send_bytes_with_priority(&self, to_send: Bytes, priority: Priority) {
         let rate = self.rate_limit.load(Ordering::Relaxed);
         // if rate < 1000 {
         // RED with max 250ms latency
         let max_queue_length = rate / 2;
-        let fill_ratio = self.send_tosend.len() as f64 / max_queue_length as f64;
+        let fill_ratio = self.send_tosend.len_ahead_of(priority) as f64 / max_queue_length as f64;
         if rand::random::<f64>() < fill_ratio.powi(2) {
             // tracing::warn!("RED dropping packet (fill ratio {:.3})", fill_ratio);
             return;
         }
         // }
-        if let Err(err) = self.send_tosend.try_send(to_send) {
+        if let Err(err) = self.send_tosend.try_send(to_send, priority) {
             if let TrySendError::Closed(_) = err {
                 self.recv_packet.close();
             } else {
@@ -133,6 +278,7 @@ impl Session {
                             - (raw_stat.total_recv_frames() as f64
                                 / raw_stat.high_recv_frame_no() as f64)
                                 .min(1.0),
+                        send_loss: raw_stat.loss(),
                         ping: raw_stat.ping(),
                     };
                     self.statistics.lock().push(stat);
@@ -143,7 +289,30 @@ impl Session {
             let frame = self.recv_packet.recv().timeout(self.recv_timeout).await;
             if let Some(frame) = frame {
                 let frame = frame.ok()?;
-                let out = self.machine.lock().process(&frame);
+                let (out, (echo_requests, echo_replies), goodbye) = {
+                    let mut machine = self.machine.lock();
+                    (
+                        machine.process(&frame),
+                        machine.take_echoes(),
+                        machine.said_goodbye(),
+                    )
+                };
+                if goodbye {
+                    tracing::debug!("[{}] other side said goodbye", self.id);
+                    self.heard_goodbye.store(true, Ordering::Relaxed);
+                    self.recv_packet.close();
+                    return None;
+                }
+                for nonce in echo_requests {
+                    if let Some(reply) = self.sealer.seal(&DataFrameV2::EchoReply { nonce }) {
+                        let _ = self.send_packet.try_send(reply);
+                    }
+                }
+                if let Some(handler) = self.echo_handler.as_ref() {
+                    for nonce in echo_replies {
+                        handler(nonce)
+                    }
+                }
                 if let Some(out) = out {
                     for o in out {
                         self.machine_output.push(o).unwrap();
@@ -163,6 +332,11 @@ impl Session {
         self.rate_limit.store(pps, Ordering::Relaxed);
     }
 
+    /// Gets the rate limit, in packets per second.
+    pub fn ratelimit(&self) -> u32 {
+        self.rate_limit.load(Ordering::Relaxed)
+    }
+
     /// Gets the statistics.
     pub fn all_stats(&self) -> Vec<SessionStat> {
         self.statistics.lock().items().iter().cloned().collect()
@@ -174,10 +348,56 @@ impl Session {
     }
 }
 
+impl Drop for Session {
+    fn drop(&mut self) {
+        // tell the other side, so that it doesn't wait out its receive timeout
+        if self.say_goodbye.load(Ordering::Relaxed) && !self.heard_goodbye.load(Ordering::Relaxed) {
+            if let Some(goodbye) = self.sealer.seal(&DataFrameV2::Goodbye) {
+                let _ = self.send_packet.try_send(goodbye);
+            }
+        }
+        for dropper in self._dropper.drain(..) {
+            dropper()
+        }
+    }
+}
+
+/// Encrypts version-2 frames under a session's sending keys.
+#[derive(Clone)]
+pub(crate) struct FrameSealer {
+    version: u64,
+    send_crypt_legacy: LegacyAEAD,
+    send_crypt_ng: NgAEAD,
+}
+
+impl FrameSealer {
+    fn new(cfg: &SessionConfig) -> Self {
+        FrameSealer {
+            version: cfg.version,
+            send_crypt_legacy: cfg.send_crypt_legacy,
+            send_crypt_ng: cfg.send_crypt_ng.clone(),
+        }
+    }
+
+    /// Pads and encrypts a frame, or returns None if the session's version doesn't use version-2 frames.
+    pub fn seal(&self, frame: &DataFrameV2) -> Option<Bytes> {
+        let padded = frame.pad();
+        match self.version {
+            2 => Some(
+                self.send_crypt_legacy
+                    .encrypt(&padded, rand::thread_rng().gen()),
+            ),
+            3 | 4 => Some(self.send_crypt_ng.encrypt(&padded)),
+            _ => None,
+        }
+    }
+}
+
 struct SessionSendCtx {
     cfg: SessionConfig,
+    sealer: FrameSealer,
     statg: Arc<StatGatherer>,
-    recv_tosend: Receiver<Bytes>,
+    recv_tosend: PriorityReceiver<Bytes>,
     rate_limit: Arc<AtomicU32>,
     recv_timeout: Duration,
     last_recv: Arc<Mutex<SystemTime>>,
@@ -209,7 +429,7 @@ async fn session_send_loop_v1(ctx: SessionSendCtx) -> Option<()> {
     );
 
     let policy_limiter = RateLimiter::direct_with_clock(
-        Quota::per_second(NonZeroU32::new(25600u32).unwrap()),
+        Quota::per_second(NonZeroU32::new(PACING_UNITS).unwrap()),
         &governor::clock::MonotonicClock,
     );
     let mut encoder = FrameEncoder::new(4);
@@ -217,9 +437,9 @@ async fn session_send_loop_v1(ctx: SessionSendCtx) -> Option<()> {
         // obtain a vector of bytes to send
         let loss = ctx.statg.loss_u8();
         to_send.clear();
-        to_send.push(ctx.recv_tosend.recv().await.ok()?);
+        to_send.push(ctx.recv_tosend.recv().await.ok()?.1);
         while to_send.len() < BURST_SIZE {
-            if let Ok(val) = ctx.recv_tosend.try_recv() {
+            if let Some((_, val)) = ctx.recv_tosend.try_recv() {
                 to_send.push(val)
             } else {
                 break;
@@ -245,13 +465,12 @@ async fn session_send_loop_v1(ctx: SessionSendCtx) -> Option<()> {
         for (idx, bts) in encoded.iter().enumerate() {
             // limit
             let limit = ctx.rate_limit.load(Ordering::Relaxed);
-            if limit < 1000 {
+            if limit < THROTTLE_BELOW {
                 if ctx.recv_tosend.len() > 100 {
                     continue;
                 }
-                let multiplier = 25600 / limit;
                 while let Err(NegativeMultiDecision::BatchNonConforming(_, err)) =
-                    policy_limiter.check_n(NonZeroU32::new(multiplier).unwrap())
+                    policy_limiter.check_n(pacing_cost(limit))
                 {
                     smol::Timer::at(err.earliest_possible()).await;
                 }
@@ -296,9 +515,10 @@ async fn session_send_loop_nextgen(ctx: SessionSendCtx, version: u64) -> Option<
         FecTimeout,
     }
 
+    // bursts of up to a twentieth of a second
     let policy_limiter = RateLimiter::direct_with_clock(
-        Quota::per_second(NonZeroU32::new(25600).unwrap())
-            .allow_burst(NonZeroU32::new(1280).unwrap()),
+        Quota::per_second(NonZeroU32::new(PACING_UNITS).unwrap())
+            .allow_burst(NonZeroU32::new(PACING_UNITS / MIN_PACED_LIMIT).unwrap()),
         &governor::clock::MonotonicClock,
     );
 
@@ -335,16 +555,15 @@ async fn session_send_loop_nextgen(ctx: SessionSendCtx, version: u64) -> Option<
             }
             Some(Event::FecTimeout)
         }
-        .or(async { Some(Event::NewPayload(ctx.recv_tosend.recv().await.ok()?)) })
+        .or(async { Some(Event::NewPayload(ctx.recv_tosend.recv().await.ok()?.1)) })
         .await;
         match event? {
             // we have something to send as a data packet.
             Event::NewPayload(send_payload) => {
                 let limit = ctx.rate_limit.load(Ordering::Relaxed);
-                if limit < 1000 {
-                    let multiplier = 25600 / limit;
+                if limit < THROTTLE_BELOW {
                     while let Err(NegativeMultiDecision::BatchNonConforming(_, err)) =
-                        policy_limiter.check_n(NonZeroU32::new(multiplier).unwrap())
+                        policy_limiter.check_n(pacing_cost(limit))
                     {
                         smol::Timer::at(err.earliest_possible()).await;
                     }
@@ -360,16 +579,8 @@ async fn session_send_loop_nextgen(ctx: SessionSendCtx, version: u64) -> Option<
                 };
                 // we now add to unfecked
                 unfecked.push((frame_no, send_payload));
-                let send_padded = send_framed.pad();
                 ctx.statg.ping_send(frame_no);
-                let send_encrypted = match version {
-                    2 => ctx
-                        .cfg
-                        .send_crypt_legacy
-                        .encrypt(&send_padded, rand::thread_rng().gen()),
-                    3 => ctx.cfg.send_crypt_ng.encrypt(&send_padded),
-                    _ => return None,
-                };
+                let send_encrypted = ctx.sealer.seal(&send_framed)?;
                 ctx.cfg.send_packet.send(send_encrypted).await.ok()?;
 
                 // increment frame no
@@ -385,7 +596,7 @@ async fn session_send_loop_nextgen(ctx: SessionSendCtx, version: u64) -> Option<
                     continue;
                 }
                 let measured_loss = ctx.statg.loss_u8();
-                if measured_loss == 0 {
+                if fec_encoder.parity_len(measured_loss, unfecked.len()) == 0 {
                     unfecked.clear();
                     continue;
                 }
@@ -394,7 +605,7 @@ async fn session_send_loop_nextgen(ctx: SessionSendCtx, version: u64) -> Option<
                 let first_frame_no = unfecked[0].0;
                 let data_count = unfecked.len();
                 let expanded = fec_encoder.encode(
-                    ctx.statg.loss_u8(),
+                    measured_loss,
                     &unfecked.iter().map(|v| v.1.clone()).collect::<Vec<_>>(),
                 );
                 let pad_size = unfecked.iter().map(|v| v.1.len()).max().unwrap_or_default() + 2;
@@ -412,15 +623,7 @@ async fn session_send_loop_nextgen(ctx: SessionSendCtx, version: u64) -> Option<
                         body: parity.clone(),
                         pad_size,
                     };
-                    let send_padded = send_framed.pad();
-                    let send_encrypted = match version {
-                        2 => ctx
-                            .cfg
-                            .send_crypt_legacy
-                            .encrypt(&send_padded, rand::thread_rng().gen()),
-                        3 => ctx.cfg.send_crypt_ng.encrypt(&send_padded),
-                        _ => return None,
-                    };
+                    let send_encrypted = ctx.sealer.seal(&send_framed)?;
                     ctx.cfg.send_packet.send(send_encrypted).await.ok()?;
                 }
             }
@@ -428,6 +631,50 @@ async fn session_send_loop_nextgen(ctx: SessionSendCtx, version: u64) -> Option<
     }
 }
 
+/// How many packets are queued inside a session.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QueueOccupancy {
+    /// Packets given to `send_bytes` that haven't been handed to the transport yet, whether still waiting to be encoded or already encoded.
+    pub send: usize,
+    /// Packets from the transport that haven't been taken by `recv_bytes` yet, whether still waiting to be decoded or already decoded.
+    pub recv: usize,
+}
+
+/// How one shard of a client session is doing.
+#[derive(Clone, Copy, Debug)]
+pub struct ShardStats {
+    pub shard_id: u8,
+    /// Whether the shard is among those the session currently spreads its packets over.
+    pub active: bool,
+    /// Whether the shard is sitting out for a while, because it was getting far fewer packets than the others.
+    pub benched: bool,
+    /// Packets received through the shard.
+    pub packets_in: u64,
+    /// Packets sent through the shard.
+    pub packets_out: u64,
+    /// Smoothed round trip of the shard's own path, as timed by echoes sent through it, if measured yet.
+    pub rtt: Option<Duration>,
+    /// Estimated fraction of the shard's packets lost.
+    pub loss: f64,
+    /// Packets per second the shard's congestion controller currently allows. The session spreads its packets over the shards in use in proportion to this.
+    pub bandwidth: f64,
+}
+
+/// Transport-level metadata about a session accepted by a Listener.
+#[derive(Clone, Debug)]
+pub struct SessionInfo {
+    /// Remote addresses of all the shards of the session.
+    pub remote_addrs: Vec<SocketAddr>,
+    /// Protocol version negotiated by the client.
+    pub version: u64,
+    /// Raw bytes received from the client, including overhead.
+    pub bytes_in: u64,
+    /// Raw bytes sent to the client, including overhead.
+    pub bytes_out: u64,
+    /// How long ago the session was established.
+    pub age: Duration,
+}
+
 /// Session stat
 #[derive(Copy, Clone, Debug)]
 pub struct SessionStat {
@@ -436,5 +683,27 @@ pub struct SessionStat {
     pub total_recv: u64,
     // pub total_parity: u64,
     pub total_loss: f64,
+    /// Recent loss of the packets we sent, as reported by the other side.
+    pub send_loss: f64,
     pub ping: Duration,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pacing_follows_limits() {
+        // every limit that throttles at all is paced to within a few percent
+        for limit in (MIN_PACED_LIMIT..THROTTLE_BELOW).step_by(7) {
+            let pace = PACING_UNITS as f64 / pacing_cost(limit).get() as f64;
+            assert!(
+                pace >= limit as f64 && pace < limit as f64 * 1.07,
+                "{}",
+                limit
+            );
+        }
+        // tiny limits pace at the lowest rate honored, rather than not at all
+        assert_eq!(pacing_cost(1), pacing_cost(MIN_PACED_LIMIT));
+    }
+}
@@ -21,6 +21,12 @@ pub struct RecvMachine {
     recv_crypt_ng: NgAEAD,
     replay_filter: ReplayFilter,
     ping_calc: Arc<StatGatherer>,
+    /// Highest nonce of an echo request answered so far.
+    echo_high: u64,
+    echo_requests: Vec<u64>,
+    echo_replies: Vec<u64>,
+    /// Whether the other side said goodbye.
+    goodbye: bool,
 }
 
 impl RecvMachine {
@@ -34,6 +40,10 @@ impl RecvMachine {
             recv_crypt_ng,
             replay_filter: ReplayFilter::default(),
             ping_calc: Default::default(),
+            echo_high: 0,
+            echo_requests: Vec::new(),
+            echo_replies: Vec::new(),
+            goodbye: false,
         }
     }
 
@@ -76,7 +86,7 @@ impl RecvMachine {
     fn process_ng(&mut self, packet: &[u8]) -> Option<Vec<Bytes>> {
         let plain_frame = match self.version {
             2 => self.recv_crypt_legacy.decrypt(packet)?,
-            3 => self.recv_crypt_ng.decrypt(packet)?,
+            3 | 4 => self.recv_crypt_ng.decrypt(packet)?,
             _ => return None,
         };
         let v2frame = DataFrameV2::depad(&plain_frame)?;
@@ -126,9 +136,37 @@ impl RecvMachine {
                     None
                 }
             }
+            DataFrameV2::EchoRequest { nonce } => {
+                if nonce > self.echo_high {
+                    self.echo_high = nonce;
+                    self.echo_requests.push(nonce);
+                }
+                None
+            }
+            DataFrameV2::EchoReply { nonce } => {
+                self.echo_replies.push(nonce);
+                None
+            }
+            DataFrameV2::Goodbye => {
+                self.goodbye = true;
+                None
+            }
         }
     }
 
+    /// Takes the nonces of echo requests to answer, and of echo replies that came in, since the last call.
+    pub fn take_echoes(&mut self) -> (Vec<u64>, Vec<u64>) {
+        (
+            std::mem::take(&mut self.echo_requests),
+            std::mem::take(&mut self.echo_replies),
+        )
+    }
+
+    /// Whether the other side said goodbye, after which nothing more will come from it.
+    pub fn said_goodbye(&self) -> bool {
+        self.goodbye
+    }
+
     /// Retrieves the inner stat gatherer.
     pub fn get_gather(&self) -> Arc<StatGatherer> {
         self.ping_calc.clone()
@@ -5,20 +5,23 @@ use smol::channel::{Receiver, Sender};
 use smol::prelude::*;
 use std::{
     collections::VecDeque,
-    convert::TryInto,
     net::{Shutdown, SocketAddr},
     time::{Duration, SystemTime},
 };
 
 use crate::{
     crypt::{triple_ecdh, Cookie, NgAEAD},
+    listener::HandshakeRng,
     protocol::HandshakeFrame,
     runtime, Backhaul,
 };
 use anyhow::Context;
 use smol_timeout::TimeoutExt;
 
-use super::{read_encrypted, write_encrypted, ObfsTCP, CONN_LIFETIME, TCP_DN_KEY, TCP_UP_KEY};
+use super::{
+    read_encrypted, write_encrypted, ObfsTCP, TcpFraming, TcpOptions, CONN_LIFETIME,
+    PADDED_FRAMING_MARKER, TCP_DN_KEY, TCP_UP_KEY,
+};
 
 /// A TCP-based backhaul, client-side.
 pub struct TcpClientBackhaul {
@@ -27,6 +30,7 @@ pub struct TcpClientBackhaul {
     fake_addr: u128,
     incoming: Receiver<(Bytes, SocketAddr)>,
     send_incoming: Sender<(Bytes, SocketAddr)>,
+    options: TcpOptions,
 }
 
 impl TcpClientBackhaul {
@@ -41,9 +45,16 @@ impl TcpClientBackhaul {
             fake_addr,
             incoming,
             send_incoming,
+            options: TcpOptions::default(),
         }
     }
 
+    /// Sets how connections opened from now on are tuned.
+    pub fn with_options(mut self, options: TcpOptions) -> Self {
+        self.options = options;
+        self
+    }
+
     /// Adds a binding.
     pub fn add_remote_key(mut self, addr: SocketAddr, key: x25519_dalek::PublicKey) -> Self {
         self.dest_to_key.insert(addr, key);
@@ -75,77 +86,91 @@ impl TcpClientBackhaul {
         if let Some(pooled) = self.get_conn_pooled(addr) {
             Ok(pooled)
         } else {
-            let my_long_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
-            let my_eph_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
-
             let pubkey = *self
                 .dest_to_key
                 .get(&addr)
                 .ok_or_else(|| anyhow::anyhow!("remote address doesn't have a public key"))?;
-            let cookie = Cookie::new(pubkey);
             // first connect
-            let mut remote = smol::net::TcpStream::connect(addr).await?;
-            remote.set_nodelay(true)?;
-            // then we send a hello
-            let init_c2s = cookie.generate_c2s().next().unwrap();
-            let init_s2c = cookie.generate_s2c().next().unwrap();
-            let init_up_key = blake3::keyed_hash(&TCP_UP_KEY, &init_c2s);
-            let init_enc = NgAEAD::new(init_up_key.as_bytes());
-            let to_send = HandshakeFrame::ClientHello {
-                long_pk: (&my_long_sk).into(),
-                eph_pk: (&my_eph_sk).into(),
-                version: 3,
-            };
-            let mut to_send = to_send.to_bytes();
-            let random_padding = vec![0u8; rand::random::<usize>() % 1024];
-            to_send.extend_from_slice(&random_padding);
-            write_encrypted(init_enc, &to_send, &mut remote).await?;
-            // now we wait for a response
-            let init_dn_key = blake3::keyed_hash(&TCP_DN_KEY, &init_s2c);
-            let init_dec = NgAEAD::new(init_dn_key.as_bytes());
-            let raw_response = read_encrypted(init_dec, &mut remote)
-                .await
-                .context("can't read response from server")?;
-            let actual_response = HandshakeFrame::from_bytes(&raw_response)?;
-            if let HandshakeFrame::ServerHello {
-                long_pk,
-                eph_pk,
-                resume_token: _,
-            } = actual_response
-            {
-                let shared_sec = triple_ecdh(&my_long_sk, &my_eph_sk, &long_pk, &eph_pk);
-                let connection = ObfsTCP::new(shared_sec, false, remote);
-                connection.write(&self.fake_addr.to_be_bytes()).await?;
-                let down_conn = connection.clone();
-                let send_incoming = self.send_incoming.clone();
-                // spawn a thread that reads from the connection
-                runtime::spawn(async move {
-                    let mut buffer = [0u8; 65536];
-                    let main = async {
-                        loop {
-                            down_conn.read_exact(&mut buffer[..2]).await?;
-                            let length =
-                                u16::from_be_bytes((&buffer[..2]).try_into().unwrap()) as usize;
-                            down_conn.read_exact(&mut buffer[..length]).await?;
-                            send_incoming
-                                .send((Bytes::copy_from_slice(&buffer[..length]), addr))
-                                .await?;
-                        }
-                    };
-                    let _: anyhow::Result<()> = main
-                        .or(async {
-                            smol::Timer::after(CONN_LIFETIME).await;
-                            Ok(())
-                        })
-                        .await;
-                })
-                .detach();
+            let mut remote = self.options.connect(addr).await?;
+            let (shared_sec, padded) = client_handshake(&mut remote, pubkey).await?;
+            let connection = ObfsTCP::new(shared_sec, false, padded, remote);
+            connection.write(&self.fake_addr.to_be_bytes()).await?;
+            let down_conn = connection.clone();
+            let send_incoming = self.send_incoming.clone();
+            // spawn a thread that reads from the connection
+            runtime::spawn(async move {
+                let mut buffer = [0u8; 65536];
+                let main = async {
+                    loop {
+                        let length = down_conn.read_datagram(&mut buffer).await?;
+                        send_incoming
+                            .send((Bytes::copy_from_slice(&buffer[..length]), addr))
+                            .await?;
+                    }
+                };
+                let _: anyhow::Result<()> = main
+                    .or(async {
+                        smol::Timer::after(CONN_LIFETIME).await;
+                        Ok(())
+                    })
+                    .await;
+            })
+            .detach();
 
-                Ok((connection, SystemTime::now()))
-            } else {
-                anyhow::bail!("server sent unrecognizable message")
-            }
+            Ok((connection, SystemTime::now()))
+        }
+    }
+}
+
+/// Sends a hello over a fresh connection and reads the server's answer. Returns the shared secret, and whether the server agreed to padded framing.
+pub(crate) async fn client_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    remote: &mut S,
+    pubkey: x25519_dalek::PublicKey,
+) -> anyhow::Result<(blake3::Hash, bool)> {
+    let my_long_sk = HandshakeRng::Os.x25519_secret();
+    let my_eph_sk = HandshakeRng::Os.x25519_secret();
+    let cookie = Cookie::new(pubkey);
+    let init_c2s = cookie.generate_c2s().next().unwrap();
+    let init_s2c = cookie.generate_s2c().next().unwrap();
+    let init_up_key = blake3::keyed_hash(&TCP_UP_KEY, &init_c2s);
+    let init_enc = NgAEAD::new(init_up_key.as_bytes());
+    let to_send = HandshakeFrame::ClientHello {
+        long_pk: (&my_long_sk).into(),
+        eph_pk: (&my_eph_sk).into(),
+        version: 3,
+    };
+    let mut to_send = to_send.to_bytes();
+    let want_padded = TcpFraming::get() == TcpFraming::Padded;
+    if want_padded {
+        to_send.extend_from_slice(PADDED_FRAMING_MARKER);
+    }
+    let random_padding = vec![0u8; rand::random::<usize>() % 1024];
+    to_send.extend_from_slice(&random_padding);
+    write_encrypted(init_enc, &to_send, remote).await?;
+    // now we wait for a response
+    let init_dn_key = blake3::keyed_hash(&TCP_DN_KEY, &init_s2c);
+    let init_dec = NgAEAD::new(init_dn_key.as_bytes());
+    let raw_response = read_encrypted(init_dec, remote)
+        .await
+        .context("can't read response from server")?;
+    let actual_response = HandshakeFrame::from_bytes(&raw_response)?;
+    if let HandshakeFrame::ServerHello {
+        long_pk,
+        eph_pk,
+        resume_token,
+    } = actual_response
+    {
+        // older servers don't know about padded framing, and send back an empty token
+        let padded = want_padded && resume_token[..] == *PADDED_FRAMING_MARKER;
+        if want_padded && !padded {
+            tracing::debug!("server doesn't support padded framing");
         }
+        Ok((
+            triple_ecdh(&my_long_sk, &my_eph_sk, &long_pk, &eph_pk),
+            padded,
+        ))
+    } else {
+        anyhow::bail!("server sent unrecognizable message")
     }
 }
 
@@ -157,16 +182,13 @@ impl Backhaul for TcpClientBackhaul {
             return Ok(());
         }
 
-        let mut buf = [0u8; 4096];
-        buf[0..2].copy_from_slice(&(to_send.len() as u16).to_be_bytes());
-        buf[2..to_send.len() + 2].copy_from_slice(&to_send);
         let res: anyhow::Result<()> = async {
             let (conn, time) = self
                 .get_conn(dest)
                 .timeout(Duration::from_secs(1))
                 .await
                 .ok_or_else(|| anyhow::anyhow!("timeout"))??;
-            conn.write(&buf[..to_send.len() + 2])
+            conn.write_datagrams(&[&to_send])
                 .or(async {
                     Err(std::io::Error::new(
                         std::io::ErrorKind::TimedOut,
@@ -1,17 +1,25 @@
-use std::{convert::TryInto, time::Duration};
-
-use async_dup::Arc;
+use std::{
+    convert::TryInto,
+    str::FromStr,
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+    time::Duration,
+};
 
 use bytes::Bytes;
 use c2_chacha::{stream_cipher::NewStreamCipher, stream_cipher::SyncStreamCipher, ChaCha8};
 
-use parking_lot::Mutex;
+use rand::Rng;
 
 use smol::prelude::*;
-use smol::{io::BufReader, net::TcpStream};
+use smol::{
+    io::{BufReader, ReadHalf, WriteHalf},
+    net::TcpStream,
+};
 
 mod client;
 pub use client::*;
+mod options;
+pub use options::TcpOptions;
 mod server;
 pub use server::*;
 
@@ -22,72 +30,247 @@ const CONN_LIFETIME: Duration = Duration::from_secs(600);
 const TCP_UP_KEY: &[u8; 32] = b"uploadtcp-----------------------";
 const TCP_DN_KEY: &[u8; 32] = b"downloadtcp---------------------";
 
+/// Length fields of padding frames have this bit set. Datagrams are never that long, so padding frames can't be mistaken for them, and are skipped whatever the framing.
+const PADDING_FLAG: u16 = 0x8000;
+
+/// Largest padding frame, not counting its length field.
+const MAX_PADDING: usize = 256;
+
+/// Clients that want padded framing put this right after their hello, and servers that agree send it back as the resume token of their hello.
+const PADDED_FRAMING_MARKER: &[u8] = b"sosistab-padded-framing";
+
+static TCP_FRAMING: AtomicU8 = AtomicU8::new(0);
+static PAYLOAD_BYTES: AtomicU64 = AtomicU64::new(0);
+static PADDING_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// How datagrams are framed in TCP connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpFraming {
+    /// Each datagram is written by itself, behind its length.
+    Plain,
+    /// Datagrams are mixed with padding frames, and written in randomly sized pieces, so that TCP segments don't show the sizes of the datagrams.
+    Padded,
+}
+
+impl TcpFraming {
+    /// Sets the framing asked for by every TCP client created from now on. Servers use padded framing with clients that ask for it, and plain framing with the rest, so this only matters for clients.
+    pub fn set(self) {
+        TCP_FRAMING.store(self as u8, Ordering::Relaxed)
+    }
+
+    /// Gets the framing used in this process.
+    pub fn get() -> Self {
+        match TCP_FRAMING.load(Ordering::Relaxed) {
+            0 => TcpFraming::Plain,
+            _ => TcpFraming::Padded,
+        }
+    }
+}
+
+impl FromStr for TcpFraming {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(TcpFraming::Plain),
+            "padded" => Ok(TcpFraming::Padded),
+            other => Err(format!(
+                "unknown TCP framing {:?} (expected plain or padded)",
+                other
+            )),
+        }
+    }
+}
+
+/// The fraction of the bytes sent over padded TCP connections that were padding, since the process started.
+pub fn tcp_padding_overhead() -> f64 {
+    let padding = PADDING_BYTES.load(Ordering::Relaxed) as f64;
+    let payload = PAYLOAD_BYTES.load(Ordering::Relaxed) as f64;
+    if padding + payload == 0.0 {
+        0.0
+    } else {
+        padding / (padding + payload)
+    }
+}
+
 /// Wrapped TCP connection, with a send and receive obfuscation key.
 #[derive(Clone)]
 struct ObfsTCP {
     inner: TcpStream,
-    buf_read: async_dup::Arc<async_dup::Mutex<BufReader<TcpStream>>>,
-    send_chacha: Arc<Mutex<ChaCha8>>,
-    recv_chacha: Arc<Mutex<ChaCha8>>,
+    reader: async_dup::Arc<async_dup::Mutex<ObfsReader<BufReader<TcpStream>>>>,
+    writer: async_dup::Arc<async_dup::Mutex<ObfsWriter<TcpStream>>>,
+    padded: bool,
 }
 
 impl ObfsTCP {
-    /// creates an ObfsTCP given a shared secret and direction
-    fn new(ss: blake3::Hash, is_server: bool, inner: TcpStream) -> Self {
-        let up_chacha = Arc::new(Mutex::new(
-            ChaCha8::new_var(
-                blake3::keyed_hash(&TCP_UP_KEY, ss.as_bytes()).as_bytes(),
-                &[0; 8],
-            )
-            .unwrap(),
-        ));
-        let dn_chacha = Arc::new(Mutex::new(
-            ChaCha8::new_var(
-                blake3::keyed_hash(&TCP_DN_KEY, ss.as_bytes()).as_bytes(),
-                &[0; 8],
-            )
-            .unwrap(),
-        ));
-        let buf_read = async_dup::Arc::new(async_dup::Mutex::new(BufReader::with_capacity(
-            65536,
-            inner.clone(),
-        )));
-        if is_server {
-            Self {
-                inner,
-                buf_read,
-                send_chacha: dn_chacha,
-                recv_chacha: up_chacha,
-            }
-        } else {
-            Self {
-                inner,
-                buf_read,
-                send_chacha: up_chacha,
-                recv_chacha: dn_chacha,
+    /// creates an ObfsTCP given a shared secret, direction and framing
+    fn new(ss: blake3::Hash, is_server: bool, padded: bool, inner: TcpStream) -> Self {
+        if crate::keylog_enabled() {
+            if let (Ok(local), Ok(peer)) = (inner.local_addr(), inner.peer_addr()) {
+                let (client, server) = if is_server {
+                    (peer, local)
+                } else {
+                    (local, peer)
+                };
+                let up_key = blake3::keyed_hash(&TCP_UP_KEY, ss.as_bytes());
+                let dn_key = blake3::keyed_hash(&TCP_DN_KEY, ss.as_bytes());
+                crate::keylog::log_tcp(client, server, up_key.as_bytes(), dn_key.as_bytes());
             }
         }
+        let (send_chacha, recv_chacha) = obfs_ciphers(ss, is_server);
+        let reader = ObfsReader {
+            inner: BufReader::with_capacity(65536, inner.clone()),
+            cipher: recv_chacha,
+        };
+        let writer = ObfsWriter {
+            inner: inner.clone(),
+            cipher: send_chacha,
+            padded,
+        };
+        Self {
+            inner,
+            reader: async_dup::Arc::new(async_dup::Mutex::new(reader)),
+            writer: async_dup::Arc::new(async_dup::Mutex::new(writer)),
+            padded,
+        }
     }
 
     async fn write(&self, msg: &[u8]) -> std::io::Result<()> {
-        assert!(msg.len() <= 2048);
-        let mut buf = [0u8; 2048];
-        let buf = &mut buf[..msg.len()];
-        buf.copy_from_slice(&msg);
-        self.send_chacha.lock().apply_keystream(buf);
-        let mut inner = self.inner.clone();
-        inner.write_all(buf).await?;
-        inner.flush().await?;
-        Ok(())
+        self.writer.lock().write(msg).await
+    }
+
+    async fn write_datagrams(&self, datagrams: &[&[u8]]) -> std::io::Result<()> {
+        self.writer.lock().write_datagrams(datagrams).await
+    }
+
+    async fn read_datagram(&self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        self.reader.lock().read_datagram(buf).await
     }
 
     async fn read_exact(&self, buf: &mut [u8]) -> std::io::Result<()> {
-        self.buf_read.lock().read_exact(buf).await?;
-        self.recv_chacha.lock().apply_keystream(buf);
+        self.reader.lock().read_exact(buf).await
+    }
+}
+
+/// The sending and receiving obfuscation ciphers of a connection, given its shared secret and which end we are.
+fn obfs_ciphers(ss: blake3::Hash, is_server: bool) -> (ChaCha8, ChaCha8) {
+    let up_key = blake3::keyed_hash(&TCP_UP_KEY, ss.as_bytes());
+    let dn_key = blake3::keyed_hash(&TCP_DN_KEY, ss.as_bytes());
+    let up_chacha = ChaCha8::new_var(up_key.as_bytes(), &[0; 8]).unwrap();
+    let dn_chacha = ChaCha8::new_var(dn_key.as_bytes(), &[0; 8]).unwrap();
+    if is_server {
+        (dn_chacha, up_chacha)
+    } else {
+        (up_chacha, dn_chacha)
+    }
+}
+
+/// The sending half of an obfuscated connection, over any stream.
+pub(crate) struct ObfsWriter<W> {
+    inner: W,
+    cipher: ChaCha8,
+    padded: bool,
+}
+
+impl<W: AsyncWrite + Unpin> ObfsWriter<W> {
+    async fn write(&mut self, msg: &[u8]) -> std::io::Result<()> {
+        let mut buf = msg.to_vec();
+        self.cipher.apply_keystream(&mut buf);
+        self.inner.write_all(&buf).await?;
+        self.inner.flush().await?;
+        Ok(())
+    }
+
+    /// Writes datagrams, each behind its length. With padded framing, a padding frame may follow them, and everything is written in up to three randomly sized pieces.
+    pub async fn write_datagrams(&mut self, datagrams: &[&[u8]]) -> std::io::Result<()> {
+        let mut buf = Vec::with_capacity(datagrams.iter().map(|d| d.len() + 2).sum());
+        for datagram in datagrams {
+            buf.extend_from_slice(&(datagram.len() as u16).to_be_bytes());
+            buf.extend_from_slice(datagram);
+        }
+        if !self.padded {
+            return self.write(&buf).await;
+        }
+        let payload_len = buf.len();
+        let cuts = {
+            let mut rng = rand::thread_rng();
+            if rng.gen() {
+                let padding = rng.gen_range(0, MAX_PADDING + 1);
+                buf.extend_from_slice(&(padding as u16 | PADDING_FLAG).to_be_bytes());
+                buf.resize(buf.len() + padding, 0);
+            }
+            let mut cuts = vec![rng.gen_range(1, buf.len()), rng.gen_range(1, buf.len())];
+            cuts.sort_unstable();
+            cuts.dedup();
+            cuts
+        };
+        PAYLOAD_BYTES.fetch_add(payload_len as u64, Ordering::Relaxed);
+        PADDING_BYTES.fetch_add((buf.len() - payload_len) as u64, Ordering::Relaxed);
+        let mut start = 0;
+        for cut in cuts.into_iter().chain(std::iter::once(buf.len())) {
+            self.write(&buf[start..cut]).await?;
+            start = cut;
+        }
+        Ok(())
+    }
+}
+
+/// The receiving half of an obfuscated connection, over any stream.
+pub(crate) struct ObfsReader<R> {
+    inner: R,
+    cipher: ChaCha8,
+}
+
+impl<R: AsyncRead + Unpin> ObfsReader<R> {
+    /// Reads the next datagram into the buffer, skipping any padding, and returns its length.
+    pub async fn read_datagram(&mut self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        loop {
+            let mut length = [0u8; 2];
+            self.read_exact(&mut length).await?;
+            let length = u16::from_be_bytes(length);
+            if length & PADDING_FLAG != 0 {
+                let mut padding = vec![0u8; (length & !PADDING_FLAG) as usize];
+                self.read_exact(&mut padding).await?;
+                continue;
+            }
+            let length = length as usize;
+            if length > buf.len() {
+                anyhow::bail!("got a packet that's too long ({})", length)
+            }
+            self.read_exact(&mut buf[..length]).await?;
+            return Ok(length);
+        }
+    }
+
+    async fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        self.inner.read_exact(buf).await?;
+        self.cipher.apply_keystream(buf);
         Ok(())
     }
 }
 
+/// Runs the client side of the obfuscated TCP protocol over a stream that is already connected to a TCP server backhaul, or to something that forwards to one. Returns the halves to exchange datagrams through.
+pub(crate) async fn obfs_connect<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    pubkey: x25519_dalek::PublicKey,
+) -> anyhow::Result<(ObfsReader<ReadHalf<S>>, ObfsWriter<WriteHalf<S>>)> {
+    let (ss, padded) = client_handshake(&mut stream, pubkey).await?;
+    let (send_chacha, recv_chacha) = obfs_ciphers(ss, false);
+    let (read, write) = smol::io::split(stream);
+    let mut writer = ObfsWriter {
+        inner: write,
+        cipher: send_chacha,
+        padded,
+    };
+    // the server tells connections apart by a random address they start with
+    writer.write(&rand::random::<u128>().to_be_bytes()).await?;
+    let reader = ObfsReader {
+        inner: read,
+        cipher: recv_chacha,
+    };
+    Ok((reader, writer))
+}
+
 async fn read_encrypted<R: AsyncRead + Unpin>(
     decrypt: NgAEAD,
     rdr: &mut R,
@@ -0,0 +1,133 @@
+use std::{net::SocketAddr, str::FromStr};
+
+use smol::net::TcpStream;
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// How the TCP backhaul tunes the sockets of the connections it opens.
+///
+/// The defaults suit interactive use, such as browsing, where every write should go out right away. Bulk transfers, such as downloads, go through fewer, fuller segments with Nagle's algorithm on and bigger buffers, at the cost of some latency on small writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpOptions {
+    /// Whether to set `TCP_NODELAY`, sending small writes right away rather than holding them back for up to a round trip to be coalesced.
+    pub nodelay: bool,
+    /// Socket buffer sizes, in bytes. None leaves them to the OS, which usually autotunes them.
+    pub send_buffer: Option<usize>,
+    pub recv_buffer: Option<usize>,
+    /// Largest TCP segment to send, for paths where big segments get dropped and path MTU discovery doesn't work. Only supported on Unix-like systems, and ignored elsewhere.
+    pub max_segment: Option<u32>,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        Self::interactive()
+    }
+}
+
+impl TcpOptions {
+    /// Every write goes out right away, and the OS picks the buffer sizes.
+    pub fn interactive() -> Self {
+        TcpOptions {
+            nodelay: true,
+            send_buffer: None,
+            recv_buffer: None,
+            max_segment: None,
+        }
+    }
+
+    /// Nagle's algorithm coalesces small writes, and 4 MiB buffers keep fast, distant links busy.
+    pub fn bulk() -> Self {
+        TcpOptions {
+            nodelay: false,
+            send_buffer: Some(4 * 1024 * 1024),
+            recv_buffer: Some(4 * 1024 * 1024),
+            max_segment: None,
+        }
+    }
+
+    /// Connects to the address with these options. The maximum segment size has to be set before connecting, so the socket is set up by hand.
+    pub(crate) async fn connect(self, addr: SocketAddr) -> std::io::Result<TcpStream> {
+        let socket = Socket::new(
+            match addr {
+                SocketAddr::V4(_) => Domain::ipv4(),
+                SocketAddr::V6(_) => Domain::ipv6(),
+            },
+            Type::stream(),
+            Some(Protocol::tcp()),
+        )?;
+        socket.set_nodelay(self.nodelay)?;
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(mss) = self.max_segment {
+            set_max_segment(&socket, mss)?;
+        }
+        // connect without blocking, so that dropping the future, say on a timeout, gives up on the connection
+        socket.set_nonblocking(true)?;
+        match socket.connect(&addr.into()) {
+            Ok(()) => (),
+            Err(err) if in_progress(&err) => (),
+            Err(err) => return Err(err),
+        }
+        let stream = smol::Async::new(socket.into_tcp_stream())?;
+        stream.writable().await?;
+        if let Some(err) = stream.get_ref().take_error()? {
+            return Err(err);
+        }
+        Ok(stream.into())
+    }
+}
+
+/// Whether a nonblocking connect failed only because it hasn't finished yet.
+fn in_progress(err: &std::io::Error) -> bool {
+    #[cfg(unix)]
+    if err.raw_os_error() == Some(libc::EINPROGRESS) {
+        return true;
+    }
+    err.kind() == std::io::ErrorKind::WouldBlock
+}
+
+#[cfg(unix)]
+fn set_max_segment(socket: &Socket, mss: u32) -> std::io::Result<()> {
+    use std::{convert::TryInto, os::unix::io::AsRawFd};
+    let mss: libc::c_int = mss
+        .try_into()
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "MSS too large"))?;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_MAXSEG,
+            &mss as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_max_segment(_socket: &Socket, mss: u32) -> std::io::Result<()> {
+    tracing::debug!("can't clamp the MSS to {} on this platform", mss);
+    Ok(())
+}
+
+impl FromStr for TcpOptions {
+    type Err = String;
+
+    /// Parses a workload, "interactive" or "bulk", into its options.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "interactive" => Ok(TcpOptions::interactive()),
+            "bulk" => Ok(TcpOptions::bulk()),
+            other => Err(format!(
+                "unknown TCP workload {:?} (expected interactive or bulk)",
+                other
+            )),
+        }
+    }
+}
@@ -15,12 +15,15 @@ use std::{
 
 use crate::{
     crypt::{triple_ecdh, Cookie, NgAEAD},
+    listener::HandshakeRng,
     protocol::HandshakeFrame,
     recfilter::RECENT_FILTER,
     runtime, Backhaul,
 };
 
-use super::{write_encrypted, ObfsTCP, CONN_LIFETIME, TCP_DN_KEY, TCP_UP_KEY};
+use super::{
+    write_encrypted, ObfsTCP, CONN_LIFETIME, PADDED_FRAMING_MARKER, TCP_DN_KEY, TCP_UP_KEY,
+};
 
 /// A TCP-based backhaul, server-side.
 pub struct TcpServerBackhaul {
@@ -115,10 +118,10 @@ async fn backhaul_one(
             let raw_hello = c2s_dec
                 .decrypt(&encrypted_hello)
                 .ok_or_else(|| anyhow::anyhow!("cannot decrypt hello"))?;
-            if !RECENT_FILTER.lock().check(&raw_hello) {
+            let real_hello = HandshakeFrame::from_bytes(&raw_hello)?;
+            if !RECENT_FILTER.check(real_hello.replay_scope(), &raw_hello) {
                 anyhow::bail!("hello failed replay check")
             }
-            let real_hello = HandshakeFrame::from_bytes(&raw_hello)?;
             // now the client has passed checks. we send back a server response using the downstream key.
             // there is an "attack" where the adversary can confuse the server and the client by replaying a different response to the client.
             // the client will be able to decrypt this, and will establish a session with bad info.
@@ -129,15 +132,23 @@ async fn backhaul_one(
                 version: 3,
             } = real_hello
             {
-                let my_eph_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+                // clients that want padded framing say so right after their hello, where older clients only have zeros
+                let hello_len = bincode::serialized_size(&real_hello)? as usize;
+                let padded =
+                    raw_hello[hello_len.min(raw_hello.len())..].starts_with(PADDED_FRAMING_MARKER);
+                let my_eph_sk = HandshakeRng::Os.x25519_secret();
                 let response = HandshakeFrame::ServerHello {
                     long_pk: (&seckey).into(),
                     eph_pk: (&my_eph_sk).into(),
-                    resume_token: Bytes::new(),
+                    resume_token: if padded {
+                        Bytes::from_static(PADDED_FRAMING_MARKER)
+                    } else {
+                        Bytes::new()
+                    },
                 };
                 write_encrypted(s2c_enc, &response.to_bytes(), &mut client).await?;
                 let ss = triple_ecdh(&seckey, &my_eph_sk, &long_pk, &eph_pk);
-                let obfs_tcp = ObfsTCP::new(ss, true, client);
+                let obfs_tcp = ObfsTCP::new(ss, true, padded, client);
                 let mut fake_addr = [0u8; 16];
                 obfs_tcp
                     .read_exact(&mut fake_addr)
@@ -164,33 +175,28 @@ async fn backhaul_one_inner(
         let mut buff = [0u8; 4096];
         loop {
             down_table.set(addr, send_down.clone());
-            obfs_tcp.read_exact(&mut buff[..2]).await?;
-            let length = u16::from_be_bytes(
-                (&buff[..2])
-                    .try_into()
-                    .context("length not right size? wtf?")?,
-            ) as usize;
-            if length > 4096 {
-                break Err(anyhow::anyhow!("got a packet that's too long ({})", length));
-            }
-            obfs_tcp.read_exact(&mut buff[..length]).await?;
+            let length = obfs_tcp.read_datagram(&mut buff).await?;
             send_upcoming
                 .send((Bytes::copy_from_slice(&buff[..length]), addr))
                 .await?;
         }
     };
     let dn_loop = async {
-        let mut buff = [0u8; 4098];
+        let mut batch: Vec<Bytes> = Vec::new();
         loop {
-            let down = recv_down.recv().await?;
-            if down.len() > 4096 {
+            batch.push(recv_down.recv().await?);
+            // with padded framing, whatever else is waiting goes out in the same writes, merging datagrams
+            if obfs_tcp.padded {
+                while let Ok(down) = recv_down.try_recv() {
+                    batch.push(down);
+                }
+            }
+            if batch.iter().any(|down| down.len() > 4096) {
                 break Err(anyhow::anyhow!("rejecting a down that's too long"));
             }
-            let length = down.len() as u16;
-            buff[..2].copy_from_slice(&length.to_be_bytes());
-            buff[2..2 + (length as usize)].copy_from_slice(&down);
-            let buff = &buff[..2 + (length as usize)];
-            obfs_tcp.write(buff).await?;
+            let datagrams: Vec<&[u8]> = batch.iter().map(|down| &down[..]).collect();
+            obfs_tcp.write_datagrams(&datagrams).await?;
+            batch.clear();
         }
     };
     up_loop.race(dn_loop).await